@@ -27,6 +27,24 @@ fn bench_parser(c: &mut Criterion) {
                                  let result = parser.process(input_pp).unwrap();
                                  black_box(result)
                              }));
+        #[cfg(feature = "threads")]
+        group.bench_function("rust-split-tape-parallel",
+                             |b| b.iter(|| {
+                                 let mut parser: Box<dyn parser::Stream<&[u8], Return = _>> = parser_parallel::streaming_from_visitor(rust_parser::SplitTapeVisitor::new, parser_parallel::ParallelConfig::default().with_chunk_size(1024 * 1024));
+                                 let result = parser.process_slice(input_pp).unwrap();
+                                 black_box(result)
+                             }));
+        // Streams copies of the input, so that the pooled chunk and output
+        // buffers are reused between iterations.
+        #[cfg(feature = "threads")]
+        {
+            let mut output = std::io::sink();
+            let mut parser = print::make_with_config(&mut output, parser_parallel::ParallelConfig::default().with_chunk_size(1024 * 1024));
+            group.bench_function("rust-print-parallel",
+                                 |b| b.iter(|| {
+                                     parser.process_streaming(&mut &input_pp[..]).unwrap()
+                                 }));
+        }
         group.finish();
     }
 
@@ -54,6 +72,24 @@ fn bench_parser(c: &mut Criterion) {
                                  let result = parser.process(input_mach).unwrap();
                                  black_box(result)
                              }));
+        #[cfg(feature = "threads")]
+        group.bench_function("rust-split-tape-parallel",
+                             |b| b.iter(|| {
+                                 let mut parser: Box<dyn parser::Stream<&[u8], Return = _>> = parser_parallel::streaming_from_visitor(rust_parser::SplitTapeVisitor::new, parser_parallel::ParallelConfig::default().with_chunk_size(1024 * 1024));
+                                 let result = parser.process_slice(input_mach).unwrap();
+                                 black_box(result)
+                             }));
+        // Streams copies of the input, so that the pooled chunk and output
+        // buffers are reused between iterations.
+        #[cfg(feature = "threads")]
+        {
+            let mut output = std::io::sink();
+            let mut parser = print::make_with_config(&mut output, parser_parallel::ParallelConfig::default().with_chunk_size(1024 * 1024));
+            group.bench_function("rust-print-parallel",
+                                 |b| b.iter(|| {
+                                     parser.process_streaming(&mut &input_mach[..]).unwrap()
+                                 }));
+        }
         group.finish();
     }
 }
@@ -12,9 +12,14 @@ fn main() {
     let args: Vec<&OsStr> = args.iter().map(|s| &s[..]).collect();
     let exec_worker = exec_parallel::ExecWorker::new(&prog, &args);
 
-    let mut stdin = utils::stdin();
     let mut stdout = utils::stdout();
 
     let mut parser = exec_parallel::make_parser(exec_worker, &mut stdout);
-    let () = parser.process_streaming(&mut stdin).unwrap();
+    match exec_parallel::flatten(utils::process_stdin(&mut parser)) {
+        Ok(_skipped_chunks) => (),
+        Err(e) => {
+            eprintln!("exec: {}", e);
+            std::process::exit(1);
+        },
+    }
 }
@@ -0,0 +1,85 @@
+use simd_sexp::*;
+use std::io::{Read, Write};
+
+const USAGE: &str = "usage: fmt [--width N] [--indent N] [--check] [FILE...]
+
+Reformats hand-written sexp files, keeping comments. Files are rewritten in
+place; with no FILE arguments, reads stdin and writes to stdout.
+
+  --width N    target line width (default 80)
+  --indent N   indentation of broken lists (default 1)
+  --check      do not write anything; exit with status 1 if any input
+               would be changed by formatting";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("fmt: {}\n\n{}", message, USAGE);
+    std::process::exit(2)
+}
+
+fn main() {
+    let mut config = format::Config::default();
+    let mut check = false;
+    let mut files = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| -> usize {
+            args.next()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_else(|| usage_error(&format!("{} expects a number", name)))
+        };
+        match arg.as_str() {
+            "--width" => config.width = number("--width"),
+            "--indent" => config.indent = number("--indent"),
+            "--check" => check = true,
+            "-h" | "--help" => { println!("{}", USAGE); return; },
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+
+    let mut would_change = false;
+
+    if files.is_empty() {
+        let mut input = Vec::new();
+        utils::stdin().read_to_end(&mut input).unwrap();
+        let output = format::format(&input[..], &config).unwrap_or_else(|e| {
+            eprintln!("fmt: <stdin>: {}", e);
+            std::process::exit(1)
+        });
+        if check {
+            would_change = output != input;
+        } else {
+            let mut stdout = utils::stdout();
+            stdout.write_all(&output[..]).unwrap();
+            stdout.flush().unwrap();
+        }
+    } else {
+        for file in files {
+            let input = std::fs::read(&file).unwrap_or_else(|e| {
+                eprintln!("fmt: {}: {}", file, e);
+                std::process::exit(1)
+            });
+            let output = format::format(&input[..], &config).unwrap_or_else(|e| {
+                eprintln!("fmt: {}: {}", file, e);
+                std::process::exit(1)
+            });
+            if output == input {
+                continue;
+            }
+            if check {
+                println!("{}", file);
+                would_change = true;
+            } else {
+                std::fs::write(&file, &output[..]).unwrap_or_else(|e| {
+                    eprintln!("fmt: {}: {}", file, e);
+                    std::process::exit(1)
+                });
+            }
+        }
+    }
+
+    if would_change {
+        std::process::exit(1);
+    }
+}
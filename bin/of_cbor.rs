@@ -0,0 +1,8 @@
+use simd_sexp::*;
+
+fn main() {
+    let mut stdout = utils::stdout();
+
+    let mut of_cbor = cbor::make_printer(&mut stdout);
+    let () = utils::process_stdin(&mut *of_cbor).unwrap();
+}
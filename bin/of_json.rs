@@ -0,0 +1,19 @@
+use simd_sexp::*;
+
+fn main() {
+    // Chunks are split at newlines, so threads need JSON Lines input.
+    let threads = match std::env::args().skip(1).collect::<Vec<_>>().as_slice() {
+        [] => false,
+        [lines] if lines == "--lines" => true,
+        _ => {
+            eprintln!("usage: of-json [--lines] < input.json");
+            eprintln!("       (with --lines, the input is JSON Lines and is parsed on several threads)");
+            std::process::exit(2);
+        },
+    };
+
+    let mut stdout = utils::stdout();
+
+    let mut of_json = of_json::make(&mut stdout, threads);
+    let () = utils::process_stdin(&mut *of_json).unwrap();
+}
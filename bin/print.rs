@@ -1,9 +1,8 @@
 use simd_sexp::*;
 
 fn main() {
-    let mut stdin = utils::stdin();
     let mut stdout = utils::stdout();
 
     let mut print = print::make(&mut stdout, true);
-    let () = print.process_streaming(&mut stdin).unwrap();
+    let () = utils::process_stdin(&mut *print).unwrap();
 }
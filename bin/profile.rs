@@ -146,7 +146,7 @@ fn main() {
                 let mut read = LoopReader::new(&input_pp[..], 4000);
                 let mut result = Vec::new();
                 let mut parser = exec_parallel::make_parser(exec_worker.clone(), &mut result);
-                let () = parser.process_streaming(&mut read).unwrap();
+                let _skipped_chunks = parser::Stream::process_streaming(&mut parser, &mut read).unwrap().unwrap();
                 std::mem::drop(parser);
                 criterion::black_box(result);
             }
@@ -158,7 +158,7 @@ fn main() {
                 let e = event_frame.start();
                 let mut result = Vec::new();
                 let mut parser = exec_parallel::make_parser(exec_worker.clone(), &mut result);
-                let () = parser.process_streaming(&mut read).unwrap();
+                let _skipped_chunks = parser::Stream::process_streaming(&mut parser, &mut read).unwrap().unwrap();
                 std::mem::drop(parser);
                 criterion::black_box(result);
                 std::mem::drop(e);
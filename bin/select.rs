@@ -1,21 +1,167 @@
 use simd_sexp::*;
 
+fn usage() -> ! {
+    eprintln!("usage: select --list-keys");
+    eprintln!("       select [--glob PATTERN]... [--regex PATTERN]... [--path PATH]... [--where PREDICATE]... [--json-lines | --tsv | --delimiter CHAR] [--quoting necessary|always|escape] [--null STRING] KEY...");
+    eprintln!("       (with --all-keys in place of KEY..., every key in the input is selected)");
+    eprintln!("       (policies: [--duplicate-keys first|last|list|error] [--missing-keys empty|null|skip|error] [--null-marker STRING])");
+    #[cfg(feature = "arrow")]
+    eprintln!("       select [--arrow | --arrow-stream] [--column-types TYPE,...] KEY...");
+    #[cfg(feature = "arrow")]
+    eprintln!("       (without --column-types, types are inferred from the first batch of rows, and later values must fit them)");
+    std::process::exit(2);
+}
+
+fn fail<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("select: {}", e);
+        std::process::exit(1)
+    })
+}
+
 fn main() {
-    let mut args = std::env::args();
+    let mut output_kind = select::OutputKind::Csv { atoms_as_sexps: false };
+    let mut delimited_options = None;
+    let mut filter: Option<select_filter::Predicate> = None;
+    #[cfg_attr(not(feature = "regex"), allow(unused_mut))]
+    let mut patterns = Vec::new();
+    let mut paths = Vec::new();
+    let mut duplicate_keys = None;
+    let mut missing_keys = None;
+    let mut null_marker = b"null".to_vec();
+    #[cfg(feature = "arrow")]
+    let mut column_types = None;
+    let mut select: Vec<Vec<u8>> = Vec::new();
+    let mut all_keys = false;
+    let mut list_keys = false;
 
-    args.next();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--all-keys" => all_keys = true,
+            "--list-keys" => list_keys = true,
+            #[cfg(feature = "regex")]
+            "--glob" | "--regex" => {
+                let pattern = args.next().unwrap_or_else(|| usage());
+                let key_pattern = if arg == "--glob" { select::KeyPattern::glob(&pattern) } else { select::KeyPattern::regex(&pattern) };
+                patterns.push(key_pattern.unwrap_or_else(|e| {
+                    eprintln!("select: {}: {}", e, pattern);
+                    std::process::exit(2);
+                }));
+            },
+            "--path" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                paths.push(select::PathSelector::parse(&path).unwrap_or_else(|e| {
+                    eprintln!("select: {}: {}", e, path);
+                    std::process::exit(2);
+                }));
+            },
+            "--where" => {
+                let predicate = args.next().unwrap_or_else(|| usage());
+                let predicate = select_filter::Predicate::parse(&predicate).unwrap_or_else(|e| {
+                    eprintln!("select: {}: {}", e, predicate);
+                    std::process::exit(2);
+                });
+                filter = Some(match filter {
+                    None => predicate,
+                    Some(filter) => select_filter::Predicate::And(Box::new(filter), Box::new(predicate)),
+                });
+            },
+            "--duplicate-keys" => {
+                duplicate_keys = Some(match args.next().as_deref() {
+                    Some("first") => select::DuplicateKeys::First,
+                    Some("last") => select::DuplicateKeys::Last,
+                    Some("list") => select::DuplicateKeys::List,
+                    Some("error") => select::DuplicateKeys::Error,
+                    _ => usage(),
+                });
+            },
+            "--missing-keys" => missing_keys = Some(args.next().unwrap_or_else(|| usage())),
+            "--null-marker" => null_marker = args.next().unwrap_or_else(|| usage()).into_bytes(),
+            "--json-lines" => output_kind = select::OutputKind::JsonLines,
+            "--tsv" => delimited_options = Some(select::DelimitedOptions::tsv()),
+            "--delimiter" => {
+                let delimiter = args.next().unwrap_or_else(|| usage());
+                match delimiter.as_bytes() {
+                    &[delimiter] => delimited_options.get_or_insert_with(select::DelimitedOptions::default).delimiter = delimiter,
+                    _ => usage(),
+                }
+            },
+            "--quoting" => {
+                let quoting = match args.next().as_deref() {
+                    Some("necessary") => select::Quoting::Necessary,
+                    Some("always") => select::Quoting::Always,
+                    Some("escape") => select::Quoting::Escape,
+                    _ => usage(),
+                };
+                delimited_options.get_or_insert_with(select::DelimitedOptions::default).quoting = quoting;
+            },
+            "--null" => {
+                let null = args.next().unwrap_or_else(|| usage());
+                delimited_options.get_or_insert_with(select::DelimitedOptions::default).null = null.into_bytes();
+            },
+            #[cfg(feature = "arrow")]
+            "--arrow" => output_kind = select::OutputKind::Arrow { format: select_arrow::Format::File, column_types: None },
+            #[cfg(feature = "arrow")]
+            "--arrow-stream" => output_kind = select::OutputKind::Arrow { format: select_arrow::Format::Stream, column_types: None },
+            #[cfg(feature = "arrow")]
+            "--column-types" => {
+                let types = args.next().unwrap_or_else(|| usage());
+                let types: Option<Vec<_>> = types.split(',').map(select_arrow::ColumnType::of_string).collect();
+                column_types = Some(types.unwrap_or_else(|| usage()));
+            },
+            _ if arg.starts_with("--") => usage(),
+            _ => select.push(arg.into_bytes()),
+        }
+    }
 
-    let select: Vec<Vec<u8>> = args.map(|s| s.as_bytes().to_owned()).collect();
-    let select = select.iter().map(|s| &s[..]);
+    if let Some(delimited_options) = delimited_options {
+        output_kind = select::OutputKind::Delimited(delimited_options);
+    }
+
+    #[cfg(feature = "arrow")]
+    if let select::OutputKind::Arrow { column_types: output_column_types, .. } = &mut output_kind {
+        if column_types.as_ref().is_some_and(|types: &Vec<_>| types.len() != select.len() + patterns.len() + paths.len()) {
+            eprintln!("select: --column-types needs one type per key, pattern and path");
+            std::process::exit(2);
+        }
+        *output_column_types = column_types;
+    }
+
+    if (all_keys || list_keys) && !select.is_empty() {
+        usage();
+    }
+
+    let missing_keys = missing_keys.map(|missing_keys| match missing_keys.as_str() {
+        "empty" => select::MissingKeys::Empty,
+        "null" => select::MissingKeys::Null(&null_marker[..]),
+        "skip" => select::MissingKeys::SkipRow,
+        "error" => select::MissingKeys::Error,
+        _ => usage(),
+    });
+
+    let options = select::Options { filter: filter.as_ref(), patterns: &patterns[..], paths: &paths[..], duplicate_keys, missing_keys };
 
-    let mut stdin = utils::stdin();
     let mut stdout = utils::stdout();
 
+    if list_keys {
+        let key_counts = fail(utils::process_stdin(&mut *select_schema::make_parser(true)));
+        fail(select_schema::print_key_counts(&key_counts, &mut stdout));
+        return;
+    }
+
+    if all_keys {
+        fail(select_schema::select_all_keys(&mut utils::stdin(), options, &mut stdout, output_kind, true));
+        return;
+    }
+
+    let select = select.iter().map(|s| &s[..]);
+
     /*
     let mut parser = parser::State::from_writing_stage2(select::Stage2::new(select, select::OutputCsv::new(false)), &mut stdout);
-    let () = parser.process_streaming(&mut stdin).unwrap();
+    let () = fail(parser.process_streaming(&mut stdin));
     */
 
-    let mut parser = select::make_parser(select, &mut stdout, select::OutputKind::Csv { atoms_as_sexps: false }, true);
-    let () = parser.process_streaming(&mut stdin).unwrap();
+    let mut parser = select::make_parser_with_options(select, options, &mut stdout, output_kind, true);
+    let () = fail(utils::process_stdin(&mut *parser));
 }
@@ -0,0 +1,799 @@
+use simd_sexp::*;
+use std::io::{Read, Write};
+
+type Input = std::io::BufReader<Box<dyn Read + Send>>;
+
+const USAGE: &str = "usage: sexp COMMAND [OPTION...] [FILE...]
+
+Commands:
+  print        print each top-level sexp on its own line
+  select       select fields of records as CSV, JSON Lines, etc.
+  exec         run a program over chunks of the input in parallel
+  validate     check that the input is well-formed
+  simplify     show where the structural characters of the input are
+  to-json, of-json, to-csexp, of-csexp, to-cbor, of-cbor, to-msgpack, of-msgpack
+               convert between formats
+
+Every command reads the given FILEs one after the other, or stdin if there
+are none (`-` also means stdin), and takes:
+  -i, --input FILE   read FILE (for commands that take other arguments)
+  --no-threads       parse on a single thread
+  -h, --help         show help for the command
+
+print, select and exec also take:
+  --workers N        parse on N worker threads, plus one each for input and
+                     output
+  --chunk-size BYTES split the input into chunks of at least BYTES
+  --adaptive-chunk-size
+                     resize chunks as we go to take about 10ms each
+  --progress         report bytes, records, throughput and worker
+                     utilisation on stderr every second
+The defaults come from the SIMD_SEXP_NUM_THREADS (counting the input and
+output threads), SIMD_SEXP_CHUNK_SIZE and SIMD_SEXP_ADAPTIVE_CHUNK_SIZE
+environment variables, if set.
+
+Run `sexp COMMAND --help` for the options of each command.";
+
+const SELECT_USAGE: &str = "usage: sexp select [OPTION...] KEY... [-- FILE...]
+       sexp select --list-keys [FILE]
+       sexp select --all-keys [OPTION...] [FILE]
+
+Selects the values of `(KEY value)` fields, one row per top-level record.
+
+Output:
+  --csv                   CSV with a header row (the default)
+  --atoms-as-sexps        in CSV, keep quoted atoms quoted
+  --values                the values of each record as a list
+  --labeled               the fields of each record as a list
+  --json-lines            one JSON object per record
+  --tsv                   tab-separated, with \\N for missing values
+  --delimiter CHAR        delimited by CHAR
+  --quoting necessary|always|escape
+  --null STRING           written for missing values in delimited output
+  --arrow, --arrow-stream Arrow IPC (with the arrow feature)
+  --column-types TYPE,... int, float, bool or string, per column; otherwise
+                          inferred from the first batch of rows, which later
+                          values must fit
+
+Selection:
+  --glob PATTERN          select every key matching PATTERN
+  --regex PATTERN         select every key matching PATTERN
+  --path PATH             select by position and tag, e.g. #1 or @Order/id
+  --where PREDICATE       only output records satisfying PREDICATE
+  --duplicate-keys first|last|list|error
+  --missing-keys empty|null|skip|error
+  --null-marker STRING    the value of missing keys with --missing-keys null
+  --list-keys             print every key in the input, with counts
+  --all-keys              select every key in the input
+  --follow                keep reading FILE as it grows (see `sexp print
+                          --help`)
+
+Also takes --workers, --chunk-size, --adaptive-chunk-size and --progress (see
+`sexp --help`).";
+
+const EXEC_USAGE: &str = "usage: sexp exec [OPTION...] PROG [ARG...]
+
+Splits the input into chunks at top-level sexp boundaries and pipes each
+chunk through a separate run of PROG, writing the outputs in order. Options
+must come before PROG.
+
+  --framing spawn|delimiter|length-prefix
+                      spawn PROG for every chunk (the default), or keep it
+                      running and frame chunks with a delimiter line (which
+                      PROG must copy to its output after each chunk's
+                      output) or a decimal length and newline (which PROG must
+                      also use in its replies)
+  --delimiter LINE    the delimiter line (default `(end-of-chunk)`)
+  --processes N       split each chunk between N runs of PROG
+  --retries N         rerun a chunk up to N times if PROG fails on it
+  --on-failure fail|skip
+                      stop at a chunk that PROG fails on (the default), or
+                      leave out its output and carry on
+  --reduce concat|sum|count|merge-sorted
+                      combine the outputs of the runs: write them in order
+                      (the default), the sum of their atoms, the number of
+                      sexps in them, or merge their sorted lines
+  --reducer COMMAND   pipe the outputs, in order, through the shell COMMAND
+
+PROG's stderr is passed through, in the order of the chunks. Exits with PROG's
+exit code if it fails on a chunk, or with 1 if chunks were skipped or there
+was another error.
+
+Also takes --workers, --chunk-size, --adaptive-chunk-size and --progress (see
+`sexp --help`).";
+
+const VALIDATE_USAGE: &str = "usage: sexp validate [--count] [FILE...]
+
+Checks each input separately, reporting the inputs that are not well-formed.
+  --count    print the number of top-level sexps in each input";
+
+const SIMPLIFY_USAGE: &str = "usage: sexp simplify [FILE...]
+
+Writes the input with each structural character replaced by a paren and
+everything else by a space. This reads the whole input and classifies it on
+a single thread, so it takes no --workers or --chunk-size.";
+
+const TO_JSON_USAGE: &str = "usage: sexp to-json [--records] [--variants] [--numbers] [--ppx] [FILE...]";
+
+const CONVERT_USAGE: &str = "usage: sexp to-csexp|of-csexp|of-cbor|of-msgpack [FILE...]
+       sexp to-cbor|to-msgpack [--records] [FILE...]
+       sexp of-json [--lines] [FILE...]
+
+of-json parses on a single thread, since JSON values may span lines. With
+--lines the input must be JSON Lines (one value per line), and is parsed on
+several threads unless --no-threads is given.";
+
+const SIMPLE_USAGE: &str = "usage: sexp print [--workers N] [--chunk-size BYTES] [--adaptive-chunk-size] [--progress] [FILE...]
+       sexp print --follow FILE
+
+With --follow, reads FILE and then keeps reading it as it grows, like `tail
+-f`, writing each top-level sexp as soon as it is complete. If FILE is
+truncated it is read again from the start, and if it is replaced (e.g. by log
+rotation) the new file is followed. Runs until killed.";
+
+struct Args {
+    command: String,
+    usage: &'static str,
+    args: std::vec::IntoIter<String>,
+    threads: bool,
+    #[cfg(feature = "threads")]
+    config: parser_parallel::ParallelConfig,
+    #[cfg(feature = "threads")]
+    progress: bool,
+    files: Vec<String>,
+}
+
+impl Args {
+    fn usage_error(&self, message: &str) -> ! {
+        eprintln!("sexp {}: {}\n\n{}", self.command, message, self.usage);
+        std::process::exit(2)
+    }
+
+    fn error(&self, message: &str) -> ! {
+        eprintln!("sexp {}: {}", self.command, message);
+        std::process::exit(1)
+    }
+
+    fn value(&mut self, option: &str) -> String {
+        self.args.next().unwrap_or_else(|| self.usage_error(&format!("{} expects a value", option)))
+    }
+
+    /// Handles the options common to every command, returning whether `arg`
+    /// was one of them.
+    fn common(&mut self, arg: &str) -> bool {
+        match arg {
+            "--threads" => self.threads = true,
+            "--no-threads" => self.threads = false,
+            "-i" | "--input" => {
+                let file = self.value(arg);
+                self.files.push(file);
+            },
+            "-h" | "--help" => {
+                println!("{}", self.usage);
+                std::process::exit(0);
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// Handles the options of commands that take a `ParallelConfig`,
+    /// returning whether `arg` was one of them.
+    #[cfg(feature = "threads")]
+    fn parallel(&mut self, arg: &str) -> bool {
+        match arg {
+            "--workers" => {
+                let n = self.value(arg);
+                let workers: usize = n.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| self.usage_error("--workers expects a positive number"));
+                self.config = self.config.clone().with_num_threads(workers + 2);
+            },
+            "--chunk-size" => {
+                let n = self.value(arg);
+                let chunk_size = n.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| self.usage_error("--chunk-size expects a positive number"));
+                self.config = self.config.clone().with_chunk_size(chunk_size);
+            },
+            "--adaptive-chunk-size" => self.config = self.config.clone().with_adaptive_chunk_size(true),
+            "--progress" => self.progress = true,
+            _ => return false,
+        }
+        true
+    }
+
+    #[cfg(not(feature = "threads"))]
+    fn parallel(&mut self, _arg: &str) -> bool {
+        false
+    }
+
+    /// The `ParallelConfig`, with `--progress` reporting to stderr until the
+    /// reporter is dropped.
+    #[cfg(feature = "threads")]
+    fn config(&self) -> (parser_parallel::ParallelConfig, Option<progress::StderrReporter>) {
+        if !self.progress {
+            return (self.config.clone(), None);
+        }
+        if !self.threads {
+            self.usage_error("--progress needs threads");
+        }
+        let reporter = progress::StderrReporter::new(self.total_size());
+        (self.config.clone().with_progress(reporter.progress()), Some(reporter))
+    }
+
+    /// The total size of the inputs, if they are all regular files.
+    #[cfg(feature = "threads")]
+    fn total_size(&self) -> Option<u64> {
+        let metadata = |file: &str| -> Option<std::fs::Metadata> {
+            if file == "-" {
+                use std::os::fd::AsFd;
+                std::fs::File::from(std::io::stdin().as_fd().try_clone_to_owned().ok()?).metadata().ok()
+            } else {
+                std::fs::metadata(file).ok()
+            }
+        };
+        if self.files.is_empty() {
+            return metadata("-").filter(|metadata| metadata.is_file()).map(|metadata| metadata.len());
+        }
+        // Plus the line breaks between them.
+        let mut total = self.files.len() as u64 - 1;
+        for file in &self.files {
+            total += metadata(file).filter(|metadata| metadata.is_file())?.len();
+        }
+        Some(total)
+    }
+
+    fn other(&mut self, arg: String) {
+        if arg.starts_with('-') && arg != "-" {
+            self.usage_error(&format!("unknown option {}", arg));
+        }
+        self.files.push(arg);
+    }
+
+    fn open(&self, file: &str) -> Box<dyn Read + Send> {
+        if file == "-" {
+            return Box::new(std::io::stdin());
+        }
+        match std::fs::File::open(file) {
+            Ok(file) => Box::new(file),
+            Err(e) => self.error(&format!("{}: {}", file, e)),
+        }
+    }
+
+    /// The inputs one after the other, separated by line breaks so that
+    /// atoms at the end of one file and the start of the next stay apart.
+    fn input(&self) -> Input {
+        let mut input: Box<dyn Read + Send> = Box::new(std::io::empty());
+        if self.files.is_empty() {
+            input = Box::new(std::io::stdin());
+        }
+        for (i, file) in self.files.iter().enumerate() {
+            let file = self.open(file);
+            input = if i == 0 { file } else { Box::new(input.chain(&b"\n"[..]).chain(file)) };
+        }
+        std::io::BufReader::with_capacity(1048576, input)
+    }
+
+    /// The single input, which may be seekable.
+    fn seekable_input(&self) -> std::io::BufReader<std::fs::File> {
+        match &self.files[..] {
+            [] => utils::stdin(),
+            [file] if file == "-" => utils::stdin(),
+            [file] => match std::fs::File::open(file) {
+                Ok(file) => std::io::BufReader::with_capacity(1048576, file),
+                Err(e) => self.error(&format!("{}: {}", file, e)),
+            },
+            _ => self.usage_error("only one input is supported here"),
+        }
+    }
+
+    /// Maps `file` into memory if it is a regular file. Errors opening it
+    /// are left to `open`.
+    #[cfg(feature = "mmap")]
+    fn mmap_file(file: &str) -> Option<memmap2::Mmap> {
+        if file == "-" {
+            return utils::mmap(&std::io::stdin());
+        }
+        utils::mmap(&std::fs::File::open(file).ok()?)
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn mmap_file(_file: &str) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Parses a single regular file in place, and reads the inputs otherwise.
+    fn process<T>(&self, parser: &mut (dyn parser::Stream<Input, Return = T> + '_)) -> Result<T, parser::Error> {
+        let mmap = match &self.files[..] {
+            [] => Self::mmap_file("-"),
+            [file] => Self::mmap_file(file),
+            _ => None,
+        };
+        match mmap {
+            Some(input) => parser.process_slice(&input[..]),
+            None => parser.process_streaming(&mut self.input()),
+        }
+    }
+
+    /// With `--follow`: parses the single input file as it grows, passing
+    /// each batch of complete sexps, one per line, to `parser`. Only returns
+    /// on error.
+    fn follow(&self, parser: &mut (dyn parser::Stream<&[u8], Return = ()> + '_)) {
+        #[cfg(feature = "threads")]
+        if self.progress {
+            self.usage_error("--progress does not work with --follow");
+        }
+        let file = match &self.files[..] {
+            [file] if file != "-" => file,
+            _ => self.usage_error("--follow needs a single FILE"),
+        };
+        let mut follower = follow::Follower::open(file).unwrap_or_else(|e| self.error(&format!("{}: {}", file, e)));
+        let mut lines = Vec::new();
+        let result = follower.follow(|tape| {
+            lines.clear();
+            follow::write_lines(&tape, &mut lines).map_err(|e| parser::Error::IOError(e.kind()))?;
+            parser.process_slice(&lines[..])?;
+            Ok(true)
+        });
+        self.check(result);
+    }
+
+    fn check<T>(&self, result: Result<T, parser::Error>) -> T {
+        result.unwrap_or_else(|e| self.error(&e.to_string()))
+    }
+
+    fn run(&self, mut parser: Box<dyn parser::Stream<Input, Return = ()> + '_>) {
+        let result = self.process(&mut *parser);
+        self.check(result);
+    }
+
+    fn flush<WriteT: Write>(&self, stdout: &mut WriteT) {
+        if let Err(e) = stdout.flush() {
+            self.error(&format!("writing output: {}", e));
+        }
+    }
+}
+
+fn print(mut args: Args) {
+    let mut follow = false;
+    while let Some(arg) = args.args.next() {
+        if arg == "--follow" {
+            follow = true;
+        } else if !(args.common(&arg) || args.parallel(&arg)) {
+            args.other(arg);
+        }
+    }
+    if follow {
+        // Line-buffered, so that each sexp is written as soon as it is read.
+        let mut stdout = std::io::stdout();
+        args.follow(&mut *print::make(&mut stdout, false));
+        return;
+    }
+    let mut stdout = utils::stdout();
+    #[cfg(feature = "threads")]
+    let (config, reporter) = args.config();
+    #[cfg(feature = "threads")]
+    let parser = if args.threads { print::make_with_config(&mut stdout, config) } else { print::make(&mut stdout, false) };
+    #[cfg(not(feature = "threads"))]
+    let parser = print::make(&mut stdout, false);
+    args.run(parser);
+    #[cfg(feature = "threads")]
+    std::mem::drop(reporter);
+    args.flush(&mut stdout);
+}
+
+fn simplify(mut args: Args) {
+    while let Some(arg) = args.args.next() {
+        if !args.common(&arg) {
+            args.other(arg);
+        }
+    }
+    let mut input = Vec::new();
+    if let Err(e) = args.input().read_to_end(&mut input) {
+        args.error(&format!("reading input: {}", e));
+    }
+    let mut stdout = utils::stdout();
+    if let Err(e) = simplify::simplify(&input[..], &mut stdout) {
+        args.error(&format!("writing output: {}", e));
+    }
+    args.flush(&mut stdout);
+}
+
+fn validate(mut args: Args) {
+    let mut count = false;
+    while let Some(arg) = args.args.next() {
+        match arg.as_str() {
+            "--count" => count = true,
+            _ if args.common(&arg) => (),
+            _ => args.other(arg),
+        }
+    }
+    let files = if args.files.is_empty() { vec!["-".to_owned()] } else { args.files.clone() };
+    let mut all_valid = true;
+    for file in files {
+        let name = if file == "-" { "<stdin>" } else { &file[..] };
+        let mut parser = validate::make_parser(args.threads);
+        let result = match Args::mmap_file(&file) {
+            Some(input) => parser.process_slice(&input[..]),
+            None => parser.process_streaming(&mut std::io::BufReader::with_capacity(1048576, args.open(&file))),
+        };
+        match result {
+            Ok(num_sexps) => if count { println!("{}: {}", name, num_sexps) },
+            Err(e) => {
+                eprintln!("sexp validate: {}: {}", name, e);
+                all_valid = false;
+            },
+        }
+    }
+    if !all_valid {
+        std::process::exit(1);
+    }
+}
+
+fn select(mut args: Args) {
+    let mut output_kind = select::OutputKind::Csv { atoms_as_sexps: false };
+    let mut delimited_options = None;
+    let mut filter: Option<select_filter::Predicate> = None;
+    #[cfg_attr(not(feature = "regex"), allow(unused_mut))]
+    let mut patterns = Vec::new();
+    let mut paths = Vec::new();
+    let mut duplicate_keys = None;
+    let mut missing_keys = None;
+    let mut null_marker = b"null".to_vec();
+    #[cfg(feature = "arrow")]
+    let mut column_types = None;
+    let mut keys = Vec::new();
+    let mut all_keys = false;
+    let mut list_keys = false;
+    let mut follow = false;
+
+    while let Some(arg) = args.args.next() {
+        match arg.as_str() {
+            "--" => {
+                args.files.extend(args.args.by_ref());
+            },
+            "--all-keys" => all_keys = true,
+            "--list-keys" => list_keys = true,
+            "--follow" => follow = true,
+            "--csv" => output_kind = select::OutputKind::Csv { atoms_as_sexps: false },
+            "--atoms-as-sexps" => output_kind = select::OutputKind::Csv { atoms_as_sexps: true },
+            "--values" => output_kind = select::OutputKind::Values,
+            "--labeled" => output_kind = select::OutputKind::Labeled,
+            "--json-lines" => output_kind = select::OutputKind::JsonLines,
+            #[cfg(feature = "regex")]
+            "--glob" | "--regex" => {
+                let pattern = args.value(&arg);
+                let key_pattern = if arg == "--glob" { select::KeyPattern::glob(&pattern) } else { select::KeyPattern::regex(&pattern) };
+                patterns.push(key_pattern.unwrap_or_else(|e| args.usage_error(&format!("{}: {}", e, pattern))));
+            },
+            #[cfg(not(feature = "regex"))]
+            "--glob" | "--regex" => args.usage_error("key patterns need the regex feature"),
+            "--path" => {
+                let path = args.value(&arg);
+                paths.push(select::PathSelector::parse(&path).unwrap_or_else(|e| args.usage_error(&format!("{}: {}", e, path))));
+            },
+            "--where" => {
+                let predicate = args.value(&arg);
+                let predicate = select_filter::Predicate::parse(&predicate).unwrap_or_else(|e| args.usage_error(&format!("{}: {}", e, predicate)));
+                filter = Some(match filter {
+                    None => predicate,
+                    Some(filter) => select_filter::Predicate::And(Box::new(filter), Box::new(predicate)),
+                });
+            },
+            "--duplicate-keys" => {
+                duplicate_keys = Some(match args.value(&arg).as_str() {
+                    "first" => select::DuplicateKeys::First,
+                    "last" => select::DuplicateKeys::Last,
+                    "list" => select::DuplicateKeys::List,
+                    "error" => select::DuplicateKeys::Error,
+                    other => args.usage_error(&format!("unknown duplicate key policy {}", other)),
+                });
+            },
+            "--missing-keys" => missing_keys = Some(args.value(&arg)),
+            "--null-marker" => null_marker = args.value(&arg).into_bytes(),
+            "--tsv" => delimited_options = Some(select::DelimitedOptions::tsv()),
+            "--delimiter" => {
+                let delimiter = match args.value(&arg).as_bytes() {
+                    &[delimiter] => delimiter,
+                    _ => args.usage_error("--delimiter expects a single byte"),
+                };
+                delimited_options.get_or_insert_with(select::DelimitedOptions::default).delimiter = delimiter;
+            },
+            "--quoting" => {
+                let quoting = match args.value(&arg).as_str() {
+                    "necessary" => select::Quoting::Necessary,
+                    "always" => select::Quoting::Always,
+                    "escape" => select::Quoting::Escape,
+                    other => args.usage_error(&format!("unknown quoting {}", other)),
+                };
+                delimited_options.get_or_insert_with(select::DelimitedOptions::default).quoting = quoting;
+            },
+            "--null" => {
+                let null = args.value(&arg);
+                delimited_options.get_or_insert_with(select::DelimitedOptions::default).null = null.into_bytes();
+            },
+            #[cfg(feature = "arrow")]
+            "--arrow" => output_kind = select::OutputKind::Arrow { format: select_arrow::Format::File, column_types: None },
+            #[cfg(feature = "arrow")]
+            "--arrow-stream" => output_kind = select::OutputKind::Arrow { format: select_arrow::Format::Stream, column_types: None },
+            #[cfg(feature = "arrow")]
+            "--column-types" => {
+                let types = args.value(&arg);
+                let types: Option<Vec<_>> = types.split(',').map(select_arrow::ColumnType::of_string).collect();
+                column_types = Some(types.unwrap_or_else(|| args.usage_error("column types are int, float, bool or string")));
+            },
+            #[cfg(not(feature = "arrow"))]
+            "--arrow" | "--arrow-stream" | "--column-types" => args.usage_error("Arrow output needs the arrow feature"),
+            _ if args.common(&arg) || args.parallel(&arg) => (),
+            _ if arg.starts_with('-') => args.usage_error(&format!("unknown option {}", arg)),
+            _ => keys.push(arg),
+        }
+    }
+
+    if all_keys || list_keys {
+        // These select keys themselves, so the arguments are files.
+        args.files.append(&mut keys);
+    }
+
+    if let Some(delimited_options) = delimited_options {
+        output_kind = select::OutputKind::Delimited(delimited_options);
+    }
+
+    #[cfg(feature = "arrow")]
+    if let select::OutputKind::Arrow { column_types: output_column_types, .. } = &mut output_kind {
+        if column_types.as_ref().is_some_and(|types: &Vec<_>| types.len() != keys.len() + patterns.len() + paths.len()) {
+            args.usage_error("--column-types needs one type per key, pattern and path");
+        }
+        *output_column_types = column_types;
+    }
+
+    if !(all_keys || list_keys) && keys.is_empty() && patterns.is_empty() && paths.is_empty() {
+        args.usage_error("nothing to select");
+    }
+
+    let missing_keys = missing_keys.map(|missing_keys| match missing_keys.as_str() {
+        "empty" => select::MissingKeys::Empty,
+        "null" => select::MissingKeys::Null(&null_marker[..]),
+        "skip" => select::MissingKeys::SkipRow,
+        "error" => select::MissingKeys::Error,
+        other => args.usage_error(&format!("unknown missing key policy {}", other)),
+    });
+
+    let options = select::Options { filter: filter.as_ref(), patterns: &patterns[..], paths: &paths[..], duplicate_keys, missing_keys };
+
+    if follow && (list_keys || all_keys) {
+        args.usage_error("--follow does not work with --list-keys or --all-keys");
+    }
+
+    if list_keys {
+        let result = args.process(&mut *select_schema::make_parser(args.threads));
+        let key_counts = args.check(result);
+        let mut stdout = utils::stdout();
+        args.check(select_schema::print_key_counts(&key_counts, &mut stdout).map_err(|e| parser::Error::IOError(e.kind())));
+        args.flush(&mut stdout);
+        return;
+    }
+
+    if all_keys {
+        let mut stdout = utils::stdout();
+        let result = select_schema::select_all_keys(&mut args.seekable_input(), options, &mut stdout, output_kind, args.threads);
+        args.check(result);
+        args.flush(&mut stdout);
+        return;
+    }
+
+    let keys = keys.iter().map(|key| key.as_bytes());
+
+    if follow {
+        #[cfg(feature = "arrow")]
+        if let select::OutputKind::Arrow { .. } = output_kind {
+            args.usage_error("--follow does not work with Arrow output");
+        }
+        let mut stdout = std::io::stdout();
+        args.follow(&mut *select::make_parser_with_options(keys, options, &mut stdout, output_kind, false));
+        return;
+    }
+
+    let mut stdout = utils::stdout();
+    #[cfg(feature = "threads")]
+    let (config, reporter) = args.config();
+    #[cfg(feature = "threads")]
+    let parser = if args.threads {
+        select::make_parser_with_config(keys, options, &mut stdout, output_kind, config)
+    } else {
+        select::make_parser_with_options(keys, options, &mut stdout, output_kind, false)
+    };
+    #[cfg(not(feature = "threads"))]
+    let parser = select::make_parser_with_options(keys, options, &mut stdout, output_kind, false);
+    args.run(parser);
+    #[cfg(feature = "threads")]
+    std::mem::drop(reporter);
+    args.flush(&mut stdout);
+}
+
+#[cfg(feature = "threads")]
+fn exec(mut args: Args) {
+    let mut prog = None;
+    let mut framing = None;
+    let mut delimiter = b"(end-of-chunk)".to_vec();
+    let mut processes = 1;
+    let mut retries = 0;
+    let mut on_failure = exec_parallel::OnFailure::Fail;
+    let mut reduce = None;
+    let mut reducer_command = None;
+    while let Some(arg) = args.args.next() {
+        match arg.as_str() {
+            "--reduce" => reduce = Some(args.value(&arg)),
+            "--reducer" => reducer_command = Some(args.value(&arg)),
+            "--retries" => {
+                let n = args.value(&arg);
+                retries = n.parse().unwrap_or_else(|_| args.usage_error("--retries expects a number"));
+            },
+            "--on-failure" => {
+                on_failure = match args.value(&arg).as_str() {
+                    "fail" => exec_parallel::OnFailure::Fail,
+                    "skip" => exec_parallel::OnFailure::Skip,
+                    other => args.usage_error(&format!("unknown failure policy {}", other)),
+                };
+            },
+            "--framing" => framing = Some(args.value(&arg)),
+            "--delimiter" => {
+                delimiter = args.value(&arg).into_bytes();
+                if delimiter.is_empty() || delimiter.contains(&b'\n') {
+                    args.usage_error("--delimiter expects a non-empty line");
+                }
+                framing.get_or_insert_with(|| "delimiter".to_owned());
+            },
+            "--processes" => {
+                let n = args.value(&arg);
+                processes = n.parse().ok().filter(|&n| n > 0).unwrap_or_else(|| args.usage_error("--processes expects a positive number"));
+            },
+            _ if args.common(&arg) || args.parallel(&arg) => (),
+            _ if arg.starts_with('-') => args.usage_error(&format!("unknown option {}", arg)),
+            _ => {
+                prog = Some(arg);
+                break;
+            },
+        }
+    }
+    let framing = match framing.as_deref() {
+        None | Some("spawn") => exec_parallel::Framing::SpawnPerChunk,
+        Some("delimiter") => exec_parallel::Framing::Delimiter(delimiter),
+        Some("length-prefix") => exec_parallel::Framing::LengthPrefix,
+        Some(other) => args.usage_error(&format!("unknown framing {}", other)),
+    };
+    let reducer_args = reducer_command.as_ref().map(|command| [std::ffi::OsStr::new("-c"), std::ffi::OsStr::new(command)]);
+    let reducer = match (reduce.as_deref(), &reducer_args) {
+        (Some(_), Some(_)) => args.usage_error("--reduce and --reducer are exclusive"),
+        (None, Some(reducer_args)) => exec_parallel::Reducer::Command { prog: std::ffi::OsStr::new("sh"), args: &reducer_args[..] },
+        (None | Some("concat"), None) => exec_parallel::Reducer::Concat,
+        (Some("sum"), None) => exec_parallel::Reducer::Sum,
+        (Some("count"), None) => exec_parallel::Reducer::Count,
+        (Some("merge-sorted"), None) => exec_parallel::Reducer::MergeSorted,
+        (Some(other), None) => args.usage_error(&format!("unknown reducer {}", other)),
+    };
+    let prog: std::ffi::OsString = prog.unwrap_or_else(|| args.usage_error("missing PROG")).into();
+    let prog_args: Vec<std::ffi::OsString> = args.args.by_ref().map(|arg| arg.into()).collect();
+    let prog_args: Vec<&std::ffi::OsStr> = prog_args.iter().map(|arg| &arg[..]).collect();
+    let exec_worker =
+        exec_parallel::ExecWorker::new(&prog, &prog_args)
+        .with_framing(framing)
+        .with_processes_per_thread(processes)
+        .with_retries(retries)
+        .with_on_failure(on_failure)
+        .with_reducer(reducer);
+    let mut stdout = utils::stdout();
+    let (config, reporter) = args.config();
+    let mut parser = exec_parallel::make_parser_with_config(exec_worker, &mut stdout, std::io::stderr(), config);
+    let result = exec_parallel::flatten(args.process(&mut parser));
+    std::mem::drop(parser);
+    std::mem::drop(reporter);
+    args.flush(&mut stdout);
+    match result {
+        Ok(0) => (),
+        Ok(_skipped_chunks) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("sexp exec: {}", e);
+            match e {
+                exec_parallel::Error::ChildFailed { failure: exec_parallel::ChildFailure::ExitCode(code @ 1..=255), .. } => std::process::exit(code),
+                _ => std::process::exit(1),
+            }
+        },
+    }
+}
+
+#[cfg(not(feature = "threads"))]
+fn exec(args: Args) {
+    args.error("exec needs the threads feature");
+}
+
+fn to_json(mut args: Args) {
+    let mut options = to_json::Options::default();
+    while let Some(arg) = args.args.next() {
+        match arg.as_str() {
+            "--records" => options.records = true,
+            "--variants" => options.variants = true,
+            "--numbers" => options.numbers = true,
+            "--ppx" => {
+                options.records = true;
+                options.variants = true;
+                options.numbers = true;
+            },
+            _ if args.common(&arg) => (),
+            _ => args.other(arg),
+        }
+    }
+    let mut stdout = utils::stdout();
+    args.run(to_json::make(&mut stdout, options, args.threads));
+    args.flush(&mut stdout);
+}
+
+fn convert(mut args: Args) {
+    let mut records = false;
+    let mut lines = false;
+    while let Some(arg) = args.args.next() {
+        match arg.as_str() {
+            "--records" if matches!(args.command.as_str(), "to-cbor" | "to-msgpack") => records = true,
+            "--lines" if args.command == "of-json" => lines = true,
+            _ if args.common(&arg) => (),
+            _ => args.other(arg),
+        }
+    }
+    let mut stdout = utils::stdout();
+    match args.command.as_str() {
+        "to-csexp" => args.run(csexp::make_writer(&mut stdout, args.threads)),
+        "of-csexp" => args.run(csexp::make_printer(&mut stdout)),
+        "of-json" => args.run(of_json::make(&mut stdout, args.threads && lines)),
+        "to-cbor" => args.run(cbor::make_writer(&mut stdout, cbor::Options { records }, args.threads)),
+        "of-cbor" => args.run(cbor::make_printer(&mut stdout)),
+        "to-msgpack" => args.run(msgpack::make_writer(&mut stdout, msgpack::Options { records }, args.threads)),
+        "of-msgpack" => args.run(msgpack::make_printer(&mut stdout)),
+        _ => unreachable!(),
+    }
+    args.flush(&mut stdout);
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let command = match args.next() {
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+        Some(command) => command,
+    };
+    let usage = match command.as_str() {
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            return;
+        },
+        "print" => SIMPLE_USAGE,
+        "select" => SELECT_USAGE,
+        "exec" => EXEC_USAGE,
+        "validate" => VALIDATE_USAGE,
+        "simplify" => SIMPLIFY_USAGE,
+        "to-json" => TO_JSON_USAGE,
+        "to-csexp" | "of-csexp" | "of-json" | "to-cbor" | "of-cbor" | "to-msgpack" | "of-msgpack" => CONVERT_USAGE,
+        _ => {
+            eprintln!("sexp: unknown command {}\n\n{}", command, USAGE);
+            std::process::exit(2);
+        },
+    };
+    let args = Args {
+        command,
+        usage,
+        args: args.collect::<Vec<_>>().into_iter(),
+        threads: true,
+        #[cfg(feature = "threads")]
+        config: parser_parallel::ParallelConfig::default(),
+        #[cfg(feature = "threads")]
+        progress: false,
+        files: Vec::new(),
+    };
+    match args.command.as_str() {
+        "print" => print(args),
+        "select" => select(args),
+        "exec" => exec(args),
+        "validate" => validate(args),
+        "simplify" => simplify(args),
+        "to-json" => to_json(args),
+        _ => convert(args),
+    }
+}
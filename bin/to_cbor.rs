@@ -0,0 +1,20 @@
+use simd_sexp::*;
+
+fn main() {
+    let mut options = cbor::Options::default();
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--records" => options.records = true,
+            _ => {
+                eprintln!("usage: to-cbor [--records]");
+                std::process::exit(2);
+            },
+        }
+    }
+
+    let mut stdout = utils::stdout();
+
+    let mut to_cbor = cbor::make_writer(&mut stdout, options, true);
+    let () = utils::process_stdin(&mut *to_cbor).unwrap();
+}
@@ -0,0 +1,8 @@
+use simd_sexp::*;
+
+fn main() {
+    let mut stdout = utils::stdout();
+
+    let mut to_csexp = csexp::make_writer(&mut stdout, true);
+    let () = utils::process_stdin(&mut *to_csexp).unwrap();
+}
@@ -0,0 +1,27 @@
+use simd_sexp::*;
+
+fn main() {
+    let mut options = to_json::Options::default();
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--records" => options.records = true,
+            "--variants" => options.variants = true,
+            "--numbers" => options.numbers = true,
+            "--ppx" => {
+                options.records = true;
+                options.variants = true;
+                options.numbers = true;
+            },
+            _ => {
+                eprintln!("usage: to-json [--records] [--variants] [--numbers] [--ppx]");
+                std::process::exit(2);
+            },
+        }
+    }
+
+    let mut stdout = utils::stdout();
+
+    let mut to_json = to_json::make(&mut stdout, options, true);
+    let () = utils::process_stdin(&mut *to_json).unwrap();
+}
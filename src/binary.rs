@@ -0,0 +1,492 @@
+//! Shared machinery for the binary formats with length-prefixed arrays and
+//! maps (`cbor` and `msgpack`).
+
+use crate::escape::{self, Unescape};
+use crate::parser;
+use crate::visitor::{self, Visitor};
+use std::io::Write;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Options {
+    /// Lists of the form `((k1 v1) (k2 v2) ...)`, as produced by
+    /// ppx_sexp_conv for records, become maps. Decoding always turns maps
+    /// back into such lists.
+    pub records: bool,
+}
+
+/// A decoded item header.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Item {
+    /// `None` for indefinite-length arrays and maps, which end with `Break`.
+    Array(Option<usize>),
+    /// The length is the number of key-value pairs.
+    Map(Option<usize>),
+    Break,
+    /// A semantic tag, which applies to the following item. Tags are ignored.
+    Tag,
+    /// A byte or text string of the given length, which follows the header.
+    String(usize),
+    Unsigned(u64),
+    Signed(i64),
+    /// CBOR negative integers, whose value is `-1 - n`.
+    Negative(u64),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+pub trait Format {
+    /// The error for malformed input.
+    const INVALID: parser::Error;
+    fn write_array_header<WriteT: Write>(writer: &mut WriteT, len: usize) -> std::io::Result<()>;
+    fn write_map_header<WriteT: Write>(writer: &mut WriteT, len: usize) -> std::io::Result<()>;
+    /// Atoms are written as text strings when they are valid UTF-8, and as
+    /// byte strings otherwise.
+    fn write_atom<WriteT: Write>(writer: &mut WriteT, atom: &[u8]) -> std::io::Result<()>;
+    /// Returns the item and the length of its header, or `None` if `input`
+    /// ends before the header does.
+    fn read_header(input: &[u8]) -> Result<Option<(Item, usize)>, parser::Error>;
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Token {
+    /// `end` is the index of the matching `ListClose`.
+    ListOpen { end: usize, len: usize },
+    ListClose,
+    Atom { start: usize, end: usize },
+}
+
+/// Buffers each top-level sexp, since array headers need the number of
+/// elements up front, and deciding whether a list is a record requires
+/// looking at all of its children.
+struct Encoder<FormatT> {
+    options: Options,
+    tokens: Vec<Token>,
+    atoms: Vec<u8>,
+    open_stack: Vec<usize>,
+    phantom: std::marker::PhantomData<FormatT>,
+}
+
+impl<FormatT: Format> Encoder<FormatT> {
+    fn new(options: Options) -> Self {
+        Self {
+            options,
+            tokens: Vec::new(),
+            atoms: Vec::new(),
+            open_stack: Vec::new(),
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.tokens.clear();
+        self.atoms.clear();
+        self.open_stack.clear();
+    }
+
+    fn add_child(&mut self) {
+        if let Some(&open_index) = self.open_stack.last() {
+            if let Token::ListOpen { len, .. } = &mut self.tokens[open_index] {
+                *len += 1;
+            }
+        }
+    }
+
+    /// Returns whether this completes a top-level sexp.
+    fn atom(&mut self, atom: &[u8]) -> bool {
+        self.add_child();
+        let start = self.atoms.len();
+        self.atoms.extend_from_slice(atom);
+        self.tokens.push(Token::Atom { start, end: self.atoms.len() });
+        self.open_stack.is_empty()
+    }
+
+    fn list_open(&mut self) {
+        self.add_child();
+        self.open_stack.push(self.tokens.len());
+        self.tokens.push(Token::ListOpen { end: 0, len: 0 });
+    }
+
+    /// Returns whether this completes a top-level sexp.
+    fn list_close(&mut self) -> Result<bool, parser::Error> {
+        let open_index = self.open_stack.pop().ok_or(parser::Error::UnmatchedCloseParen)?;
+        let end = self.tokens.len();
+        if let Token::ListOpen { end: open_end, .. } = &mut self.tokens[open_index] {
+            *open_end = end;
+        }
+        self.tokens.push(Token::ListClose);
+        Ok(self.open_stack.is_empty())
+    }
+
+    fn next_sibling(&self, index: usize) -> usize {
+        match self.tokens[index] {
+            Token::ListOpen { end, .. } => end + 1,
+            _ => index + 1,
+        }
+    }
+
+    fn is_record(&self, start: usize, end: usize) -> bool {
+        let mut index = start + 1;
+        while index < end {
+            // each field must be a list of exactly a key atom and a value
+            match self.tokens[index] {
+                Token::ListOpen { end: field_end, len: 2 } if matches!(self.tokens[index + 1], Token::Atom { .. }) => {
+                    index = field_end + 1;
+                },
+                _ => return false,
+            }
+        }
+        index > start + 1
+    }
+
+    fn write_value<WriteT: Write>(&self, writer: &mut WriteT, index: usize) -> std::io::Result<()> {
+        match self.tokens[index] {
+            Token::Atom { start, end } => FormatT::write_atom(writer, &self.atoms[start..end]),
+            Token::ListOpen { end, len } => {
+                let is_record = self.options.records && self.is_record(index, end);
+                if is_record {
+                    FormatT::write_map_header(writer, len)?;
+                } else {
+                    FormatT::write_array_header(writer, len)?;
+                }
+                let mut child = index + 1;
+                while child < end {
+                    if is_record {
+                        self.write_value(writer, child + 1)?;
+                        self.write_value(writer, child + 2)?;
+                    } else {
+                        self.write_value(writer, child)?;
+                    }
+                    child = self.next_sibling(child);
+                }
+                Ok(())
+            },
+            Token::ListClose => unreachable!(),
+        }
+    }
+
+    fn flush<WriteT: Write>(&mut self, writer: &mut WriteT) -> std::io::Result<()> {
+        self.write_value(writer, 0)?;
+        self.tokens.clear();
+        self.atoms.clear();
+        Ok(())
+    }
+}
+
+/// Encodes any `ReadVisitable`. Each top-level sexp becomes one top-level
+/// item, so the output is a sequence of items.
+pub struct Generator<'a, FormatT, WriteT> {
+    writer: &'a mut WriteT,
+    encoder: Encoder<FormatT>,
+}
+
+impl<'a, FormatT: Format, WriteT: Write> Generator<'a, FormatT, WriteT> {
+    pub fn new(writer: &'a mut WriteT, options: Options) -> Self {
+        Self { writer, encoder: Encoder::new(options) }
+    }
+}
+
+impl<'a, FormatT: Format, WriteT: Write> visitor::ReadVisitor for Generator<'a, FormatT, WriteT> {
+    fn reset(&mut self) {
+        self.encoder.reset();
+    }
+    fn atom(&mut self, atom: &[u8]) {
+        if self.encoder.atom(atom) {
+            self.encoder.flush(self.writer).unwrap();
+        }
+    }
+    fn list_open(&mut self) {
+        self.encoder.list_open();
+    }
+    fn list_close(&mut self) {
+        if self.encoder.list_close().unwrap() {
+            self.encoder.flush(self.writer).unwrap();
+        }
+    }
+    fn eof(&mut self) {
+    }
+}
+
+/// Encodes regular sexp input.
+pub struct Stage2<FormatT> {
+    unescape: escape::GenericUnescape,
+    atom_buffer: Vec<u8>,
+    encoder: Encoder<FormatT>,
+}
+
+impl<FormatT: Format> Stage2<FormatT> {
+    pub fn new(options: Options) -> Self {
+        Self {
+            unescape: escape::GenericUnescape::new(),
+            atom_buffer: Vec::new(),
+            encoder: Encoder::new(options),
+        }
+    }
+}
+
+impl<FormatT: Format> parser::WritingStage2 for Stage2<FormatT> {
+    fn reset(&mut self) {
+        self.encoder.reset();
+    }
+
+    #[inline]
+    fn process_one<WriteT: Write>(&mut self, writer: &mut WriteT, input: parser::Input, this_index: usize, next_index: usize, _is_eof: bool) -> Result<usize, parser::Error> {
+        let top_level_done = match input.input[this_index - input.offset] {
+            b'(' => { self.encoder.list_open(); false },
+            b')' => self.encoder.list_close()?,
+            b' ' | b'\t' | b'\n' => false,
+            b'"' => {
+                self.atom_buffer.resize(next_index - this_index, 0u8);
+                let (_, atom_len) =
+                    self.unescape.unescape(
+                        &input.input[(this_index + 1 - input.offset)..(next_index - input.offset)],
+                        &mut self.atom_buffer[..])
+                    .ok_or(parser::Error::BadQuotedAtom)?;
+                self.encoder.atom(&self.atom_buffer[..atom_len])
+            },
+            _ => self.encoder.atom(&input.input[(this_index - input.offset)..(next_index - input.offset)]),
+        };
+        if top_level_done {
+            self.encoder.flush(writer).unwrap();
+        }
+        Ok(next_index)
+    }
+
+    fn process_eof<WriteT: Write>(&mut self, _writer: &mut WriteT) -> Result<(), parser::Error> {
+        if !self.encoder.open_stack.is_empty() {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FrameKind {
+    Array,
+    Map,
+    /// The `(k v)` list for one entry of a map.
+    Pair,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Frame {
+    kind: FrameKind,
+    /// Items still to come, or `None` if the length is indefinite.
+    remaining: Option<usize>,
+}
+
+/// Decodes a sequence of items, driving any `Visitor`. Arrays become lists,
+/// maps become `((k v) ...)` lists, strings become atoms and other scalars
+/// become atoms of their textual representation (e.g. `-1`, `1.5`, `true`,
+/// `null`).
+pub struct State<FormatT, VisitorT: Visitor> {
+    visitor: VisitorT,
+    context_stack: Vec<VisitorT::Context>,
+    frames: Vec<Frame>,
+    /// Input that has been received but not yet consumed, because it ends
+    /// part way through an item.
+    input: Vec<u8>,
+    scalar_buffer: Vec<u8>,
+    phantom: std::marker::PhantomData<FormatT>,
+}
+
+impl<FormatT: Format, VisitorT: Visitor> State<FormatT, VisitorT> {
+    pub fn new(visitor: VisitorT) -> Self {
+        Self {
+            visitor,
+            context_stack: Vec::new(),
+            frames: Vec::new(),
+            input: Vec::new(),
+            scalar_buffer: Vec::new(),
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    pub fn reset(&mut self, input_size_hint: Option<usize>) {
+        self.visitor.reset(input_size_hint);
+        self.context_stack.clear();
+        self.frames.clear();
+        self.input.clear();
+    }
+
+    fn list_open(&mut self, kind: FrameKind, remaining: Option<usize>) {
+        let new_context = self.visitor.list_open(self.context_stack.last_mut());
+        self.context_stack.push(new_context);
+        self.frames.push(Frame { kind, remaining });
+    }
+
+    fn list_close(&mut self) {
+        self.frames.pop();
+        let context = self.context_stack.pop().unwrap();
+        self.visitor.list_close(context, self.context_stack.last_mut());
+    }
+
+    /// Called before each item, once it is known to be complete.
+    fn item_start(&mut self) {
+        if let Some(Frame { kind: FrameKind::Map, .. }) = self.frames.last() {
+            self.list_open(FrameKind::Pair, Some(2));
+        }
+    }
+
+    /// Called after each item, closing any lists that are now complete.
+    fn item_end(&mut self) {
+        while let Some(Frame { remaining: Some(remaining), .. }) = self.frames.last_mut() {
+            *remaining -= 1;
+            if *remaining > 0 {
+                break;
+            }
+            self.list_close();
+        }
+    }
+
+    fn atom(&mut self, atom: &[u8]) {
+        let mut intermediate_atom = self.visitor.atom_reserve(atom.len());
+        self.visitor.atom_borrow(&mut intermediate_atom)[..atom.len()].copy_from_slice(atom);
+        self.visitor.atom(intermediate_atom, atom.len(), self.context_stack.last_mut());
+    }
+
+    fn scalar(&mut self, item: Item) {
+        let mut buffer = std::mem::take(&mut self.scalar_buffer);
+        buffer.clear();
+        match item {
+            Item::Unsigned(n) => write!(buffer, "{}", n).unwrap(),
+            Item::Signed(n) => write!(buffer, "{}", n).unwrap(),
+            Item::Negative(n) => write!(buffer, "{}", -1 - (n as i128)).unwrap(),
+            Item::Float(x) => write!(buffer, "{:?}", x).unwrap(),
+            Item::Bool(b) => write!(buffer, "{}", b).unwrap(),
+            Item::Null => buffer.extend_from_slice(b"null"),
+            _ => unreachable!(),
+        }
+        self.atom(&buffer[..]);
+        self.scalar_buffer = buffer;
+    }
+
+    /// Returns the number of bytes consumed; anything after that is an
+    /// incomplete item.
+    fn process_slice(&mut self, input: &[u8]) -> Result<usize, parser::Error> {
+        let mut index = 0;
+        while index < input.len() {
+            let (item, header_len) = match FormatT::read_header(&input[index..])? {
+                Some(x) => x,
+                None => break,
+            };
+            let payload_start = index + header_len;
+            match item {
+                Item::Tag => (),
+                Item::Break => {
+                    match self.frames.last() {
+                        Some(Frame { kind: FrameKind::Array | FrameKind::Map, remaining: None }) => (),
+                        _ => return Err(parser::Error::UnmatchedCloseParen),
+                    }
+                    self.list_close();
+                    self.item_end();
+                },
+                Item::Array(len) | Item::Map(len) => {
+                    let kind = if matches!(item, Item::Array(_)) { FrameKind::Array } else { FrameKind::Map };
+                    self.item_start();
+                    self.list_open(kind, len);
+                    if len == Some(0) {
+                        self.list_close();
+                        self.item_end();
+                    }
+                },
+                Item::String(len) => {
+                    if input.len() - payload_start < len {
+                        break;
+                    }
+                    self.item_start();
+                    self.atom(&input[payload_start..(payload_start + len)]);
+                    self.item_end();
+                    index = payload_start + len;
+                    continue;
+                },
+                _ => {
+                    self.item_start();
+                    self.scalar(item);
+                    self.item_end();
+                },
+            }
+            index = payload_start;
+        }
+        Ok(index)
+    }
+
+    pub fn process_partial(&mut self, new_input: &[u8]) -> Result<(), parser::Error> {
+        if self.input.is_empty() {
+            let consumed = self.process_slice(new_input)?;
+            self.input.extend_from_slice(&new_input[consumed..]);
+        } else {
+            let mut input = std::mem::take(&mut self.input);
+            input.extend_from_slice(new_input);
+            let consumed = self.process_slice(&input[..])?;
+            input.drain(..consumed);
+            self.input = input;
+        }
+        Ok(())
+    }
+
+    pub fn process_eof(&mut self) -> Result<VisitorT::Return, parser::Error> {
+        if !self.input.is_empty() {
+            return Err(FormatT::INVALID);
+        }
+        if !self.frames.is_empty() {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(self.visitor.eof())
+    }
+
+    pub fn process_streaming<BufReadT: std::io::BufRead>(&mut self, buf_reader: &mut BufReadT) -> Result<VisitorT::Return, parser::Error> {
+        self.reset(None);
+
+        loop {
+            match buf_reader.fill_buf() {
+                Ok(&[]) => { return self.process_eof(); },
+                Ok(buf) => {
+                    self.process_partial(buf)?;
+                    let len = buf.len();
+                    buf_reader.consume(len);
+                },
+                Err(e) => { return Err(parser::Error::IOError(e.kind())) },
+            }
+        }
+    }
+
+    pub fn process_all(&mut self, input: &[u8]) -> Result<VisitorT::Return, parser::Error> {
+        self.reset(Some(input.len()));
+        self.process_partial(input)?;
+        self.process_eof()
+    }
+}
+
+impl<FormatT: Format, VisitorT: Visitor + parser::ExtractPartialResult> parser::ExtractPartialResult for State<FormatT, VisitorT> {
+    type PartialReturn = VisitorT::PartialReturn;
+    fn extract_partial_result(&mut self) -> Self::PartialReturn {
+        self.visitor.extract_partial_result()
+    }
+}
+
+impl<FormatT: Format, VisitorT: Visitor> parser::Parse for State<FormatT, VisitorT> {
+    type Return = VisitorT::Return;
+    fn process(&mut self, input: &[u8]) -> Result<Self::Return, parser::Error> {
+        self.process_all(input)
+    }
+}
+
+impl<FormatT: Format, VisitorT: Visitor + parser::ExtractPartialResult> parser::ParsePartial for State<FormatT, VisitorT> {
+    fn process_partial(&mut self, input: &[u8]) -> Result<(), parser::Error> {
+        self.process_partial(input)
+    }
+    fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
+        self.process_eof()
+    }
+}
+
+impl<BufReadT: std::io::BufRead, FormatT: Format, VisitorT: Visitor> parser::Stream<BufReadT> for State<FormatT, VisitorT> {
+    type Return = VisitorT::Return;
+    fn process_streaming(&mut self, buf_reader: &mut BufReadT) -> Result<Self::Return, parser::Error> {
+        self.process_streaming(buf_reader)
+    }
+    fn process_slice(&mut self, input: &[u8]) -> Result<Self::Return, parser::Error> {
+        self.process_all(input)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::binary::{self, Item};
+use crate::csexp;
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use std::io::{BufRead, Write};
+
+pub use binary::Options;
+
+/// CBOR (RFC 8949).
+#[derive(Copy, Clone, Debug)]
+pub struct Cbor;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+/// Additional information value for indefinite lengths (and for `break`).
+const INDEFINITE: u8 = 31;
+
+fn write_head<WriteT: Write>(writer: &mut WriteT, major: u8, n: usize) -> std::io::Result<()> {
+    let major = major << 5;
+    let n = n as u64;
+    if n < 24 {
+        writer.write_all(&[major | n as u8])
+    } else if n <= u8::MAX as u64 {
+        writer.write_all(&[major | 24, n as u8])
+    } else if n <= u16::MAX as u64 {
+        writer.write_all(&[major | 25])?;
+        writer.write_all(&(n as u16).to_be_bytes())
+    } else if n <= u32::MAX as u64 {
+        writer.write_all(&[major | 26])?;
+        writer.write_all(&(n as u32).to_be_bytes())
+    } else {
+        writer.write_all(&[major | 27])?;
+        writer.write_all(&n.to_be_bytes())
+    }
+}
+
+fn half_to_f64(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = (half & 0x3ff) as f64;
+    let value = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 => if mantissa == 0.0 { f64::INFINITY } else { f64::NAN },
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    if half & 0x8000 != 0 { -value } else { value }
+}
+
+impl binary::Format for Cbor {
+    const INVALID: parser::Error = parser::Error::InvalidCbor;
+
+    fn write_array_header<WriteT: Write>(writer: &mut WriteT, len: usize) -> std::io::Result<()> {
+        write_head(writer, MAJOR_ARRAY, len)
+    }
+
+    fn write_map_header<WriteT: Write>(writer: &mut WriteT, len: usize) -> std::io::Result<()> {
+        write_head(writer, MAJOR_MAP, len)
+    }
+
+    fn write_atom<WriteT: Write>(writer: &mut WriteT, atom: &[u8]) -> std::io::Result<()> {
+        let major = if std::str::from_utf8(atom).is_ok() { MAJOR_TEXT } else { MAJOR_BYTES };
+        write_head(writer, major, atom.len())?;
+        writer.write_all(atom)
+    }
+
+    fn read_header(input: &[u8]) -> Result<Option<(Item, usize)>, parser::Error> {
+        let initial = match input.first() {
+            None => return Ok(None),
+            Some(&initial) => initial,
+        };
+        let major = initial >> 5;
+        let info = initial & 0x1f;
+        let argument_len = match info {
+            0..=23 => 0,
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            INDEFINITE => 0,
+            _ => return Err(parser::Error::InvalidCbor),
+        };
+        let header_len = 1 + argument_len;
+        if input.len() < header_len {
+            return Ok(None);
+        }
+        let argument = if info < 24 {
+            info as u64
+        } else {
+            input[1..header_len].iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64)
+        };
+        let len = || usize::try_from(argument).map_err(|_| parser::Error::InvalidCbor);
+        let item = match (major, info) {
+            (MAJOR_BYTES | MAJOR_TEXT, INDEFINITE) => {
+                // chunked strings are not supported
+                return Err(parser::Error::InvalidCbor);
+            },
+            (MAJOR_UNSIGNED | MAJOR_NEGATIVE | MAJOR_TAG, INDEFINITE) => return Err(parser::Error::InvalidCbor),
+            (MAJOR_ARRAY, INDEFINITE) => Item::Array(None),
+            (MAJOR_MAP, INDEFINITE) => Item::Map(None),
+            (MAJOR_UNSIGNED, _) => Item::Unsigned(argument),
+            (MAJOR_NEGATIVE, _) => Item::Negative(argument),
+            (MAJOR_BYTES | MAJOR_TEXT, _) => Item::String(len()?),
+            (MAJOR_ARRAY, _) => Item::Array(Some(len()?)),
+            (MAJOR_MAP, _) => Item::Map(Some(len()?)),
+            (MAJOR_TAG, _) => Item::Tag,
+            (MAJOR_SIMPLE, 20) => Item::Bool(false),
+            (MAJOR_SIMPLE, 21) => Item::Bool(true),
+            // undefined is treated as null
+            (MAJOR_SIMPLE, 22 | 23) => Item::Null,
+            (MAJOR_SIMPLE, 25) => Item::Float(half_to_f64(argument as u16)),
+            (MAJOR_SIMPLE, 26) => Item::Float(f32::from_bits(argument as u32) as f64),
+            (MAJOR_SIMPLE, 27) => Item::Float(f64::from_bits(argument)),
+            (MAJOR_SIMPLE, INDEFINITE) => Item::Break,
+            _ => return Err(parser::Error::InvalidCbor),
+        };
+        Ok(Some((item, header_len)))
+    }
+}
+
+pub type Generator<'a, WriteT> = binary::Generator<'a, Cbor, WriteT>;
+pub type Stage2 = binary::Stage2<Cbor>;
+pub type State<VisitorT> = binary::State<Cbor, VisitorT>;
+
+/// Converts regular sexps into CBOR, one top-level item per top-level sexp.
+pub fn make_writer<'a, ReadT: BufRead + Send, WriteT: Write>
+    (stdout: &'a mut WriteT, options: Options, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return parser_parallel::streaming_from_writing_stage2(move || { Stage2::new(options) }, stdout, config);
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    parser::streaming_from_writing_stage2(Stage2::new(options), stdout)
+}
+
+/// Converts a sequence of CBOR items into regular sexps, one per line.
+pub fn make_printer<'a, ReadT: BufRead, WriteT: Write>
+    (stdout: &'a mut WriteT)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    Box::new(State::new(csexp::Print::new(stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust_parser::{Sexp, SexpFactory, SingleTapeVisitor, SplitTapeVisitor};
+    use crate::visitor;
+
+    fn run_test(input: &[u8], expected_output: Result<&str, parser::Error>) {
+        let validate = |name: &str, output: Result<String, parser::Error>| {
+            assert_eq!(output.as_deref().map_err(|e| e.clone()), expected_output, "{}", name);
+        };
+
+        let sexps_to_string = |sexps: Vec<Sexp>| {
+            // print as a list, so adjacent atoms are separated as on the tapes
+            let list = Sexp::List(sexps).to_string();
+            list[1..(list.len() - 1)].to_string()
+        };
+
+        {
+            let mut parser = State::new(visitor::SimpleVisitor::new(SexpFactory::new()));
+            validate("SimpleVisitor<SexpFactory>", parser.process_all(input).map(sexps_to_string));
+        }
+
+        {
+            let mut parser = State::new(visitor::SimpleVisitor::new(SexpFactory::new()));
+            let mut buf_reader = std::io::BufReader::with_capacity(1, input);
+            validate("SimpleVisitor<SexpFactory> (process_streaming)", parser.process_streaming(&mut buf_reader).map(sexps_to_string));
+        }
+
+        {
+            let mut parser = State::new(SplitTapeVisitor::new());
+            validate("SplitTapeVisitor", parser.process_all(input).map(|tape| tape.to_string()));
+        }
+
+        {
+            let mut parser = State::new(SingleTapeVisitor::new());
+            let mut buf_reader = std::io::BufReader::with_capacity(3, input);
+            validate("SingleTapeVisitor (process_streaming)", parser.process_streaming(&mut buf_reader).map(|tape| tape.to_string()));
+        }
+    }
+
+    #[test] fn test_text() { run_test(b"\x63foo", Ok("foo")); }
+    #[test] fn test_bytes() { run_test(b"\x42\xff\x00", Ok("\"\\255\\000\"")); }
+    #[test] fn test_array() { run_test(b"\x82\x63foo\x81\x63bar\x80", Ok("(foo(bar))()")); }
+    #[test] fn test_map() { run_test(b"\xa2\x61a\x01\x61b\xa0", Ok("((a 1)(b()))")); }
+    #[test] fn test_indefinite() { run_test(b"\x9f\x01\xbf\x61a\x02\xff\xff", Ok("(1((a 2)))")); }
+    #[test] fn test_tag() { run_test(b"\xc1\x1a\x00\x01\x00\x00", Ok("65536")); }
+    #[test] fn test_long_string() { run_test(&[&b"\x78\x64"[..], &[b'x'; 100][..]].concat()[..], Ok(&"x".repeat(100))); }
+
+    #[test]
+    fn test_scalars() {
+        run_test(b"\x9f\x00\x17\x18\x18\x20\x38\x63\xf4\xf5\xf6\xf7\xf9\x3e\x00\xfa\x3f\xc0\x00\x00\xfb\x3f\xb9\x99\x99\x99\x99\x99\x9a\xff",
+                 Ok("(0 23 24 -1 -100 false true null null 1.5 1.5 0.1)"));
+    }
+
+    #[test]
+    fn test_errors() {
+        run_test(b"\x82\x01", Err(parser::Error::UnmatchedOpenParen));
+        run_test(b"\x63fo", Err(parser::Error::InvalidCbor));
+        run_test(b"\x19\x01", Err(parser::Error::InvalidCbor));
+        run_test(b"\xff", Err(parser::Error::UnmatchedCloseParen));
+        run_test(b"\x7f\x61a\xff", Err(parser::Error::InvalidCbor));
+        run_test(b"\x1c", Err(parser::Error::InvalidCbor));
+        run_test(b"\xbf\x61a\xff", Err(parser::Error::UnmatchedCloseParen));
+    }
+
+    fn generate(sexp: &Sexp, options: Options) -> Vec<u8> {
+        use visitor::ReadVisitable;
+        let mut output = Vec::new();
+        sexp.visit(&mut Generator::new(&mut output, options));
+        output
+    }
+
+    #[test]
+    fn test_generator() {
+        let atom = |a: &[u8]| Sexp::Atom(a.to_vec());
+        let record = Sexp::List(vec![
+            Sexp::List(vec![atom(b"a"), atom(b"1")]),
+            Sexp::List(vec![atom(b"b"), Sexp::List(vec![atom(b"\xff")])]),
+        ]);
+        assert_eq!(generate(&record, Options::default()), b"\x82\x82\x61a\x611\x82\x61b\x81\x41\xff");
+        assert_eq!(generate(&record, Options { records: true }), b"\xa2\x61a\x611\x61b\x81\x41\xff");
+        let long = atom(&[b'x'; 300][..]);
+        assert_eq!(&generate(&long, Options::default())[..3], b"\x79\x01\x2c");
+    }
+
+    fn round_trip_test(options: Options, input: &[u8], expected_output: &str) {
+        for threads in [false, true] {
+            let mut cbor = Vec::new();
+            let mut writer = make_writer(&mut cbor, options, threads);
+            writer.process_streaming(&mut std::io::BufReader::with_capacity(5, input)).unwrap();
+            std::mem::drop(writer);
+
+            let mut output = Vec::new();
+            let mut printer = make_printer(&mut output);
+            printer.process_streaming(&mut std::io::BufReader::with_capacity(5, &cbor[..])).unwrap();
+            std::mem::drop(printer);
+            assert_eq!(String::from_utf8(output).unwrap(), expected_output, "options: {:?}, threads: {}", options, threads);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip_test(Options::default(), b"(foo \"a b\" (\"\\255\" ()))\nbar\n", "(foo\"a b\"(\"\\255\"()))\nbar\n");
+        round_trip_test(Options { records: true }, b"((a 1) (b ((c x))))\n((a) (b))", "((a 1)(b((c x))))\n((a)(b))\n");
+    }
+
+    #[test]
+    fn test_round_trip_many_lines() {
+        let input = b"((id 1) (name \"x y\"))\n".repeat(50000);
+        let expected_output = "((id 1)(name\"x y\"))\n".repeat(50000);
+        round_trip_test(Options { records: true }, &input[..], &expected_output[..]);
+    }
+}
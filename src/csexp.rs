@@ -0,0 +1,465 @@
+use crate::escape::{self, Unescape};
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use crate::visitor::{self, Visitor};
+use std::io::{BufRead, Write};
+
+/// Length prefixes longer than this are rejected rather than risking overflow.
+const MAX_LENGTH_DIGITS: usize = 19;
+
+/// Writes one atom in canonical form: `<decimal length>:<bytes>`.
+pub fn write_atom<WriteT: Write>(writer: &mut WriteT, atom: &[u8]) -> std::io::Result<()> {
+    write!(writer, "{}:", atom.len())?;
+    writer.write_all(atom)
+}
+
+/// Writes canonical sexps, e.g. `(3:foo(3:bar))`. Top-level sexps are written
+/// back to back, which is unambiguous.
+pub struct Generator<'a, WriteT> {
+    writer: &'a mut WriteT,
+}
+
+impl<'a, WriteT: Write> Generator<'a, WriteT> {
+    pub fn new(writer: &'a mut WriteT) -> Self {
+        Self { writer }
+    }
+}
+
+impl<'a, WriteT: Write> visitor::ReadVisitor for Generator<'a, WriteT> {
+    fn reset(&mut self) {
+    }
+    fn atom(&mut self, atom: &[u8]) {
+        write_atom(self.writer, atom).unwrap();
+    }
+    fn list_open(&mut self) {
+        self.writer.write_all(b"(").unwrap();
+    }
+    fn list_close(&mut self) {
+        self.writer.write_all(b")").unwrap();
+    }
+    fn eof(&mut self) {
+    }
+}
+
+/// Converts regular sexps into canonical sexps.
+pub struct Stage2 {
+    unescape: escape::GenericUnescape,
+    atom_buffer: Vec<u8>,
+}
+
+impl Stage2 {
+    pub fn new() -> Self {
+        Self {
+            unescape: escape::GenericUnescape::new(),
+            atom_buffer: Vec::new(),
+        }
+    }
+}
+
+impl Default for Stage2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl parser::WritingStage2 for Stage2 {
+    fn reset(&mut self) {
+    }
+
+    #[inline]
+    fn process_one<WriteT: Write>(&mut self, writer: &mut WriteT, input: parser::Input, this_index: usize, next_index: usize, _is_eof: bool) -> Result<usize, parser::Error> {
+        match input.input[this_index - input.offset] {
+            b'(' => writer.write_all(b"(").unwrap(),
+            b')' => writer.write_all(b")").unwrap(),
+            b' ' | b'\t' | b'\n' => (),
+            b'"' => {
+                self.atom_buffer.resize(next_index - this_index, 0u8);
+                let (_, atom_len) =
+                    self.unescape.unescape(
+                        &input.input[(this_index + 1 - input.offset)..(next_index - input.offset)],
+                        &mut self.atom_buffer[..])
+                    .ok_or(parser::Error::BadQuotedAtom)?;
+                write_atom(writer, &self.atom_buffer[..atom_len]).unwrap();
+            },
+            _ => {
+                write_atom(writer, &input.input[(this_index - input.offset)..(next_index - input.offset)]).unwrap();
+            },
+        }
+        Ok(next_index)
+    }
+
+    fn process_eof<WriteT: Write>(&mut self, _writer: &mut WriteT) -> Result<(), parser::Error> {
+        Ok(())
+    }
+}
+
+/// Reads canonical sexps, driving any `Visitor`. Unlike `parser::State` there
+/// is no stage-1: the length prefixes mean there is nothing to classify, and
+/// atoms are copied out in bulk.
+pub struct State<VisitorT: Visitor> {
+    visitor: VisitorT,
+    context_stack: Vec<VisitorT::Context>,
+    /// Input that has been received but not yet consumed, because it ends
+    /// part way through an atom.
+    input: Vec<u8>,
+}
+
+impl<VisitorT: Visitor> State<VisitorT> {
+    pub fn new(visitor: VisitorT) -> Self {
+        Self {
+            visitor,
+            context_stack: Vec::new(),
+            input: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self, input_size_hint: Option<usize>) {
+        self.visitor.reset(input_size_hint);
+        self.context_stack.clear();
+        self.input.clear();
+    }
+
+    /// Parses the length prefix at the start of `input`. Returns the length
+    /// and the index of the first byte of the atom, or `None` if `input` ends
+    /// before the `:`.
+    #[inline]
+    fn length_prefix(input: &[u8]) -> Result<Option<(usize, usize)>, parser::Error> {
+        let search_len = std::cmp::min(input.len(), MAX_LENGTH_DIGITS + 1);
+        let colon_index = match memchr::memchr(b':', &input[..search_len]) {
+            Some(colon_index) => colon_index,
+            None if search_len > MAX_LENGTH_DIGITS => return Err(parser::Error::InvalidCsexp),
+            None => return Ok(None),
+        };
+        let digits = &input[..colon_index];
+        // canonical lengths have no leading zeros
+        if digits.is_empty() || (digits[0] == b'0' && digits.len() > 1) {
+            return Err(parser::Error::InvalidCsexp);
+        }
+        let mut length = 0usize;
+        for &digit in digits {
+            if !digit.is_ascii_digit() {
+                return Err(parser::Error::InvalidCsexp);
+            }
+            length = length * 10 + (digit - b'0') as usize;
+        }
+        Ok(Some((length, colon_index + 1)))
+    }
+
+    /// Returns the number of bytes consumed; anything after that is an
+    /// incomplete atom.
+    fn process_slice(&mut self, input: &[u8]) -> Result<usize, parser::Error> {
+        let mut index = 0;
+        while index < input.len() {
+            match input[index] {
+                b'(' => {
+                    let new_context = self.visitor.list_open(self.context_stack.last_mut());
+                    self.context_stack.push(new_context);
+                    index += 1;
+                },
+                b')' => {
+                    let context = self.context_stack.pop().ok_or(parser::Error::UnmatchedCloseParen)?;
+                    self.visitor.list_close(context, self.context_stack.last_mut());
+                    index += 1;
+                },
+                b'0'..=b'9' => {
+                    let (length, atom_start) = match Self::length_prefix(&input[index..])? {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    let atom_start = index + atom_start;
+                    if input.len() - atom_start < length {
+                        break;
+                    }
+                    let mut atom = self.visitor.atom_reserve(length);
+                    self.visitor.atom_borrow(&mut atom)[..length].copy_from_slice(&input[atom_start..(atom_start + length)]);
+                    self.visitor.atom(atom, length, self.context_stack.last_mut());
+                    index = atom_start + length;
+                },
+                _ => return Err(parser::Error::InvalidCsexp),
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn process_partial(&mut self, new_input: &[u8]) -> Result<(), parser::Error> {
+        if self.input.is_empty() {
+            let consumed = self.process_slice(new_input)?;
+            self.input.extend_from_slice(&new_input[consumed..]);
+        } else {
+            let mut input = std::mem::take(&mut self.input);
+            input.extend_from_slice(new_input);
+            let consumed = self.process_slice(&input[..])?;
+            input.drain(..consumed);
+            self.input = input;
+        }
+        Ok(())
+    }
+
+    pub fn process_eof(&mut self) -> Result<VisitorT::Return, parser::Error> {
+        if !self.input.is_empty() {
+            return Err(parser::Error::InvalidCsexp);
+        }
+        if !self.context_stack.is_empty() {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(self.visitor.eof())
+    }
+
+    pub fn process_streaming<BufReadT: BufRead>(&mut self, buf_reader: &mut BufReadT) -> Result<VisitorT::Return, parser::Error> {
+        self.reset(None);
+
+        loop {
+            match buf_reader.fill_buf() {
+                Ok(&[]) => { return self.process_eof(); },
+                Ok(buf) => {
+                    self.process_partial(buf)?;
+                    let len = buf.len();
+                    buf_reader.consume(len);
+                },
+                Err(e) => { return Err(parser::Error::IOError(e.kind())) },
+            }
+        }
+    }
+
+    pub fn process_all(&mut self, input: &[u8]) -> Result<VisitorT::Return, parser::Error> {
+        self.reset(Some(input.len()));
+        self.process_partial(input)?;
+        self.process_eof()
+    }
+}
+
+impl<VisitorT: Visitor + parser::ExtractPartialResult> parser::ExtractPartialResult for State<VisitorT> {
+    type PartialReturn = VisitorT::PartialReturn;
+    fn extract_partial_result(&mut self) -> Self::PartialReturn {
+        self.visitor.extract_partial_result()
+    }
+}
+
+impl<VisitorT: Visitor> parser::Parse for State<VisitorT> {
+    type Return = VisitorT::Return;
+    fn process(&mut self, input: &[u8]) -> Result<Self::Return, parser::Error> {
+        self.process_all(input)
+    }
+}
+
+impl<VisitorT: Visitor + parser::ExtractPartialResult> parser::ParsePartial for State<VisitorT> {
+    fn process_partial(&mut self, input: &[u8]) -> Result<(), parser::Error> {
+        self.process_partial(input)
+    }
+    fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
+        self.process_eof()
+    }
+}
+
+impl<BufReadT: BufRead, VisitorT: Visitor> parser::Stream<BufReadT> for State<VisitorT> {
+    type Return = VisitorT::Return;
+    fn process_streaming(&mut self, buf_reader: &mut BufReadT) -> Result<Self::Return, parser::Error> {
+        self.process_streaming(buf_reader)
+    }
+    fn process_slice(&mut self, input: &[u8]) -> Result<Self::Return, parser::Error> {
+        self.process_all(input)
+    }
+}
+
+/// Visitor that prints regular sexps, one top-level sexp per line, like
+/// `print`.
+pub struct Print<'a, WriteT> {
+    writer: &'a mut WriteT,
+    escape_is_necessary: escape::IsNecessary,
+    atom_buffer: Vec<u8>,
+    naked_atom_needs_space: bool,
+}
+
+impl<'a, WriteT: Write> Print<'a, WriteT> {
+    pub fn new(writer: &'a mut WriteT) -> Self {
+        Self {
+            writer,
+            escape_is_necessary: escape::IsNecessary::new(),
+            atom_buffer: Vec::new(),
+            naked_atom_needs_space: false,
+        }
+    }
+
+    fn end_of_top_level(&mut self) {
+        self.writer.write_all(b"\n").unwrap();
+        self.naked_atom_needs_space = false;
+    }
+}
+
+impl<'a, WriteT: Write> Visitor for Print<'a, WriteT> {
+    type IntermediateAtom = Vec<u8>;
+    type Context = ();
+    type Return = ();
+
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.naked_atom_needs_space = false;
+    }
+
+    fn atom_reserve(&mut self, length_upper_bound: usize) -> Self::IntermediateAtom {
+        let mut atom = std::mem::take(&mut self.atom_buffer);
+        atom.resize(length_upper_bound, 0u8);
+        atom
+    }
+
+    fn atom_borrow<'b, 'c : 'b>(&'c mut self, atom: &'b mut Self::IntermediateAtom) -> &'b mut [u8] {
+        &mut atom[..]
+    }
+
+    fn atom(&mut self, atom: Self::IntermediateAtom, length: usize, parent_context: Option<&mut ()>) {
+        let atom_slice = &atom[..length];
+        if self.escape_is_necessary.eval(atom_slice) {
+            self.writer.write_all(b"\"").unwrap();
+            escape::escape(atom_slice, self.writer).unwrap();
+            self.writer.write_all(b"\"").unwrap();
+            self.naked_atom_needs_space = false;
+        } else {
+            if self.naked_atom_needs_space {
+                self.writer.write_all(b" ").unwrap();
+            }
+            self.writer.write_all(atom_slice).unwrap();
+            self.naked_atom_needs_space = true;
+        }
+        self.atom_buffer = atom;
+        if parent_context.is_none() {
+            self.end_of_top_level();
+        }
+    }
+
+    fn list_open(&mut self, _parent_context: Option<&mut ()>) {
+        self.writer.write_all(b"(").unwrap();
+        self.naked_atom_needs_space = false;
+    }
+
+    fn list_close(&mut self, _context: (), parent_context: Option<&mut ()>) {
+        self.writer.write_all(b")").unwrap();
+        self.naked_atom_needs_space = false;
+        if parent_context.is_none() {
+            self.end_of_top_level();
+        }
+    }
+
+    fn eof(&mut self) {
+    }
+}
+
+/// Converts regular sexps into canonical sexps.
+pub fn make_writer<'a, ReadT: BufRead + Send, WriteT: Write>
+    (stdout: &'a mut WriteT, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return parser_parallel::streaming_from_writing_stage2(Stage2::new, stdout, config);
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    parser::streaming_from_writing_stage2(Stage2::new(), stdout)
+}
+
+/// Converts canonical sexps into regular sexps.
+pub fn make_printer<'a, ReadT: BufRead, WriteT: Write>
+    (stdout: &'a mut WriteT)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    Box::new(State::new(Print::new(stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust_parser::{Sexp, SexpFactory, SingleTapeVisitor, SplitTapeVisitor};
+
+    fn run_test(input: &[u8], expected_output: Result<&str, parser::Error>) {
+        let validate = |name: &str, output: Result<String, parser::Error>| {
+            assert_eq!(output.as_deref().map_err(|e| e.clone()), expected_output, "{}", name);
+        };
+
+        let sexps_to_string = |sexps: Vec<Sexp>| {
+            // print as a list, so adjacent atoms are separated as on the tapes
+            let list = Sexp::List(sexps).to_string();
+            list[1..(list.len() - 1)].to_string()
+        };
+
+        {
+            let mut parser = State::new(visitor::SimpleVisitor::new(SexpFactory::new()));
+            validate("SimpleVisitor<SexpFactory>", parser.process_all(input).map(sexps_to_string));
+        }
+
+        {
+            let mut parser = State::new(visitor::SimpleVisitor::new(SexpFactory::new()));
+            let mut buf_reader = std::io::BufReader::with_capacity(1, input);
+            validate("SimpleVisitor<SexpFactory> (process_streaming)", parser.process_streaming(&mut buf_reader).map(sexps_to_string));
+        }
+
+        {
+            let mut parser = State::new(SplitTapeVisitor::new());
+            validate("SplitTapeVisitor", parser.process_all(input).map(|tape| tape.to_string()));
+        }
+
+        {
+            let mut parser = State::new(SingleTapeVisitor::new());
+            let mut buf_reader = std::io::BufReader::with_capacity(3, input);
+            validate("SingleTapeVisitor (process_streaming)", parser.process_streaming(&mut buf_reader).map(|tape| tape.to_string()));
+        }
+    }
+
+    #[test] fn test_atom() { run_test(b"3:foo", Ok("foo")); }
+    #[test] fn test_empty_atom() { run_test(b"0:", Ok("\"\"")); }
+    #[test] fn test_list() { run_test(b"(3:foo(3:bar)())", Ok("(foo(bar)())")); }
+    #[test] fn test_many() { run_test(b"1:a(1:b)1:c1:d", Ok("a(b)c d")); }
+    #[test] fn test_binary() { run_test(b"(5:a b()2:\n\")", Ok("(\"a b()\"\"\\n\\\"\")")); }
+    #[test] fn test_long() { run_test(format!("100:{}", "x".repeat(100)).as_bytes(), Ok(&"x".repeat(100))); }
+
+    #[test]
+    fn test_errors() {
+        run_test(b"(3:foo", Err(parser::Error::UnmatchedOpenParen));
+        run_test(b"3:foo)", Err(parser::Error::UnmatchedCloseParen));
+        run_test(b"4:foo", Err(parser::Error::InvalidCsexp));
+        run_test(b"3foo", Err(parser::Error::InvalidCsexp));
+        run_test(b"03:foo", Err(parser::Error::InvalidCsexp));
+        run_test(b"3:foo 3:bar", Err(parser::Error::InvalidCsexp));
+        run_test(b"99999999999999999999:", Err(parser::Error::InvalidCsexp));
+    }
+
+    #[test]
+    fn test_generator() {
+        use visitor::ReadVisitable;
+        let sexp = Sexp::List(vec![Sexp::Atom(b"foo".to_vec()), Sexp::List(vec![Sexp::Atom(b"a b".to_vec())])]);
+        let mut output = Vec::new();
+        sexp.visit(&mut Generator::new(&mut output));
+        assert_eq!(&output[..], b"(3:foo(3:a b))");
+    }
+
+    fn round_trip_test(input: &[u8], expected_csexp: &[u8], expected_output: &str) {
+        for threads in [false, true] {
+            let mut csexp = Vec::new();
+            let mut writer = make_writer(&mut csexp, threads);
+            writer.process_streaming(&mut std::io::BufReader::with_capacity(5, input)).unwrap();
+            std::mem::drop(writer);
+            assert_eq!(&csexp[..], expected_csexp, "threads: {}", threads);
+
+            let mut output = Vec::new();
+            let mut printer = make_printer(&mut output);
+            printer.process_streaming(&mut std::io::BufReader::with_capacity(5, &csexp[..])).unwrap();
+            std::mem::drop(printer);
+            assert_eq!(String::from_utf8(output).unwrap(), expected_output, "threads: {}", threads);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip_test(b"(foo \"a b\" (\"\\n\"))\nbar\n", b"(3:foo3:a b(1:\n))3:bar", "(foo\"a b\"(\"\\n\"))\nbar\n");
+    }
+
+    #[test]
+    fn test_round_trip_many_lines() {
+        let input = b"((id 1) (name \"x y\"))\n".repeat(50000);
+        let expected_csexp = b"((2:id1:1)(4:name3:x y))".repeat(50000);
+        let expected_output = "((id 1)(name\"x y\"))\n".repeat(50000);
+        round_trip_test(&input[..], &expected_csexp[..], &expected_output[..]);
+    }
+}
@@ -0,0 +1,651 @@
+use crate::escape::{self, Unescape};
+use crate::{parser, structural};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Range;
+
+pub type Error = parser::Error;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    LineComment,
+    BlockComment,
+    SexpComment,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: Vec<u8>,
+}
+
+impl Trivia {
+    pub fn whitespace(text: &[u8]) -> Self {
+        Self { kind: TriviaKind::Whitespace, text: text.to_vec() }
+    }
+
+    pub fn is_comment(&self) -> bool {
+        self.kind != TriviaKind::Whitespace
+    }
+}
+
+/// An atom, stored exactly as it was spelled in the input (including the
+/// surrounding double quotes, if any).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Atom {
+    raw: Vec<u8>,
+}
+
+impl Atom {
+    /// Creates an atom with the given value, quoting it only if necessary.
+    pub fn new(value: &[u8]) -> Self {
+        let mut raw = Vec::with_capacity(value.len());
+        if escape::IsNecessary::new().eval(value) {
+            raw.push(b'"');
+            escape::escape(value, &mut raw).unwrap();
+            raw.push(b'"');
+        } else {
+            raw.extend_from_slice(value);
+        }
+        Self { raw }
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        &self.raw[..]
+    }
+
+    pub fn is_quoted(&self) -> bool {
+        self.raw.first() == Some(&b'"')
+    }
+
+    /// The (unescaped) value of the atom.
+    pub fn value(&self) -> Cow<'_, [u8]> {
+        if self.is_quoted() {
+            let mut value = vec![0u8; self.raw.len()];
+            let (_, value_len) = escape::GenericUnescape::new().unescape(&self.raw[1..], &mut value[..]).unwrap();
+            value.truncate(value_len);
+            Cow::Owned(value)
+        } else {
+            Cow::Borrowed(&self.raw[..])
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Node {
+    Atom(Atom),
+    List(List),
+}
+
+impl Node {
+    pub fn atom(value: &[u8]) -> Self {
+        Node::Atom(Atom::new(value))
+    }
+
+    /// Creates a list whose children are separated by single spaces.
+    pub fn list<NodesT: IntoIterator<Item = Node>>(nodes: NodesT) -> Self {
+        let mut list = List::new();
+        for node in nodes {
+            list.push(node);
+        }
+        Node::List(list)
+    }
+
+    pub fn as_atom(&self) -> Option<&Atom> {
+        match self {
+            Node::Atom(atom) => Some(atom),
+            Node::List(_) => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&List> {
+        match self {
+            Node::Atom(_) => None,
+            Node::List(list) => Some(list),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut List> {
+        match self {
+            Node::Atom(_) => None,
+            Node::List(list) => Some(list),
+        }
+    }
+
+    pub fn write<WriteT: Write>(&self, writer: &mut WriteT) -> std::io::Result<()> {
+        match self {
+            Node::Atom(atom) => writer.write_all(&atom.raw[..]),
+            Node::List(list) => {
+                writer.write_all(b"(")?;
+                list.write_contents(writer)?;
+                writer.write_all(b")")
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Element {
+    pub leading: Vec<Trivia>,
+    pub node: Node,
+}
+
+impl Element {
+    fn has_comments(&self) -> bool {
+        self.leading.iter().any(Trivia::is_comment)
+    }
+
+    /// Number of whitespace trivia in front of the first comment (if any).
+    fn leading_whitespace_len(&self) -> usize {
+        self.leading.iter().position(Trivia::is_comment).unwrap_or(self.leading.len())
+    }
+
+    pub fn write<WriteT: Write>(&self, writer: &mut WriteT) -> std::io::Result<()> {
+        for trivia in self.leading.iter() {
+            writer.write_all(&trivia.text[..])?;
+        }
+        self.node.write(writer)
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct List {
+    children: Vec<Element>,
+    /// Trivia after the last child, before the closing paren.
+    pub trailing: Vec<Trivia>,
+}
+
+impl List {
+    pub fn new() -> Self {
+        Self { children: Vec::new(), trailing: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    pub fn children(&self) -> &[Element] {
+        &self.children[..]
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Node> {
+        self.children.get(index).map(|element| &element.node)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Node> {
+        self.children.get_mut(index).map(|element| &mut element.node)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Node> {
+        self.children.iter().map(|element| &element.node)
+    }
+
+    /// The separator to use in front of a newly inserted child, copied from an
+    /// existing sibling where possible so that the list keeps its layout.
+    fn separator_like(&self, index: usize) -> Vec<Trivia> {
+        let sibling =
+            self.children.get(index).filter(|_| index > 0)
+            .or_else(|| self.children.last().filter(|_| self.children.len() > 1));
+        match sibling {
+            Some(sibling) if !sibling.has_comments() && !sibling.leading.is_empty() => sibling.leading.clone(),
+            _ => vec![Trivia::whitespace(b" ")],
+        }
+    }
+
+    /// Inserts `node` so that it becomes the child at `index`. Trivia around
+    /// the existing children is left untouched, except that when inserting at
+    /// the front the previous first child is given a separator.
+    pub fn insert(&mut self, index: usize, node: Node) {
+        assert!(index <= self.children.len(), "insertion index (is {}) should be <= len (is {})", index, self.children.len());
+        let leading =
+            if self.children.is_empty() {
+                Vec::new()
+            } else if index == 0 {
+                let separator = self.separator_like(1);
+                let old_first = &mut self.children[0];
+                let leading: Vec<Trivia> = old_first.leading.drain(..old_first.leading_whitespace_len()).collect();
+                old_first.leading.splice(0..0, separator);
+                leading
+            } else {
+                self.separator_like(index)
+            };
+        self.children.insert(index, Element { leading, node });
+    }
+
+    pub fn push(&mut self, node: Node) {
+        self.insert(self.children.len(), node)
+    }
+
+    /// Replaces the child at `index`, keeping the trivia in front of it.
+    pub fn replace(&mut self, index: usize, node: Node) -> Node {
+        std::mem::replace(&mut self.children[index].node, node)
+    }
+
+    /// Removes the child at `index` along with any comments attached to it.
+    pub fn remove(&mut self, index: usize) -> Node {
+        let mut removed = self.children.remove(index);
+        if index == 0 {
+            if let Some(new_first) = self.children.first_mut() {
+                let new_first_whitespace_len = new_first.leading_whitespace_len();
+                let removed_whitespace_len = removed.leading_whitespace_len();
+                new_first.leading.splice(..new_first_whitespace_len, removed.leading.drain(..removed_whitespace_len));
+            }
+        }
+        removed.node
+    }
+
+    fn write_contents<WriteT: Write>(&self, writer: &mut WriteT) -> std::io::Result<()> {
+        for element in self.children.iter() {
+            element.write(writer)?;
+        }
+        for trivia in self.trailing.iter() {
+            writer.write_all(&trivia.text[..])?;
+        }
+        Ok(())
+    }
+}
+
+/**
+Lossless concrete syntax tree: the top-level sequence of sexps in a file.
+
+Unlike `rust_parser::Sexp` and the tapes, a `Document` remembers everything
+about the input it was parsed from: whitespace, comments (`; line`,
+`#| block |#` and `#; sexp` comments) and the exact spelling of every atom. Any
+such trivia is attached to the element that follows it; trivia before a closing
+paren (or before EOF at the top-level) is kept on the enclosing list.
+
+Writing out an unmodified `Document` reproduces the input byte-for-byte.
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Document {
+    pub body: List,
+}
+
+impl Document {
+    pub fn write<WriteT: Write>(&self, writer: &mut WriteT) -> std::io::Result<()> {
+        self.body.write_contents(writer)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.write(&mut output).unwrap();
+        output
+    }
+}
+
+// Start of parser implementation
+
+#[derive(Clone, Debug)]
+enum Token {
+    Open(usize),
+    Close(usize),
+    Atom(Range<usize>),
+    Comment(TriviaKind, Range<usize>),
+    SexpCommentMarker(Range<usize>),
+}
+
+/// Turns the structural indices produced by stage-1 into tokens. Stage-1 knows
+/// nothing about comments, so whenever we find one we skip past it by hand and
+/// restart stage-1 afterwards (comments can contain unbalanced double quotes,
+/// which would otherwise throw stage-1 off).
+struct Tokenizer<'a, ClassifierT> {
+    input: &'a [u8],
+    initial_classifier: ClassifierT,
+    classifier: ClassifierT,
+    classified_up_to: usize,
+    indices: VecDeque<usize>,
+    unescape: escape::GenericUnescape,
+    scratch: Vec<u8>,
+}
+
+impl<'a, ClassifierT: structural::Classifier> Tokenizer<'a, ClassifierT> {
+    fn new(input: &'a [u8], classifier: ClassifierT) -> Self {
+        Self {
+            input,
+            initial_classifier: classifier.clone(),
+            classifier,
+            classified_up_to: 0,
+            indices: VecDeque::new(),
+            unescape: escape::GenericUnescape::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    fn restart(&mut self, input_index: usize) {
+        self.classifier = self.initial_classifier.clone();
+        self.classified_up_to = input_index;
+        self.indices.clear();
+    }
+
+    fn next_index(&mut self) -> Option<usize> {
+        while self.indices.is_empty() && self.classified_up_to < self.input.len() {
+            let classified_up_to = &mut self.classified_up_to;
+            let indices = &mut self.indices;
+            self.classifier.structural_indices_bitmask(&self.input[*classified_up_to..], |bitmask, bitmask_len| {
+                crate::extract::safe_generic(|bit_offset| {
+                    indices.push_back(*classified_up_to + bit_offset);
+                }, bitmask);
+                *classified_up_to += bitmask_len;
+                structural::CallbackResult::Finish
+            });
+        }
+        self.indices.pop_front()
+    }
+
+    fn peek_index(&mut self) -> Option<usize> {
+        let index = self.next_index()?;
+        self.indices.push_front(index);
+        Some(index)
+    }
+
+    fn block_comment_end(&self, start: usize) -> Result<usize, Error> {
+        let mut depth = 0usize;
+        let mut i = start;
+        while i + 1 < self.input.len() {
+            match &self.input[i..(i + 2)] {
+                // As in sexplib, `|#` inside a quoted string does not end the
+                // comment.
+                [b'"', _] => {
+                    i += 1;
+                    while i < self.input.len() && self.input[i] != b'"' {
+                        i += if self.input[i] == b'\\' { 2 } else { 1 };
+                    }
+                    i += 1;
+                },
+                b"#|" => { depth += 1; i += 2; },
+                b"|#" => {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 {
+                        return Ok(i);
+                    }
+                },
+                _ => { i += 1; },
+            }
+        }
+        Err(Error::UnterminatedComment)
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, Error> {
+        loop {
+            let this_index = match self.next_index() {
+                None => return Ok(None),
+                Some(this_index) => this_index,
+            };
+            match self.input[this_index] {
+                b'(' => return Ok(Some(Token::Open(this_index))),
+                b')' => return Ok(Some(Token::Close(this_index))),
+                b' ' | b'\t' | b'\n' => (),
+                b'"' => {
+                    // The unescaped atom is no longer than the input up to the
+                    // next structural character, as in `parser::Stage2`.
+                    let next_index = self.peek_index().unwrap_or(self.input.len());
+                    self.scratch.resize(next_index - this_index, 0);
+                    let (input_consumed, _) =
+                        self.unescape.unescape(&self.input[(this_index + 1)..], &mut self.scratch[..])
+                        .ok_or(Error::BadQuotedAtom)?;
+                    return Ok(Some(Token::Atom(this_index..(this_index + input_consumed + 2))));
+                },
+                _ => {
+                    let next_index = self.peek_index().unwrap_or(self.input.len());
+                    let atom = &self.input[this_index..next_index];
+                    let comment_start =
+                        (0..atom.len()).find(|&i| atom[i] == b';' || (atom[i] == b'#' && matches!(atom.get(i + 1), Some(b'|' | b';'))));
+                    return Ok(Some(match comment_start {
+                        None => Token::Atom(this_index..next_index),
+                        Some(0) => {
+                            let token = match atom {
+                                [b'#', b';', ..] => Token::SexpCommentMarker(this_index..(this_index + 2)),
+                                [b'#', b'|', ..] => Token::Comment(TriviaKind::BlockComment, this_index..self.block_comment_end(this_index)?),
+                                _ => {
+                                    let end = memchr::memchr(b'\n', &self.input[this_index..]).map_or(self.input.len(), |i| this_index + i);
+                                    Token::Comment(TriviaKind::LineComment, this_index..end)
+                                },
+                            };
+                            let end = match &token { Token::SexpCommentMarker(range) | Token::Comment(_, range) => range.end, _ => unreachable!() };
+                            self.restart(end);
+                            token
+                        },
+                        Some(comment_start) => {
+                            self.restart(this_index + comment_start);
+                            Token::Atom(this_index..(this_index + comment_start))
+                        },
+                    }));
+                },
+            }
+        }
+    }
+}
+
+enum Frame {
+    List { leading: Vec<Trivia>, list: List },
+    SexpComment { leading: Vec<Trivia>, start: usize },
+}
+
+struct Builder<'a> {
+    input: &'a [u8],
+    stack: Vec<Frame>,
+    pending_trivia: Vec<Trivia>,
+    last_end: usize,
+    body: List,
+}
+
+impl<'a> Builder<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            stack: Vec::new(),
+            pending_trivia: Vec::new(),
+            last_end: 0,
+            body: List::new(),
+        }
+    }
+
+    fn gap(&mut self, start: usize) {
+        if start > self.last_end {
+            self.pending_trivia.push(Trivia::whitespace(&self.input[self.last_end..start]));
+        }
+    }
+
+    fn element(&mut self, element: Element) {
+        match self.stack.last_mut() {
+            None => self.body.children.push(element),
+            Some(Frame::List { list, .. }) => list.children.push(element),
+            Some(Frame::SexpComment { .. }) => {
+                // The element just completed is the one being commented out;
+                // the whole thing (including any nested trivia) becomes
+                // trivia for whatever follows.
+                let (leading, start) = match self.stack.pop() {
+                    Some(Frame::SexpComment { leading, start }) => (leading, start),
+                    _ => unreachable!(),
+                };
+                self.pending_trivia = leading;
+                self.pending_trivia.push(Trivia { kind: TriviaKind::SexpComment, text: self.input[start..self.last_end].to_vec() });
+            },
+        }
+    }
+
+    fn token(&mut self, token: Token) -> Result<(), Error> {
+        match token {
+            Token::Open(index) => {
+                self.gap(index);
+                let leading = std::mem::take(&mut self.pending_trivia);
+                self.stack.push(Frame::List { leading, list: List::new() });
+                self.last_end = index + 1;
+            },
+            Token::Close(index) => {
+                self.gap(index);
+                self.last_end = index + 1;
+                match self.stack.pop() {
+                    Some(Frame::List { leading, mut list }) => {
+                        list.trailing = std::mem::take(&mut self.pending_trivia);
+                        self.element(Element { leading, node: Node::List(list) });
+                    },
+                    _ => return Err(Error::UnmatchedCloseParen),
+                }
+            },
+            Token::Atom(range) => {
+                self.gap(range.start);
+                self.last_end = range.end;
+                let leading = std::mem::take(&mut self.pending_trivia);
+                self.element(Element { leading, node: Node::Atom(Atom { raw: self.input[range].to_vec() }) });
+            },
+            Token::Comment(kind, range) => {
+                self.gap(range.start);
+                self.last_end = range.end;
+                self.pending_trivia.push(Trivia { kind, text: self.input[range].to_vec() });
+            },
+            Token::SexpCommentMarker(range) => {
+                self.gap(range.start);
+                self.last_end = range.end;
+                let leading = std::mem::take(&mut self.pending_trivia);
+                self.stack.push(Frame::SexpComment { leading, start: range.start });
+            },
+        }
+        Ok(())
+    }
+
+    fn eof(mut self) -> Result<Document, Error> {
+        self.gap(self.input.len());
+        self.last_end = self.input.len();
+        while let Some(frame) = self.stack.pop() {
+            match frame {
+                Frame::List { .. } => return Err(Error::UnmatchedOpenParen),
+                Frame::SexpComment { mut leading, start } => {
+                    // A dangling "#;" at the end of the input: keep it verbatim.
+                    leading.push(Trivia { kind: TriviaKind::SexpComment, text: self.input[start..].to_vec() });
+                    self.pending_trivia = leading;
+                },
+            }
+        }
+        self.body.trailing = self.pending_trivia;
+        Ok(Document { body: self.body })
+    }
+}
+
+fn parse_with_classifier<ClassifierT: structural::Classifier>(input: &[u8], classifier: ClassifierT) -> Result<Document, Error> {
+    let mut tokenizer = Tokenizer::new(input, classifier);
+    let mut builder = Builder::new(input);
+    while let Some(token) = tokenizer.next_token()? {
+        builder.token(token)?;
+    }
+    builder.eof()
+}
+
+struct MakeCstFromClassifierCps<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> structural::MakeClassifierCps<'a> for MakeCstFromClassifierCps<'a> {
+    type Return = Result<Document, Error>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        parse_with_classifier(self.input, classifier)
+    }
+}
+
+pub fn parse(input: &[u8]) -> Result<Document, Error> {
+    structural::make_classifier_cps(MakeCstFromClassifierCps { input })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_test(input: &[u8]) {
+        let document = parse(input).unwrap();
+        let output = document.to_bytes();
+        if output != input {
+            println!("input:      {:?}", String::from_utf8_lossy(input));
+            println!("actual out: {:?}", String::from_utf8_lossy(&output[..]));
+            panic!("cst round-trip test failed");
+        }
+
+        let generic = parse_with_classifier(input, structural::Generic::new()).unwrap();
+        assert_eq!(generic, document);
+    }
+
+    fn edit_test<F: FnOnce(&mut Document)>(input: &[u8], f: F, expected_output: &str) {
+        let mut document = parse(input).unwrap();
+        f(&mut document);
+        assert_eq!(String::from_utf8(document.to_bytes()).unwrap(), expected_output);
+    }
+
+    fn list_mut<'a>(document: &'a mut Document, index: usize) -> &'a mut List {
+        document.body.get_mut(index).unwrap().as_list_mut().unwrap()
+    }
+
+    #[test] fn test_empty() { run_test(b""); }
+    #[test] fn test_whitespace() { run_test(b"  \n\t "); }
+    #[test] fn test_atoms() { run_test(b"foo  bar\n baz"); }
+    #[test] fn test_lists() { run_test(b"(foo (bar  baz) ())\n(x)"); }
+    #[test] fn test_quoting() { run_test(br#"("foo" "\x41\065\n" a"b"c "")"#); }
+    #[test] fn test_line_comment() { run_test(b"; hello (world\n(foo ; bar \" baz\n quux)\n;eof"); }
+    #[test] fn test_line_comment_in_atom() { run_test(b"(foo;bar\n baz)"); }
+    #[test] fn test_block_comment() { run_test(b"(foo #| bar #| nested |# \"( |#\" |# baz)"); }
+    #[test] fn test_block_comment_quoted() { run_test(br#"(a #| "|#" "\"|#" |# b)"#); }
+    #[test] fn test_sexp_comment() { run_test(b"(foo #; (bar \"baz\") quux #;#;a b c)\n#;"); }
+    #[test] fn test_long() { run_test(&b"(foo \"bar baz\" ; comment\n (a b c))\n".repeat(20)[..]); }
+
+    #[test]
+    fn test_structure() {
+        let document = parse(b"(a ; c\n \"b\\n\") #;x").unwrap();
+        assert_eq!(document.body.len(), 1);
+        let list = document.body.get(0).unwrap().as_list().unwrap();
+        assert_eq!(list.len(), 2);
+        let b = &list.children()[1];
+        assert_eq!(b.leading.iter().map(|trivia| trivia.kind).collect::<Vec<_>>(),
+                   vec![TriviaKind::Whitespace, TriviaKind::LineComment, TriviaKind::Whitespace]);
+        let b = b.node.as_atom().unwrap();
+        assert!(b.is_quoted());
+        assert_eq!(&b.value()[..], b"b\n");
+        assert_eq!(document.body.trailing.last().unwrap(), &Trivia { kind: TriviaKind::SexpComment, text: b"#;x".to_vec() });
+
+        let document = parse(br#"(a #| "|#" |# b)"#).unwrap();
+        let list = document.body.get(0).unwrap().as_list().unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.children()[1].leading.iter().map(|trivia| trivia.kind).collect::<Vec<_>>(),
+                   vec![TriviaKind::Whitespace, TriviaKind::BlockComment, TriviaKind::Whitespace]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(b"(foo"), Err(Error::UnmatchedOpenParen));
+        assert_eq!(parse(b"foo)"), Err(Error::UnmatchedCloseParen));
+        assert_eq!(parse(b"\"foo"), Err(Error::BadQuotedAtom));
+        assert_eq!(parse(b"#| foo"), Err(Error::UnterminatedComment));
+        assert_eq!(parse(b"#| \"foo |#"), Err(Error::UnterminatedComment));
+    }
+
+    #[test]
+    fn test_insert() {
+        edit_test(b"(a\n ; about b\n b)", |document| list_mut(document, 0).push(Node::atom(b"c d")), "(a\n ; about b\n b \"c d\")");
+        edit_test(b"(a\n b)", |document| list_mut(document, 0).push(Node::atom(b"c")), "(a\n b\n c)");
+        edit_test(b"(a\n b)", |document| list_mut(document, 0).insert(1, Node::atom(b"c")), "(a\n c\n b)");
+        edit_test(b"( a\n b)", |document| list_mut(document, 0).insert(0, Node::atom(b"c")), "( c\n a\n b)");
+        edit_test(b"(\n ; about a\n a)", |document| list_mut(document, 0).insert(0, Node::atom(b"b")), "(\n b ; about a\n a)");
+        edit_test(b"()", |document| list_mut(document, 0).push(Node::list([Node::atom(b"x"), Node::atom(b"y")])), "((x y))");
+    }
+
+    #[test]
+    fn test_replace() {
+        edit_test(b"(a ; keep me\n \"b\" c)", |document| {
+            let old = list_mut(document, 0).replace(1, Node::atom(b"B"));
+            assert_eq!(old, Node::Atom(Atom { raw: b"\"b\"".to_vec() }));
+        }, "(a ; keep me\n B c)");
+    }
+
+    #[test]
+    fn test_remove() {
+        edit_test(b"(a b c)", |document| { list_mut(document, 0).remove(1); }, "(a c)");
+        edit_test(b"(a b c)", |document| { list_mut(document, 0).remove(0); }, "(b c)");
+        edit_test(b"(a\n ; about b\n b\n c)", |document| { list_mut(document, 0).remove(1); }, "(a\n c)");
+        edit_test(b"( a ; about b\n b)", |document| { list_mut(document, 0).remove(0); }, "( ; about b\n b)");
+        edit_test(b"x\n(a)\ny\n", |document| { document.body.remove(1); }, "x\ny\n");
+    }
+}
@@ -15,3 +15,48 @@ pub fn escape<WriterT: std::io::Write>(input: &[u8], output: &mut WriterT) -> Re
     Ok(())
 }
 
+
+/// Like `escape_is_necessary`, for fields separated by `delimiter` rather
+/// than a comma.
+pub fn escape_is_necessary_with_delimiter(input: &[u8], delimiter: u8) -> bool {
+    memchr::memchr3(b'"', delimiter, b'\n', input).is_some() || memchr::memchr(b'\r', input).is_some()
+}
+
+/// Writes `input` without quotes, backslash-escaping backslashes, line breaks,
+/// tabs and `delimiter` (as in the "linear" TSV convention).
+pub fn escape_backslash<WriterT: std::io::Write>(input: &[u8], delimiter: u8, output: &mut WriterT) -> Result<(), std::io::Error> {
+    let mut i = 0;
+    for (j, &ch) in input.iter().enumerate() {
+        let escaped: &[u8] = match ch {
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            _ if ch == delimiter => &[b'\\', delimiter],
+            _ => continue,
+        };
+        output.write_all(&input[i..j])?;
+        output.write_all(escaped)?;
+        i = j + 1;
+    }
+    output.write_all(&input[i..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_backslash() {
+        let mut output = Vec::new();
+        escape_backslash(b"a\tb\\c\nd|e", b'|', &mut output).unwrap();
+        assert_eq!(output, b"a\\tb\\\\c\\nd\\|e");
+    }
+
+    #[test]
+    fn test_escape_is_necessary_with_delimiter() {
+        assert!(escape_is_necessary_with_delimiter(b"a\tb", b'\t'));
+        assert!(escape_is_necessary_with_delimiter(b"a\rb", b'\t'));
+        assert!(!escape_is_necessary_with_delimiter(b"a,b", b'\t'));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::escape;
+
+/// Writes `input` as the contents of a JSON string (without the surrounding
+/// double quotes). Bytes that are not part of valid UTF-8 sequences are
+/// interpreted as Latin-1 and written as `\u00XX` escapes.
+pub fn escape<WriterT: std::io::Write>(input: &[u8], output: &mut WriterT) -> Result<(), std::io::Error> {
+    let mut input = input;
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(input) {
+            Ok(valid) => (valid.as_bytes(), &[][..]),
+            Err(e) => {
+                let (valid, rest) = input.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                input = &rest[invalid_len..];
+                (valid, &rest[..invalid_len])
+            },
+        };
+        let mut i = 0;
+        for (j, &ch) in valid.iter().enumerate() {
+            let escaped: &[u8] = match ch {
+                b'"' => b"\\\"",
+                b'\\' => b"\\\\",
+                b'\n' => b"\\n",
+                b'\r' => b"\\r",
+                b'\t' => b"\\t",
+                0x00..=0x1F | 0x7F => b"",
+                _ => continue,
+            };
+            output.write_all(&valid[i..j])?;
+            if escaped.is_empty() {
+                write!(output, "\\u{:04x}", ch)?;
+            } else {
+                output.write_all(escaped)?;
+            }
+            i = j + 1;
+        }
+        output.write_all(&valid[i..])?;
+        for &ch in invalid {
+            write!(output, "\\u{:04x}", ch)?;
+        }
+        if invalid.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Unescapes the contents of a JSON string, producing UTF-8.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GenericUnescape {}
+
+impl GenericUnescape {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+fn hex4(input: &[u8]) -> Option<u32> {
+    if input.len() < 4 {
+        return None;
+    }
+    let mut result = 0u32;
+    for &ch in &input[..4] {
+        result = result * 16 + (ch as char).to_digit(16)?;
+    }
+    Some(result)
+}
+
+impl escape::Unescape for GenericUnescape {
+    fn unescape(&self, input: &[u8], output: &mut [u8]) -> Option<(usize, usize)> {
+        let mut input_index = 0;
+        let mut output_index = 0;
+        loop {
+            let copy_len = memchr::memchr2(b'"', b'\\', &input[input_index..])?;
+            let copy_from = &input[input_index..(input_index + copy_len)];
+            if copy_from.iter().any(|&ch| ch < 0x20) {
+                return None;
+            }
+            output[output_index..(output_index + copy_len)].copy_from_slice(copy_from);
+            input_index += copy_len;
+            output_index += copy_len;
+            if input[input_index] == b'"' {
+                return Some((input_index, output_index));
+            }
+            input_index += 1;
+            let ch = match *input.get(input_index)? {
+                ch @ (b'"' | b'\\' | b'/') => ch,
+                b'b' => b'\x08',
+                b'f' => b'\x0c',
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'u' => {
+                    let mut code_point = hex4(&input[(input_index + 1)..])?;
+                    input_index += 5;
+                    if (0xD800..0xDC00).contains(&code_point) {
+                        // a high surrogate must be followed by a low surrogate
+                        if input.get(input_index..(input_index + 2))? != b"\\u" {
+                            return None;
+                        }
+                        let low = hex4(&input[(input_index + 2)..])?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        code_point = 0x10000 + ((code_point - 0xD800) << 10) + (low - 0xDC00);
+                        input_index += 6;
+                    }
+                    let ch = char::from_u32(code_point)?;
+                    output_index += ch.encode_utf8(&mut output[output_index..]).len();
+                    continue;
+                },
+                _ => return None,
+            };
+            output[output_index] = ch;
+            input_index += 1;
+            output_index += 1;
+        }
+    }
+}
+
+/// Whether the (unquoted) atom can be written as a JSON number as-is.
+pub fn is_number(input: &[u8]) -> bool {
+    fn digits(input: &[u8], i: &mut usize) -> usize {
+        let start = *i;
+        while *i < input.len() && input[*i].is_ascii_digit() {
+            *i += 1;
+        }
+        *i - start
+    }
+
+    let mut i = 0;
+    if input.first() == Some(&b'-') {
+        i += 1;
+    }
+    let integer_start = i;
+    match digits(input, &mut i) {
+        0 => return false,
+        1 => (),
+        _ => if input[integer_start] == b'0' { return false; },
+    }
+    if input.get(i) == Some(&b'.') {
+        i += 1;
+        if digits(input, &mut i) == 0 {
+            return false;
+        }
+    }
+    if matches!(input.get(i), Some(b'e' | b'E')) {
+        i += 1;
+        if matches!(input.get(i), Some(b'+' | b'-')) {
+            i += 1;
+        }
+        if digits(input, &mut i) == 0 {
+            return false;
+        }
+    }
+    i == input.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_test(input: &[u8], expected_output: &str) {
+        let mut output = Vec::new();
+        escape(input, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    #[test] fn test_plain() { run_test(b"foo bar", "foo bar"); }
+    #[test] fn test_special() { run_test(b"a\"b\\c\nd\x01", "a\\\"b\\\\c\\nd\\u0001"); }
+    #[test] fn test_utf8() { run_test("caf\u{e9}".as_bytes(), "caf\u{e9}"); }
+    #[test] fn test_invalid_utf8() { run_test(b"a\xffb\xc3", "a\\u00ffb\\u00c3"); }
+
+    fn run_unescape_test(input: &[u8], expected_output: Option<&str>) {
+        use escape::Unescape;
+        let mut input = input.to_vec();
+        input.push(b'"');
+        let mut output = vec![0u8; input.len()];
+        let output = GenericUnescape::new().unescape(&input[..], &mut output[..])
+            .map(|(_, output_len)| String::from_utf8(output[..output_len].to_vec()).unwrap());
+        assert_eq!(output.as_deref(), expected_output);
+    }
+
+    #[test] fn test_unescape_plain() { run_unescape_test(b"foo bar", Some("foo bar")); }
+    #[test] fn test_unescape_special() { run_unescape_test(br#"a\"b\\c\/\n\t\b\f"#, Some("a\"b\\c/\n\t\x08\x0c")); }
+    #[test] fn test_unescape_unicode() { run_unescape_test(br#"caf\u00e9 \ud83d\ude00"#, Some("caf\u{e9} \u{1f600}")); }
+    #[test] fn test_unescape_bad() {
+        run_unescape_test(br#"\q"#, None);
+        run_unescape_test(br#"\u12"#, None);
+        run_unescape_test(br#"\ud83d"#, None);
+        run_unescape_test(b"a\nb", None);
+    }
+
+    #[test]
+    fn test_is_number() {
+        for number in ["0", "-1", "123", "1.5", "-0.25", "1e10", "2.5E-3"] {
+            assert!(is_number(number.as_bytes()), "{}", number);
+        }
+        for not_number in ["", "-", "01", "1.", ".5", "1e", "1_000", "nan", "inf", "0x10", "+1"] {
+            assert!(!is_number(not_number.as_bytes()), "{}", not_number);
+        }
+    }
+}
@@ -1,64 +1,789 @@
 use crate::parser;
 use crate::parser_parallel;
 use std::ffi::OsStr;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// Why a child process failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChildFailure {
+    ExitCode(i32),
+    Killed,
+    IOError(std::io::ErrorKind),
+}
+
+impl std::fmt::Display for ChildFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChildFailure::ExitCode(code) => { write!(f, "exit code {}", code) }
+            ChildFailure::Killed => { write!(f, "killed by a signal") }
+            ChildFailure::IOError(e) => { write!(f, "IO error: {}", e) }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    Parser(parser::Error),
+    /// `start..end` are the input bytes of the chunk.
+    ChildFailed { chunk: usize, start: usize, end: usize, failure: ChildFailure },
+    ReducerFailed(ChildFailure),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parser(e) => { write!(f, "{}", e) }
+            Error::ChildFailed { chunk, start, end, failure } => {
+                write!(f, "Child process failed on chunk {} (input bytes {}..{}): {}", chunk, start, end, failure)
+            }
+            Error::ReducerFailed(failure) => { write!(f, "Reducer failed: {}", failure) }
+        }
+    }
+}
+
+impl From<parser::Error> for Error {
+    fn from(e: parser::Error) -> Self {
+        Error::Parser(e)
+    }
+}
+
+/// How chunks of input are passed to the child processes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Spawn a new child for every chunk, which reads the chunk until EOF.
+    /// This works with any filter, including ones that only flush at EOF.
+    SpawnPerChunk,
+    /// Keep the children running, writing the delimiter on a line of its own
+    /// after every chunk. The child must copy that line to its output once it
+    /// has written the output for the chunk.
+    Delimiter(Vec<u8>),
+    /// Keep the children running, writing every chunk as its length in
+    /// decimal, a newline, and the chunk itself. The child must reply in the
+    /// same way.
+    LengthPrefix,
+}
+
+/// What to do with a chunk that the child fails on (after any retries).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Stop with `Error::ChildFailed`.
+    Fail,
+    /// Drop the chunk's output, report it on stderr and carry on.
+    Skip,
+}
+
+/// How the outputs of the chunks are combined, in order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reducer<'a> {
+    /// Write the outputs one after the other.
+    Concat,
+    /// Write the sum of the atoms in the outputs, which must be numbers.
+    Sum,
+    /// Write the number of top-level sexps in the outputs.
+    Count,
+    /// Merge the lines of the outputs, each of which must be sorted
+    /// bytewise. This keeps every output until the end.
+    MergeSorted,
+    /// Pipe the outputs through a single run of a program.
+    Command { prog: &'a OsStr, args: &'a [&'a OsStr] },
+}
+
+/// One run of a child over a piece of a chunk.
+struct Run {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    failure: Option<ChildFailure>,
+}
+
+impl Run {
+    fn failed(stderr: Vec<u8>, failure: ChildFailure) -> Self {
+        Self { stdout: Vec::new(), stderr, failure: Some(failure) }
+    }
+}
+
+fn failure_of_status(status: std::process::ExitStatus) -> Option<ChildFailure> {
+    if status.success() {
+        None
+    } else {
+        Some(status.code().map_or(ChildFailure::Killed, ChildFailure::ExitCode))
+    }
+}
+
+fn command(prog: &OsStr, args: &[&OsStr]) -> Command {
+    let mut command = Command::new(prog);
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+/// A long-lived child process.
+struct Child {
+    process: std::process::Child,
+    stdin: Option<std::process::ChildStdin>,
+    stdout: std::io::BufReader<std::process::ChildStdout>,
+    /// Filled in the background, since stderr isn't framed.
+    stderr: Arc<Mutex<Vec<u8>>>,
+    stderr_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Child {
+    fn spawn(prog: &OsStr, args: &[&OsStr]) -> std::io::Result<Self> {
+        let mut process = command(prog, args).spawn()?;
+        let stdin = process.stdin.take();
+        let stdout = std::io::BufReader::new(process.stdout.take().expect("Failed to open stdout"));
+        let mut child_stderr = process.stderr.take().expect("Failed to open stderr");
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let stderr_thread = {
+            let stderr = stderr.clone();
+            std::thread::spawn(move || {
+                let mut buffer = [0; 4096];
+                while let Ok(len @ 1..) = child_stderr.read(&mut buffer) {
+                    stderr.lock().unwrap().extend_from_slice(&buffer[..len]);
+                }
+            })
+        };
+        Ok(Self { process, stdin, stdout, stderr, stderr_thread: Some(stderr_thread) })
+    }
+
+    /// Runs a piece through `child`, spawning it first if needed. If this
+    /// fails, the child is shut down and `child` is left empty.
+    fn run(child: &mut Option<Child>, prog: &OsStr, args: &[&OsStr], framing: &Framing, input: &[u8]) -> Run {
+        let mut process = match child.take() {
+            Some(process) => process,
+            None => match Child::spawn(prog, args) {
+                Ok(process) => process,
+                Err(e) => return Run::failed(Vec::new(), ChildFailure::IOError(e.kind())),
+            },
+        };
+        match process.process(framing, input) {
+            Ok(stdout) => {
+                // Best effort: stderr written late ends up with the next chunk.
+                let stderr = std::mem::take(&mut *process.stderr.lock().unwrap());
+                *child = Some(process);
+                Run { stdout, stderr, failure: None }
+            },
+            Err(e) => {
+                let (stderr, failure) = process.shut_down(e);
+                Run::failed(stderr, failure)
+            },
+        }
+    }
+
+    fn process(&mut self, framing: &Framing, input: &[u8]) -> std::io::Result<Vec<u8>> {
+        let stdin = self.stdin.as_mut().expect("Failed to open stdin");
+        let stdout = &mut self.stdout;
+        let process = &mut self.process;
+        let threads_result = crossbeam_utils::thread::scope(|scope| {
+            let writer = scope.spawn(move |_| {
+                match framing {
+                    Framing::SpawnPerChunk => unreachable!(),
+                    Framing::Delimiter(delimiter) => {
+                        // Chunks start with the line break they were split
+                        // at. Move it to the end, so that the delimiter is on
+                        // a line of its own and the output can be taken as is.
+                        let input = input.strip_prefix(b"\n").unwrap_or(input);
+                        stdin.write_all(input)?;
+                        if !input.is_empty() && !input.ends_with(b"\n") {
+                            stdin.write_all(b"\n")?;
+                        }
+                        stdin.write_all(delimiter)?;
+                        stdin.write_all(b"\n")
+                    },
+                    Framing::LengthPrefix => {
+                        writeln!(stdin, "{}", input.len())?;
+                        stdin.write_all(input)
+                    },
+                }
+            });
+            let output = match framing {
+                Framing::SpawnPerChunk => unreachable!(),
+                Framing::Delimiter(delimiter) => read_until_delimiter(stdout, delimiter),
+                Framing::LengthPrefix => read_length_prefixed(stdout),
+            };
+            if output.is_err() {
+                // Otherwise the writer might block forever. If the child has
+                // already exited, this doesn't change its exit status.
+                let _ = process.kill();
+            }
+            // If the child stopped reading, [output] has the more useful error.
+            let written = writer.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+            output.and_then(|output| written.map(|()| output))
+        });
+
+        match threads_result {
+            Err(e) => std::panic::resume_unwind(e),
+            Ok(result) => result,
+        }
+    }
+
+    /// Works out why the child failed with `error`, after which it can't be
+    /// used again.
+    fn shut_down(mut self, error: std::io::Error) -> (Vec<u8>, ChildFailure) {
+        std::mem::drop(self.stdin.take());
+        let failure = match self.process.wait() {
+            // Otherwise it broke the protocol and was killed.
+            Ok(status) if error.kind() == std::io::ErrorKind::UnexpectedEof => failure_of_status(status),
+            _ => None,
+        };
+        if let Some(stderr_thread) = self.stderr_thread.take() {
+            let _ = stderr_thread.join();
+        }
+        let stderr = std::mem::take(&mut *self.stderr.lock().unwrap());
+        (stderr, failure.unwrap_or(ChildFailure::IOError(error.kind())))
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        std::mem::drop(self.stdin.take());
+        let _ = self.process.wait();
+        if let Some(stderr_thread) = self.stderr_thread.take() {
+            let _ = stderr_thread.join();
+        }
+    }
+}
+
+fn read_until_delimiter<BufReadT: BufRead>(reader: &mut BufReadT, delimiter: &[u8]) -> std::io::Result<Vec<u8>> {
+    // Only a line consisting of exactly the delimiter ends the frame.
+    let mut output = Vec::new();
+    loop {
+        let line_start = output.len();
+        reader.read_until(b'\n', &mut output)?;
+        let line = &output[line_start..];
+        if !line.ends_with(b"\n") {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if &line[..line.len() - 1] == delimiter {
+            output.truncate(line_start);
+            return Ok(output);
+        }
+    }
+}
+
+fn read_length_prefixed<BufReadT: BufRead>(reader: &mut BufReadT) -> std::io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    reader.read_until(b'\n', &mut header)?;
+    if header.pop() != Some(b'\n') {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let len: usize =
+        std::str::from_utf8(&header[..]).ok()
+        .and_then(|header| header.trim().parse().ok())
+        .ok_or(std::io::ErrorKind::InvalidData)?;
+    let mut output = vec![0; len];
+    reader.read_exact(&mut output[..])?;
+    Ok(output)
+}
+
+fn spawn_and_run(prog: &OsStr, args: &[&OsStr], input: &[u8]) -> Run {
+    let mut child = match command(prog, args).spawn() {
+        Ok(child) => child,
+        Err(e) => return Run::failed(Vec::new(), ChildFailure::IOError(e.kind())),
+    };
+    let mut stdin = child.stdin.take().expect("Failed to open stdin");
+
+    let threads_result = crossbeam_utils::thread::scope(|scope| {
+        let writer = scope.spawn(move |_| stdin.write_all(input));
+        let output = child.wait_with_output();
+        let written = writer.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+        (output, written)
+    });
+
+    match threads_result {
+        Err(e) => std::panic::resume_unwind(e),
+        Ok((Err(e), _)) => Run::failed(Vec::new(), ChildFailure::IOError(e.kind())),
+        Ok((Ok(output), written)) => {
+            let failure = match (failure_of_status(output.status), written) {
+                (Some(failure), _) => Some(failure),
+                // It's fine for the child to exit without reading everything.
+                (None, Err(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => Some(ChildFailure::IOError(e.kind())),
+                (None, _) => None,
+            };
+            match failure {
+                Some(failure) => Run::failed(output.stderr, failure),
+                None => Run { stdout: output.stdout, stderr: output.stderr, failure: None },
+            }
+        },
+    }
+}
+
+/// Splits `input` into at most `n` pieces, at the same kind of boundary that
+/// `parser_parallel` splits chunks at.
+fn split_chunk(input: &[u8], n: usize) -> Vec<&[u8]> {
+    let mut pieces = Vec::with_capacity(n);
+    let mut rest = input;
+    for remaining in (1..n).rev() {
+        let offset = rest.len() / (remaining + 1);
+        let split_index =
+            memchr::memchr_iter(b'\n', &rest[offset..])
+            .map(|x| x + offset)
+            .find(|&split_index| { split_index > 0 && split_index + 1 < rest.len() && rest[split_index + 1] != b' ' });
+        match split_index {
+            None => break,
+            Some(split_index) => {
+                pieces.push(&rest[..split_index]);
+                rest = &rest[split_index..];
+            },
+        }
+    }
+    pieces.push(rest);
+    pieces
+}
+
+#[derive(Default)]
+struct Sum {
+    int: i64,
+    float: Option<f64>,
+}
+
+impl Sum {
+    fn add(&mut self, atom: &[u8]) -> Result<(), parser::Error> {
+        let atom = std::str::from_utf8(atom).map_err(|_| parser::Error::NotANumber)?;
+        if self.float.is_none() {
+            if let Some(int) = atom.parse().ok().and_then(|x| self.int.checked_add(x)) {
+                self.int = int;
+                return Ok(());
+            }
+        }
+        let x: f64 = atom.parse().map_err(|_| parser::Error::NotANumber)?;
+        *self.float.get_or_insert(self.int as f64) += x;
+        Ok(())
+    }
+}
+
+/// The reducer program, whose output is collected in the background.
+struct ReducerProcess {
+    process: std::process::Child,
+    stdin: Option<std::process::ChildStdin>,
+    write_error: Option<std::io::ErrorKind>,
+    stdout_thread: std::thread::JoinHandle<std::io::Result<Vec<u8>>>,
+    stderr_thread: std::thread::JoinHandle<std::io::Result<Vec<u8>>>,
+}
+
+impl ReducerProcess {
+    fn spawn(prog: &OsStr, args: &[&OsStr]) -> std::io::Result<Self> {
+        let mut process = command(prog, args).spawn()?;
+        let stdin = process.stdin.take();
+        let read_to_end = |mut reader: Box<dyn Read + Send>| {
+            std::thread::spawn(move || {
+                let mut output = Vec::new();
+                reader.read_to_end(&mut output).map(|_| output)
+            })
+        };
+        let stdout_thread = read_to_end(Box::new(process.stdout.take().expect("Failed to open stdout")));
+        let stderr_thread = read_to_end(Box::new(process.stderr.take().expect("Failed to open stderr")));
+        Ok(Self { process, stdin, write_error: None, stdout_thread, stderr_thread })
+    }
+
+    fn write(&mut self, output: &[u8]) {
+        if let Some(stdin) = &mut self.stdin {
+            if let Err(e) = stdin.write_all(output) {
+                // The reducer may legitimately stop reading early.
+                self.write_error = Some(e.kind());
+                self.stdin = None;
+            }
+        }
+    }
+
+    /// Returns the reducer's stdout and stderr.
+    fn finish(mut self) -> Result<(Vec<u8>, Vec<u8>), ChildFailure> {
+        std::mem::drop(self.stdin.take());
+        let status = self.process.wait().map_err(|e| ChildFailure::IOError(e.kind()))?;
+        let join = |thread: std::thread::JoinHandle<std::io::Result<Vec<u8>>>| {
+            thread.join().unwrap_or_else(|e| std::panic::resume_unwind(e)).map_err(|e| ChildFailure::IOError(e.kind()))
+        };
+        let stdout = join(self.stdout_thread)?;
+        let stderr = join(self.stderr_thread)?;
+        if let Some(failure) = failure_of_status(status) {
+            return Err(failure);
+        }
+        match self.write_error {
+            Some(e) if e != std::io::ErrorKind::BrokenPipe => Err(ChildFailure::IOError(e)),
+            _ => Ok((stdout, stderr)),
+        }
+    }
+}
+
+/// The state of the reducer part-way through the input.
+enum Reduction {
+    Concat,
+    Sum(Sum),
+    Count(usize),
+    MergeSorted(Vec<Vec<u8>>),
+    /// Spawned at the first output.
+    Command(Option<ReducerProcess>),
+}
+
+impl Reduction {
+    fn new(reducer: &Reducer) -> Self {
+        match reducer {
+            Reducer::Concat => Reduction::Concat,
+            Reducer::Sum => Reduction::Sum(Sum::default()),
+            Reducer::Count => Reduction::Count(0),
+            Reducer::MergeSorted => Reduction::MergeSorted(Vec::new()),
+            Reducer::Command { .. } => Reduction::Command(None),
+        }
+    }
+}
+
+/// Merges the lines of `outputs`, keeping lines from earlier outputs first
+/// when they compare equal.
+fn merge_sorted<WriteT: Write>(outputs: &[Vec<u8>], writer: &mut WriteT) -> std::io::Result<()> {
+    let mut lines: Vec<_> = outputs.iter().map(|output| {
+        output.split(|&ch| ch == b'\n').filter(|line| !line.is_empty())
+    }).collect();
+    let mut heap = std::collections::BinaryHeap::new();
+    for (index, lines) in lines.iter_mut().enumerate() {
+        if let Some(line) = lines.next() {
+            heap.push(std::cmp::Reverse((line, index)));
+        }
+    }
+    while let Some(std::cmp::Reverse((line, index))) = heap.pop() {
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+        if let Some(line) = lines[index].next() {
+            heap.push(std::cmp::Reverse((line, index)));
+        }
+    }
+    Ok(())
+}
+
+/// The output of a chunk, as passed from the workers to `ExecJoiner`.
+pub struct ChunkOutput {
+    /// One output per child the chunk was split between.
+    stdout: Vec<Vec<u8>>,
+    stderr: Vec<u8>,
+    input_len: usize,
+    failure: Option<ChildFailure>,
+}
 
-#[derive(Clone)]
 pub struct ExecWorker<'a> {
     prog: &'a OsStr,
     args: &'a [&'a OsStr],
+    framing: Framing,
+    processes_per_thread: usize,
+    retries: usize,
+    on_failure: OnFailure,
+    reducer: Reducer<'a>,
+    children: Vec<Option<Child>>,
 }
 
 impl<'a> ExecWorker<'a> {
     pub fn new(prog: &'a OsStr, args:&'a [&'a OsStr]) -> Self {
-        Self { prog, args }
+        Self {
+            prog,
+            args,
+            framing: Framing::SpawnPerChunk,
+            processes_per_thread: 1,
+            retries: 0,
+            on_failure: OnFailure::Fail,
+            reducer: Reducer::Concat,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        if let Framing::Delimiter(delimiter) = &framing {
+            assert!(!delimiter.is_empty() && !delimiter.contains(&b'\n'), "Bad delimiter");
+        }
+        self.framing = framing;
+        self
+    }
+
+    /// Each worker thread splits its chunks between this many children.
+    pub fn with_processes_per_thread(mut self, processes_per_thread: usize) -> Self {
+        self.processes_per_thread = std::cmp::max(processes_per_thread, 1);
+        self
+    }
+
+    /// Chunks that the child fails on (by exiting with a non-zero status, or
+    /// breaking the framing protocol) are rerun this many times.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    pub fn with_reducer(mut self, reducer: Reducer<'a>) -> Self {
+        self.reducer = reducer;
+        self
+    }
+
+    /// Children are kept running between chunks, so chunks can be much smaller
+    /// than when spawning a child per chunk.
+    fn default_chunk_size(&self) -> usize {
+        match self.framing {
+            Framing::SpawnPerChunk => 50 * 1024 * 1024,
+            Framing::Delimiter(_) | Framing::LengthPrefix => 1024 * 1024,
+        }
+    }
+
+    /// Runs `input` through the children once, appending to `stderr`.
+    fn run(&mut self, input: &[u8], stderr: &mut Vec<u8>) -> (Vec<Vec<u8>>, Option<ChildFailure>) {
+        let pieces = split_chunk(input, self.processes_per_thread);
+        let persistent = self.framing != Framing::SpawnPerChunk;
+        if persistent {
+            self.children.resize_with(self.processes_per_thread, || None);
+        }
+
+        let (prog, args, framing, children) = (self.prog, self.args, &self.framing, &mut self.children);
+        let threads_result = crossbeam_utils::thread::scope(|scope| {
+            let handles: Vec<_> =
+                if persistent {
+                    children.iter_mut().zip(pieces).map(|(child, piece)| {
+                        scope.spawn(move |_| Child::run(child, prog, args, framing, piece))
+                    }).collect()
+                } else {
+                    pieces.into_iter().map(|piece| {
+                        scope.spawn(move |_| spawn_and_run(prog, args, piece))
+                    }).collect()
+                };
+            handles.into_iter().map(|handle| {
+                handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+            }).collect::<Vec<_>>()
+        });
+        let runs = match threads_result {
+            Err(e) => std::panic::resume_unwind(e),
+            Ok(runs) => runs,
+        };
+
+        let mut stdout = Vec::with_capacity(runs.len());
+        let mut failure = None;
+        for run in runs {
+            stdout.push(run.stdout);
+            stderr.extend_from_slice(&run.stderr[..]);
+            failure = failure.or(run.failure);
+        }
+        (stdout, failure)
+    }
+}
+
+impl<'a> Clone for ExecWorker<'a> {
+    /// The clone starts its own children.
+    fn clone(&self) -> Self {
+        Self {
+            prog: self.prog,
+            args: self.args,
+            framing: self.framing.clone(),
+            processes_per_thread: self.processes_per_thread,
+            retries: self.retries,
+            on_failure: self.on_failure,
+            reducer: self.reducer,
+            children: Vec::new(),
+        }
     }
 }
 
 impl<'a> parser::Parse for ExecWorker<'a> {
-    type Return = Vec<u8>;
+    type Return = ChunkOutput;
     fn process(&mut self, input: &[u8]) -> Result<Self::Return, parser::Error> {
-        let threads_result = crossbeam_utils::thread::scope(|scope| {
-            let mut command =
-                Command::new(self.prog)
-                .args(self.args)
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::null())
-                .spawn()
-                .expect("Failed to spawn child process");
-
-            let mut stdin = command.stdin.take().expect("Failed to open stdin");
-
-            // TODO: thread-pool?
-            scope.spawn(move |_| {
-                stdin.write_all(input).expect("Failed to write to stdin");
-            });
+        let mut stderr = Vec::new();
+        let mut attempts = 0;
+        loop {
+            let (stdout, failure) = self.run(input, &mut stderr);
+            if failure.is_none() || attempts == self.retries {
+                return Ok(ChunkOutput { stdout, stderr, input_len: input.len(), failure });
+            }
+            attempts += 1;
+        }
+    }
+}
 
-            let output = command.wait_with_output().expect("Failed to read stdout");
-            output.stdout
-        });
+/// Reduces the outputs of the chunks in order, forwarding the children's
+/// stderr and handling failed chunks. Returns the number of chunks skipped.
+pub struct ExecJoiner<'a, WriteT, StderrT> {
+    worker: ExecWorker<'a>,
+    /// Where a failure of a child or the reducer is put, while processing is
+    /// stopped with a placeholder `parser::Error`.
+    failure: Arc<Mutex<Option<Error>>>,
+    stdout: &'a mut WriteT,
+    stderr: StderrT,
+    reduction: Reduction,
+    chunk: usize,
+    offset: usize,
+    skipped: usize,
+}
 
-        match threads_result {
-            Err(e) => std::panic::resume_unwind(e),
-            Ok(result) => Ok(result),
+/// Records `error` for `ExecStream` to return.
+fn fail(failure: &Mutex<Option<Error>>, error: Error) -> parser::Error {
+    *failure.lock().unwrap() = Some(error);
+    parser::Error::IOError(std::io::ErrorKind::Other)
+}
+
+impl<'a, WriteT: Write, StderrT: Write> ExecJoiner<'a, WriteT, StderrT> {
+    fn reduce(&mut self, output: Vec<u8>) -> Result<(), parser::Error> {
+        match &mut self.reduction {
+            Reduction::Concat => self.stdout.write_all(&output[..]).map_err(|e| parser::Error::IOError(e.kind())),
+            Reduction::Sum(sum) => {
+                for atom in output.split(|ch| ch.is_ascii_whitespace()).filter(|atom| !atom.is_empty()) {
+                    match atom {
+                        [b'"', atom @ .., b'"'] => sum.add(atom)?,
+                        _ => sum.add(atom)?,
+                    }
+                }
+                Ok(())
+            },
+            Reduction::Count(count) => {
+                *count += crate::validate::make_parser(false).process_streaming(&mut &output[..])?;
+                Ok(())
+            },
+            Reduction::MergeSorted(outputs) => {
+                outputs.push(output);
+                Ok(())
+            },
+            Reduction::Command(process) => {
+                if process.is_none() {
+                    let (prog, args) = match self.worker.reducer {
+                        Reducer::Command { prog, args } => (prog, args),
+                        _ => unreachable!(),
+                    };
+                    let spawned = ReducerProcess::spawn(prog, args).map_err(|e| fail(&self.failure, Error::ReducerFailed(ChildFailure::IOError(e.kind()))))?;
+                    *process = Some(spawned);
+                }
+                process.as_mut().unwrap().write(&output[..]);
+                Ok(())
+            },
         }
     }
 }
 
+impl<'a, WriteT: Write, StderrT: Write> parser_parallel::Joiner for ExecJoiner<'a, WriteT, StderrT> {
+    type Worker = ExecWorker<'a>;
+    type Return = usize;
+
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.reduction = Reduction::new(&self.worker.reducer);
+        self.chunk = 0;
+        self.offset = 0;
+        self.skipped = 0;
+    }
+
+    fn create_worker(&mut self) -> Self::Worker {
+        self.worker.clone()
+    }
+
+    fn join(&mut self, result: ChunkOutput) -> Result<(), parser::Error> {
+        let io_error = |e: std::io::Error| parser::Error::IOError(e.kind());
+        let (chunk, start) = (self.chunk, self.offset);
+        self.chunk += 1;
+        self.offset += result.input_len;
+        self.stderr.write_all(&result.stderr[..]).map_err(io_error)?;
+        match result.failure {
+            None => result.stdout.into_iter().try_for_each(|output| self.reduce(output)),
+            Some(failure) => {
+                let error = Error::ChildFailed { chunk, start, end: self.offset, failure };
+                match self.worker.on_failure {
+                    OnFailure::Fail => Err(fail(&self.failure, error)),
+                    OnFailure::Skip => {
+                        self.skipped += 1;
+                        writeln!(self.stderr, "exec: {}; skipped", error).map_err(io_error)
+                    },
+                }
+            },
+        }
+    }
+
+    fn process_eof(&mut self) -> Result<usize, parser::Error> {
+        let io_error = |e: std::io::Error| parser::Error::IOError(e.kind());
+        match std::mem::replace(&mut self.reduction, Reduction::new(&self.worker.reducer)) {
+            Reduction::Concat => (),
+            Reduction::Sum(Sum { int, float: None }) => writeln!(self.stdout, "{}", int).map_err(io_error)?,
+            Reduction::Sum(Sum { float: Some(float), .. }) => writeln!(self.stdout, "{}", float).map_err(io_error)?,
+            Reduction::Count(count) => writeln!(self.stdout, "{}", count).map_err(io_error)?,
+            Reduction::MergeSorted(outputs) => merge_sorted(&outputs[..], self.stdout).map_err(io_error)?,
+            Reduction::Command(process) => {
+                let process = match process {
+                    Some(process) => process,
+                    // Run it over no input, as a pipeline would.
+                    None => {
+                        self.reduce(Vec::new())?;
+                        match std::mem::replace(&mut self.reduction, Reduction::Concat) {
+                            Reduction::Command(Some(process)) => process,
+                            _ => unreachable!(),
+                        }
+                    },
+                };
+                let (stdout, stderr) = process.finish().map_err(|failure| fail(&self.failure, Error::ReducerFailed(failure)))?;
+                self.stderr.write_all(&stderr[..]).map_err(io_error)?;
+                self.stdout.write_all(&stdout[..]).map_err(io_error)?;
+            },
+        }
+        Ok(self.skipped)
+    }
+}
+
+/// Returns the number of chunks skipped, or why the children or reducer
+/// failed, as `Ok` so that it works wherever a `parser::Stream` does.
+pub struct ExecStream<'a, ReadT> {
+    stream: Box<dyn parser::Stream<ReadT, Return = usize> + 'a>,
+    failure: Arc<Mutex<Option<Error>>>,
+}
+
+impl<'a, ReadT> ExecStream<'a, ReadT> {
+    fn result(&mut self, result: Result<usize, parser::Error>) -> Result<usize, Error> {
+        let failure = self.failure.lock().unwrap().take();
+        result.map_err(|e| failure.unwrap_or(Error::Parser(e)))
+    }
+}
+
+impl<'a, ReadT> parser::Stream<ReadT> for ExecStream<'a, ReadT> {
+    type Return = Result<usize, Error>;
+    fn process_streaming(&mut self, buf_reader: &mut ReadT) -> Result<Self::Return, parser::Error> {
+        let result = self.stream.process_streaming(buf_reader);
+        Ok(self.result(result))
+    }
+    fn process_slice(&mut self, input: &[u8]) -> Result<Self::Return, parser::Error> {
+        let result = self.stream.process_slice(input);
+        Ok(self.result(result))
+    }
+}
+
+/// Flattens the result of running an `ExecStream`.
+pub fn flatten(result: Result<Result<usize, Error>, parser::Error>) -> Result<usize, Error> {
+    result.map_err(Error::Parser).and_then(|result| result)
+}
+
+/// The children's stderr is forwarded to our stderr.
 pub fn make_parser<'a, ReadT: BufRead + Send, WriteT: Write>
     (params: ExecWorker<'a>, stdout: &'a mut WriteT)
-     -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+     -> ExecStream<'a, ReadT>
+{
+    make_parser_with_stderr(params, stdout, std::io::stderr())
+}
+
+pub fn make_parser_with_stderr<'a, ReadT: BufRead + Send, WriteT: Write, StderrT: Write + 'a>
+    (params: ExecWorker<'a>, stdout: &'a mut WriteT, stderr: StderrT)
+     -> ExecStream<'a, ReadT>
+{
+    make_parser_with_config(params, stdout, stderr, parser_parallel::ParallelConfig::default())
+}
+
+/// Unless `config` sets a chunk size, chunks are 50MiB when spawning a child
+/// per chunk and 1MiB otherwise.
+pub fn make_parser_with_config<'a, ReadT: BufRead + Send, WriteT: Write, StderrT: Write + 'a>
+    (params: ExecWorker<'a>, stdout: &'a mut WriteT, stderr: StderrT, config: parser_parallel::ParallelConfig)
+     -> ExecStream<'a, ReadT>
 {
-    parser_parallel::streaming_from_worker(move || {
-        params.clone()
-    }, stdout, 50 * 1024 * 1024)
+    let config = config.with_default_chunk_size(params.default_chunk_size());
+    let reduction = Reduction::new(&params.reducer);
+    let failure = Arc::new(Mutex::new(None));
+    let joiner = ExecJoiner { worker: params, failure: failure.clone(), stdout, stderr, reduction, chunk: 0, offset: 0, skipped: 0 };
+    ExecStream { stream: parser_parallel::streaming_new(joiner, config), failure }
 }
 
 #[cfg(feature = "ocaml")]
 mod ocaml_ffi {
     use super::*;
+    use crate::parser::Stream;
     use std::collections::LinkedList;
     use std::ffi::OsString;
     use crate::utils;
@@ -86,6 +811,216 @@ mod ocaml_ffi {
         let params = ExecWorker::new(&prog.0[..], &args[..]);
 
         let mut parser = make_parser(params, &mut stdout);
-        let () = parser.process_streaming(&mut stdin).unwrap();
+        let _skipped_chunks: usize = parser.process_streaming(&mut stdin).unwrap().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Stream;
+
+    fn run(worker: ExecWorker, input: &[u8]) -> (Result<usize, Error>, Vec<u8>, Vec<u8>) {
+        let mut output = Vec::new();
+        let mut stderr = Vec::new();
+        let mut parser = make_parser_with_stderr(worker, &mut output, &mut stderr);
+        let result = flatten(parser.process_streaming(&mut std::io::BufReader::new(input)));
+        std::mem::drop(parser);
+        (result, output, stderr)
+    }
+
+    fn run_test(worker: ExecWorker, input: &[u8], expected: &[u8]) {
+        let (result, output, _) = run(worker, input);
+        assert_eq!(result, Ok(0));
+        assert!(output == expected, "{:?}", String::from_utf8_lossy(&output[..output.len().min(200)]));
+    }
+
+    fn input_of_len(len: usize) -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..len {
+            writeln!(input, "((foo {})\n (bar \"baz\"))", i).unwrap();
+        }
+        input
+    }
+
+    fn input() -> Vec<u8> {
+        // a few chunks' worth, to exercise the persistent children
+        input_of_len(200000)
+    }
+
+    #[test]
+    fn test_split_chunk() {
+        let input = b"(a)\n(b)\n (c)\n(d)";
+        assert_eq!(split_chunk(input, 1), vec![&input[..]]);
+        assert_eq!(split_chunk(input, 2), vec![&b"(a)\n(b)\n (c)"[..], &b"\n(d)"[..]]);
+        assert_eq!(split_chunk(b"(a)", 3), vec![&b"(a)"[..]]);
+    }
+
+    #[test]
+    fn test_spawn_per_chunk() {
+        let input = input();
+        let cat = OsStr::new("cat");
+        run_test(ExecWorker::new(cat, &[]), &input[..], &input[..]);
+        run_test(ExecWorker::new(cat, &[]).with_processes_per_thread(3), &input[..], &input[..]);
+    }
+
+    #[test]
+    fn test_delimiter() {
+        let input = input();
+        let cat = OsStr::new("cat");
+        for processes_per_thread in [1, 2] {
+            let worker =
+                ExecWorker::new(cat, &[])
+                .with_framing(Framing::Delimiter(b"(end-of-chunk)".to_vec()))
+                .with_processes_per_thread(processes_per_thread);
+            run_test(worker, &input[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_delimiter_line_filter() {
+        // Small chunks, through a filter that writes whole lines (including
+        // the delimiter's) and, like many, drops blank ones.
+        let mut input = Vec::new();
+        for i in 0..100 {
+            writeln!(input, "(r {})", i).unwrap();
+        }
+        let args = [OsStr::new("-c"), OsStr::new(r#"grep --line-buffered ."#)];
+        for processes_per_thread in [1, 2] {
+            let worker =
+                ExecWorker::new(OsStr::new("sh"), &args[..])
+                .with_framing(Framing::Delimiter(b"(end-of-chunk)".to_vec()))
+                .with_processes_per_thread(processes_per_thread);
+            let mut output = Vec::new();
+            let config = parser_parallel::ParallelConfig::default().with_chunk_size(12);
+            let mut parser = make_parser_with_config(worker, &mut output, std::io::sink(), config);
+            assert_eq!(parser.process_streaming(&mut &input[..]), Ok(Ok(0)));
+            std::mem::drop(parser);
+            assert_eq!(String::from_utf8(output).unwrap(), String::from_utf8(input.clone()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_delimiter_within_line() {
+        // Lines that merely end in the delimiter are output, not frame ends.
+        let input = b"(a)\n(b)\n".to_vec();
+        let args = [OsStr::new("-c"), OsStr::new(r#"sed -u '/^(end-of-chunk)$/!s/$/(end-of-chunk)/'"#)];
+        for processes_per_thread in [1, 2] {
+            let worker =
+                ExecWorker::new(OsStr::new("sh"), &args[..])
+                .with_framing(Framing::Delimiter(b"(end-of-chunk)".to_vec()))
+                .with_processes_per_thread(processes_per_thread);
+            let mut output = Vec::new();
+            let config = parser_parallel::ParallelConfig::default().with_chunk_size(4);
+            let mut parser = make_parser_with_config(worker, &mut output, std::io::sink(), config);
+            assert_eq!(parser.process_streaming(&mut &input[..]), Ok(Ok(0)));
+            std::mem::drop(parser);
+            assert_eq!(String::from_utf8(output).unwrap(), "(a)(end-of-chunk)\n(b)(end-of-chunk)\n");
+        }
+    }
+
+    #[test]
+    fn test_length_prefix() {
+        let input = input();
+        // echoes every frame back, upper-cased
+        let args = [OsStr::new("-c"), OsStr::new("while read n; do echo $n; head -c $n | tr a-z A-Z; done")];
+        let worker =
+            ExecWorker::new(OsStr::new("sh"), &args[..])
+            .with_framing(Framing::LengthPrefix)
+            .with_processes_per_thread(2);
+        run_test(worker, &input[..], &input.to_ascii_uppercase()[..]);
+    }
+
+    #[test]
+    fn test_failure() {
+        let input = b"(a)\n(b)\n";
+        let args = [OsStr::new("-c"), OsStr::new("cat; echo oops >&2; exit 3")];
+        let (result, output, stderr) = run(ExecWorker::new(OsStr::new("sh"), &args[..]), input);
+        let failure = ChildFailure::ExitCode(3);
+        assert_eq!(result, Err(Error::ChildFailed { chunk: 0, start: 0, end: input.len(), failure }));
+        assert_eq!(output, b"");
+        assert_eq!(stderr, b"oops\n");
+
+        let args = [OsStr::new("-c"), OsStr::new("kill -9 $$")];
+        let (result, _, _) = run(ExecWorker::new(OsStr::new("sh"), &args[..]), input);
+        assert!(matches!(result, Err(Error::ChildFailed { failure: ChildFailure::Killed, .. })), "{:?}", result);
+
+        let (result, _, _) = run(ExecWorker::new(OsStr::new("/nonexistent"), &[]), input);
+        let failure = ChildFailure::IOError(std::io::ErrorKind::NotFound);
+        assert_eq!(result, Err(Error::ChildFailed { chunk: 0, start: 0, end: input.len(), failure }));
+    }
+
+    #[test]
+    fn test_failure_in_later_chunk() {
+        // two chunks with persistent children, failing on the last line
+        let mut input = input_of_len(50000);
+        input.extend_from_slice(b"(bad)\n");
+        let args = [OsStr::new("-c"), OsStr::new(r#"while IFS= read -r line; do
+            case "$line" in *bad*) echo "bad line" >&2; exit 4;; esac
+            printf '%s\n' "$line"
+          done"#)];
+        let worker = || {
+            ExecWorker::new(OsStr::new("sh"), &args[..])
+            .with_framing(Framing::Delimiter(b"(end-of-chunk)".to_vec()))
+        };
+
+        let (result, _, stderr) = run(worker(), &input[..]);
+        let start = match result {
+            Err(Error::ChildFailed { chunk: 1, start, end, failure: ChildFailure::ExitCode(4) }) if end == input.len() => start,
+            _ => panic!("{:?}", result),
+        };
+        assert_eq!(input[start], b'\n');
+        assert_eq!(stderr, b"bad line\n");
+
+        let (result, output, stderr) = run(worker().with_retries(2).with_on_failure(OnFailure::Skip), &input[..]);
+        assert_eq!(result, Ok(1));
+        // With the line break that the second chunk started with.
+        assert!(output == input[..=start]);
+        let error = Error::ChildFailed { chunk: 1, start, end: input.len(), failure: ChildFailure::ExitCode(4) };
+        assert_eq!(String::from_utf8(stderr).unwrap(), format!("bad line\nbad line\nbad line\nexec: {}; skipped\n", error));
+    }
+
+    #[test]
+    fn test_retries() {
+        let marker = std::env::temp_dir().join(format!("simd-sexp-test-retries-{}", std::process::id()));
+        let script = format!("if [ -e '{}' ]; then cat; else touch '{}'; exit 5; fi", marker.display(), marker.display());
+        let args = [OsStr::new("-c"), OsStr::new(&script)];
+        let input = b"(a)\n(b)\n";
+        run_test(ExecWorker::new(OsStr::new("sh"), &args[..]).with_retries(1), input, input);
+        std::fs::remove_file(marker).unwrap();
+    }
+
+    #[test]
+    fn test_reducers() {
+        let mut input = Vec::new();
+        for i in 0..1000 {
+            writeln!(input, "(a {})", (i * 7919) % 1000).unwrap();
+        }
+        let sh = OsStr::new("sh");
+        let sh_args = |script| [OsStr::new("-c"), OsStr::new(script)];
+        let worker = |args| ExecWorker::new(sh, args).with_processes_per_thread(3);
+        let (wc, echo, cat, sort) = (sh_args("wc -l"), sh_args("echo 0.5"), sh_args("cat"), sh_args("LC_ALL=C sort"));
+
+        run_test(worker(&wc).with_reducer(Reducer::Sum), &input[..], b"1000\n");
+        run_test(worker(&echo).with_reducer(Reducer::Sum), &input[..], b"1.5\n");
+        run_test(worker(&cat).with_reducer(Reducer::Count), &input[..], b"1000\n");
+
+        let mut lines: Vec<_> = input.split(|&ch| ch == b'\n').filter(|line| !line.is_empty()).collect();
+        lines.sort();
+        let expected: Vec<u8> = lines.iter().flat_map(|line| line.iter().chain(b"\n")).copied().collect();
+        run_test(worker(&sort).with_reducer(Reducer::MergeSorted), &input[..], &expected[..]);
+
+        let count_lines = sh_args("wc -l | tr -d ' '");
+        let reducer = Reducer::Command { prog: sh, args: &count_lines };
+        run_test(worker(&cat).with_reducer(reducer), &input[..], b"1000\n");
+        run_test(worker(&cat).with_reducer(reducer), b"", b"0\n");
+
+        let not_a_number = sh_args("echo x");
+        let (result, _, _) = run(worker(&not_a_number).with_reducer(Reducer::Sum), &input[..]);
+        assert_eq!(result, Err(Error::Parser(parser::Error::NotANumber)));
+        let exit = sh_args("exit 2");
+        let reducer = Reducer::Command { prog: sh, args: &exit };
+        let (result, _, _) = run(worker(&cat).with_reducer(reducer), &input[..]);
+        assert_eq!(result, Err(Error::ReducerFailed(ChildFailure::ExitCode(2))));
     }
 }
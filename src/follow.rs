@@ -0,0 +1,258 @@
+use crate::parser::{self, ParsePartial};
+use crate::rust_generator::Generator;
+use crate::rust_parser::{SingleTape, SingleTapeVisitor};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type Parser = Box<dyn ParsePartial<Return = SingleTape, PartialReturn = SingleTape>>;
+
+fn new_parser() -> Parser {
+    parser::partial_parser_from_visitor(SingleTapeVisitor::new())
+}
+
+fn io_error(e: std::io::Error) -> parser::Error {
+    parser::Error::IOError(e.kind())
+}
+
+/// Reads a file from the start and then as it grows, like `tail -f`, handing
+/// out each top-level sexp once it is complete. If the file is truncated it
+/// starts again from the start, and if another file is moved into its place
+/// (e.g. by log rotation) it finishes the old one and then follows the new one.
+/// A sexp cut off by either is dropped.
+pub struct Follower {
+    path: PathBuf,
+    file: std::fs::File,
+    /// The device and inode of `file`.
+    id: (u64, u64),
+    position: u64,
+    parser: Parser,
+    poll_interval: Duration,
+    buffer: Vec<u8>,
+}
+
+impl Follower {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, parser::Error> {
+        let path = path.as_ref().to_owned();
+        let file = std::fs::File::open(&path).map_err(io_error)?;
+        let metadata = file.metadata().map_err(io_error)?;
+        Ok(Self {
+            path,
+            file,
+            id: (metadata.dev(), metadata.ino()),
+            position: 0,
+            parser: new_parser(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            buffer: vec![0; 1024 * 1024],
+        })
+    }
+
+    /// How long to wait before looking again once the end of the file is
+    /// reached.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Parses the rest of the file as it is now, returning whether there was
+    /// any.
+    fn read_to_end(&mut self) -> Result<bool, parser::Error> {
+        let mut read_any = false;
+        loop {
+            let len = self.file.read(&mut self.buffer[..]).map_err(io_error)?;
+            if len == 0 {
+                self.parser.flush_partial()?;
+                return Ok(read_any);
+            }
+            read_any = true;
+            self.position += len as u64;
+            self.parser.process_partial(&self.buffer[..len])?;
+        }
+    }
+
+    /// Reads to the end of the file as it is now, returning the sexps
+    /// completed, which may be none.
+    pub fn poll(&mut self) -> Result<SingleTape, parser::Error> {
+        if self.read_to_end()? {
+            return Ok(self.parser.extract_partial_result());
+        }
+
+        // Nothing new here, so check whether the file has been truncated or
+        // replaced. If the path is missing, a new file may be on its way.
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(self.parser.extract_partial_result()),
+        };
+        if (metadata.dev(), metadata.ino()) != self.id {
+            // The old file may have grown since we looked.
+            self.read_to_end()?;
+            let tape = self.finish()?;
+            self.file = std::fs::File::open(&self.path).map_err(io_error)?;
+            self.id = (metadata.dev(), metadata.ino());
+            Ok(tape)
+        } else if metadata.len() < self.position {
+            let tape = self.finish()?;
+            self.file.rewind().map_err(io_error)?;
+            Ok(tape)
+        } else {
+            Ok(self.parser.extract_partial_result())
+        }
+    }
+
+    /// Ends the input so far, returning the sexps it completes (such as an
+    /// atom at the very end), and starts a new parser.
+    fn finish(&mut self) -> Result<SingleTape, parser::Error> {
+        let mut tape = self.parser.extract_partial_result();
+        let mut parser = std::mem::replace(&mut self.parser, new_parser());
+        match parser.process_eof() {
+            Ok(rest) => tape.tape.extend_from_slice(&rest.tape[..]),
+            Err(parser::Error::UnmatchedOpenParen) => (),
+            Err(e) => return Err(e),
+        }
+        self.position = 0;
+        Ok(tape)
+    }
+
+    /// Calls `f` on the sexps as they are completed, until it returns
+    /// `Ok(false)` or an error.
+    pub fn follow<F: FnMut(SingleTape) -> Result<bool, parser::Error>>(&mut self, mut f: F) -> Result<(), parser::Error> {
+        loop {
+            let tape = self.poll()?;
+            if tape.tape.is_empty() {
+                std::thread::sleep(self.poll_interval);
+            } else if !f(tape)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Keeps the first write error, which `Generator` would panic on, and drops
+/// any later writes.
+struct KeepError<'a, WriteT> {
+    writer: &'a mut WriteT,
+    error: Option<std::io::Error>,
+}
+
+impl<'a, WriteT: Write> Write for KeepError<'a, WriteT> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write_all(buf) {
+                self.error = Some(e);
+            }
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The top-level sexps of `tape`, one per line.
+pub fn write_lines<WriteT: Write>(tape: &SingleTape, writer: &mut WriteT) -> std::io::Result<()> {
+    let mut writer = KeepError { writer, error: None };
+    let mut i = 0;
+    while i < tape.tape.len() {
+        let end = i + 1 + (tape.tape[i] / 2) as usize;
+        tape.visit_range(i..end, &mut Generator::new(&mut writer));
+        writer.write_all(b"\n")?;
+        if let Some(e) = writer.error.take() {
+            return Err(e);
+        }
+        i = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::sync::mpsc;
+
+    fn to_lines(tape: &SingleTape) -> String {
+        let mut output = Vec::new();
+        write_lines(tape, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut parser = new_parser();
+        parser.process_partial(b"(a b) (c d)").unwrap();
+        let tape = parser.process_eof().unwrap();
+        let mut buffer = [0u8; 8];
+        let result = write_lines(&tape, &mut &mut buffer[..]);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(&buffer[..], b"(a b)\n(c");
+    }
+
+    #[test]
+    fn test_flush_partial() {
+        let mut parser = new_parser();
+        parser.process_partial(b"(a (b c))\n").unwrap();
+        assert_eq!(to_lines(&parser.extract_partial_result()), "");
+        parser.flush_partial().unwrap();
+        assert_eq!(to_lines(&parser.extract_partial_result()), "(a(b c))\n");
+        parser.process_partial(b"foo \"x y\"\n(d").unwrap();
+        parser.flush_partial().unwrap();
+        assert_eq!(to_lines(&parser.extract_partial_result()), "foo\n\"x y\"\n");
+        parser.process_partial(b" e)").unwrap();
+        parser.flush_partial().unwrap();
+        parser.flush_partial().unwrap();
+        assert_eq!(to_lines(&parser.extract_partial_result()), "(d e)\n");
+        parser.process_partial(b"\n(f)").unwrap();
+        assert_eq!(to_lines(&parser.process_eof().unwrap()), "(f)\n");
+    }
+
+    #[test]
+    fn test_follow() {
+        let dir = std::env::temp_dir().join(format!("simd-sexp-test-follow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        std::fs::write(&path, b"(a 1)\n(b").unwrap();
+
+        // The writer waits for the reader to see each step before the next.
+        let (step_send, step_recv) = mpsc::channel::<()>();
+        let writer_path = path.clone();
+        let writer = std::thread::spawn(move || {
+            let append = |data: &[u8]| {
+                OpenOptions::new().append(true).open(&writer_path).unwrap().write_all(data).unwrap();
+            };
+            step_recv.recv().unwrap();
+            append(b" 2)\n(c 3)\n(d");
+            step_recv.recv().unwrap();
+            // Truncated: `(d` is dropped.
+            std::fs::write(&writer_path, b"").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            append(b"(e 4)\n");
+            step_recv.recv().unwrap();
+            // Rotated, after one last record in the old file.
+            append(b"(f 5)\nlast");
+            std::fs::rename(&writer_path, writer_path.with_extension("1")).unwrap();
+            std::fs::write(&writer_path, b"(g 6)\n").unwrap();
+        });
+
+        let mut follower = Follower::open(&path).unwrap().with_poll_interval(Duration::from_millis(5));
+        let mut lines = String::new();
+        let expected = ["(a 1)\n", "(a 1)\n(b 2)\n(c 3)\n", "(a 1)\n(b 2)\n(c 3)\n(e 4)\n", "(a 1)\n(b 2)\n(c 3)\n(e 4)\n(f 5)\nlast\n(g 6)\n"];
+        let mut step = 0;
+        follower.follow(|tape| {
+            lines += &to_lines(&tape);
+            if lines == expected[step] {
+                step += 1;
+                if step == expected.len() {
+                    return Ok(false);
+                }
+                step_send.send(()).unwrap();
+            }
+            assert!(expected[step].starts_with(&lines[..]), "{:?}", lines);
+            Ok(true)
+        }).unwrap();
+        writer.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
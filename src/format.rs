@@ -0,0 +1,279 @@
+use crate::cst::{self, Node, Trivia, TriviaKind};
+use crate::escape;
+
+pub type Error = cst::Error;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    /// Lines are kept within this many columns where possible.
+    pub width: usize,
+    /// Children of a list that does not fit on one line are indented by this
+    /// many columns relative to the list's opening paren.
+    pub indent: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { width: 80, indent: 1 }
+    }
+}
+
+fn count_newlines(trivia: &Trivia) -> usize {
+    match trivia.kind {
+        TriviaKind::Whitespace => memchr::memchr_iter(b'\n', &trivia.text[..]).count(),
+        _ => 0,
+    }
+}
+
+/// Reindents and reflows a parsed document. Comments are kept, and so are
+/// single blank lines between elements; everything else about the original
+/// layout is discarded. Atoms are requoted, so `"foo"` becomes `foo`.
+struct Printer<'a> {
+    config: &'a Config,
+    escape_is_necessary: escape::IsNecessary,
+    output: Vec<u8>,
+    column: usize,
+    /// Set after a line comment: nothing else may go on the current line.
+    must_break: bool,
+}
+
+impl<'a> Printer<'a> {
+    fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            escape_is_necessary: escape::IsNecessary::new(),
+            output: Vec::new(),
+            column: 0,
+            must_break: false,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+        match memchr::memrchr(b'\n', bytes) {
+            None => self.column += bytes.len(),
+            Some(i) => self.column = bytes.len() - i - 1,
+        }
+    }
+
+    fn newline(&mut self, blank_line: bool, indent: usize) {
+        if blank_line {
+            self.output.push(b'\n');
+        }
+        self.output.push(b'\n');
+        self.output.extend((0..indent).map(|_| b' '));
+        self.column = indent;
+        self.must_break = false;
+    }
+
+    fn at_line_start(&self) -> bool {
+        matches!(self.output.last(), None | Some(b'\n' | b'('))
+    }
+
+    fn atom(&self, atom: &cst::Atom) -> Vec<u8> {
+        let value = atom.value();
+        let mut output = Vec::with_capacity(value.len());
+        if self.escape_is_necessary.eval(&value[..]) {
+            output.push(b'"');
+            escape::escape(&value[..], &mut output).unwrap();
+            output.push(b'"');
+        } else {
+            output.extend_from_slice(&value[..]);
+        }
+        output
+    }
+
+    /// The width of `node` printed on a single line, or `None` if it contains
+    /// comments (which force line breaks).
+    fn flat_width(&self, node: &Node) -> Option<usize> {
+        match node {
+            Node::Atom(atom) => Some(self.atom(atom).len()),
+            Node::List(list) => {
+                if list.trailing.iter().any(Trivia::is_comment) {
+                    return None;
+                }
+                let mut width = 2 + list.len().saturating_sub(1);
+                for element in list.children() {
+                    if element.leading.iter().any(Trivia::is_comment) {
+                        return None;
+                    }
+                    width += self.flat_width(&element.node)?;
+                }
+                Some(width)
+            },
+        }
+    }
+
+    fn flat(&mut self, node: &Node) {
+        match node {
+            Node::Atom(atom) => {
+                let atom = self.atom(atom);
+                self.write(&atom[..]);
+            },
+            Node::List(list) => {
+                self.write(b"(");
+                for (i, child) in list.iter().enumerate() {
+                    if i > 0 {
+                        self.write(b" ");
+                    }
+                    self.flat(child);
+                }
+                self.write(b")");
+            },
+        }
+    }
+
+    /// Writes the comments among `trivia`. Returns whether the original input
+    /// had a blank line after the last comment.
+    fn comments(&mut self, trivia: &[Trivia], indent: usize, allow_blank_lines: bool) -> bool {
+        let mut newlines = 0;
+        for trivia in trivia {
+            if !trivia.is_comment() {
+                newlines += count_newlines(trivia);
+                continue;
+            }
+            if self.must_break || (newlines > 0 && !self.at_line_start()) {
+                self.newline(allow_blank_lines && newlines > 1, indent);
+            } else if !self.at_line_start() {
+                self.write(b" ");
+            }
+            let text = match trivia.kind {
+                TriviaKind::LineComment => trivia.text.trim_ascii_end(),
+                _ => &trivia.text[..],
+            };
+            self.write(text);
+            self.must_break = trivia.kind == TriviaKind::LineComment;
+            newlines = 0;
+        }
+        allow_blank_lines && newlines > 1
+    }
+
+    /// `suffix_width` is the number of columns that will immediately follow
+    /// the node on the same line (closing parens of enclosing lists).
+    fn node(&mut self, node: &Node, suffix_width: usize) {
+        let fits = self.flat_width(node).is_some_and(|width| self.column + width + suffix_width <= self.config.width);
+        let list = match node {
+            Node::List(list) if !fits => list,
+            _ => { return self.flat(node); },
+        };
+
+        let indent = self.column + self.config.indent;
+        // Lists of atoms are filled, rather than having one atom per line.
+        let fill = list.children().iter().all(|element| {
+            element.leading.iter().all(|trivia| !trivia.is_comment())
+                && matches!(element.node, Node::Atom(_))
+        });
+        let trailing_has_comments = list.trailing.iter().any(Trivia::is_comment);
+
+        self.write(b"(");
+        for (i, element) in list.children().iter().enumerate() {
+            let blank_line = self.comments(&element.leading[..], indent, i > 0);
+            let child_suffix_width =
+                if i + 1 == list.len() && !trailing_has_comments { suffix_width + 1 } else { 0 };
+            if self.must_break || (i > 0 && !fill) {
+                self.newline(blank_line, indent);
+            } else if i > 0 {
+                let width = self.flat_width(&element.node).unwrap();
+                if self.column + 1 + width + child_suffix_width <= self.config.width {
+                    self.write(b" ");
+                } else {
+                    self.newline(false, indent);
+                }
+            } else if !self.at_line_start() {
+                // After a block comment.
+                self.write(b" ");
+            }
+            self.node(&element.node, child_suffix_width);
+        }
+        self.comments(&list.trailing[..], indent, false);
+        if self.must_break {
+            self.newline(false, indent);
+        }
+        self.write(b")");
+    }
+
+    fn document(&mut self, document: &cst::Document) {
+        let body = &document.body;
+        for element in body.children() {
+            let blank_line = self.comments(&element.leading[..], 0, true);
+            if !self.output.is_empty() {
+                self.newline(blank_line, 0);
+            }
+            self.node(&element.node, 0);
+        }
+        self.comments(&body.trailing[..], 0, true);
+        if !self.output.is_empty() {
+            self.output.push(b'\n');
+        }
+    }
+}
+
+pub fn format_document(document: &cst::Document, config: &Config) -> Vec<u8> {
+    let mut printer = Printer::new(config);
+    printer.document(document);
+    printer.output
+}
+
+pub fn format(input: &[u8], config: &Config) -> Result<Vec<u8>, Error> {
+    Ok(format_document(&cst::parse(input)?, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_test(config: Config, input: &[u8], expected_output: &str) {
+        let output = String::from_utf8(format(input, &config).unwrap()).unwrap();
+        if output != expected_output {
+            println!("input:      {}", String::from_utf8_lossy(input));
+            println!("expect out: {}", expected_output);
+            println!("actual out: {}", output);
+            panic!("format test failed");
+        }
+        let output_again = String::from_utf8(format(output.as_bytes(), &config).unwrap()).unwrap();
+        assert_eq!(output, output_again, "format is not idempotent");
+    }
+
+    fn narrow(width: usize) -> Config {
+        Config { width, ..Config::default() }
+    }
+
+    #[test] fn test_empty() { run_test(Config::default(), b"  \n", ""); }
+    #[test] fn test_flat() { run_test(Config::default(), b"(foo\n   bar   (baz))  x", "(foo bar (baz))\nx\n"); }
+    #[test] fn test_requote() { run_test(Config::default(), br#"("foo" "a b" "\x41")"#, "(foo \"a b\" A)\n"); }
+
+    #[test]
+    fn test_break() {
+        run_test(narrow(20), b"(library (name foo) (libraries core async unix))",
+                 "(library\n (name foo)\n (libraries core\n  async unix))\n");
+    }
+
+    #[test]
+    fn test_indent() {
+        run_test(Config { width: 12, indent: 2 }, b"(a (b c) (d e f g))",
+                 "(a\n  (b c)\n  (d e f g))\n");
+    }
+
+    #[test]
+    fn test_comments() {
+        run_test(Config::default(),
+                 b"; header\n\n\n(library ; lib\n  (name foo)\n\n  ; deps\n  (libraries x) #| block |#\n  ; trailing\n )\n#; (old)",
+                 "; header\n\n(library ; lib\n (name foo)\n\n ; deps\n (libraries x) #| block |#\n ; trailing\n )\n#; (old)\n");
+    }
+
+    #[test]
+    fn test_comment_first() {
+        run_test(Config::default(), b"(; first\n a b)", "(; first\n a\n b)\n");
+    }
+
+    #[test]
+    fn test_block_comment_first() {
+        run_test(Config::default(), b"(#|c|# a)", "(#|c|# a)\n");
+        run_test(Config::default(), b"(#|c|#a b)", "(#|c|# a\n b)\n");
+    }
+
+    #[test]
+    fn test_blank_lines() {
+        run_test(Config::default(), b"(a)\n\n\n\n(b)\n(c)", "(a)\n\n(b)\n(c)\n");
+    }
+}
@@ -1,23 +1,43 @@
+pub mod binary;
+pub mod cbor;
 pub mod clmul;
+pub mod csexp;
+pub mod cst;
 pub mod escape;
 pub mod escape_csv;
+pub mod escape_json;
 #[cfg(feature = "threads")]
 pub mod exec_parallel;
 pub mod extract;
 pub mod find_quote_transitions;
+pub mod follow;
+pub mod format;
+pub mod msgpack;
+pub mod of_json;
 #[cfg(feature = "ocaml")]
 pub mod ocaml_parser;
+#[cfg(feature = "threads")]
+pub mod par_map;
 pub mod parser;
 #[cfg(feature = "threads")]
 pub mod parser_parallel;
 pub mod print;
+pub mod progress;
 pub mod ranges;
 pub mod rust_generator;
 pub mod rust_parser;
 pub mod select;
+#[cfg(feature = "arrow")]
+pub mod select_arrow;
+pub mod select_filter;
+pub mod select_rows;
+pub mod select_schema;
+pub mod simplify;
 pub mod start_stop_transitions;
 pub mod structural;
+pub mod to_json;
 pub mod utils;
+pub mod validate;
 pub mod varint;
 pub mod visitor;
 pub mod vector_classifier;
@@ -0,0 +1,219 @@
+use crate::binary::{self, Item};
+use crate::csexp;
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use std::io::{BufRead, Write};
+
+pub use binary::Options;
+
+/// MessagePack.
+#[derive(Copy, Clone, Debug)]
+pub struct Msgpack;
+
+/// Writes a header with a fixed-size form (if `fix` is given, as the prefix
+/// and the maximum length it can hold) and 8-, 16- and 32-bit forms (markers
+/// of `None` are not available).
+fn write_header<WriteT: Write>(writer: &mut WriteT, len: usize, fix: Option<(u8, usize)>, markers: [Option<u8>; 3]) -> std::io::Result<()> {
+    match (fix, markers) {
+        (Some((prefix, max)), _) if len <= max => writer.write_all(&[prefix | len as u8]),
+        (_, [Some(marker), _, _]) if len <= u8::MAX as usize => writer.write_all(&[marker, len as u8]),
+        (_, [_, Some(marker), _]) if len <= u16::MAX as usize => {
+            writer.write_all(&[marker])?;
+            writer.write_all(&(len as u16).to_be_bytes())
+        },
+        (_, [_, _, Some(marker)]) => {
+            let len = u32::try_from(len).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "too long for MessagePack"))?;
+            writer.write_all(&[marker])?;
+            writer.write_all(&len.to_be_bytes())
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn be_u64(input: &[u8]) -> u64 {
+    input.iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+impl binary::Format for Msgpack {
+    const INVALID: parser::Error = parser::Error::InvalidMsgpack;
+
+    fn write_array_header<WriteT: Write>(writer: &mut WriteT, len: usize) -> std::io::Result<()> {
+        write_header(writer, len, Some((0x90, 15)), [None, Some(0xdc), Some(0xdd)])
+    }
+
+    fn write_map_header<WriteT: Write>(writer: &mut WriteT, len: usize) -> std::io::Result<()> {
+        write_header(writer, len, Some((0x80, 15)), [None, Some(0xde), Some(0xdf)])
+    }
+
+    fn write_atom<WriteT: Write>(writer: &mut WriteT, atom: &[u8]) -> std::io::Result<()> {
+        if std::str::from_utf8(atom).is_ok() {
+            write_header(writer, atom.len(), Some((0xa0, 31)), [Some(0xd9), Some(0xda), Some(0xdb)])?;
+        } else {
+            write_header(writer, atom.len(), None, [Some(0xc4), Some(0xc5), Some(0xc6)])?;
+        }
+        writer.write_all(atom)
+    }
+
+    fn read_header(input: &[u8]) -> Result<Option<(Item, usize)>, parser::Error> {
+        let marker = match input.first() {
+            None => return Ok(None),
+            Some(&marker) => marker,
+        };
+        let argument_len = match marker {
+            0xcc | 0xd0 | 0xd9 | 0xc4 => 1,
+            0xcd | 0xd1 | 0xda | 0xc5 | 0xdc | 0xde => 2,
+            0xce | 0xd2 | 0xca | 0xdb | 0xc6 | 0xdd | 0xdf => 4,
+            0xcf | 0xd3 | 0xcb => 8,
+            _ => 0,
+        };
+        let header_len = 1 + argument_len;
+        if input.len() < header_len {
+            return Ok(None);
+        }
+        let argument = be_u64(&input[1..header_len]);
+        let item = match marker {
+            0x00..=0x7f => Item::Unsigned(marker as u64),
+            0x80..=0x8f => Item::Map(Some((marker & 0x0f) as usize)),
+            0x90..=0x9f => Item::Array(Some((marker & 0x0f) as usize)),
+            0xa0..=0xbf => Item::String((marker & 0x1f) as usize),
+            0xc0 => Item::Null,
+            0xc2 => Item::Bool(false),
+            0xc3 => Item::Bool(true),
+            0xc4..=0xc6 | 0xd9..=0xdb => Item::String(argument as usize),
+            0xca => Item::Float(f32::from_bits(argument as u32) as f64),
+            0xcb => Item::Float(f64::from_bits(argument)),
+            0xcc..=0xcf => Item::Unsigned(argument),
+            // sign-extend
+            0xd0..=0xd3 => Item::Signed(((argument << (64 - 8 * argument_len)) as i64) >> (64 - 8 * argument_len)),
+            0xdc | 0xdd => Item::Array(Some(argument as usize)),
+            0xde | 0xdf => Item::Map(Some(argument as usize)),
+            0xe0..=0xff => Item::Signed(marker as i8 as i64),
+            // 0xc1 is never used, and extension types are not supported
+            _ => return Err(parser::Error::InvalidMsgpack),
+        };
+        Ok(Some((item, header_len)))
+    }
+}
+
+pub type Generator<'a, WriteT> = binary::Generator<'a, Msgpack, WriteT>;
+pub type Stage2 = binary::Stage2<Msgpack>;
+pub type State<VisitorT> = binary::State<Msgpack, VisitorT>;
+
+/// Converts regular sexps into MessagePack, one top-level item per top-level
+/// sexp.
+pub fn make_writer<'a, ReadT: BufRead + Send, WriteT: Write>
+    (stdout: &'a mut WriteT, options: Options, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return parser_parallel::streaming_from_writing_stage2(move || { Stage2::new(options) }, stdout, config);
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    parser::streaming_from_writing_stage2(Stage2::new(options), stdout)
+}
+
+/// Converts a sequence of MessagePack items into regular sexps, one per line.
+pub fn make_printer<'a, ReadT: BufRead, WriteT: Write>
+    (stdout: &'a mut WriteT)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    Box::new(State::new(csexp::Print::new(stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust_parser::{Sexp, SexpFactory, SingleTapeVisitor, SplitTapeVisitor};
+    use crate::visitor;
+
+    fn run_test(input: &[u8], expected_output: Result<&str, parser::Error>) {
+        let validate = |name: &str, output: Result<String, parser::Error>| {
+            assert_eq!(output.as_deref().map_err(|e| e.clone()), expected_output, "{}", name);
+        };
+
+        let sexps_to_string = |sexps: Vec<Sexp>| {
+            // print as a list, so adjacent atoms are separated as on the tapes
+            let list = Sexp::List(sexps).to_string();
+            list[1..(list.len() - 1)].to_string()
+        };
+
+        {
+            let mut parser = State::new(visitor::SimpleVisitor::new(SexpFactory::new()));
+            let mut buf_reader = std::io::BufReader::with_capacity(1, input);
+            validate("SimpleVisitor<SexpFactory> (process_streaming)", parser.process_streaming(&mut buf_reader).map(sexps_to_string));
+        }
+
+        {
+            let mut parser = State::new(SplitTapeVisitor::new());
+            validate("SplitTapeVisitor", parser.process_all(input).map(|tape| tape.to_string()));
+        }
+
+        {
+            let mut parser = State::new(SingleTapeVisitor::new());
+            validate("SingleTapeVisitor", parser.process_all(input).map(|tape| tape.to_string()));
+        }
+    }
+
+    #[test] fn test_str() { run_test(b"\xa3foo\xd9\x01x", Ok("foo x")); }
+    #[test] fn test_bin() { run_test(b"\xc4\x02\xff\x00", Ok("\"\\255\\000\"")); }
+    #[test] fn test_array() { run_test(b"\x92\xa3foo\x91\xa3bar\x90", Ok("(foo(bar))()")); }
+    #[test] fn test_map() { run_test(b"\x82\xa1a\x01\xa1b\x80", Ok("((a 1)(b()))")); }
+    #[test] fn test_array16() { run_test(b"\xdc\x00\x02\x01\x02", Ok("(1 2)")); }
+
+    #[test]
+    fn test_scalars() {
+        run_test(b"\x9d\x00\x7f\xcc\xff\xcd\x01\x00\xff\xe0\xd0\x80\xd1\xff\x00\xc2\xc3\xc0\xca\x3f\xc0\x00\x00\xcb\x3f\xb9\x99\x99\x99\x99\x99\x9a",
+                 Ok("(0 127 255 256 -1 -32 -128 -256 false true null 1.5 0.1)"));
+    }
+
+    #[test]
+    fn test_errors() {
+        run_test(b"\x92\x01", Err(parser::Error::UnmatchedOpenParen));
+        run_test(b"\xa3fo", Err(parser::Error::InvalidMsgpack));
+        run_test(b"\xc1", Err(parser::Error::InvalidMsgpack));
+        run_test(b"\xd4\x01\x00", Err(parser::Error::InvalidMsgpack));
+    }
+
+    fn generate(sexp: &Sexp, options: Options) -> Vec<u8> {
+        use visitor::ReadVisitable;
+        let mut output = Vec::new();
+        sexp.visit(&mut Generator::new(&mut output, options));
+        output
+    }
+
+    #[test]
+    fn test_generator() {
+        let atom = |a: &[u8]| Sexp::Atom(a.to_vec());
+        let record = Sexp::List(vec![
+            Sexp::List(vec![atom(b"a"), atom(b"1")]),
+            Sexp::List(vec![atom(b"b"), Sexp::List(vec![atom(b"\xff")])]),
+        ]);
+        assert_eq!(generate(&record, Options::default()), b"\x92\x92\xa1a\xa11\x92\xa1b\x91\xc4\x01\xff");
+        assert_eq!(generate(&record, Options { records: true }), b"\x82\xa1a\xa11\xa1b\x91\xc4\x01\xff");
+        let long = Sexp::List((0..16).map(|_| atom(&[b'x'; 32][..])).collect());
+        assert_eq!(&generate(&long, Options::default())[..5], b"\xdc\x00\x10\xd9\x20");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for threads in [false, true] {
+            let input = b"((a 1) (b (\"x y\" \"\\255\")))\nfoo\n".repeat(1000);
+            let mut msgpack = Vec::new();
+            let mut writer = make_writer(&mut msgpack, Options { records: true }, threads);
+            writer.process_streaming(&mut &input[..]).unwrap();
+            std::mem::drop(writer);
+
+            let mut output = Vec::new();
+            let mut printer = make_printer(&mut output);
+            printer.process_streaming(&mut std::io::BufReader::with_capacity(5, &msgpack[..])).unwrap();
+            std::mem::drop(printer);
+            assert_eq!(String::from_utf8(output).unwrap(), "((a 1)(b(\"x y\"\"\\255\")))\nfoo\n".repeat(1000), "threads: {}", threads);
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use crate::escape::{self, Unescape};
+use crate::escape_json;
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use crate::structural::{self, CallbackResult};
+use std::io::{BufRead, Write};
+
+/// Stage-1 for JSON. Like `structural::Generic`, this marks the start of
+/// every string, the start and end of every other scalar, and every
+/// structural character (`{}[]:,`) outside of strings.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Generic {
+    escape: bool,
+    quote_state: bool,
+    scalar_like: bool,
+}
+
+impl Generic {
+    pub fn new() -> Self {
+        Self {
+            escape: false,
+            quote_state: false,
+            scalar_like: false,
+        }
+    }
+
+    #[inline]
+    fn structural_indices_bitmask_one(&mut self, input_buf: &[u8]) -> (u64, usize) {
+        let chunk_len = std::cmp::min(64, input_buf.len());
+        let mut result = 0u64;
+        for (i, &ch) in input_buf[0..chunk_len].iter().enumerate() {
+            let quote_state_change = ch == b'"' && !(self.quote_state && self.escape);
+            let escape = ch == b'\\' && !self.escape;
+            let scalar_like = match ch {
+                b'"' | b' ' | b'\n' | b'\t' | b'\r' | b'{' | b'}' | b'[' | b']' | b':' | b',' => false,
+                _ => !self.quote_state,
+            };
+            let structural = match ch {
+                b'{' | b'}' | b'[' | b']' | b':' | b',' => !self.quote_state,
+                _ => false,
+            };
+            let scalar_like_state_change = scalar_like ^ self.scalar_like;
+            self.escape = escape;
+            self.scalar_like = scalar_like;
+            self.quote_state ^= quote_state_change;
+            if (self.quote_state && quote_state_change) || (!self.quote_state && scalar_like_state_change) || structural {
+                result |= 1u64 << i;
+            }
+        }
+        (result, chunk_len)
+    }
+}
+
+impl Default for Generic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl structural::Classifier for Generic {
+    const NAME: &'static str = "JSON Generic";
+
+    #[inline]
+    fn structural_indices_bitmask<F: FnMut(u64, usize) -> CallbackResult>(&mut self, input_buf: &[u8], mut f: F) {
+        for chunk in input_buf.chunks(64) {
+            let (result, chunk_len) = self.structural_indices_bitmask_one(chunk);
+            debug_assert!(chunk_len == chunk.len());
+            match f(result, chunk_len) {
+                CallbackResult::Continue => (),
+                CallbackResult::Finish => { return; },
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use core::arch::x86_64::*;
+
+    use crate::{clmul, vector_classifier, xor_masked_adjacent, utils, find_quote_transitions, ranges};
+    use crate::structural::{Classifier, CallbackResult};
+    use vector_classifier::ClassifierBuilder;
+    use clmul::Clmul;
+
+    use super::Generic;
+
+    #[derive(Clone, Debug)]
+    pub struct Avx2 {
+        /* constants */
+        clmul: clmul::Sse2Pclmulqdq,
+        structural_classifier: vector_classifier::Avx2Classifier,
+        scalar_terminator_classifier: vector_classifier::Avx2Classifier,
+        xor_masked_adjacent: xor_masked_adjacent::Bmi2,
+
+        /* fallback */
+        generic: Generic,
+    }
+
+    impl Avx2 {
+        pub fn new() -> Option<Self> {
+            let clmul = clmul::Sse2Pclmulqdq::new()?;
+            let vector_classifier_builder = vector_classifier::Avx2Builder::new()?;
+            let xor_masked_adjacent = xor_masked_adjacent::Bmi2::new()?;
+
+            let structural_classifier =
+                vector_classifier_builder.build(&vector_classifier::LookupTables::from_accepting_chars(b"{}[]:,").unwrap());
+            let scalar_terminator_classifier =
+                vector_classifier_builder.build(&vector_classifier::LookupTables::from_accepting_chars(b" \t\n\r{}[]:,\"").unwrap());
+
+            let generic = Generic::new();
+
+            Some(Self {
+                clmul,
+                structural_classifier,
+                scalar_terminator_classifier,
+                xor_masked_adjacent,
+                generic,
+            })
+        }
+
+        pub fn get_generic_state(&mut self) -> Generic {
+            self.generic
+        }
+
+        #[target_feature(enable = "avx2,bmi2,sse2,ssse3,pclmulqdq")]
+        #[inline]
+        unsafe fn classify_structural_avx2(&self, mut input: __m256i) -> __m256i {
+            self.structural_classifier.classify_avx2(std::slice::from_mut(&mut input));
+            _mm256_xor_si256(_mm256_cmpeq_epi8(input, _mm256_set1_epi8(0)), _mm256_set1_epi8(-1))
+        }
+
+        #[target_feature(enable = "avx2,bmi2,sse2,ssse3,pclmulqdq")]
+        #[inline]
+        unsafe fn classify_quote_avx2(&self, input: __m256i) -> __m256i {
+            _mm256_cmpeq_epi8(input, _mm256_set1_epi8(b'"' as i8))
+        }
+
+        #[target_feature(enable = "avx2,bmi2,sse2,ssse3,pclmulqdq")]
+        #[inline]
+        unsafe fn classify_backslash_avx2(&self, input: __m256i) -> __m256i {
+            _mm256_cmpeq_epi8(input, _mm256_set1_epi8(b'\\' as i8))
+        }
+
+        #[target_feature(enable = "avx2,bmi2,sse2,ssse3,pclmulqdq")]
+        #[inline]
+        unsafe fn classify_scalar_like_avx2(&self, mut input: __m256i) -> __m256i {
+            self.scalar_terminator_classifier.classify_avx2(std::slice::from_mut(&mut input));
+            _mm256_cmpeq_epi8(input, _mm256_set1_epi8(0))
+        }
+
+        #[target_feature(enable = "avx2,bmi2,sse2,ssse3,pclmulqdq")]
+        #[inline]
+        unsafe fn structural_indices_bitmask_one_avx2(&mut self, input_lo: __m256i, input_hi: __m256i) -> u64 {
+            let structural_lo = self.classify_structural_avx2(input_lo);
+            let quote_lo = self.classify_quote_avx2(input_lo);
+            let backslash_lo = self.classify_backslash_avx2(input_lo);
+            let scalar_like_lo = self.classify_scalar_like_avx2(input_lo);
+
+            let structural_hi = self.classify_structural_avx2(input_hi);
+            let quote_hi = self.classify_quote_avx2(input_hi);
+            let backslash_hi = self.classify_backslash_avx2(input_hi);
+            let scalar_like_hi = self.classify_scalar_like_avx2(input_hi);
+
+            let bm_structural = utils::make_bitmask(structural_lo, structural_hi);
+            let bm_quote = utils::make_bitmask(quote_lo, quote_hi);
+            let bm_backslash = utils::make_bitmask(backslash_lo, backslash_hi);
+            let bm_scalar_like = utils::make_bitmask(scalar_like_lo, scalar_like_hi);
+
+            let (escaped, escape_state) = ranges::odd_range_ends(bm_backslash, self.generic.escape);
+            self.generic.escape = escape_state;
+
+            let escaped_quotes = bm_quote & escaped;
+            let unescaped_quotes = bm_quote & !escaped;
+            let prev_quote_state = self.generic.quote_state;
+            let (quote_transitions, quote_state) = find_quote_transitions::find_quote_transitions(&self.clmul, &self.xor_masked_adjacent, unescaped_quotes, escaped_quotes, self.generic.quote_state);
+            self.generic.quote_state = quote_state;
+            let quoted_areas = self.clmul.clmul(quote_transitions) ^ (if prev_quote_state { !0u64 } else { 0u64 });
+
+            let bm_scalar_like = bm_scalar_like & !quoted_areas;
+
+            let special = (quote_transitions & quoted_areas) | (!quoted_areas & (bm_structural | ranges::range_transitions(bm_scalar_like, self.generic.scalar_like)));
+
+            self.generic.scalar_like = bm_scalar_like >> 63 != 0;
+
+            special
+        }
+    }
+
+    impl Classifier for Avx2 {
+        const NAME: &'static str = "JSON AVX2";
+
+        #[inline(always)]
+        fn structural_indices_bitmask<F: FnMut(u64, usize) -> CallbackResult>(&mut self, input_buf: &[u8], mut f: F) {
+            let (prefix, aligned, suffix) = unsafe { input_buf.align_to::<(__m256i, __m256i)>() };
+            if utils::unlikely(!prefix.is_empty()) {
+                let (bitmask, len) = self.generic.structural_indices_bitmask_one(prefix);
+                debug_assert!(len == prefix.len());
+                match f(bitmask, len) {
+                    CallbackResult::Continue => (),
+                    CallbackResult::Finish => { return; },
+                }
+            }
+            for (lo, hi) in aligned {
+                unsafe {
+                    let bitmask = self.structural_indices_bitmask_one_avx2(*lo, *hi);
+                    match f(bitmask, 64) {
+                        CallbackResult::Continue => (),
+                        CallbackResult::Finish => { return; },
+                    }
+                }
+            }
+            if utils::unlikely(!suffix.is_empty()) {
+                let (bitmask, len) = self.generic.structural_indices_bitmask_one(suffix);
+                debug_assert!(len == suffix.len());
+                f(bitmask, len);
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub use x86::*;
+
+pub fn make_classifier_cps<'a, Cps: structural::MakeClassifierCps<'a>>(cps: Cps) -> Cps::Return {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if let Some(classifier) = Avx2::new() {
+            return cps.f(classifier);
+        }
+    }
+
+    cps.f(Generic::new())
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Expect {
+    /// Just after `[`
+    ValueOrEnd,
+    /// Just after `{`
+    KeyOrEnd,
+    /// After `,` in an array, or `:` in an object
+    Value,
+    /// After `,` in an object
+    Key,
+    /// After a key
+    Colon,
+    /// After a value in an array or object
+    CommaOrEnd,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Frame {
+    Array(Expect),
+    Object(Expect),
+}
+
+/// Converts each top-level JSON value into a sexp on its own line. Objects
+/// become records `((k1 v1) (k2 v2) ...)`, arrays become lists, and scalars
+/// become atoms (`true`, `false` and `null` included).
+pub struct Stage2 {
+    escape_is_necessary: escape::IsNecessary,
+    unescape: escape_json::GenericUnescape,
+    atom_buffer: Vec<u8>,
+
+    // varying
+    stack: Vec<Frame>,
+    naked_atom_needs_space: bool,
+}
+
+impl Stage2 {
+    pub fn new() -> Self {
+        Self {
+            escape_is_necessary: escape::IsNecessary::new(),
+            unescape: escape_json::GenericUnescape::new(),
+            atom_buffer: Vec::new(),
+            stack: Vec::new(),
+            naked_atom_needs_space: false,
+        }
+    }
+
+    fn output_atom<WriteT: Write>(&mut self, writer: &mut WriteT, atom: &[u8]) {
+        if self.escape_is_necessary.eval(atom) {
+            writer.write_all(b"\"").unwrap();
+            escape::escape(atom, writer).unwrap();
+            writer.write_all(b"\"").unwrap();
+            self.naked_atom_needs_space = false;
+        } else {
+            if self.naked_atom_needs_space {
+                writer.write_all(b" ").unwrap();
+            }
+            writer.write_all(atom).unwrap();
+            self.naked_atom_needs_space = true;
+        }
+    }
+
+    fn list_open<WriteT: Write>(&mut self, writer: &mut WriteT) {
+        writer.write_all(b"(").unwrap();
+        self.naked_atom_needs_space = false;
+    }
+
+    fn list_close<WriteT: Write>(&mut self, writer: &mut WriteT) {
+        writer.write_all(b")").unwrap();
+        self.naked_atom_needs_space = false;
+    }
+
+    /// Checks that a value may start here, and marks the value as seen.
+    fn value<WriteT: Write>(&mut self, writer: &mut WriteT, is_key: bool) -> Result<(), parser::Error> {
+        let frame = match self.stack.last_mut() {
+            None => return Ok(()),
+            Some(frame) => frame,
+        };
+        match (frame, is_key) {
+            (Frame::Array(expect @ (Expect::ValueOrEnd | Expect::Value)), false) |
+            (Frame::Object(expect @ Expect::Value), false) => {
+                *expect = Expect::CommaOrEnd;
+                Ok(())
+            },
+            (Frame::Object(expect @ (Expect::KeyOrEnd | Expect::Key)), true) => {
+                *expect = Expect::Colon;
+                self.list_open(writer);
+                Ok(())
+            },
+            _ => Err(parser::Error::InvalidJson),
+        }
+    }
+
+    fn value_end<WriteT: Write>(&mut self, writer: &mut WriteT) {
+        if self.stack.is_empty() {
+            writer.write_all(b"\n").unwrap();
+            self.naked_atom_needs_space = false;
+        }
+    }
+}
+
+impl Default for Stage2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl parser::WritingStage2 for Stage2 {
+    fn reset(&mut self) {
+        self.stack.clear();
+        self.naked_atom_needs_space = false;
+    }
+
+    #[inline]
+    fn process_one<WriteT: Write>(&mut self, writer: &mut WriteT, input: parser::Input, this_index: usize, next_index: usize, _is_eof: bool) -> Result<usize, parser::Error> {
+        let ch = input.input[this_index - input.offset];
+
+        match ch {
+            b'[' | b'{' => {
+                self.value(writer, false)?;
+                self.stack.push(if ch == b'[' { Frame::Array(Expect::ValueOrEnd) } else { Frame::Object(Expect::KeyOrEnd) });
+                self.list_open(writer);
+            },
+            b']' | b'}' => {
+                match (self.stack.pop(), ch) {
+                    (Some(Frame::Array(Expect::ValueOrEnd | Expect::CommaOrEnd)), b']') |
+                    (Some(Frame::Object(Expect::KeyOrEnd)), b'}') => (),
+                    (Some(Frame::Object(Expect::CommaOrEnd)), b'}') => {
+                        // close the last (key value) pair
+                        self.list_close(writer);
+                    },
+                    (None, _) => return Err(parser::Error::UnmatchedCloseParen),
+                    _ => return Err(parser::Error::InvalidJson),
+                }
+                self.list_close(writer);
+                self.value_end(writer);
+            },
+            b':' => {
+                match self.stack.last_mut() {
+                    Some(Frame::Object(expect @ Expect::Colon)) => { *expect = Expect::Value; },
+                    _ => return Err(parser::Error::InvalidJson),
+                }
+            },
+            b',' => {
+                match self.stack.last_mut() {
+                    Some(Frame::Array(expect @ Expect::CommaOrEnd)) => { *expect = Expect::Value; },
+                    Some(Frame::Object(expect @ Expect::CommaOrEnd)) => {
+                        *expect = Expect::Key;
+                        self.list_close(writer);
+                    },
+                    _ => return Err(parser::Error::InvalidJson),
+                }
+            },
+            b' ' | b'\t' | b'\n' | b'\r' => (),
+            b'"' => {
+                let is_key = matches!(self.stack.last(), Some(Frame::Object(Expect::KeyOrEnd | Expect::Key)));
+                self.value(writer, is_key)?;
+                let mut atom_buffer = std::mem::take(&mut self.atom_buffer);
+                atom_buffer.resize(next_index - this_index, 0u8);
+                let (input_consumed, atom_len) =
+                    self.unescape.unescape(
+                        &input.input[(this_index + 1 - input.offset)..(next_index - input.offset)],
+                        &mut atom_buffer[..])
+                    .ok_or(parser::Error::BadQuotedAtom)?;
+                if this_index + 1 + input_consumed + 1 != next_index
+                    && !matches!(input.input.get(next_index - input.offset), Some(b',' | b':' | b']' | b'}'))
+                {
+                    // something other than whitespace or structure follows
+                    // the closing quote
+                    let rest = &input.input[(this_index + input_consumed + 2 - input.offset)..(next_index - input.offset)];
+                    if rest.iter().any(|ch| !matches!(ch, b' ' | b'\t' | b'\n' | b'\r')) {
+                        return Err(parser::Error::InvalidJson);
+                    }
+                }
+                self.output_atom(writer, &atom_buffer[..atom_len]);
+                self.atom_buffer = atom_buffer;
+                if !is_key {
+                    self.value_end(writer);
+                }
+            },
+            _ => {
+                self.value(writer, false)?;
+                let scalar = &input.input[(this_index - input.offset)..(next_index - input.offset)];
+                if !(escape_json::is_number(scalar) || scalar == b"true" || scalar == b"false" || scalar == b"null") {
+                    return Err(parser::Error::InvalidJson);
+                }
+                self.output_atom(writer, scalar);
+                self.value_end(writer);
+            },
+        }
+
+        Ok(next_index)
+    }
+
+    fn process_eof<WriteT: Write>(&mut self, _writer: &mut WriteT) -> Result<(), parser::Error> {
+        if !self.stack.is_empty() {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(())
+    }
+}
+
+struct MakeStreamingFromClassifierCps<'a, WriteT, BufReadT> {
+    writer: &'a mut WriteT,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+impl<'a, WriteT: Write, BufReadT: BufRead> structural::MakeClassifierCps<'a> for MakeStreamingFromClassifierCps<'a, WriteT, BufReadT> {
+    type Return = Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        Box::new(parser::State::new(classifier, parser::WritingStage2Adapter::new(Stage2::new(), self.writer)))
+    }
+}
+
+#[cfg(feature = "threads")]
+struct MakeParallelStreamingFromClassifierCps<'a, WriteT, BufReadT> {
+    writer: &'a mut WriteT,
+    config: parser_parallel::ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, WriteT: Write, BufReadT: BufRead + Send> structural::MakeClassifierCps<'a> for MakeParallelStreamingFromClassifierCps<'a, WriteT, BufReadT> {
+    type Return = Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        parser_parallel::streaming_from_pooled_worker(move |buffer_pool| {
+            parser::State::new(classifier.clone(), parser_parallel::WritingStage2Adapter::new(Stage2::new()).with_buffer_pool(buffer_pool.clone()))
+        }, self.writer, self.config)
+    }
+}
+
+/// With `threads`, the input is split into chunks at line boundaries, so it
+/// must be JSON Lines (or at least not contain newlines followed by anything
+/// other than whitespace inside a value).
+pub fn make<'a, ReadT: BufRead + Send, WriteT: Write>
+    (stdout: &'a mut WriteT, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return make_classifier_cps(MakeParallelStreamingFromClassifierCps { writer: stdout, config, phantom: std::marker::PhantomData });
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    make_classifier_cps(MakeStreamingFromClassifierCps { writer: stdout, phantom: std::marker::PhantomData })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_json;
+
+    fn run_test(input: &[u8], expected_output: Result<&str, parser::Error>) {
+        let threads_options: &[bool] = if cfg!(feature = "threads") { &[false, true] } else { &[false] };
+        for &threads in threads_options {
+            let mut output = Vec::new();
+            let mut parser = make(&mut output, threads);
+            let ok = parser.process_streaming(&mut std::io::BufReader::with_capacity(7, input));
+            std::mem::drop(parser);
+            let output = ok.map(move |()| String::from_utf8(output).unwrap());
+            assert_eq!(output.as_deref().map_err(|e| e.clone()), expected_output, "threads: {}", threads);
+        }
+    }
+
+    fn round_trip_test(options: to_json::Options, input: &str) {
+        let mut json = Vec::new();
+        let mut to_json = to_json::make(&mut json, options, false);
+        to_json.process_streaming(&mut input.as_bytes()).unwrap();
+        std::mem::drop(to_json);
+
+        let mut output = Vec::new();
+        let mut of_json = make(&mut output, false);
+        of_json.process_streaming(&mut &json[..]).unwrap();
+        std::mem::drop(of_json);
+
+        assert_eq!(String::from_utf8(output).unwrap(), input, "via JSON: {}", String::from_utf8_lossy(&json[..]));
+    }
+
+    #[test] fn test_scalars() { run_test(br#"1 -2.5e3 "a b" true false null"#, Ok("1\n-2.5e3\n\"a b\"\ntrue\nfalse\nnull\n")); }
+    #[test] fn test_array() { run_test(br#"[1, [], ["x", [true]]]"#, Ok("(1()(x(true)))\n")); }
+    #[test] fn test_object() { run_test(br#"{"a": 1, "b c": {"d": [2, 3]}, "e": {}}"#, Ok("((a 1)(\"b c\"((d(2 3))))(e()))\n")); }
+    #[test] fn test_strings() { run_test(br#"["caf\u00e9", "x\"y", "(", ""]"#, Ok("(\"caf\\195\\169\"\"x\\\"y\"\"(\"\"\")\n")); }
+    #[test] fn test_whitespace() { run_test(b"{\r\n  \"a\" :\t[ 1 ,2 ]\r\n}\n", Ok("((a(1 2)))\n")); }
+
+    #[test]
+    fn test_many_lines() {
+        let input = "{\"id\": 1, \"name\": \"x y\", \"tags\": [\"a\", \"b\"]}\n".repeat(50000);
+        let expected_output = "((id 1)(name\"x y\")(tags(a b)))\n".repeat(50000);
+        run_test(input.as_bytes(), Ok(&expected_output[..]));
+    }
+
+    #[test]
+    fn test_errors() {
+        run_test(b"[1", Err(parser::Error::UnmatchedOpenParen));
+        run_test(b"1]", Err(parser::Error::UnmatchedCloseParen));
+        run_test(b"\"a", Err(parser::Error::BadQuotedAtom));
+        run_test(b"[1 2]", Err(parser::Error::InvalidJson));
+        run_test(b"{1: 2}", Err(parser::Error::InvalidJson));
+        run_test(b"{\"a\" 2}", Err(parser::Error::InvalidJson));
+        run_test(b"{\"a\": 2,}", Err(parser::Error::InvalidJson));
+        run_test(b"[1}", Err(parser::Error::InvalidJson));
+        run_test(b"[nope]", Err(parser::Error::InvalidJson));
+        run_test(b"\"a\"b", Err(parser::Error::InvalidJson));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let plain = to_json::Options::default();
+        let records = to_json::Options { records: true, ..plain };
+        round_trip_test(plain, "(a(b\"c d\")())\nfoo\n(\"\\\"\"\"\\n\")\n");
+        round_trip_test(records, "((a 1)(b((c(x y)))))\n(1 2)\n");
+    }
+
+    #[test]
+    fn test_structural() {
+        use structural::Classifier;
+        use rand::{prelude::Distribution, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let chars = b"{}[]:, \n\"\\a";
+        let random_char = rand::distributions::Uniform::new(0, chars.len()).map(|i| chars[i]);
+
+        #[cfg(target_arch = "x86_64")]
+        if Avx2::new().is_some() {
+            for _ in 0..1000 {
+                let input: Vec<u8> = (0..300).map(|_| random_char.sample(&mut rng)).collect();
+                let collect = |classifier: &mut dyn FnMut(&mut Vec<bool>)| {
+                    let mut output = Vec::new();
+                    classifier(&mut output);
+                    output
+                };
+                let mut generic = Generic::new();
+                let mut avx2 = Avx2::new().unwrap();
+                let generic_output = collect(&mut |output| {
+                    generic.structural_indices_bitmask(&input[..], |bitmask, bitmask_len| {
+                        output.extend((0..bitmask_len).map(|i| bitmask & (1 << i) != 0));
+                        CallbackResult::Continue
+                    })
+                });
+                let avx2_output = collect(&mut |output| {
+                    avx2.structural_indices_bitmask(&input[..], |bitmask, bitmask_len| {
+                        output.extend((0..bitmask_len).map(|i| bitmask & (1 << i) != 0));
+                        CallbackResult::Continue
+                    })
+                });
+                assert_eq!(generic_output, avx2_output);
+                assert_eq!(generic, avx2.get_generic_state());
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::parser;
+use crate::parser_parallel;
+use crate::rust_parser::{SexpRef, SplitTape, SplitTapeVisitor};
+use crate::structural;
+use std::io::BufRead;
+use std::sync::Mutex;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// Results are delivered in the order of the input.
+    Ordered,
+    /// Results are delivered as soon as their chunk is done.
+    Unordered,
+}
+
+/// Parses a chunk into a tape and maps `f` over its top-level sexps.
+struct Worker<'a, ParseT, F, SinkT> {
+    parser: ParseT,
+    f: &'a F,
+    /// Only for `Order::Unordered`.
+    sink: Option<&'a Mutex<SinkT>>,
+}
+
+impl<'a, OutT, ParseT, F, SinkT> parser::Parse for Worker<'a, ParseT, F, SinkT>
+where
+    ParseT: parser::Parse<Return = SplitTape>,
+    F: Fn(SexpRef) -> Option<OutT>,
+    SinkT: FnMut(OutT),
+{
+    type Return = Vec<OutT>;
+    fn process(&mut self, input: &[u8]) -> Result<Vec<OutT>, parser::Error> {
+        let tape = self.parser.process(input)?;
+        // Collected before taking the sink, so that `f` runs in parallel.
+        let outputs: Vec<OutT> = tape.iter().filter_map(self.f).collect();
+        match self.sink {
+            None => Ok(outputs),
+            Some(sink) => {
+                outputs.into_iter().for_each(&mut *sink.lock().unwrap());
+                Ok(Vec::new())
+            },
+        }
+    }
+}
+
+/// Delivers each chunk's results to the sink, in the order of the input.
+struct Joiner<'a, WorkerT, SinkT> {
+    create_worker: Box<dyn Fn() -> WorkerT + 'a>,
+    sink: &'a Mutex<SinkT>,
+}
+
+impl<'a, OutT, WorkerT: parser::Parse<Return = Vec<OutT>>, SinkT: FnMut(OutT)> parser_parallel::Joiner for Joiner<'a, WorkerT, SinkT> {
+    type Worker = WorkerT;
+    type Return = ();
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+    }
+    fn create_worker(&mut self) -> Self::Worker {
+        (self.create_worker)()
+    }
+    fn join(&mut self, result: Vec<OutT>) -> Result<(), parser::Error> {
+        result.into_iter().for_each(&mut *self.sink.lock().unwrap());
+        Ok(())
+    }
+    fn process_eof(&mut self) -> Result<(), parser::Error> {
+        Ok(())
+    }
+}
+
+struct MakeParallelStreamingFromClassifierCps<'a, F, SinkT, BufReadT> {
+    f: &'a F,
+    sink: &'a Mutex<SinkT>,
+    order: Order,
+    config: parser_parallel::ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+impl<'a, OutT: Send, F, SinkT, BufReadT> structural::MakeClassifierCps<'a> for MakeParallelStreamingFromClassifierCps<'a, F, SinkT, BufReadT>
+where
+    F: Fn(SexpRef) -> Option<OutT> + Sync,
+    SinkT: FnMut(OutT) + Send,
+    BufReadT: BufRead + Send,
+{
+    type Return = Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        let (f, sink) = (self.f, self.sink);
+        let worker_sink = match self.order {
+            Order::Ordered => None,
+            Order::Unordered => Some(sink),
+        };
+        let create_worker = move || {
+            let parser = parser::State::new(classifier.clone(), parser::VisitorState::new(SplitTapeVisitor::new()));
+            Worker { parser, f, sink: worker_sink }
+        };
+        parser_parallel::streaming_new(Joiner { create_worker: Box::new(create_worker), sink }, self.config)
+    }
+}
+
+/// Calls `f` on every top-level sexp of `reader`, in parallel, and passes
+/// the results to `sink` (one call at a time) in the given order.
+pub fn par_map_with_sink<ReadT, OutT, F, SinkT>(reader: &mut ReadT, order: Order, f: F, sink: SinkT) -> Result<(), parser::Error>
+where
+    ReadT: BufRead + Send,
+    OutT: Send,
+    F: Fn(SexpRef) -> Option<OutT> + Sync,
+    SinkT: FnMut(OutT) + Send,
+{
+    par_map_with_sink_and_config(reader, order, f, sink, parser_parallel::ParallelConfig::default())
+}
+
+/// Like `par_map_with_sink`, but split up as `config` says.
+pub fn par_map_with_sink_and_config<ReadT, OutT, F, SinkT>(reader: &mut ReadT, order: Order, f: F, sink: SinkT, config: parser_parallel::ParallelConfig) -> Result<(), parser::Error>
+where
+    ReadT: BufRead + Send,
+    OutT: Send,
+    F: Fn(SexpRef) -> Option<OutT> + Sync,
+    SinkT: FnMut(OutT) + Send,
+{
+    let sink = Mutex::new(sink);
+    let mut parser = structural::make_classifier_cps(MakeParallelStreamingFromClassifierCps { f: &f, sink: &sink, order, config, phantom: std::marker::PhantomData });
+    parser.process_streaming(reader)
+}
+
+/// Calls `f` on every top-level sexp of `reader`, in parallel, collecting
+/// the results in the given order.
+pub fn par_map<ReadT, OutT, F>(reader: &mut ReadT, order: Order, f: F) -> Result<Vec<OutT>, parser::Error>
+where
+    ReadT: BufRead + Send,
+    OutT: Send,
+    F: Fn(SexpRef) -> Option<OutT> + Sync,
+{
+    let mut outputs = Vec::new();
+    par_map_with_sink(reader, order, f, |output| outputs.push(output))?;
+    Ok(outputs)
+}
+
+/// Calls `f` on every top-level sexp of `reader`, in parallel and in no
+/// particular order. For side effects in order, use `par_map_with_sink`.
+pub fn par_for_each<ReadT, F>(reader: &mut ReadT, f: F) -> Result<(), parser::Error>
+where
+    ReadT: BufRead + Send,
+    F: Fn(SexpRef) + Sync,
+{
+    par_map_with_sink(reader, Order::Unordered, |sexp| { f(sexp); None::<()> }, |()| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn input() -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..100000 {
+            input.extend_from_slice(format!("((id {})\n (name \"x {}\"))\n", i, i).as_bytes());
+        }
+        input
+    }
+
+    fn id(sexp: SexpRef) -> Option<i64> {
+        std::str::from_utf8(sexp.field(b"id")?.atom()?).ok()?.parse().ok()
+    }
+
+    #[test]
+    fn test_ordered() {
+        let input = input();
+        let ids = par_map(&mut &input[..], Order::Ordered, id).unwrap();
+        assert_eq!(ids, (0..100000).collect::<Vec<i64>>());
+
+        let even = par_map(&mut &input[..], Order::Ordered, |sexp| id(sexp).filter(|id| id % 2 == 0)).unwrap();
+        assert_eq!(even, (0..100000).step_by(2).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn test_unordered() {
+        let input = input();
+        let mut names = par_map(&mut &input[..], Order::Unordered, |sexp| Some(sexp.field(b"name")?.atom()?.to_vec())).unwrap();
+        names.sort();
+        let mut expected: Vec<_> = (0..100000).map(|i| format!("x {}", i).into_bytes()).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+
+        let count = AtomicUsize::new(0);
+        par_for_each(&mut &input[..], |sexp| if sexp.is_list() { count.fetch_add(1, Ordering::Relaxed); }).unwrap();
+        assert_eq!(count.into_inner(), 100000);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        // `f` runs on both workers at once, even when unordered.
+        let input = input();
+        let config = parser_parallel::ParallelConfig::default().with_num_threads(4).with_chunk_size(10000);
+        let (running, max_running) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let mut count = 0;
+        par_map_with_sink_and_config(&mut &input[..], Order::Unordered, |sexp| {
+            let id = id(sexp)?;
+            if id % 1000 == 0 {
+                // Waits a while for a call on the other worker.
+                max_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                let start = std::time::Instant::now();
+                while max_running.load(Ordering::SeqCst) < 2 && start.elapsed().as_millis() < 1000 {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                    max_running.fetch_max(running.load(Ordering::SeqCst), Ordering::SeqCst);
+                }
+                running.fetch_sub(1, Ordering::SeqCst);
+            }
+            Some(id)
+        }, |_| count += 1, config).unwrap();
+        assert_eq!(count, 100000);
+        assert_eq!(max_running.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_error() {
+        let mut input = input();
+        input.extend_from_slice(b"((id 1)))\n");
+        input.extend_from_slice(&input.clone()[..]);
+        for order in [Order::Ordered, Order::Unordered] {
+            assert_eq!(par_map(&mut &input[..], order, id), Err(parser::Error::UnmatchedCloseParen));
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{escape, extract, structural};
+use crate::progress::Progress;
 use crate::utils::*;
 use crate::visitor::*;
 use std::io::{BufRead, Write};
+use std::sync::Arc;
 
 #[derive(Copy, Clone)]
 pub struct Input<'a> {
@@ -167,13 +169,64 @@ pub struct State<ClassifierT, Stage2T> {
     input: Vec<u8>,
     input_start_index: usize,
     input_index_to_keep: usize,
+    progress: Option<ProgressState>,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct ProgressState {
+    progress: Arc<dyn Progress>,
+    /// The input reported so far, and the depth at which it ends.
+    input_index: usize,
+    depth: usize,
+}
+
+impl ProgressState {
+    /// Reports the input up to `input_end`, and the top-level sexps that end
+    /// at `indices` (counted like `validate::Stage2`).
+    fn report(&mut self, input: Input, indices: &[usize], input_end: usize) {
+        let input_end = std::cmp::min(input_end, input.offset + input.input.len());
+        self.progress.bytes_consumed(input_end - self.input_index);
+        self.input_index = input_end;
+
+        let mut num_records = 0;
+        for &index in indices {
+            match input.input[index - input.offset] {
+                b'(' => self.depth += 1,
+                b')' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    if self.depth == 0 {
+                        num_records += 1;
+                    }
+                },
+                b' ' | b'\t' | b'\n' => (),
+                _ => if self.depth == 0 { num_records += 1 },
+            }
+        }
+        self.progress.records(num_records);
+    }
+}
+
+/// New kinds of input and tools add variants, so matches on this need a
+/// wildcard arm.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum Error {
     UnmatchedOpenParen,
     UnmatchedCloseParen,
     BadQuotedAtom,
+    UnterminatedComment,
+    InvalidJson,
+    InvalidCsexp,
+    InvalidCbor,
+    InvalidMsgpack,
+    InvalidPredicate,
+    InvalidKeyPattern,
+    InvalidPath,
+    DuplicateKey,
+    MissingKey,
+    NotANumber,
+    /// A value that does not fit the type inferred for its column. `row`
+    /// counts the selected rows from 0.
+    ColumnTypeMismatch { column: String, row: usize, value: String },
     IOError(std::io::ErrorKind),
 }
 
@@ -183,6 +236,20 @@ impl std::fmt::Display for Error {
             Error::UnmatchedOpenParen => { write!(f, "Unmatched open paren") }
             Error::UnmatchedCloseParen => { write!(f, "Unmatched close paren") }
             Error::BadQuotedAtom => { write!(f, "Bad quoted atom") }
+            Error::UnterminatedComment => { write!(f, "Unterminated comment") }
+            Error::InvalidJson => { write!(f, "Invalid JSON") }
+            Error::InvalidCsexp => { write!(f, "Invalid canonical sexp") }
+            Error::InvalidCbor => { write!(f, "Invalid CBOR") }
+            Error::InvalidMsgpack => { write!(f, "Invalid MessagePack") }
+            Error::InvalidPredicate => { write!(f, "Invalid filter predicate") }
+            Error::InvalidKeyPattern => { write!(f, "Invalid key pattern") }
+            Error::InvalidPath => { write!(f, "Invalid selector path") }
+            Error::DuplicateKey => { write!(f, "Duplicate key in record") }
+            Error::MissingKey => { write!(f, "Missing key in record") }
+            Error::NotANumber => { write!(f, "Expected a number") }
+            Error::ColumnTypeMismatch { column, row, value } => {
+                write!(f, "Value {} in row {} does not fit the type inferred for column {}", value, row, column)
+            }
             Error::IOError(e) => { write!(f, "IO error: {}", e) }
         }
     }
@@ -207,9 +274,16 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> State<ClassifierT, St
             input: Vec::new(),
             input_start_index: 0,
             input_index_to_keep: 0,
+            progress: None,
         }
     }
 
+    /// Reports the bytes consumed and the top-level sexps seen to `progress`.
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(ProgressState { progress, input_index: 0, depth: 0 });
+        self
+    }
+
     pub fn reset(&mut self, input_size_hint: Option<usize>) {
         self.stage2.reset(input_size_hint);
         self.input_index = 0;
@@ -217,6 +291,10 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> State<ClassifierT, St
         self.input.clear();
         self.input_start_index = 0;
         self.input_index_to_keep = 0;
+        if let Some(progress) = &mut self.progress {
+            progress.input_index = 0;
+            progress.depth = 0;
+        }
     }
 
     pub fn process_partial(&mut self, new_input: &[u8]) -> Result<(), Error> {
@@ -250,6 +328,11 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> State<ClassifierT, St
                 debug_assert!(self.input_index_to_keep <= self.indices_buffer[indices_index + 1]);
             }
 
+            if let Some(progress) = &mut self.progress {
+                let input = Input { input: &self.input[..], offset: self.input_start_index };
+                progress.report(input, &self.indices_buffer[..self.indices_len.saturating_sub(1)], self.input_index);
+            }
+
             if self.indices_len > 0 {
                 self.indices_buffer[0] = self.indices_buffer[self.indices_len - 1];
                 self.indices_len = 1;
@@ -270,6 +353,31 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> State<ClassifierT, St
         Ok(())
     }
 
+    /// `process_partial` holds back the last structural character until it
+    /// sees the next one. A close paren does without it, so this processes
+    /// one there, and a top-level list that has just been closed is complete.
+    /// Only for `Stage2`s that ignore `next_index` at parens, like
+    /// `VisitorState`.
+    pub fn flush_partial(&mut self) -> Result<(), Error> {
+        if self.indices_len == 1 {
+            let index = self.indices_buffer[0];
+            if self.input[index - self.input_start_index] == b')' {
+                self.input_index_to_keep =
+                    self.stage2.process_one(
+                        Input { input: &self.input[..], offset: self.input_start_index },
+                        index,
+                        index + 1,
+                        false)?;
+                if let Some(progress) = &mut self.progress {
+                    let input = Input { input: &self.input[..], offset: self.input_start_index };
+                    progress.report(input, &self.indices_buffer[..1], self.input_index);
+                }
+                self.indices_len = 0;
+            }
+        }
+        Ok(())
+    }
+
     // TODO: I think I want this function to consume self, but that plays
     // weirdly with Box<dyn Parser>
     pub fn process_eof(&mut self) -> Result<Stage2T::Return, Error> {
@@ -280,6 +388,10 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> State<ClassifierT, St
                 self.indices_buffer[self.indices_len - 1],
                 self.input.len() + self.input_start_index,
                 true)?;
+            if let Some(progress) = &mut self.progress {
+                let input = Input { input: &self.input[..], offset: self.input_start_index };
+                progress.report(input, &self.indices_buffer[..1], self.input_start_index + self.input.len());
+            }
         }
         self.stage2.process_eof()
     }
@@ -325,9 +437,16 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> State<ClassifierT, St
                 self.stage2.process_one(Input { input, offset: 0 }, self.indices_buffer[indices_index], self.indices_buffer[indices_index + 1], false)?;
             }
 
+            if let Some(progress) = &mut self.progress {
+                progress.report(Input { input, offset: 0 }, &self.indices_buffer[..self.indices_len.saturating_sub(1)], self.input_index);
+            }
+
             if self.input_index >= input.len() {
                 if self.indices_len > 0 {
                     self.stage2.process_one(Input { input, offset: 0 }, self.indices_buffer[self.indices_len - 1], input.len(), true)?;
+                    if let Some(progress) = &mut self.progress {
+                        progress.report(Input { input, offset: 0 }, &self.indices_buffer[self.indices_len - 1..self.indices_len], input.len());
+                    }
                 }
                 return self.stage2.process_eof();
             }
@@ -352,6 +471,12 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2> Parse for State<Class
 
 pub trait ParsePartial: Parse + ExtractPartialResult {
     fn process_partial(&mut self, input: &[u8]) -> Result<(), Error>;
+    /// Processes what `process_partial` has held back, where that can be done
+    /// before more input arrives, so that `extract_partial_result` has every
+    /// complete sexp so far.
+    fn flush_partial(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
     fn process_eof(&mut self) -> Result<Self::Return, Error>;
 }
 
@@ -359,6 +484,9 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2 + ExtractPartialResult
     fn process_partial(&mut self, input: &[u8]) -> Result<(), Error> {
         self.process_partial(input)
     }
+    fn flush_partial(&mut self) -> Result<(), Error> {
+        self.flush_partial()
+    }
     fn process_eof(&mut self) -> Result<Self::Return, Error> {
         self.process_eof()
     }
@@ -367,6 +495,9 @@ impl<ClassifierT: structural::Classifier, Stage2T: Stage2 + ExtractPartialResult
 pub trait Stream<BufReadT> {
     type Return;
     fn process_streaming(&mut self, buf_reader: &mut BufReadT) -> Result<Self::Return, Error>;
+    /// Processes input that is already in memory (e.g. a memory-mapped file)
+    /// without copying it.
+    fn process_slice(&mut self, input: &[u8]) -> Result<Self::Return, Error>;
 }
 
 impl<BufReadT: BufRead, ClassifierT: structural::Classifier, Stage2T: Stage2> Stream<BufReadT> for State<ClassifierT, Stage2T> {
@@ -374,6 +505,9 @@ impl<BufReadT: BufRead, ClassifierT: structural::Classifier, Stage2T: Stage2> St
     fn process_streaming(&mut self, buf_reader: &mut BufReadT) -> Result<Self::Return, Error> {
         self.process_streaming(buf_reader)
     }
+    fn process_slice(&mut self, input: &[u8]) -> Result<Self::Return, Error> {
+        self.process_all(input)
+    }
 }
 
 struct MakeParserFromClassifierCps<Stage2T> {
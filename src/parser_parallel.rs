@@ -1,14 +1,76 @@
 use crate::parser::{self, Parse};
+use crate::progress::Progress;
+use crate::rust_parser::Tape;
 use crate::structural;
+use crate::visitor::Visitor;
 use std::io::{BufRead, Write};
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 pub type Error = parser::Error;
 pub type Input<'a> = parser::Input<'a>;
 
-pub struct WorkUnit {
+/// A chunk of input: either a copy, when reading from a `BufRead`, or a
+/// sub-slice of input that is already in memory.
+pub struct WorkUnit<BufferT = Vec<u8>> {
     index: usize,
-    buffer: Vec<u8>,
+    buffer: BufferT,
+}
+
+/// A bounded pool of byte buffers, so that chunks can reuse the buffers of
+/// earlier chunks rather than allocating their own. Buffers put back while
+/// the pool is full are dropped.
+pub struct BufferPool {
+    free_send: crossbeam_channel::Sender<Vec<u8>>,
+    free_recv: crossbeam_channel::Receiver<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        let (free_send, free_recv) = crossbeam_channel::bounded(std::cmp::max(1, capacity));
+        Self { free_send, free_recv }
+    }
+
+    /// An empty buffer, which keeps the capacity of a pooled one if there is
+    /// one.
+    pub fn get(&self) -> Vec<u8> {
+        self.free_recv.try_recv().unwrap_or_default()
+    }
+
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > 0 {
+            buffer.clear();
+            let _ = self.free_send.try_send(buffer);
+        }
+    }
+
+    /// The number of buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.free_recv.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free_recv.is_empty()
+    }
+}
+
+/// Chunks copied into buffers from the `BufferPool` go back to it once
+/// parsed.
+trait ChunkBuffer: AsRef<[u8]> {
+    fn recycle(self, pool: &BufferPool);
+}
+
+impl ChunkBuffer for Vec<u8> {
+    fn recycle(self, pool: &BufferPool) {
+        pool.put(self)
+    }
+}
+
+impl ChunkBuffer for &[u8] {
+    fn recycle(self, _pool: &BufferPool) {
+    }
 }
 
 /// A `Joiner` is a thing that knows how to spawn some `Worker`s that process
@@ -34,12 +96,21 @@ pub trait Joiner {
 pub struct WritingStage2Adapter<WritingStage2> {
     buffer: Vec<u8>,
     writing_stage2: WritingStage2,
+    buffer_pool: Option<Arc<BufferPool>>,
 }
 
 impl<WritingStage2T: parser::WritingStage2> WritingStage2Adapter<WritingStage2T> {
-    fn new(writing_stage2: WritingStage2T) -> Self {
+    pub fn new(writing_stage2: WritingStage2T) -> Self {
         let buffer = Vec::new();
-        Self { buffer, writing_stage2 }
+        Self { buffer, writing_stage2, buffer_pool: None }
+    }
+
+    /// Takes the buffer for each chunk from `buffer_pool`, which the
+    /// `WritingJoiner` of `streaming_from_pooled_worker` returns them to.
+    pub fn with_buffer_pool(mut self, buffer_pool: Arc<BufferPool>) -> Self {
+        self.buffer = buffer_pool.get();
+        self.buffer_pool = Some(buffer_pool);
+        self
     }
 }
 
@@ -58,23 +129,27 @@ impl<WritingStage2T: parser::WritingStage2> parser::Stage2 for WritingStage2Adap
     #[inline]
     fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
         let () = self.writing_stage2.process_eof(&mut self.buffer)?;
-        Ok(std::mem::take(&mut self.buffer))
+        let next_buffer = self.buffer_pool.as_ref().map_or_else(Vec::new, |buffer_pool| buffer_pool.get());
+        Ok(std::mem::replace(&mut self.buffer, next_buffer))
     }
 }
 
 /// A canonical implementation of a `Joiner` which is parameterised across all
 /// `Worker`s that return a `Vec<u8>`. This writes the resulting byte chunks to
-/// the given writer in the correct order.
+/// the given writer in the correct order, then puts them back in its
+/// `BufferPool`.
 pub struct WritingJoiner<'a, WriteT, WorkerT> {
     create_writing_worker: Box<dyn Fn() -> WorkerT + 'a>,
     writer: &'a mut WriteT,
+    buffer_pool: Arc<BufferPool>,
 }
 
 impl<'a, WriteT: Write, WorkerT: Parse<Return = Vec<u8>>> WritingJoiner<'a, WriteT, WorkerT> {
-    fn new(create_writing_worker: Box<dyn Fn() -> WorkerT + 'a>, writer: &'a mut WriteT) -> Self {
+    fn new(create_writing_worker: Box<dyn Fn() -> WorkerT + 'a>, writer: &'a mut WriteT, buffer_pool: Arc<BufferPool>) -> Self {
         Self {
             create_writing_worker,
             writer,
+            buffer_pool,
         }
     }
 }
@@ -90,29 +165,230 @@ impl<'a, WriteT: Write, WorkerT: Parse<Return = Vec<u8>>> Joiner for WritingJoin
     fn join(&mut self, result: <Self::Worker as Parse>::Return) -> Result<(), Error> {
         self.writer.write_all(&result[..]).map_err(|e| {
             Error::IOError(e.kind())
-        })
+        })?;
+        self.buffer_pool.put(result);
+        Ok(())
     }
     fn process_eof(&mut self) -> Result<Self::Return, Error> {
         Ok(())
     }
 }
 
+/// A `Joiner` for `Worker`s that parse chunks into tapes, which it appends
+/// into a single tape.
+pub struct TapeJoiner<'a, WorkerT: Parse> {
+    create_worker: Box<dyn Fn() -> WorkerT + 'a>,
+    tape: WorkerT::Return,
+}
+
+impl<'a, TapeT: Tape, WorkerT: Parse<Return = TapeT>> TapeJoiner<'a, WorkerT> {
+    pub fn new(create_worker: Box<dyn Fn() -> WorkerT + 'a>) -> Self {
+        Self {
+            create_worker,
+            tape: TapeT::default(),
+        }
+    }
+}
+
+impl<'a, TapeT: Tape, WorkerT: Parse<Return = TapeT>> Joiner for TapeJoiner<'a, WorkerT> {
+    type Worker = WorkerT;
+    type Return = TapeT;
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.tape = TapeT::default();
+    }
+    fn create_worker(&mut self) -> Self::Worker {
+        (self.create_worker)()
+    }
+    fn join(&mut self, result: TapeT) -> Result<(), Error> {
+        self.tape.append(result);
+        Ok(())
+    }
+    fn process_eof(&mut self) -> Result<TapeT, Error> {
+        Ok(std::mem::take(&mut self.tape))
+    }
+}
+
 // Start of main parallel parser implementation
 
+/// The chunk size used when neither the caller nor the environment picks one.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// With adaptive chunk sizes, chunks are sized to take about this long to
+/// process, within `MIN_ADAPTIVE_CHUNK_SIZE..=MAX_ADAPTIVE_CHUNK_SIZE`.
+const ADAPTIVE_CHUNK_TIME: Duration = Duration::from_millis(10);
+const MIN_ADAPTIVE_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_ADAPTIVE_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Keeps the default from taking over very large machines unasked; more threads
+/// can still be asked for explicitly.
+pub const MAX_DEFAULT_NUM_THREADS: usize = 64;
+
+/// How a parallel parser splits up its work. `ParallelConfig::default()`
+/// honours the `SIMD_SEXP_NUM_THREADS`, `SIMD_SEXP_CHUNK_SIZE`,
+/// `SIMD_SEXP_ADAPTIVE_CHUNK_SIZE` (0 or 1) and `SIMD_SEXP_LOOKAHEAD`
+/// environment variables.
+#[derive(Clone, Debug)]
+pub struct ParallelConfig {
+    /// The total number of threads, including one for input and one for
+    /// output, so at least three.
+    pub num_threads: usize,
+
+    /// The minimum size of a chunk. The first valid break point (new line)
+    /// after this point will form the end of the chunk. `None` leaves it to
+    /// the caller.
+    pub chunk_size: Option<usize>,
+
+    /// Resize chunks as we go, starting from `chunk_size`, so that each takes
+    /// about `ADAPTIVE_CHUNK_TIME` to process.
+    pub adaptive_chunk_size: bool,
+
+    /// The number of chunks per worker thread that may be in flight ahead of
+    /// the last fully-joined chunk.
+    pub lookahead: usize,
+
+    /// Bytes are reported as chunks are joined, and records by the workers
+    /// (if they are built from a `Stage2` or `Visitor` here).
+    pub progress: Option<Arc<dyn Progress>>,
+}
+
+impl ParallelConfig {
+    /// One thread per physical core, plus one, between 3 and
+    /// `MAX_DEFAULT_NUM_THREADS`.
+    pub fn default_num_threads() -> usize {
+        let desired_num_threads = num_cpus::get_physical() + 1; // + 1 because it's not uncommon for the output thread to be idle most of the time
+        std::cmp::min(std::cmp::max(desired_num_threads, 3), MAX_DEFAULT_NUM_THREADS)
+    }
+
+    pub fn with_num_threads(mut self, num_threads: usize) -> Self {
+        assert!(num_threads >= 3, "parser_parallel requires at least three threads: two for I/O and at least one worker thread.");
+        self.num_threads = num_threads;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunks must be at least one byte");
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Sets the chunk size unless it has been set already.
+    pub fn with_default_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size.get_or_insert(chunk_size);
+        self
+    }
+
+    pub fn with_adaptive_chunk_size(mut self, adaptive_chunk_size: bool) -> Self {
+        self.adaptive_chunk_size = adaptive_chunk_size;
+        self
+    }
+
+    pub fn with_lookahead(mut self, lookahead: usize) -> Self {
+        assert!(lookahead >= 1, "parser_parallel requires a lookahead of at least one chunk.");
+        self.lookahead = lookahead;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// The `Progress` for workers' `parser::State`s, which leave the bytes to
+    /// us.
+    fn worker_progress(&self) -> Option<Arc<dyn Progress>> {
+        self.progress.clone().map(|progress| Arc::new(RecordsOnly(progress)) as Arc<dyn Progress>)
+    }
+
+    fn num_worker_threads(&self) -> usize {
+        self.num_threads - 2
+    }
+
+    /// Enough buffers for every chunk that can be in flight at once.
+    fn buffer_pool_capacity(&self) -> usize {
+        self.num_worker_threads() * (self.lookahead + 1)
+    }
+}
+
+impl Default for ParallelConfig {
+    fn default() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+        Self {
+            num_threads: var("SIMD_SEXP_NUM_THREADS").map_or_else(Self::default_num_threads, |num_threads: usize| num_threads.max(3)),
+            chunk_size: var("SIMD_SEXP_CHUNK_SIZE").filter(|&chunk_size| chunk_size > 0),
+            adaptive_chunk_size: var("SIMD_SEXP_ADAPTIVE_CHUNK_SIZE") == Some(1),
+            lookahead: var("SIMD_SEXP_LOOKAHEAD").filter(|&lookahead| lookahead > 0).unwrap_or(10),
+            progress: None,
+        }
+    }
+}
+
+struct RecordsOnly(Arc<dyn Progress>);
+
+impl Progress for RecordsOnly {
+    fn records(&self, count: usize) {
+        self.0.records(count)
+    }
+}
+
+fn with_worker_progress<ClassifierT: structural::Classifier, Stage2T: parser::Stage2>
+    (parser: parser::State<ClassifierT, Stage2T>, progress: &Option<Arc<dyn Progress>>)
+    -> parser::State<ClassifierT, Stage2T>
+{
+    match progress {
+        None => parser,
+        Some(progress) => parser.with_progress(progress.clone()),
+    }
+}
+
+/// A running average of how long the workers take per byte of input, for
+/// adaptive chunk sizes.
+struct ChunkCost {
+    picos_per_byte: AtomicU64,
+}
+
+impl ChunkCost {
+    fn new() -> Self {
+        Self { picos_per_byte: AtomicU64::new(0) }
+    }
+
+    /// Races between workers may lose a sample, which doesn't matter.
+    fn record(&self, len: usize, elapsed: Duration) {
+        if len == 0 {
+            return;
+        }
+        let sample = std::cmp::max(1, (elapsed.as_nanos() * 1000 / len as u128) as u64);
+        let average = match self.picos_per_byte.load(Ordering::Relaxed) {
+            0 => sample,
+            average => average - average / 8 + sample / 8,
+        };
+        self.picos_per_byte.store(average, Ordering::Relaxed);
+    }
+
+    fn chunk_size(&self, initial_chunk_size: usize) -> usize {
+        match self.picos_per_byte.load(Ordering::Relaxed) {
+            0 => initial_chunk_size,
+            picos_per_byte => {
+                let chunk_size = (ADAPTIVE_CHUNK_TIME.as_nanos() * 1000 / picos_per_byte as u128) as usize;
+                chunk_size.clamp(MIN_ADAPTIVE_CHUNK_SIZE, MAX_ADAPTIVE_CHUNK_SIZE)
+            },
+        }
+    }
+}
+
 struct WorkResult<ResultT> {
     index: usize,
+    len: usize,
     result: ResultT,
 }
 
 
 pub struct State<JoinerT> {
     joiner: JoinerT,
-
-    /// The minimum size of a chunk. The first valid break point (new line) after
-    /// this point will form the end of the chunk.
-    chunk_size: usize,
-
-    num_threads: usize,
+    config: ParallelConfig,
+    /// For chunks copied from a `BufRead`, kept between inputs.
+    buffer_pool: BufferPool,
 }
 
 impl<JoinerT: Joiner> State<JoinerT>
@@ -120,59 +396,65 @@ where
     JoinerT::Worker : Send,
     <JoinerT::Worker as Parse>::Return : Send
 {
-    pub fn with_num_threads(joiner: JoinerT, chunk_size: usize, num_threads: usize) -> Self {
-        assert!(num_threads >= 3, "parser_parallel requires at least three threads: two for I/O and at least one worker thread.");
+    pub fn new(joiner: JoinerT, config: ParallelConfig) -> Self {
+        assert!(config.num_threads >= 3, "parser_parallel requires at least three threads: two for I/O and at least one worker thread.");
+        let buffer_pool = BufferPool::new(config.buffer_pool_capacity());
         State {
             joiner,
-            chunk_size,
-            num_threads,
+            config,
+            buffer_pool,
         }
     }
 
-    pub fn new(joiner: JoinerT, chunk_size: usize) -> Self {
-        let desired_num_threads = num_cpus::get_physical() + 1; // + 1 because it's not uncommon for the output thread to be idle most of the time
-        Self::with_num_threads(joiner, chunk_size, std::cmp::min(std::cmp::max(desired_num_threads, 3), 6))
-    }
-
-    fn num_worker_threads(&self) -> usize {
-        self.num_threads - 2
-    }
-
-    fn worker_thread(
+    /// Stops once the output thread has gone away, e.g. because of an error.
+    fn worker_thread<BufferT: ChunkBuffer>(
         mut parser: JoinerT::Worker,
-        work_recv: &crossbeam_channel::Receiver<WorkUnit>,
-        results_send: crossbeam_channel::Sender<WorkResult<<JoinerT::Worker as Parse>::Return>>)
-        -> Result<(), Error>
+        work_recv: crossbeam_channel::Receiver<WorkUnit<BufferT>>,
+        results_send: crossbeam_channel::Sender<WorkResult<Result<<JoinerT::Worker as Parse>::Return, Error>>>,
+        buffer_pool: &BufferPool,
+        chunk_cost: Option<&ChunkCost>,
+        progress: Option<(usize, &dyn Progress)>)
     {
         #[cfg(feature = "vtune")] let domain = ittapi::Domain::new(std::thread::current().name().unwrap());
 
-        loop {
-            match work_recv.recv() {
-                Ok(work_unit) => {
-                    #[cfg(feature = "vtune")] let task = ittapi::Task::begin(&domain, "work_unit");
-                    let result = parser.process(&work_unit.buffer[..])?;
-                    #[cfg(feature = "vtune")] task.end();
-                    results_send.send(WorkResult{ index: work_unit.index, result }).map_err(|_| ()).unwrap();
-                },
-                Err(crossbeam_channel::RecvError) => { return Ok(()); }
+        while let Ok(work_unit) = work_recv.recv() {
+            #[cfg(feature = "vtune")] let task = ittapi::Task::begin(&domain, "work_unit");
+            let len = work_unit.buffer.as_ref().len();
+            let start = Instant::now();
+            let result = parser.process(work_unit.buffer.as_ref());
+            if let Some(chunk_cost) = chunk_cost {
+                chunk_cost.record(len, start.elapsed());
+            }
+            if let Some((thread_index, progress)) = progress {
+                progress.worker_busy(thread_index, start.elapsed());
+            }
+            work_unit.buffer.recycle(buffer_pool);
+            #[cfg(feature = "vtune")] task.end();
+            if results_send.send(WorkResult{ index: work_unit.index, len, result }).is_err() {
+                return;
             }
         }
     }
 
+    /// Stops early, without error, once the workers have gone away.
     fn input_thread<BufReadT : std::io::BufRead>(
         work_send: crossbeam_channel::Sender<WorkUnit>,
         buf_reader: &mut BufReadT,
-        chunk_size: usize)
+        buffer_pool: &BufferPool,
+        chunk_size: usize,
+        chunk_cost: Option<&ChunkCost>)
         -> Result<(), Error>
     {
         #[cfg(feature = "vtune")] let domain = ittapi::Domain::new("input");
 
-        let mut next_work_unit = Vec::new();
+        let mut next_work_unit = buffer_pool.get();
         let mut next_work_unit_index = 0;
 
         loop {
             #[cfg(feature = "vtune")] let task = ittapi::Task::begin(&domain, "handle_input");
 
+            let chunk_size = chunk_cost.map_or(chunk_size, |chunk_cost| chunk_cost.chunk_size(chunk_size));
+
             let mut work_unit_to_dispatch = None;
             let mut just_reached_eof = false;
             while work_unit_to_dispatch.is_none() {
@@ -196,7 +478,7 @@ where
                         match split_index {
                             Some(split_index) => {
                                 next_work_unit.extend_from_slice(&buffer[..split_index]);
-                                work_unit_to_dispatch = Some(std::mem::take(&mut next_work_unit));
+                                work_unit_to_dispatch = Some(std::mem::replace(&mut next_work_unit, buffer_pool.get()));
                                 next_work_unit.reserve(chunk_size + 16 * 1024);
                                 next_work_unit.extend_from_slice(&buffer[split_index..]);
                             },
@@ -214,7 +496,9 @@ where
 
             let work_unit_to_dispatch = work_unit_to_dispatch.unwrap();
             let work_unit_index = next_work_unit_index;
-            work_send.send(WorkUnit { index: work_unit_index, buffer: work_unit_to_dispatch }).unwrap();
+            if work_send.send(WorkUnit { index: work_unit_index, buffer: work_unit_to_dispatch }).is_err() {
+                return Ok(());
+            }
 
             next_work_unit_index += 1;
 
@@ -224,16 +508,52 @@ where
         }
     }
 
-    fn output_thread(
+    /// Like `input_thread`, but hands out sub-slices of `input` instead of
+    /// copying it.
+    fn slice_input_thread<'b>(
+        work_send: crossbeam_channel::Sender<WorkUnit<&'b [u8]>>,
+        input: &'b [u8],
+        chunk_size: usize,
+        chunk_cost: Option<&ChunkCost>)
+        -> Result<(), Error>
+    {
+        let mut start: usize = 0;
+        let mut work_unit_index = 0;
+
+        loop {
+            let chunk_size = chunk_cost.map_or(chunk_size, |chunk_cost| chunk_cost.chunk_size(chunk_size));
+            // Past the line break that this chunk starts with, so that every
+            // chunk is at least one byte.
+            let offset = std::cmp::min(start.saturating_add(std::cmp::max(chunk_size, 1)), input.len());
+            let end =
+                memchr::memchr_iter(b'\n', &input[offset..])
+                .map(|x| x + offset)
+                .find(|split_index| { split_index + 1 < input.len() && input[split_index + 1] != b' ' })
+                .unwrap_or(input.len());
+
+            if work_send.send(WorkUnit { index: work_unit_index, buffer: &input[start..end] }).is_err() || end == input.len() {
+                return Ok(());
+            }
+
+            start = end;
+            work_unit_index += 1;
+        }
+    }
+
+    /// Joins results in order, stopping at the first error. `progress` comes
+    /// with a handle on the work queue, for its length.
+    fn output_thread<BufferT>(
         joiner: &mut JoinerT,
-        results_recv: crossbeam_channel::Receiver<WorkResult<<JoinerT::Worker as Parse>::Return>>,
-        lookahead_num_chunks: usize)
+        results_recv: crossbeam_channel::Receiver<WorkResult<Result<<JoinerT::Worker as Parse>::Return, Error>>>,
+        lookahead_num_chunks: usize,
+        progress: Option<(&dyn Progress, crossbeam_channel::Receiver<WorkUnit<BufferT>>)>)
         -> Result<(), Error>
     {
         #[cfg(feature = "vtune")] let domain = ittapi::Domain::new("output");
 
-        let mut output_queue: VecDeque<Option<<JoinerT::Worker as Parse>::Return>> = VecDeque::with_capacity(lookahead_num_chunks);
+        let mut output_queue = VecDeque::with_capacity(lookahead_num_chunks);
         let mut output_queue_start_index = 0;
+        let mut output_queue_num_results = 0;
 
         loop {
             match results_recv.recv() {
@@ -246,48 +566,88 @@ where
                         output_queue.resize_with(rel_index + 1, || None);
                     }
                     debug_assert!(output_queue[rel_index].is_none());
-                    output_queue[rel_index] = Some(result.result);
+                    output_queue[rel_index] = Some(result);
+                    output_queue_num_results += 1;
                     while let Some(Some(_)) = output_queue.front() {
                         #[cfg(feature = "vtune")] let task = ittapi::Task::begin(&domain, "handle_output");
                         let in_order_result = output_queue.pop_front().unwrap().unwrap();
                         output_queue_start_index += 1;
-                        joiner.join(in_order_result)?;
+                        output_queue_num_results -= 1;
+                        joiner.join(in_order_result.result?)?;
+                        if let Some((progress, _)) = &progress {
+                            progress.bytes_consumed(in_order_result.len);
+                        }
                         #[cfg(feature = "vtune")] task.end();
                     }
+                    if let Some((progress, work_recv)) = &progress {
+                        progress.queue_occupancy(work_recv.len(), output_queue_num_results + results_recv.len());
+                    }
                 },
             }
         }
     }
 
-    pub fn process_streaming<'a, BufReadT : std::io::BufRead + Send>(&'a mut self, buf_reader: &mut BufReadT) -> Result<JoinerT::Return, Error> {
-        self.joiner.reset(None);
+    pub fn process_streaming<BufReadT : std::io::BufRead + Send>(&mut self, buf_reader: &mut BufReadT) -> Result<JoinerT::Return, Error> {
+        self.process_chunks(None, |work_send, buffer_pool, chunk_size, chunk_cost| {
+            Self::input_thread(work_send, buf_reader, buffer_pool, chunk_size, chunk_cost)
+        })
+    }
+
+    /// Like `process_streaming`, but the workers parse sub-slices of `input`
+    /// in place, rather than copies of it.
+    pub fn process_slice(&mut self, input: &[u8]) -> Result<JoinerT::Return, Error> {
+        self.process_chunks(Some(input.len()), |work_send, _buffer_pool, chunk_size, chunk_cost| {
+            Self::slice_input_thread(work_send, input, chunk_size, chunk_cost)
+        })
+    }
+
+    /// Runs `input_thread` alongside the worker and output threads.
+    fn process_chunks<BufferT, InputThreadF>(&mut self, input_size_hint: Option<usize>, input_thread: InputThreadF) -> Result<JoinerT::Return, Error>
+    where
+        BufferT: ChunkBuffer + Send,
+        InputThreadF: FnOnce(crossbeam_channel::Sender<WorkUnit<BufferT>>, &BufferPool, usize, Option<&ChunkCost>) -> Result<(), Error> + Send,
+    {
+        self.joiner.reset(input_size_hint);
 
         // The maximum number of chunks ahead of the last fully-joined (in order) chunk
         // that we're willing to start processing of.
-        let lookahead_num_chunks = self.num_worker_threads() * 10;
+        let lookahead_num_chunks = self.config.num_worker_threads() * self.config.lookahead;
+        let chunk_size = self.config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        let chunk_cost = ChunkCost::new();
+        let chunk_cost = if self.config.adaptive_chunk_size { Some(&chunk_cost) } else { None };
+        let progress = self.config.progress.clone();
+        let progress = progress.as_deref();
+        let buffer_pool = &self.buffer_pool;
 
         let (work_send, work_recv) = crossbeam_channel::bounded(lookahead_num_chunks);
         let (results_send, results_recv) = crossbeam_channel::bounded(lookahead_num_chunks);
 
         let threads_result = crossbeam_utils::thread::scope(|scope| {
-            scope.builder().name("input".to_owned()).spawn(|_| {
-                Self::input_thread(work_send, buf_reader, self.chunk_size).unwrap();
+            let input_thread = scope.builder().name("input".to_owned()).spawn(move |_| {
+                input_thread(work_send, buffer_pool, chunk_size, chunk_cost)
             }).unwrap();
 
-            for thread_index in 0..self.num_worker_threads() {
+            for thread_index in 0..self.config.num_worker_threads() {
                 let worker = self.joiner.create_worker();
+                let work_recv = work_recv.clone();
                 let results_send = results_send.clone();
-                scope.builder().name(format!("worker #{}", thread_index + 1)).spawn(|_| {
-                    // TODO: figure out a better error handling story
-                    Self::worker_thread(worker, &work_recv, results_send).unwrap()
+                scope.builder().name(format!("worker #{}", thread_index + 1)).spawn(move |_| {
+                    Self::worker_thread(worker, work_recv, results_send, buffer_pool, chunk_cost, progress.map(|progress| (thread_index, progress)))
                 }).unwrap();
             }
+            // The output thread drops its handle on the work queue as it
+            // stops, so that the input thread does not wait forever.
+            let output_progress = progress.map(|progress| (progress, work_recv.clone()));
+            std::mem::drop(work_recv);
             std::mem::drop(results_send);
 
-            Self::output_thread(&mut self.joiner, results_recv, lookahead_num_chunks).unwrap();
+            let output_result = Self::output_thread(&mut self.joiner, results_recv, lookahead_num_chunks, output_progress);
+            // An input error truncates the results, so it takes precedence.
+            let input_result = input_thread.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+            input_result.and(output_result)
         });
 
-        let () = threads_result.map_err(|e| std::panic::resume_unwind(e)).unwrap();
+        let () = threads_result.unwrap_or_else(|e| std::panic::resume_unwind(e))?;
 
         self.joiner.process_eof()
     }
@@ -300,42 +660,223 @@ where JoinerT::Worker : Send, <JoinerT::Worker as Parse>::Return : Send
     fn process_streaming(&mut self, buf_reader: &mut BufReadT) -> Result<Self::Return, Error> {
         State::process_streaming(self, buf_reader)
     }
+    fn process_slice(&mut self, input: &[u8]) -> Result<Self::Return, Error> {
+        State::process_slice(self, input)
+    }
 }
 
-pub fn streaming_new<'a, JoinerT: Joiner + 'a, BufReadT: BufRead + Send>(joiner: JoinerT, chunk_size: usize) -> Box<dyn parser::Stream<BufReadT, Return = JoinerT::Return> + 'a>
+pub fn streaming_new<'a, JoinerT: Joiner + 'a, BufReadT: BufRead + Send>(joiner: JoinerT, config: ParallelConfig) -> Box<dyn parser::Stream<BufReadT, Return = JoinerT::Return> + 'a>
 where
     JoinerT::Worker : Send,
     <JoinerT::Worker as Parse>::Return : Send
 {
-    Box::new(State::new(joiner, chunk_size))
+    Box::new(State::new(joiner, config))
 }
 
 pub fn streaming_from_worker<'a, WriteT: Write, WorkerT: Parse<Return = Vec<u8>> + Send + 'a, F: Fn() -> WorkerT + 'a, BufReadT: BufRead + Send>
-    (create_worker: F, writer: &'a mut WriteT, chunk_size: usize)
+    (create_worker: F, writer: &'a mut WriteT, config: ParallelConfig)
      -> Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>
 {
-    streaming_new(WritingJoiner::new(Box::new(create_worker), writer), chunk_size)
+    streaming_from_pooled_worker(move |_| create_worker(), writer, config)
+}
+
+/// Like `streaming_from_worker`, but the outputs are put back in the given
+/// `BufferPool` once written, for workers to take their next output buffer
+/// from (see `WritingStage2Adapter::with_buffer_pool`).
+pub fn streaming_from_pooled_worker<'a, WriteT: Write, WorkerT: Parse<Return = Vec<u8>> + Send + 'a, F: Fn(&Arc<BufferPool>) -> WorkerT + 'a, BufReadT: BufRead + Send>
+    (create_worker: F, writer: &'a mut WriteT, config: ParallelConfig)
+     -> Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>
+{
+    let buffer_pool = Arc::new(BufferPool::new(config.buffer_pool_capacity()));
+    let joiner_buffer_pool = buffer_pool.clone();
+    let create_worker = move || create_worker(&buffer_pool);
+    streaming_new(WritingJoiner::new(Box::new(create_worker), writer, joiner_buffer_pool), config)
 }
 
 struct MakeStreamingFromClassifierCps<'a, WriteT, F, BufReadT> {
     create_writing_stage2: F,
     writer: &'a mut WriteT,
-    chunk_size: usize,
+    config: ParallelConfig,
     phantom: std::marker::PhantomData<*const BufReadT>,
 }
 
 impl<'a, WriteT: Write, WritingStage2T: parser::WritingStage2 + Send + 'a, F: Fn() -> WritingStage2T + 'a, BufReadT: BufRead + Send> structural::MakeClassifierCps<'a> for MakeStreamingFromClassifierCps<'a, WriteT, F, BufReadT> {
     type Return = Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>;
     fn f<ClassifierT: structural::Classifier + 'a>(self: Self, classifier: ClassifierT) -> Self::Return {
-        streaming_from_worker(move || {
-            parser::State::new(classifier.clone(), WritingStage2Adapter::new((self.create_writing_stage2)()))
-        }, self.writer, self.chunk_size)
+        let progress = self.config.worker_progress();
+        let create_writing_stage2 = self.create_writing_stage2;
+        streaming_from_pooled_worker(move |buffer_pool| {
+            let writing_stage2 = WritingStage2Adapter::new(create_writing_stage2()).with_buffer_pool(buffer_pool.clone());
+            with_worker_progress(parser::State::new(classifier.clone(), writing_stage2), &progress)
+        }, self.writer, self.config)
     }
 }
 
 pub fn streaming_from_writing_stage2<'a, WriteT: Write, WritingStage2T: parser::WritingStage2 + Send + 'a, F: Fn() -> WritingStage2T + 'a, BufReadT: BufRead + Send>
-    (create_writing_stage2: F, writer: &'a mut WriteT, chunk_size: usize)
+    (create_writing_stage2: F, writer: &'a mut WriteT, config: ParallelConfig)
      -> Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>
 {
-    structural::make_classifier_cps(MakeStreamingFromClassifierCps { create_writing_stage2, writer, chunk_size, phantom: std::marker::PhantomData })
+    structural::make_classifier_cps(MakeStreamingFromClassifierCps { create_writing_stage2, writer, config, phantom: std::marker::PhantomData })
+}
+
+struct MakeStreamingFromVisitorCps<F, BufReadT> {
+    create_visitor: F,
+    config: ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+impl<'a, VisitorT, F, BufReadT> structural::MakeClassifierCps<'a> for MakeStreamingFromVisitorCps<F, BufReadT>
+where
+    VisitorT: Visitor + Send + 'a,
+    VisitorT::Context: Send,
+    VisitorT::Return: Tape + Send,
+    F: Fn() -> VisitorT + 'a,
+    BufReadT: BufRead + Send,
+{
+    type Return = Box<dyn parser::Stream<BufReadT, Return = VisitorT::Return> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        let create_visitor = self.create_visitor;
+        let progress = self.config.worker_progress();
+        let create_worker = move || {
+            with_worker_progress(parser::State::new(classifier.clone(), parser::VisitorState::new(create_visitor())), &progress)
+        };
+        streaming_new(TapeJoiner::new(Box::new(create_worker)), self.config)
+    }
+}
+
+/// Parses chunks into tapes (e.g. with `SplitTapeVisitor` or
+/// `SingleTapeVisitor`) in parallel, returning the same tape as
+/// `parser::streaming_from_visitor` would. For input that is already in
+/// memory, use `process_slice`.
+pub fn streaming_from_visitor<'a, VisitorT, F, BufReadT>(create_visitor: F, config: ParallelConfig) -> Box<dyn parser::Stream<BufReadT, Return = VisitorT::Return> + 'a>
+where
+    VisitorT: Visitor + Send + 'a,
+    VisitorT::Context: Send,
+    VisitorT::Return: Tape + Send,
+    F: Fn() -> VisitorT + 'a,
+    BufReadT: BufRead + Send,
+{
+    structural::make_classifier_cps(MakeStreamingFromVisitorCps { create_visitor, config, phantom: std::marker::PhantomData })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust_parser::{SingleTapeVisitor, SplitTapeVisitor};
+
+    fn input() -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..20000 {
+            input.extend_from_slice(format!("((id {})\n (name \"x\\n{}\") (tags (a bc def)))\n{}\n", i, i, i).as_bytes());
+        }
+        input
+    }
+
+    #[test]
+    fn test_split_tape() {
+        let input = input();
+        let expected = parser::parser_from_visitor(SplitTapeVisitor::new()).process(&input[..]).unwrap();
+        for chunk_size in [1000, 100000, 10000000] {
+            let mut parser = streaming_from_visitor(SplitTapeVisitor::new, ParallelConfig::default().with_chunk_size(chunk_size));
+            let tape = parser.process_streaming(&mut &input[..]).unwrap();
+            assert!(tape.tape == expected.tape && tape.atoms == expected.atoms, "{}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_slice() {
+        let input = input();
+        let expected = parser::parser_from_visitor(SplitTapeVisitor::new()).process(&input[..]).unwrap();
+        let configs = [
+            ParallelConfig { chunk_size: Some(0), ..ParallelConfig::default() },
+            ParallelConfig::default().with_chunk_size(1),
+            ParallelConfig::default().with_chunk_size(1000),
+            ParallelConfig::default().with_chunk_size(10000000),
+            ParallelConfig::default().with_chunk_size(1000).with_adaptive_chunk_size(true),
+        ];
+        for config in configs {
+            let mut parser: Box<dyn parser::Stream<&[u8], Return = _>> = streaming_from_visitor(SplitTapeVisitor::new, config.clone());
+            let tape = parser.process_slice(&input[..]).unwrap();
+            assert!(tape.tape == expected.tape && tape.atoms == expected.atoms, "{:?}", config);
+        }
+
+        let mut parser: Box<dyn parser::Stream<&[u8], Return = _>> = streaming_from_visitor(SplitTapeVisitor::new, ParallelConfig::default().with_chunk_size(1000));
+        assert!(parser.process_slice(b"").unwrap().tape.is_empty());
+        let mut unmatched = input.clone();
+        unmatched.extend_from_slice(b"(a\n");
+        assert_eq!(parser.process_slice(&unmatched[..]).err(), Some(Error::UnmatchedOpenParen));
+    }
+
+    #[test]
+    fn test_config() {
+        let input = input();
+        let expected = parser::parser_from_visitor(SplitTapeVisitor::new()).process(&input[..]).unwrap();
+        let configs = [
+            ParallelConfig::default().with_num_threads(3).with_lookahead(1).with_chunk_size(1000),
+            ParallelConfig::default().with_num_threads(16).with_chunk_size(1000),
+            ParallelConfig::default().with_chunk_size(1000).with_adaptive_chunk_size(true),
+        ];
+        for config in configs {
+            let mut parser = streaming_from_visitor(SplitTapeVisitor::new, config.clone());
+            let tape = parser.process_streaming(&mut &input[..]).unwrap();
+            assert!(tape.tape == expected.tape && tape.atoms == expected.atoms, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_adaptive_chunk_size() {
+        let chunk_cost = ChunkCost::new();
+        assert_eq!(chunk_cost.chunk_size(1000), 1000);
+        chunk_cost.record(1_000_000, Duration::from_millis(1));
+        assert_eq!(chunk_cost.chunk_size(1000), 10_000_000);
+        chunk_cost.record(1, Duration::from_secs(1));
+        assert_eq!(chunk_cost.chunk_size(1000), MIN_ADAPTIVE_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let buffer_pool = BufferPool::new(2);
+        assert_eq!(buffer_pool.get().capacity(), 0);
+        buffer_pool.put(Vec::new());
+        buffer_pool.put(vec![1; 10]);
+        buffer_pool.put(vec![2; 20]);
+        buffer_pool.put(vec![3; 30]);
+        assert_eq!(buffer_pool.len(), 2);
+        let buffer = buffer_pool.get();
+        assert!(buffer.is_empty() && buffer.capacity() >= 10);
+        buffer_pool.get();
+        assert!(buffer_pool.is_empty());
+
+        // Both the input chunks and the outputs are recycled, and the output
+        // is unchanged.
+        let input = input();
+        let mut expected = Vec::new();
+        crate::print::make(&mut expected, false).process_streaming(&mut &input[..]).unwrap();
+        let config = ParallelConfig::default().with_chunk_size(1000);
+        let output_pool = Arc::new(BufferPool::new(config.buffer_pool_capacity()));
+        let mut output = Vec::new();
+        let create_worker = {
+            let output_pool = output_pool.clone();
+            move || parser::State::new(structural::Generic::new(), WritingStage2Adapter::new(crate::print::Stage2::new()).with_buffer_pool(output_pool.clone()))
+        };
+        let mut parser = State::new(WritingJoiner::new(Box::new(create_worker), &mut output, output_pool.clone()), config);
+        for _ in 0..2 {
+            parser.process_streaming(&mut &input[..]).unwrap();
+            assert!(!parser.buffer_pool.is_empty());
+            assert!(!output_pool.is_empty());
+        }
+        std::mem::drop(parser);
+        assert_eq!(output.len(), 2 * expected.len());
+        assert!(output == [&expected[..], &expected[..]].concat());
+    }
+
+    #[test]
+    fn test_single_tape() {
+        let input = input();
+        let expected = parser::parser_from_visitor(SingleTapeVisitor::new()).process(&input[..]).unwrap();
+        let mut parser = streaming_from_visitor(SingleTapeVisitor::new, ParallelConfig::default().with_chunk_size(1000));
+        let tape = parser.process_streaming(&mut &input[..]).unwrap();
+        assert!(tape.tape == expected.tape);
+        assert_eq!(parser.process_streaming(&mut &b"(a"[..]).err(), Some(Error::UnmatchedOpenParen));
+    }
 }
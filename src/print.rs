@@ -94,8 +94,7 @@ pub fn make<'a, ReadT: BufRead + Send, WriteT: Write>
 {
     #[cfg(feature = "threads")]
     if threads {
-        let chunk_size = 256 * 1024;
-        return parser_parallel::streaming_from_writing_stage2(|| { Stage2::new() }, stdout, chunk_size);
+        return make_with_config(stdout, parser_parallel::ParallelConfig::default());
     }
 
     #[cfg(not(feature = "threads"))]
@@ -104,6 +103,14 @@ pub fn make<'a, ReadT: BufRead + Send, WriteT: Write>
     parser::streaming_from_writing_stage2(Stage2::new(), stdout)
 }
 
+#[cfg(feature = "threads")]
+pub fn make_with_config<'a, ReadT: BufRead + Send, WriteT: Write>
+    (stdout: &'a mut WriteT, config: parser_parallel::ParallelConfig)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    parser_parallel::streaming_from_writing_stage2(|| { Stage2::new() }, stdout, config)
+}
+
 #[cfg(feature = "ocaml")]
 mod ocaml_ffi {
     use super::*;
@@ -0,0 +1,229 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// Receives reports from `parser::State` (see `with_progress`) and
+/// `parser_parallel::State` (see `ParallelConfig::with_progress`) as they
+/// go. The methods may be called from several threads at once, so they
+/// should be cheap.
+pub trait Progress: Send + Sync {
+    /// `len` more bytes of the input have been parsed.
+    fn bytes_consumed(&self, _len: usize) {}
+
+    /// `count` more top-level sexps have been parsed.
+    fn records(&self, _count: usize) {}
+
+    /// Worker thread `worker` of a parallel parser spent `busy` on a chunk.
+    fn worker_busy(&self, _worker: usize, _busy: Duration) {}
+
+    /// The number of chunks waiting for a worker, and the number of finished
+    /// chunks waiting to be joined in order.
+    fn queue_occupancy(&self, _waiting: usize, _finished: usize) {}
+}
+
+impl std::fmt::Debug for dyn Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// A `Progress` that adds everything up.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub bytes: AtomicU64,
+    pub records: AtomicU64,
+    pub busy_nanos: AtomicU64,
+    /// One more than the highest worker index seen.
+    pub workers: AtomicUsize,
+    pub waiting: AtomicUsize,
+    pub finished: AtomicUsize,
+}
+
+impl Progress for Counters {
+    fn bytes_consumed(&self, len: usize) {
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn records(&self, count: usize) {
+        self.records.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn worker_busy(&self, worker: usize, busy: Duration) {
+        self.busy_nanos.fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        self.workers.fetch_max(worker + 1, Ordering::Relaxed);
+    }
+
+    fn queue_occupancy(&self, waiting: usize, finished: usize) {
+        self.waiting.store(waiting, Ordering::Relaxed);
+        self.finished.store(finished, Ordering::Relaxed);
+    }
+}
+
+fn human_bytes(bytes: f64) -> String {
+    let mut bytes = bytes;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if bytes < 1024. {
+            return format!("{:.1}{}", bytes, unit);
+        }
+        bytes /= 1024.;
+    }
+    format!("{:.1}TiB", bytes)
+}
+
+struct Snapshot {
+    time: Instant,
+    bytes: u64,
+    busy_nanos: u64,
+}
+
+impl Snapshot {
+    fn take(counters: &Counters) -> Self {
+        Self {
+            time: Instant::now(),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            busy_nanos: counters.busy_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Renders `counters` as one line, with rates since `since`.
+fn render(counters: &Counters, total: Option<u64>, since: &Snapshot, now: &Snapshot) -> String {
+    let seconds = (now.time - since.time).as_secs_f64().max(1e-9);
+    let mut line = human_bytes(now.bytes as f64);
+    if let Some(total) = total.filter(|&total| total > 0) {
+        line += &format!(" of {} ({:.0}%)", human_bytes(total as f64), now.bytes as f64 * 100. / total as f64);
+    }
+    line += &format!(", {} records, {}/s", counters.records.load(Ordering::Relaxed), human_bytes((now.bytes - since.bytes) as f64 / seconds));
+    let workers = counters.workers.load(Ordering::Relaxed);
+    if workers > 0 {
+        let busy = (now.busy_nanos - since.busy_nanos) as f64 / 1e9 / seconds / workers as f64;
+        line += &format!(", {} workers {:.0}% busy, {} chunks waiting, {} to join",
+                         workers, busy * 100., counters.waiting.load(Ordering::Relaxed), counters.finished.load(Ordering::Relaxed));
+    }
+    line
+}
+
+/// Writes the `Counters` to stderr about once a second until dropped, then
+/// writes a summary. On a terminal each report overwrites the last.
+pub struct StderrReporter {
+    counters: Arc<Counters>,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl StderrReporter {
+    /// `total` is the size of the input, if known.
+    pub fn new(total: Option<u64>) -> Self {
+        let counters = Arc::new(Counters::default());
+        let (stop, stopped) = mpsc::channel();
+        let thread_counters = counters.clone();
+        let thread = std::thread::spawn(move || {
+            let counters = thread_counters;
+            let is_terminal = std::io::stderr().is_terminal();
+            let start = Snapshot::take(&counters);
+            let mut last = Snapshot::take(&counters);
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_secs(1)) {
+                let now = Snapshot::take(&counters);
+                let line = render(&counters, total, &last, &now);
+                if is_terminal {
+                    eprint!("\r{}\x1b[K", line);
+                } else {
+                    eprintln!("{}", line);
+                }
+                last = now;
+            }
+            let line = render(&counters, total, &start, &Snapshot::take(&counters));
+            if is_terminal {
+                eprintln!("\r{}\x1b[K", line);
+            } else {
+                eprintln!("{}", line);
+            }
+            let _ = std::io::stderr().flush();
+        });
+        Self { counters, stop: Some(stop), thread: Some(thread) }
+    }
+
+    pub fn progress(&self) -> Arc<dyn Progress> {
+        self.counters.clone()
+    }
+}
+
+impl Drop for StderrReporter {
+    fn drop(&mut self) {
+        std::mem::drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, Parse};
+    use crate::structural;
+    use crate::validate;
+
+    fn input() -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..10000 {
+            input.extend_from_slice(format!("((id {})\n (name \"x\\n{}\"))\natom\n", i, i).as_bytes());
+        }
+        input
+    }
+
+    #[test]
+    fn test_serial() {
+        let input = input();
+        let counters = Arc::new(Counters::default());
+        let mut parser = parser::State::new(structural::Generic::new(), validate::Stage2::new()).with_progress(counters.clone());
+        assert_eq!(parser.process(&input[..]), Ok(20000));
+        assert_eq!(counters.bytes.load(Ordering::Relaxed), input.len() as u64);
+        assert_eq!(counters.records.load(Ordering::Relaxed), 20000);
+
+        let counters = Arc::new(Counters::default());
+        let mut parser = parser::State::new(structural::Generic::new(), validate::Stage2::new()).with_progress(counters.clone());
+        assert_eq!(parser.process_streaming(&mut std::io::BufReader::with_capacity(1000, &input[..])), Ok(20000));
+        assert_eq!(counters.bytes.load(Ordering::Relaxed), input.len() as u64);
+        assert_eq!(counters.records.load(Ordering::Relaxed), 20000);
+    }
+
+    #[cfg(feature = "threads")]
+    #[test]
+    fn test_parallel() {
+        use crate::parser_parallel::{self, ParallelConfig};
+        use crate::print;
+
+        let input = input();
+        let counters = Arc::new(Counters::default());
+        let config = ParallelConfig::default().with_num_threads(4).with_chunk_size(10000).with_progress(counters.clone());
+        let mut output = Vec::new();
+        print::make_with_config(&mut output, config).process_streaming(&mut &input[..]).unwrap();
+        assert_eq!(counters.bytes.load(Ordering::Relaxed), input.len() as u64);
+        assert_eq!(counters.records.load(Ordering::Relaxed), 20000);
+        assert!((1..=2).contains(&counters.workers.load(Ordering::Relaxed)));
+        assert!(counters.busy_nanos.load(Ordering::Relaxed) > 0);
+
+        let counters = Arc::new(Counters::default());
+        let config = ParallelConfig::default().with_chunk_size(10000).with_progress(counters.clone());
+        let mut parser: Box<dyn parser::Stream<&[u8], Return = _>> = parser_parallel::streaming_from_visitor(crate::rust_parser::SplitTapeVisitor::new, config);
+        parser.process_slice(&input[..]).unwrap();
+        assert_eq!(counters.bytes.load(Ordering::Relaxed), input.len() as u64);
+        assert_eq!(counters.records.load(Ordering::Relaxed), 20000);
+    }
+
+    #[test]
+    fn test_render() {
+        let counters = Counters::default();
+        let start = Snapshot { time: Instant::now(), bytes: 0, busy_nanos: 0 };
+        counters.bytes_consumed(3 << 20);
+        counters.records(1000);
+        let now = Snapshot { time: start.time + Duration::from_secs(2), bytes: 3 << 20, busy_nanos: 0 };
+        assert_eq!(render(&counters, Some(6 << 20), &start, &now), "3.0MiB of 6.0MiB (50%), 1000 records, 1.5MiB/s");
+        counters.worker_busy(1, Duration::from_secs(1));
+        counters.queue_occupancy(3, 1);
+        let now = Snapshot { busy_nanos: 1_000_000_000, ..now };
+        assert_eq!(render(&counters, None, &start, &now), "3.0MiB, 1000 records, 1.5MiB/s, 2 workers 25% busy, 3 chunks waiting, 1 to join");
+    }
+}
@@ -134,6 +134,140 @@ impl std::fmt::Display for SplitTape {
     }
 }
 
+/// A tape that chunks of input can be parsed into separately, and then
+/// appended in order.
+pub trait Tape: Default {
+    fn append(&mut self, other: Self);
+}
+
+impl Tape for SplitTape {
+    /// The atom offsets of `other` are rebased.
+    fn append(&mut self, mut other: Self) {
+        if self.tape.is_empty() && self.atoms.is_empty() {
+            *self = other;
+            return;
+        }
+        let atoms_base: u32 = self.atoms.len().try_into().unwrap();
+        let mut i = 0;
+        while i < other.tape.len() {
+            if other.tape[i] % 2 == 1 {
+                i += 1;
+            } else {
+                other.tape[i + 1] += atoms_base;
+                i += 2;
+            }
+        }
+        self.tape.append(&mut other.tape);
+        self.atoms.append(&mut other.atoms);
+    }
+}
+
+impl SplitTape {
+    /// The top-level sexps on the tape.
+    pub fn iter(&self) -> SexpRefIter<'_> {
+        SexpRefIter { tape: &self.tape[..], atoms: &self.atoms[..] }
+    }
+}
+
+/// A sexp borrowed from a `SplitTape`. Atoms are unescaped.
+#[derive(Copy, Clone)]
+pub struct SexpRef<'a> {
+    /// Exactly the words of this sexp.
+    tape: &'a [u32],
+    atoms: &'a [u8],
+}
+
+impl<'a> SexpRef<'a> {
+    /// `None` for a list.
+    pub fn atom(&self) -> Option<&'a [u8]> {
+        if self.is_list() {
+            None
+        } else {
+            let start = self.tape[1] as usize;
+            Some(&self.atoms[start..(start + (self.tape[0] / 2) as usize)])
+        }
+    }
+
+    pub fn is_list(&self) -> bool {
+        self.tape[0] % 2 == 1
+    }
+
+    /// The elements of a list, or nothing for an atom.
+    pub fn iter(&self) -> SexpRefIter<'a> {
+        let tape = if self.is_list() { &self.tape[1..] } else { &[] };
+        SexpRefIter { tape, atoms: self.atoms }
+    }
+
+    pub fn get(&self, index: usize) -> Option<SexpRef<'a>> {
+        self.iter().nth(index)
+    }
+
+    /// The value of the first `(key value)` element of a record.
+    pub fn field(&self, key: &[u8]) -> Option<SexpRef<'a>> {
+        self.iter().find_map(|field| {
+            let mut elements = field.iter();
+            match (elements.next(), elements.next(), elements.next()) {
+                (Some(k), Some(value), None) if k.atom() == Some(key) => Some(value),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn to_sexp(&self) -> Sexp {
+        match self.atom() {
+            Some(atom) => Sexp::Atom(atom.to_vec()),
+            None => Sexp::List(self.iter().map(|sexp| sexp.to_sexp()).collect()),
+        }
+    }
+
+    fn visit_internal<VisitorT: visitor::ReadVisitor>(&self, visitor: &mut VisitorT) {
+        match self.atom() {
+            Some(atom) => visitor.atom(atom),
+            None => {
+                visitor.list_open();
+                for sexp in self.iter() {
+                    sexp.visit_internal(visitor);
+                }
+                visitor.list_close();
+            },
+        }
+    }
+}
+
+impl<'a> visitor::ReadVisitable for SexpRef<'a> {
+    fn visit<VisitorT: visitor::ReadVisitor>(&self, visitor: &mut VisitorT) {
+        visitor.reset();
+        self.visit_internal(visitor);
+        visitor.eof();
+    }
+}
+
+impl<'a> std::fmt::Display for SexpRef<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use visitor::ReadVisitable;
+        let mut output = Vec::new();
+        let mut generator = rust_generator::Generator::new(&mut output);
+        self.visit(&mut generator);
+        f.write_str(std::str::from_utf8(&output[..]).unwrap())
+    }
+}
+
+pub struct SexpRefIter<'a> {
+    tape: &'a [u32],
+    atoms: &'a [u8],
+}
+
+impl<'a> Iterator for SexpRefIter<'a> {
+    type Item = SexpRef<'a>;
+    fn next(&mut self) -> Option<SexpRef<'a>> {
+        let x = *self.tape.first()?;
+        let len = if x % 2 == 1 { 1 + (x / 2) as usize } else { 2 };
+        let (tape, rest) = self.tape.split_at(len);
+        self.tape = rest;
+        Some(SexpRef { tape, atoms: self.atoms })
+    }
+}
+
 pub struct SplitTapeVisitor {
     tape: SplitTape,
 }
@@ -219,18 +353,34 @@ impl SingleTape {
     }
 }
 
+impl Tape for SingleTape {
+    /// The words of a `SingleTape` don't depend on their position.
+    fn append(&mut self, mut other: Self) {
+        self.tape.append(&mut other.tape);
+    }
+}
+
 impl visitor::ReadVisitable for SingleTape {
     fn visit<VisitorT: visitor::ReadVisitor>(&self, visitor: &mut VisitorT) {
+        self.visit_range(0..self.tape.len(), visitor)
+    }
+}
+
+impl SingleTape {
+    /// Visits the sexps in `range` of the tape, which must start and end at
+    /// top-level sexps.
+    pub fn visit_range<VisitorT: visitor::ReadVisitor>(&self, range: std::ops::Range<usize>, visitor: &mut VisitorT) {
+        let tape = &self.tape[range];
         let mut i = 0usize;
         let mut list_ends: Vec<usize> = Vec::new();
         visitor.reset();
-        while i < self.tape.len() {
-            let x = self.tape[i];
+        while i < tape.len() {
+            let x = tape[i];
             i += 1;
             let is_atom = x % 2 == 0;
             let len = (x / 2) as usize;
             if is_atom {
-                let padded_atom_slice = utils::slice_u32_to_u8(&self.tape[i..(i + len)]);
+                let padded_atom_slice = utils::slice_u32_to_u8(&tape[i..(i + len)]);
                 let atom_len =
                     padded_atom_slice.len()
                     - (padded_atom_slice[padded_atom_slice.len() - 1] as usize)
@@ -267,6 +417,9 @@ impl std::fmt::Display for SingleTape {
 pub struct SingleTapeVisitor {
     tape: SingleTape,
     len_of_valid_partial_result_prefix: usize,
+    /// The length of the partial results extracted so far, which the
+    /// `tape_start_index`es of lists still open are relative to.
+    len_of_extracted_partial_results: usize,
 }
 
 impl SingleTapeVisitor {
@@ -274,6 +427,7 @@ impl SingleTapeVisitor {
         Self {
             tape: SingleTape::new(),
             len_of_valid_partial_result_prefix: 0,
+            len_of_extracted_partial_results: 0,
         }
     }
 }
@@ -287,6 +441,7 @@ impl parser::ExtractPartialResult for SingleTapeVisitor {
         // now split_tape contains elements in the range
         // [0..self.len_of_valid_partial_result_prefix]
 
+        self.len_of_extracted_partial_results += self.len_of_valid_partial_result_prefix;
         self.len_of_valid_partial_result_prefix = 0;
         SingleTape { tape: split_tape }
     }
@@ -341,7 +496,7 @@ impl visitor::Visitor for SingleTapeVisitor {
 
     #[inline(always)]
     fn list_open(&mut self, _: Option<&mut SingleTapeVisitorContext>) -> SingleTapeVisitorContext {
-        let tape_start_index = self.tape.tape.len();
+        let tape_start_index = self.len_of_extracted_partial_results + self.tape.tape.len();
         self.tape.tape.push(0);
         SingleTapeVisitorContext {
             tape_start_index,
@@ -350,8 +505,9 @@ impl visitor::Visitor for SingleTapeVisitor {
 
     #[inline(always)]
     fn list_close(&mut self, context: SingleTapeVisitorContext, parent_context: Option<&mut SingleTapeVisitorContext>) {
-        let x: u32 = ((self.tape.tape.len() - context.tape_start_index - 1) * 2 + 1).try_into().unwrap();
-        self.tape.tape[context.tape_start_index] = x;
+        let tape_start_index = context.tape_start_index - self.len_of_extracted_partial_results;
+        let x: u32 = ((self.tape.tape.len() - tape_start_index - 1) * 2 + 1).try_into().unwrap();
+        self.tape.tape[tape_start_index] = x;
 
         if let None = parent_context {
             // We know this list_close is closing a top-level sexp.
@@ -361,6 +517,8 @@ impl visitor::Visitor for SingleTapeVisitor {
 
     #[inline(always)]
     fn eof(&mut self) -> Self::Return {
+        self.len_of_valid_partial_result_prefix = 0;
+        self.len_of_extracted_partial_results = 0;
         std::mem::take(&mut self.tape)
     }
 }
@@ -592,6 +750,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sexp_ref() {
+        let mut parser = parser::parser_from_visitor(SplitTapeVisitor::new());
+        let tape = parser.process(br#"((a 1) (b "x y") (c (d e)) (a 2)) foo ()"#).unwrap();
+        let sexps: Vec<_> = tape.iter().collect();
+        assert_eq!(sexps.len(), 3);
+        let record = sexps[0];
+        assert!(record.is_list());
+        assert_eq!(record.atom(), None);
+        assert_eq!(record.iter().count(), 4);
+        assert_eq!(record.field(b"a").unwrap().atom(), Some(&b"1"[..]));
+        assert_eq!(record.field(b"b").unwrap().atom(), Some(&b"x y"[..]));
+        assert_eq!(record.field(b"c").unwrap().to_string(), "(d e)");
+        assert!(record.field(b"d").is_none());
+        assert_eq!(record.get(3).unwrap().to_string(), "(a 2)");
+        assert!(record.get(4).is_none());
+        assert_eq!(sexps[1].atom(), Some(&b"foo"[..]));
+        assert_eq!(sexps[1].iter().count(), 0);
+        assert_eq!(sexps[2].to_string(), "()");
+        assert_eq!(record.to_sexp().to_string(), record.to_string());
+    }
+
     #[test] fn test_1() { run_test(br#"foo"#, Ok(r#"foo"#)); }
     #[test] fn test_2() { run_test(br#"foo bar"#, Ok(r#"foo bar"#)); }
     #[test] fn test_3() { run_test(br#"foo   bar"#, Ok(r#"foo bar"#)); }
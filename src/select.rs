@@ -1,10 +1,14 @@
 use crate::escape::{self, Unescape};
 use crate::escape_csv;
+use crate::escape_json;
 use crate::parser;
 #[cfg(feature = "threads")]
 use crate::parser_parallel;
+#[cfg(feature = "arrow")]
+use crate::select_arrow;
+use crate::select_filter;
 use crate::utils;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{BufRead, Write};
 use std::ops::Range;
 
@@ -16,17 +20,95 @@ enum State {
     Ignore,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum OutputKind {
     Values,
     Labeled,
     Csv { atoms_as_sexps: bool },
+    /// One JSON object per line, with the keys as field names.
+    JsonLines,
+    /// Like `Csv`, but with a configurable delimiter, quoting and null
+    /// representation (e.g. TSV).
+    Delimited(DelimitedOptions),
+    /// Arrow IPC record batches, one per parallel chunk. Column types are
+    /// inferred if not given (one per key).
+    #[cfg(feature = "arrow")]
+    Arrow { format: select_arrow::Format, column_types: Option<Vec<select_arrow::ColumnType>> },
 }
 
 pub trait Output {
     fn reset(&mut self, keys: &Vec<&[u8]>);
+    /// `value_range` is the text of the selected sexp in `input`, possibly
+    /// with trailing whitespace. It stays in the input until `eol`.
     fn select<WriteT: Write>(&mut self, writer: &mut WriteT, keys: &Vec<&[u8]>, key_id: usize, input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool);
     fn eol<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input);
+
+    /// Like `select`, where `key` is the key that was found. This differs
+    /// from `keys[key_id]` for a key matching a pattern.
+    #[allow(clippy::too_many_arguments)]
+    fn select_key<WriteT: Write>(&mut self, writer: &mut WriteT, keys: &Vec<&[u8]>, key_id: usize, _key: &[u8], input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool) {
+        self.select(writer, keys, key_id, input, value_range, has_output_on_line)
+    }
+
+    /// Like `select_key`, for a value that is not in the input: the list of
+    /// `DuplicateKeys::List`, or the value of a missing key. By default the
+    /// value is selected as an input of its own, so outputs that hold on to
+    /// ranges until `eol` must copy it instead.
+    fn select_value<WriteT: Write>(&mut self, writer: &mut WriteT, keys: &Vec<&[u8]>, key_id: usize, key: &[u8], value: &[u8], has_output_on_line: bool) {
+        let input = parser::Input { input: value, offset: 0 };
+        self.select_key(writer, keys, key_id, key, &input, 0..value.len(), has_output_on_line)
+    }
+}
+
+fn input_value<'b>(input: &parser::Input<'b>, value_range: Range<usize>) -> &'b [u8] {
+    &input.input[(value_range.start - input.offset)..(value_range.end - input.offset)]
+}
+
+/// Where a value held by a `HeldRow` is.
+#[derive(Clone, Debug)]
+enum Held {
+    Input(Range<usize>),
+    /// In `HeldRow::buf`.
+    Copied(Range<usize>),
+}
+
+/// The value of each column of a record, held until its `eol`. Values are
+/// held as ranges of the input, and only those passed to
+/// `Output::select_value` are copied.
+#[derive(Default)]
+pub(crate) struct HeldRow {
+    values: Vec<Option<Held>>,
+    buf: Vec<u8>,
+}
+
+impl HeldRow {
+    pub(crate) fn reset(&mut self, num_columns: usize) {
+        self.values.clear();
+        self.values.resize(num_columns, None);
+        self.buf.clear();
+    }
+
+    /// A later value replaces an earlier one.
+    pub(crate) fn hold(&mut self, column: usize, value_range: Range<usize>) {
+        self.values[column] = Some(Held::Input(value_range));
+    }
+
+    pub(crate) fn hold_copy(&mut self, column: usize, value: &[u8]) {
+        self.values[column] = Some(Held::Copied(push_value(&mut self.buf, value)));
+    }
+
+    /// Calls `f` on each column and its value, in order, and forgets them.
+    pub(crate) fn drain<F: FnMut(usize, Option<&[u8]>)>(&mut self, input: &parser::Input, mut f: F) {
+        for (column, held) in self.values.iter_mut().enumerate() {
+            let value = match held.take() {
+                None => None,
+                Some(Held::Input(value_range)) => Some(input_value(input, value_range)),
+                Some(Held::Copied(value_range)) => Some(&self.buf[value_range]),
+            };
+            f(column, value);
+        }
+        self.buf.clear();
+    }
 }
 
 pub struct OutputValues {
@@ -42,7 +124,7 @@ impl Output for OutputValues {
     fn reset(&mut self, _keys: &Vec<&[u8]>) {
     }
     fn select<WriteT: Write>(&mut self, writer: &mut WriteT, _keys: &Vec<&[u8]>, _key_id: usize, input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool) {
-        let value = &input.input[(value_range.start - input.offset)..(value_range.end - input.offset)];
+        let value = input_value(input, value_range);
         writer.write_all(if has_output_on_line { &b" "[..] } else { &b"("[..] }).unwrap();
         writer.write_all(&value[..]).unwrap();
     }
@@ -52,11 +134,12 @@ impl Output for OutputValues {
 }
 
 pub struct OutputLabeled {
+    is_necessary: escape::IsNecessary,
 }
 
 impl OutputLabeled {
     pub fn new() -> Self {
-        Self { }
+        Self { is_necessary: escape::IsNecessary::new() }
     }
 }
 
@@ -64,11 +147,18 @@ impl Output for OutputLabeled {
     fn reset(&mut self, _keys: &Vec<&[u8]>) {
     }
     fn select<WriteT: Write>(&mut self, writer: &mut WriteT, keys: &Vec<&[u8]>, key_id: usize, input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool) {
-        let key = keys[key_id];
-        let value = &input.input[(value_range.start - input.offset)..(value_range.end - input.offset)];
+        self.select_key(writer, keys, key_id, keys[key_id], input, value_range, has_output_on_line)
+    }
+    fn select_key<WriteT: Write>(&mut self, writer: &mut WriteT, _keys: &Vec<&[u8]>, _key_id: usize, key: &[u8], input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool) {
+        let value = input_value(input, value_range);
         writer.write_all(&b"(("[(has_output_on_line as usize)..]).unwrap();
-        // TODO: escape key if necessary
-        writer.write_all(&key[..]).unwrap();
+        if self.is_necessary.eval(key) {
+            writer.write_all(&b"\""[..]).unwrap();
+            escape::escape(key, writer).unwrap();
+            writer.write_all(&b"\""[..]).unwrap();
+        } else {
+            writer.write_all(&key[..]).unwrap();
+        }
         writer.write_all(&b" "[..]).unwrap();
         writer.write_all(&value[..]).unwrap();
         writer.write_all(&b")"[..]).unwrap();
@@ -80,14 +170,14 @@ impl Output for OutputLabeled {
 
 pub struct OutputCsv {
     atoms_as_sexps: bool,
-    row: Vec<Range<usize>>,
+    row: HeldRow,
 }
 
 impl OutputCsv {
     pub fn new(atoms_as_sexps: bool) -> Self {
         Self {
             atoms_as_sexps,
-            row: Vec::new(),
+            row: HeldRow::default(),
         }
     }
 
@@ -110,21 +200,22 @@ impl OutputCsv {
 
 impl Output for OutputCsv {
     fn reset(&mut self, keys: &Vec<&[u8]>) {
-        self.row.resize(keys.len(), 0..0);
+        self.row.reset(keys.len());
     }
     fn select<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _input: &parser::Input, value_range: Range<usize>, _has_output_on_line: bool) {
-        self.row[key_id] = value_range;
+        self.row.hold(key_id, value_range);
+    }
+    fn select_value<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _key: &[u8], value: &[u8], _has_output_on_line: bool) {
+        self.row.hold_copy(key_id, value);
     }
     fn eol<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input) {
-        let mut needs_comma = false;
-        for value_range in self.row.iter_mut() {
-            if needs_comma {
+        let atoms_as_sexps = self.atoms_as_sexps;
+        self.row.drain(input, |column, value| {
+            if column > 0 {
                 writer.write_all(&b","[..]).unwrap();
             }
-            needs_comma = true;
-            if !Range::is_empty(value_range) {
-                let value = &input.input[(value_range.start - input.offset)..(value_range.end - input.offset)];
-                if !self.atoms_as_sexps && value[0] == b'\"' {
+            if let Some(value) = value {
+                if !atoms_as_sexps && value[0] == b'\"' {
                     // quoted atom -> plain string -> quoted CSV.
                     // TODO: This could be done faster.
                     let mut plain_string: Vec<u8> = value.iter().map(|_| 0u8).collect();
@@ -145,22 +236,401 @@ impl Output for OutputCsv {
                     }
                 }
             }
+        });
+        writer.write_all(&b"\n"[..]).unwrap();
+    }
+}
 
-            *value_range = 0..0;
+/// Appends `value` to `buf`, returning its range there.
+pub(crate) fn push_value(buf: &mut Vec<u8>, value: &[u8]) -> Range<usize> {
+    let start = buf.len();
+    buf.extend_from_slice(value);
+    start..buf.len()
+}
+
+/// Quoted atoms become JSON strings of their unescaped contents, and other
+/// values (unquoted atoms and lists) become JSON strings of their text.
+/// Missing keys are left out of the object.
+pub struct OutputJsonLines {
+    unescaped: Vec<u8>,
+}
+
+impl OutputJsonLines {
+    pub fn new() -> Self {
+        Self { unescaped: Vec::new() }
+    }
+}
+
+impl Default for OutputJsonLines {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_json_string<WriteT: Write>(writer: &mut WriteT, string: &[u8]) {
+    writer.write_all(&b"\""[..]).unwrap();
+    escape_json::escape(string, writer).unwrap();
+    writer.write_all(&b"\""[..]).unwrap();
+}
+
+/// Returns the selected value without trailing whitespace, with quoted atoms
+/// unescaped into `unescaped`.
+pub(crate) fn unescaped_value<'b>(value: &'b [u8], unescaped: &'b mut Vec<u8>) -> &'b [u8] {
+    let value = value.trim_ascii_end();
+    if value[0] == b'\"' {
+        unescaped.resize(value.len(), 0u8);
+        let (_, unescaped_len) = escape::GenericUnescape::new().unescape(&value[1..], &mut unescaped[..]).unwrap();
+        &unescaped[..unescaped_len]
+    } else {
+        value
+    }
+}
+
+impl Output for OutputJsonLines {
+    fn reset(&mut self, _keys: &Vec<&[u8]>) {
+    }
+    fn select<WriteT: Write>(&mut self, writer: &mut WriteT, keys: &Vec<&[u8]>, key_id: usize, input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool) {
+        self.select_key(writer, keys, key_id, keys[key_id], input, value_range, has_output_on_line)
+    }
+    fn select_key<WriteT: Write>(&mut self, writer: &mut WriteT, _keys: &Vec<&[u8]>, _key_id: usize, key: &[u8], input: &parser::Input, value_range: Range<usize>, has_output_on_line: bool) {
+        writer.write_all(if has_output_on_line { &b","[..] } else { &b"{"[..] }).unwrap();
+        write_json_string(writer, key);
+        writer.write_all(&b":"[..]).unwrap();
+        write_json_string(writer, unescaped_value(input_value(input, value_range), &mut self.unescaped));
+    }
+    fn eol<WriteT: Write>(&mut self, writer: &mut WriteT, _input: &parser::Input) {
+        writer.write_all(&b"}\n"[..]).unwrap();
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Quoting {
+    /// CSV-style: fields containing the delimiter, a double quote or a line
+    /// break are surrounded by double quotes, with double quotes doubled.
+    Necessary,
+    /// CSV-style, but every non-null field is quoted.
+    Always,
+    /// Fields are never quoted; instead backslashes, tabs, line breaks and the
+    /// delimiter are backslash-escaped.
+    Escape,
+}
+
+#[derive(Clone, Debug)]
+pub struct DelimitedOptions {
+    pub delimiter: u8,
+    pub quoting: Quoting,
+    /// Written in place of missing values.
+    pub null: Vec<u8>,
+}
+
+impl DelimitedOptions {
+    /// Tab-separated, backslash-escaped, with `\N` for missing values.
+    pub fn tsv() -> Self {
+        Self { delimiter: b'\t', quoting: Quoting::Escape, null: b"\\N".to_vec() }
+    }
+}
+
+impl Default for DelimitedOptions {
+    fn default() -> Self {
+        Self { delimiter: b'\t', quoting: Quoting::Necessary, null: Vec::new() }
+    }
+}
+
+pub struct OutputDelimited {
+    options: DelimitedOptions,
+    row: HeldRow,
+    unescaped: Vec<u8>,
+}
+
+impl OutputDelimited {
+    pub fn new(options: DelimitedOptions) -> Self {
+        Self {
+            options,
+            row: HeldRow::default(),
+            unescaped: Vec::new(),
+        }
+    }
+
+    fn write_field<WriteT: Write>(options: &DelimitedOptions, field: &[u8], writer: &mut WriteT) {
+        match options.quoting {
+            Quoting::Necessary if !escape_csv::escape_is_necessary_with_delimiter(field, options.delimiter) =>
+                writer.write_all(field).unwrap(),
+            Quoting::Necessary | Quoting::Always => escape_csv::escape(field, writer).unwrap(),
+            Quoting::Escape => escape_csv::escape_backslash(field, options.delimiter, writer).unwrap(),
+        }
+    }
+
+    pub fn print_header<'a, KeysT: Iterator<Item=&'a [u8]>, WriteT: Write>(options: &DelimitedOptions, keys: KeysT, writer: &mut WriteT) {
+        for (i, key) in keys.enumerate() {
+            if i > 0 {
+                writer.write_all(&[options.delimiter]).unwrap();
+            }
+            Self::write_field(options, key, writer);
         }
         writer.write_all(&b"\n"[..]).unwrap();
     }
 }
 
+impl Output for OutputDelimited {
+    fn reset(&mut self, keys: &Vec<&[u8]>) {
+        self.row.reset(keys.len());
+    }
+    fn select<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _input: &parser::Input, value_range: Range<usize>, _has_output_on_line: bool) {
+        self.row.hold(key_id, value_range);
+    }
+    fn select_value<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _key: &[u8], value: &[u8], _has_output_on_line: bool) {
+        self.row.hold_copy(key_id, value);
+    }
+    fn eol<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input) {
+        let options = &self.options;
+        let unescaped = &mut self.unescaped;
+        self.row.drain(input, |column, value| {
+            if column > 0 {
+                writer.write_all(&[options.delimiter]).unwrap();
+            }
+            match value {
+                None => writer.write_all(&options.null[..]).unwrap(),
+                Some(value) => Self::write_field(options, unescaped_value(value, unescaped), writer),
+            }
+        });
+        writer.write_all(&b"\n"[..]).unwrap();
+    }
+}
+
+/// Selects every key matching a glob or regex. In `Labeled` and `JsonLines`
+/// output each match is labeled with its own key; otherwise the matches are
+/// grouped into a single column named after the pattern. Patterns need the
+/// `regex` feature.
+#[derive(Clone, Debug)]
+pub struct KeyPattern {
+    pub name: Vec<u8>,
+    #[cfg(feature = "regex")]
+    regex: regex::bytes::Regex,
+    #[cfg(not(feature = "regex"))]
+    never: std::convert::Infallible,
+}
+
+impl KeyPattern {
+    #[cfg(feature = "regex")]
+    fn is_match(&self, key: &[u8]) -> bool {
+        self.regex.is_match(key)
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn is_match(&self, _key: &[u8]) -> bool {
+        match self.never {}
+    }
+
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: &str) -> Result<Self, parser::Error> {
+        let regex = regex::bytes::Regex::new(pattern).map_err(|_| parser::Error::InvalidKeyPattern)?;
+        Ok(Self { name: pattern.as_bytes().to_owned(), regex })
+    }
+
+    /// `*` matches any sequence of characters, `?` any single character, and
+    /// `[...]` any of the given characters.
+    #[cfg(feature = "regex")]
+    pub fn glob(pattern: &str) -> Result<Self, parser::Error> {
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                '[' => {
+                    regex.push('[');
+                    loop {
+                        match chars.next() {
+                            None => return Err(parser::Error::InvalidKeyPattern),
+                            Some(']') => break,
+                            Some('!') if regex.ends_with('[') => regex.push('^'),
+                            Some(ch @ ('\\' | '[' | '^' | '&' | '~')) => {
+                                regex.push('\\');
+                                regex.push(ch);
+                            },
+                            Some(ch) => regex.push(ch),
+                        }
+                    }
+                    regex.push(']');
+                },
+                _ => regex.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        regex.push('$');
+        let regex = regex::bytes::Regex::new(&regex).map_err(|_| parser::Error::InvalidKeyPattern)?;
+        Ok(Self { name: pattern.as_bytes().to_owned(), regex })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathStep {
+    /// The nth element (from 0) of a list.
+    Nth(usize),
+    /// The payload of a variant with the given tag, i.e. `payload` in
+    /// `(Tag payload)`. With more than one argument, the first is selected.
+    Tag(Vec<u8>),
+}
+
+/// Selects by position and variant tag rather than by key, e.g. `#1` (the
+/// second element of each record), `@Order` (the payload of records tagged
+/// `Order`) or `@Order/#0/id` (the value of `id` anywhere within the first
+/// argument of `Order`). Steps are separated by `/` and a key may only come
+/// last.
+#[derive(Clone, Debug)]
+pub struct PathSelector {
+    pub name: Vec<u8>,
+    pub steps: Vec<PathStep>,
+    pub key: Option<Vec<u8>>,
+}
+
+impl PathSelector {
+    pub fn parse(input: &str) -> Result<Self, parser::Error> {
+        let mut steps = Vec::new();
+        let mut key = None;
+        for component in input.split('/') {
+            if key.is_some() {
+                return Err(parser::Error::InvalidPath);
+            }
+            if let Some(n) = component.strip_prefix('#') {
+                steps.push(PathStep::Nth(n.parse().map_err(|_| parser::Error::InvalidPath)?));
+            } else if let Some(tag) = component.strip_prefix('@') {
+                if tag.is_empty() {
+                    return Err(parser::Error::InvalidPath);
+                }
+                steps.push(PathStep::Tag(tag.as_bytes().to_owned()));
+            } else if !component.is_empty() {
+                key = Some(component.as_bytes().to_owned());
+            } else {
+                return Err(parser::Error::InvalidPath);
+            }
+        }
+        if steps.is_empty() {
+            return Err(parser::Error::InvalidPath);
+        }
+        Ok(Self { name: input.as_bytes().to_owned(), steps, key })
+    }
+}
+
+/// What to do when a key appears more than once in a record.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DuplicateKeys {
+    First,
+    Last,
+    /// Every value of the key is output as a single list, even if there is
+    /// only one, so that the shape of the value doesn't depend on the number
+    /// of occurrences.
+    List,
+    Error,
+}
+
+/// What to do when a selected key does not appear in a record. A pattern
+/// counts as a key named after the pattern, missing if no key matches it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MissingKeys<'a> {
+    /// The key is output with the value `""`.
+    Empty,
+    /// The key is output with the marker as if it were an unquoted atom.
+    Null(&'a [u8]),
+    SkipRow,
+    Error,
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Options<'a> {
+    /// Only records satisfying the filter are output. Keys that the filter
+    /// mentions are selected too, but not output unless they are also
+    /// selected explicitly.
+    pub filter: Option<&'a select_filter::Predicate>,
+    /// Columns after the explicitly selected keys. Keys that are selected
+    /// explicitly are not matched against the patterns.
+    pub patterns: &'a [KeyPattern],
+    /// Columns after the patterns.
+    pub paths: &'a [PathSelector],
+    /// If neither policy is given, every occurrence of a key is output, in
+    /// the order of the input, and missing keys are left out (in `Csv`,
+    /// `Delimited` and `Arrow`, a later occurrence replaces an earlier one,
+    /// and missing keys are empty or null). If either is given, keys are
+    /// output in the order they were selected in. Distinct keys matching the
+    /// same pattern still share a column in `Csv`, `Delimited` and `Arrow`.
+    pub duplicate_keys: Option<DuplicateKeys>,
+    pub missing_keys: Option<MissingKeys<'a>>,
+}
+
+impl<'a> Options<'a> {
+    /// The names of the columns after the explicitly selected keys.
+    pub fn extra_columns(&self) -> impl Iterator<Item = &'a [u8]> {
+        let patterns: &'a [KeyPattern] = self.patterns;
+        let paths: &'a [PathSelector] = self.paths;
+        patterns.iter().map(|pattern| &pattern.name[..]).chain(paths.iter().map(|path| &path.name[..]))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum PathState {
+    Searching,
+    TagMatched,
+    KeyMatched,
+    /// The key's value starts here.
+    Value(usize),
+    /// The list starting here is the selected element.
+    Focus(usize),
+    Dead,
+}
+
+/// The progress of a path selector within a list.
+#[derive(Copy, Clone, Debug)]
+struct PathActive {
+    path: u32,
+    /// The number of steps taken to reach the list; if all of them, the list
+    /// is searched for the key.
+    step: u32,
+    state: PathState,
+}
+
+/// Key ids from here on refer to keys that matched a pattern.
+const PATTERN_MATCH_KEY_ID: u32 = 1 << 31;
+const MAX_PATTERN_MATCHES: usize = 1 << 16;
+
 pub struct Stage2<'a, OutputT> {
     // varying
     stack: Vec<State>,
     has_output_on_line: bool,
+    /// With a filter or a policy, the fields of the current record are held
+    /// here until the record has been seen in full.
+    record: Vec<(u32, Range<usize>)>,
+    /// The fields of `record` that are output, with their columns.
+    record_columns: Vec<(usize, u32, Range<usize>)>,
+    list_buf: Vec<u8>,
+    filter_values: Vec<Option<Range<usize>>>,
+    filter_buf: Vec<u8>,
+    /// The keys seen so far that are not selected explicitly, with their key
+    /// ids if they match a pattern.
+    pattern_cache: HashMap<Vec<u8>, Option<u32>>,
+    /// For each key id from `PATTERN_MATCH_KEY_ID`, the key and the column
+    /// of its pattern. The first `num_fixed_pattern_matches` are keys used by
+    /// the filter.
+    pattern_matches: Vec<(Vec<u8>, usize)>,
+    /// With path selectors, for each open list: the start of its entries in
+    /// `path_active` and the index of its next element.
+    path_frames: Vec<(usize, usize)>,
+    path_active: Vec<PathActive>,
+    path_buf: Vec<u8>,
 
     // static
     output: OutputT,
     select_tree: BTreeMap<&'a [u8], u32>,
     select_vec: Vec<&'a [u8]>,
+    filter: Option<&'a select_filter::Predicate>,
+    duplicate_keys: Option<DuplicateKeys>,
+    missing_keys: Option<MissingKeys<'a>>,
+    buffer_records: bool,
+    /// The column of each pattern.
+    patterns: Vec<(usize, &'a KeyPattern)>,
+    /// The column of each path selector.
+    paths: Vec<(usize, &'a PathSelector)>,
+    /// Key ids below this that are not columns are only used by the filter.
+    num_key_ids: usize,
+    num_fixed_pattern_matches: usize,
     unescape: escape::GenericUnescape,
 }
 
@@ -173,16 +643,357 @@ impl<'a, OutputT> Stage2<'a, OutputT> {
         Self {
             stack: Vec::with_capacity(64),
             has_output_on_line: false,
+            record: Vec::new(),
+            record_columns: Vec::new(),
+            list_buf: Vec::new(),
+            filter_values: Vec::new(),
+            filter_buf: Vec::new(),
+            pattern_cache: HashMap::new(),
+            pattern_matches: Vec::new(),
+            path_frames: Vec::new(),
+            path_active: Vec::new(),
+            path_buf: Vec::new(),
             output,
             select_tree,
             select_vec,
+            filter: None,
+            duplicate_keys: None,
+            missing_keys: None,
+            buffer_records: false,
+            patterns: Vec::new(),
+            paths: Vec::new(),
+            num_key_ids: 0,
+            num_fixed_pattern_matches: 0,
             unescape: escape::GenericUnescape::new(),
         }
     }
+
+    pub fn with_options(mut self, options: Options<'a>) -> Self {
+        for pattern in options.patterns {
+            self.patterns.push((self.select_vec.len(), pattern));
+            self.select_vec.push(&pattern.name[..]);
+        }
+        for path in options.paths {
+            self.paths.push((self.select_vec.len(), path));
+            self.select_vec.push(&path.name[..]);
+        }
+        if let Some(filter) = options.filter {
+            let mut next_key_id = self.select_vec.len();
+            filter.iter_keys(&mut |key| {
+                if self.select_tree.contains_key(key) {
+                    return;
+                }
+                // keys only used by the filter are still output if they match a pattern
+                let key_id = match self.patterns.iter().find(|(_, pattern)| pattern.is_match(key)) {
+                    Some((column, _)) => {
+                        self.pattern_matches.push((key.to_owned(), *column));
+                        PATTERN_MATCH_KEY_ID + (self.pattern_matches.len() - 1) as u32
+                    },
+                    None => {
+                        next_key_id += 1;
+                        (next_key_id - 1).try_into().unwrap()
+                    },
+                };
+                self.select_tree.insert(key, key_id);
+            });
+            self.num_key_ids = next_key_id;
+            self.num_fixed_pattern_matches = self.pattern_matches.len();
+            self.filter_values.resize(next_key_id + self.pattern_matches.len(), None);
+        }
+        self.filter = options.filter;
+        self.duplicate_keys = options.duplicate_keys;
+        self.missing_keys = options.missing_keys;
+        self.buffer_records = options.filter.is_some() || options.duplicate_keys.is_some() || options.missing_keys.is_some();
+        self
+    }
+
+    pub fn output_mut(&mut self) -> &mut OutputT {
+        &mut self.output
+    }
+
+    /// The index in `filter_values` of a key used by the filter.
+    fn filter_slot(&self, key_id: u32) -> usize {
+        if key_id < PATTERN_MATCH_KEY_ID {
+            key_id as usize
+        } else {
+            self.num_key_ids + (key_id - PATTERN_MATCH_KEY_ID) as usize
+        }
+    }
+
+    #[inline]
+    fn key_id(&mut self, key: &[u8]) -> Option<u32> {
+        if let Some(key_id) = self.select_tree.get(key) {
+            return Some(*key_id);
+        }
+        if self.patterns.is_empty() {
+            return None;
+        }
+        if let Some(key_id) = self.pattern_cache.get(key) {
+            return *key_id;
+        }
+        let key_id = self.patterns.iter().find(|(_, pattern)| pattern.is_match(key)).map(|(column, _)| {
+            self.pattern_matches.push((key.to_owned(), *column));
+            PATTERN_MATCH_KEY_ID + (self.pattern_matches.len() - 1) as u32
+        });
+        self.pattern_cache.insert(key.to_owned(), key_id);
+        key_id
+    }
+}
+
+/// Returns the column and the key of `key_id`, or `None` if the key is only
+/// selected for the filter.
+#[inline]
+fn resolve_key_id<'b>(select_vec: &'b [&[u8]], pattern_matches: &'b [(Vec<u8>, usize)], key_id: u32) -> Option<(usize, &'b [u8])> {
+    if key_id < PATTERN_MATCH_KEY_ID {
+        select_vec.get(key_id as usize).map(|key| (key_id as usize, *key))
+    } else {
+        let (key, column) = &pattern_matches[(key_id - PATTERN_MATCH_KEY_ID) as usize];
+        Some((*column, &key[..]))
+    }
+}
+
+impl<'a, OutputT: Output> Stage2<'a, OutputT> {
+    #[inline]
+    fn select<WriteT: Write>(&mut self, writer: &mut WriteT, key_id: u32, input: &parser::Input, value_range: Range<usize>) {
+        if self.buffer_records {
+            self.record.push((key_id, value_range));
+        } else {
+            let (column, key) = resolve_key_id(&self.select_vec, &self.pattern_matches, key_id).unwrap();
+            self.output.select_key(writer, &self.select_vec, column, key, input, value_range, self.has_output_on_line);
+            self.has_output_on_line = true;
+        }
+    }
+
+    fn filter_record(&mut self, input: &parser::Input, filter: &select_filter::Predicate) -> bool {
+        self.filter_buf.clear();
+        self.filter_values.fill(None);
+        for (key_id, value_range) in self.record.iter() {
+            let filter_slot = self.filter_slot(*key_id);
+            if let Some(filter_value @ None) = self.filter_values.get_mut(filter_slot) {
+                let value = input.input[(value_range.start - input.offset)..(value_range.end - input.offset)].trim_ascii_end();
+                let start = self.filter_buf.len();
+                if value[0] == b'"' {
+                    self.filter_buf.resize(start + value.len(), 0u8);
+                    let (_, unescaped_len) = self.unescape.unescape(&value[1..], &mut self.filter_buf[start..]).unwrap();
+                    self.filter_buf.truncate(start + unescaped_len);
+                } else {
+                    self.filter_buf.extend_from_slice(value);
+                }
+                *filter_value = Some(start..self.filter_buf.len());
+            }
+        }
+        let lookup = |key: &[u8]| {
+            let key_id = *self.select_tree.get(key)?;
+            self.filter_values[self.filter_slot(key_id)].clone().map(|range| &self.filter_buf[range])
+        };
+        filter.eval(&lookup)
+    }
+
+    /// Whether the atom `input[this_index..next_index]` is `expected`, after
+    /// unescaping.
+    fn atom_is(&mut self, input: &parser::Input, this_index: usize, next_index: usize, expected: &[u8]) -> bool {
+        let atom = &input.input[(this_index - input.offset)..(next_index - input.offset)];
+        if atom[0] != b'"' {
+            return atom == expected;
+        }
+        self.path_buf.resize(atom.len(), 0u8);
+        match self.unescape.unescape(&atom[1..], &mut self.path_buf[..]) {
+            Some((_, len)) => &self.path_buf[..len] == expected,
+            None => false,
+        }
+    }
+
+    /// Advances the path selectors past an element of the innermost list,
+    /// which is an atom ending at `next_index` or a list starting at
+    /// `this_index`. For a list, pushes its frame.
+    fn path_element<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input, this_index: usize, next_index: usize, is_list: bool) {
+        let child_start = self.path_active.len();
+        match self.path_frames.last_mut() {
+            None => {
+                // a record: every path starts here
+                for path in 0..self.paths.len() {
+                    self.path_active.push(PathActive { path: path as u32, step: 0, state: PathState::Searching });
+                }
+            },
+            Some((active_start, index)) => {
+                let (active_start, index) = (*active_start, std::mem::replace(index, *index + 1));
+                for j in active_start..child_start {
+                    let entry = self.path_active[j];
+                    let (column, path) = self.paths[entry.path as usize];
+                    let step = entry.step as usize;
+                    if step < path.steps.len() {
+                        let advance = match (&path.steps[step], entry.state) {
+                            (PathStep::Nth(n), _) => index == *n,
+                            (PathStep::Tag(tag), PathState::Searching) => {
+                                if index == 0 && !is_list && self.atom_is(input, this_index, next_index, tag) {
+                                    self.path_active[j].state = PathState::TagMatched;
+                                }
+                                false
+                            },
+                            (PathStep::Tag(_), PathState::TagMatched) => index == 1,
+                            _ => false,
+                        };
+                        if !advance {
+                            continue;
+                        }
+                        let state = match (step + 1 == path.steps.len() && path.key.is_none(), is_list) {
+                            (true, true) => PathState::Focus(this_index),
+                            (true, false) => {
+                                self.select(writer, column as u32, input, this_index..next_index);
+                                continue;
+                            },
+                            (false, true) => PathState::Searching,
+                            (false, false) => continue,
+                        };
+                        self.path_active.push(PathActive { path: entry.path, step: entry.step + 1, state });
+                    } else if let Some(key) = &path.key {
+                        // searching for `(key value)` within the selected element
+                        let state = match (entry.state, index) {
+                            (PathState::Searching, 0) if !is_list && self.atom_is(input, this_index, next_index, key) => PathState::KeyMatched,
+                            (PathState::KeyMatched, 1) => PathState::Value(this_index),
+                            _ => PathState::Dead,
+                        };
+                        self.path_active[j].state = state;
+                        if is_list {
+                            self.path_active.push(PathActive { state: PathState::Searching, ..entry });
+                        }
+                    }
+                }
+            },
+        }
+        if is_list {
+            self.path_frames.push((child_start, 0));
+        } else {
+            self.path_active.truncate(child_start);
+        }
+    }
+
+    /// Pops the frame of the innermost list, which ends at `this_index`.
+    fn path_close<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input, this_index: usize) {
+        let (active_start, _) = self.path_frames.pop().unwrap();
+        for j in active_start..self.path_active.len() {
+            let entry = self.path_active[j];
+            let column = self.paths[entry.path as usize].0 as u32;
+            match entry.state {
+                PathState::Focus(start) => self.select(writer, column, input, start..(this_index + 1)),
+                PathState::Value(start) => self.select(writer, column, input, start..this_index),
+                _ => (),
+            }
+        }
+        self.path_active.truncate(active_start);
+    }
+
+    /// Outputs the buffered record, applying the filter and the policies.
+    fn output_record<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input) -> Result<(), parser::Error> {
+        let keep = match self.filter {
+            Some(filter) => self.filter_record(input, filter),
+            None => true,
+        };
+        let result = if keep { self.output_fields(writer, input) } else { Ok(()) };
+        self.record.clear();
+        result
+    }
+
+    fn output_fields<WriteT: Write>(&mut self, writer: &mut WriteT, input: &parser::Input) -> Result<(), parser::Error> {
+        if self.duplicate_keys.is_none() && self.missing_keys.is_none() {
+            for (key_id, value_range) in self.record.iter() {
+                if let Some((column, key)) = resolve_key_id(&self.select_vec, &self.pattern_matches, *key_id) {
+                    self.output.select_key(writer, &self.select_vec, column, key, input, value_range.clone(), self.has_output_on_line);
+                    self.has_output_on_line = true;
+                }
+            }
+            return Ok(());
+        }
+
+        self.record_columns.clear();
+        for (key_id, value_range) in self.record.iter() {
+            if let Some((column, _)) = resolve_key_id(&self.select_vec, &self.pattern_matches, *key_id) {
+                self.record_columns.push((column, *key_id, value_range.clone()));
+            }
+        }
+        // stable, so the occurrences of each key stay in order
+        self.record_columns.sort_by_key(|(column, _, _)| *column);
+
+        let mut num_present = 0;
+        let mut last_column = None;
+        for (column, _, _) in self.record_columns.iter() {
+            if last_column != Some(*column) {
+                num_present += 1;
+                last_column = Some(*column);
+            }
+        }
+        if num_present < self.select_vec.len() {
+            match self.missing_keys {
+                Some(MissingKeys::SkipRow) => return Ok(()),
+                Some(MissingKeys::Error) => return Err(parser::Error::MissingKey),
+                _ => (),
+            }
+        }
+
+        let mut start = 0;
+        for column in 0..self.select_vec.len() {
+            let end = start + self.record_columns[start..].iter().take_while(|(c, _, _)| *c == column).count();
+            if start == end {
+                let missing_value = match self.missing_keys {
+                    Some(MissingKeys::Empty) => &b"\"\""[..],
+                    Some(MissingKeys::Null(marker)) => marker,
+                    _ => continue,
+                };
+                self.output.select_value(writer, &self.select_vec, column, self.select_vec[column], missing_value, self.has_output_on_line);
+                self.has_output_on_line = true;
+                continue;
+            }
+            let fields = &self.record_columns[start..end];
+            for (i, (_, key_id, _)) in fields.iter().enumerate() {
+                if fields[..i].iter().any(|(_, other_key_id, _)| other_key_id == key_id) {
+                    continue;
+                }
+                let mut occurrences = fields[i..].iter().filter(|(_, other_key_id, _)| other_key_id == key_id).map(|(_, _, value_range)| value_range.clone());
+                let (_, key) = resolve_key_id(&self.select_vec, &self.pattern_matches, *key_id).unwrap();
+                let select_vec = &self.select_vec;
+                let has_output_on_line = &mut self.has_output_on_line;
+                let output = &mut self.output;
+                let mut select = |value_range: Range<usize>| {
+                    output.select_key(writer, select_vec, column, key, input, value_range, *has_output_on_line);
+                    *has_output_on_line = true;
+                };
+                match self.duplicate_keys {
+                    None => occurrences.for_each(select),
+                    Some(DuplicateKeys::First) => select(occurrences.next().unwrap()),
+                    Some(DuplicateKeys::Last) => select(occurrences.next_back().unwrap()),
+                    Some(DuplicateKeys::Error) => {
+                        let value = occurrences.next().unwrap();
+                        if occurrences.next().is_some() {
+                            return Err(parser::Error::DuplicateKey);
+                        }
+                        select(value)
+                    },
+                    Some(DuplicateKeys::List) => {
+                        self.list_buf.clear();
+                        self.list_buf.push(b'(');
+                        for (i, value_range) in occurrences.enumerate() {
+                            if i > 0 {
+                                self.list_buf.push(b' ');
+                            }
+                            self.list_buf.extend_from_slice(input_value(input, value_range).trim_ascii_end());
+                        }
+                        self.list_buf.push(b')');
+                        output.select_value(writer, select_vec, column, key, &self.list_buf[..], *has_output_on_line);
+                        *has_output_on_line = true;
+                    },
+                }
+            }
+            start = end;
+        }
+        Ok(())
+    }
 }
 
 impl<'a, OutputT: Output> parser::WritingStage2 for Stage2<'a, OutputT> {
     fn reset(&mut self) {
+        self.record.clear();
+        self.path_frames.clear();
+        self.path_active.clear();
         self.output.reset(&self.select_vec);
     }
 
@@ -212,13 +1023,15 @@ impl<'a, OutputT: Output> parser::WritingStage2 for Stage2<'a, OutputT> {
                     },
                     None => (),
                 }
+                if !self.paths.is_empty() {
+                    self.path_element(writer, &input, this_index, this_index + 1, true);
+                }
                 self.stack.push(State::Start);
             }
             b')' => {
                 match self.stack.pop() {
                     Some(State::Selected(key_id, start_offset)) => {
-                        self.output.select(writer, &self.select_vec, key_id as usize, &input, start_offset..this_index, self.has_output_on_line);
-                        self.has_output_on_line = true;
+                        self.select(writer, key_id, &input, start_offset..this_index);
                     },
                     None => {
                         utils::cold();
@@ -226,7 +1039,18 @@ impl<'a, OutputT: Output> parser::WritingStage2 for Stage2<'a, OutputT> {
                     },
                     Some(_) => (),
                 }
+                if !self.paths.is_empty() {
+                    self.path_close(writer, &input, this_index);
+                }
 
+                if self.stack.is_empty() && self.buffer_records {
+                    self.output_record(writer, &input)?;
+                }
+                if self.stack.len() == 0 && self.pattern_cache.len() > MAX_PATTERN_MATCHES {
+                    // no key ids are in use between records
+                    self.pattern_cache.clear();
+                    self.pattern_matches.truncate(self.num_fixed_pattern_matches);
+                }
                 if self.stack.len() == 0 && self.has_output_on_line {
                     self.output.eol(writer, &input);
                     self.has_output_on_line = false;
@@ -248,6 +1072,9 @@ impl<'a, OutputT: Output> parser::WritingStage2 for Stage2<'a, OutputT> {
                             &mut out[..])
                         .ok_or(parser::Error::BadQuotedAtom)?;
                 }
+                if !self.paths.is_empty() && !self.path_frames.is_empty() {
+                    self.path_element(writer, &input, this_index, next_index, false);
+                }
                 let stack_index = self.stack.len().wrapping_sub(1);
                 match self.stack.get_mut(stack_index) {
                     Some(stack_element) => {
@@ -266,12 +1093,11 @@ impl<'a, OutputT: Output> parser::WritingStage2 for Stage2<'a, OutputT> {
                                         self.unescape.unescape(
                                             &input.input[(this_index + 1 - input.offset)..(next_index - input.offset)],
                                             &mut buf[..])
-                                                     .and_then(|(_, output_len)| self.select_tree.get(&buf[..output_len]))
-                                                     .map(|x| *x)
+                                                     .and_then(|(_, output_len)| self.key_id(&buf[..output_len]))
                                     } else {
-                                        self.select_tree.get(&input.input[(this_index - input.offset)..(next_index - input.offset)]).map(|x| *x)
+                                        self.key_id(&input.input[(this_index - input.offset)..(next_index - input.offset)])
                                     };
-                                *stack_element = match key_id {
+                                self.stack[stack_index] = match key_id {
                                     None => State::Ignore,
                                     Some(key_id) => State::SelectNext(key_id),
                                 }
@@ -299,41 +1125,89 @@ pub fn make_parser<'a, KeysT: IntoIterator<Item = &'a [u8]>, ReadT: BufRead + Se
     (keys: KeysT, stdout: &'a mut WriteT, output_kind: OutputKind, threads: bool)
     -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
 {
-    let keys: Vec<&'a [u8]> = keys.into_iter().collect();
+    make_parser_with_options(keys, Options::default(), stdout, output_kind, threads)
+}
 
+pub fn make_parser_with_options<'a, KeysT: IntoIterator<Item = &'a [u8]>, ReadT: BufRead + Send, WriteT: Write>
+    (keys: KeysT, options: Options<'a>, stdout: &'a mut WriteT, output_kind: OutputKind, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
     #[cfg(feature = "threads")]
     if threads {
-        let chunk_size = 256 * 1024;
-        return match output_kind {
-            OutputKind::Values =>
-                parser_parallel::streaming_from_writing_stage2(move || {
-                    Stage2::new(keys.clone(), OutputValues::new())
-                }, stdout, chunk_size),
-            OutputKind::Labeled =>
-                parser_parallel::streaming_from_writing_stage2(move || {
-                    Stage2::new(keys.clone(), OutputLabeled::new())
-                }, stdout, chunk_size),
-            OutputKind::Csv { atoms_as_sexps } => {
-                OutputCsv::print_header(keys.iter().map(|x| *x), stdout);
-                parser_parallel::streaming_from_writing_stage2(move || {
-                    Stage2::new(keys.clone(), OutputCsv::new(atoms_as_sexps))
-                }, stdout, chunk_size)
-            },
-        };
+        return make_parser_with_config(keys, options, stdout, output_kind, parser_parallel::ParallelConfig::default());
     }
 
     #[cfg(not(feature = "threads"))]
     let _ = threads;
 
+    let keys: Vec<&'a [u8]> = keys.into_iter().collect();
+    let columns = || keys.iter().copied().chain(options.extra_columns());
+
+    #[cfg(feature = "arrow")]
+    if let OutputKind::Arrow { format, column_types } = output_kind {
+        return select_arrow::make_parser(keys, options, stdout, format, column_types, false);
+    }
+
+    match output_kind {
+        OutputKind::Values =>
+            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputValues::new()).with_options(options), stdout),
+        OutputKind::Labeled =>
+            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputLabeled::new()).with_options(options), stdout),
+        OutputKind::Csv { atoms_as_sexps } => {
+            OutputCsv::print_header(columns(), stdout);
+            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputCsv::new(atoms_as_sexps)).with_options(options), stdout)
+        },
+        OutputKind::JsonLines =>
+            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputJsonLines::new()).with_options(options), stdout),
+        OutputKind::Delimited(delimited_options) => {
+            OutputDelimited::print_header(&delimited_options, columns(), stdout);
+            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputDelimited::new(delimited_options)).with_options(options), stdout)
+        },
+        #[cfg(feature = "arrow")]
+        OutputKind::Arrow { .. } => unreachable!(),
+    }
+}
+
+#[cfg(feature = "threads")]
+pub fn make_parser_with_config<'a, KeysT: IntoIterator<Item = &'a [u8]>, ReadT: BufRead + Send, WriteT: Write>
+    (keys: KeysT, options: Options<'a>, stdout: &'a mut WriteT, output_kind: OutputKind, config: parser_parallel::ParallelConfig)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    let keys: Vec<&'a [u8]> = keys.into_iter().collect();
+    let columns = || keys.iter().copied().chain(options.extra_columns());
+
+    #[cfg(feature = "arrow")]
+    if let OutputKind::Arrow { format, column_types } = output_kind {
+        return select_arrow::make_parser_with_config(keys, options, stdout, format, column_types, config);
+    }
+
     match output_kind {
         OutputKind::Values =>
-            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputValues::new()), stdout),
+            parser_parallel::streaming_from_writing_stage2(move || {
+                Stage2::new(keys.clone(), OutputValues::new()).with_options(options)
+            }, stdout, config),
         OutputKind::Labeled =>
-            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputLabeled::new()), stdout),
+            parser_parallel::streaming_from_writing_stage2(move || {
+                Stage2::new(keys.clone(), OutputLabeled::new()).with_options(options)
+            }, stdout, config),
         OutputKind::Csv { atoms_as_sexps } => {
-            OutputCsv::print_header(keys.iter().map(|x| *x), stdout);
-            parser::streaming_from_writing_stage2(Stage2::new(keys, OutputCsv::new(atoms_as_sexps)), stdout)
+            OutputCsv::print_header(columns(), stdout);
+            parser_parallel::streaming_from_writing_stage2(move || {
+                Stage2::new(keys.clone(), OutputCsv::new(atoms_as_sexps)).with_options(options)
+            }, stdout, config)
+        },
+        OutputKind::JsonLines =>
+            parser_parallel::streaming_from_writing_stage2(move || {
+                Stage2::new(keys.clone(), OutputJsonLines::new()).with_options(options)
+            }, stdout, config),
+        OutputKind::Delimited(delimited_options) => {
+            OutputDelimited::print_header(&delimited_options, columns(), stdout);
+            parser_parallel::streaming_from_writing_stage2(move || {
+                Stage2::new(keys.clone(), OutputDelimited::new(delimited_options.clone())).with_options(options)
+            }, stdout, config)
         },
+        #[cfg(feature = "arrow")]
+        OutputKind::Arrow { .. } => unreachable!(),
     }
 }
 
@@ -369,6 +1243,8 @@ mod ocaml_ffi {
         }
     }
 
+    // Only passed in from OCaml, which has a constructor for just some of
+    // the kinds, so there is no `IntoValue`.
     unsafe impl<'a> ocaml::FromValue<'a> for OutputKind {
         fn from_value(v: ocaml::Value) -> Self {
             unsafe {
@@ -386,19 +1262,6 @@ mod ocaml_ffi {
         }
     }
 
-    unsafe impl ocaml::IntoValue for OutputKind {
-        fn into_value(self, _rt: &ocaml::Runtime) -> ocaml::Value {
-            match self {
-                OutputKind::Values => Self::values(),
-                OutputKind::Labeled => Self::labeled(),
-                OutputKind::Csv { atoms_as_sexps: false } => Self::csv(),
-                OutputKind::Csv { atoms_as_sexps: true } =>
-                    // TODO
-                    unimplemented!(),
-            }
-        }
-    }
-
     #[ocaml::func]
     pub fn ml_multi_select(keys: LinkedList<ByteString>, output_kind: OutputKind, threads: bool) {
         let mut stdin = utils::stdin();
@@ -416,15 +1279,31 @@ mod tests {
     use super::*;
 
     fn run_test(output_kind: OutputKind, input: &[u8], keys: &[&[u8]], expected_output: Result<&[u8], parser::Error>) {
-        let mut output = Vec::new();
-        let mut parser = make_parser(keys.iter().map(|x| *x), &mut output, output_kind, false);
-        let ok = parser.process_streaming(&mut std::io::BufReader::new(input));
-        std::mem::drop(parser);
-        let output = ok.map(move |()| output);
+        run_test_with_options(output_kind, input, keys, None, &[], expected_output)
+    }
+
+    fn run_test_with_filter(output_kind: OutputKind, input: &[u8], keys: &[&[u8]], filter: Option<&str>, expected_output: Result<&[u8], parser::Error>) {
+        run_test_with_options(output_kind, input, keys, filter, &[], expected_output)
+    }
+
+    fn run_test_with_options(output_kind: OutputKind, input: &[u8], keys: &[&[u8]], filter: Option<&str>, patterns: &[KeyPattern], expected_output: Result<&[u8], parser::Error>) {
+        let filter = filter.map(|filter| select_filter::Predicate::parse(filter).unwrap());
+        run_test_with_policies(output_kind, input, keys, Options { filter: filter.as_ref(), patterns, ..Options::default() }, expected_output)
+    }
 
-        assert_eq!(output.map(|output| String::from_utf8(output).unwrap()),
-                   expected_output.map(|expected_output| String::from_utf8(expected_output.to_owned()).unwrap()),
-                   "output_kind: {:?}", output_kind);
+    fn run_test_with_policies(output_kind: OutputKind, input: &[u8], keys: &[&[u8]], options: Options, expected_output: Result<&[u8], parser::Error>) {
+        let threads_options: &[bool] = if cfg!(feature = "threads") { &[false, true] } else { &[false] };
+        for &threads in threads_options {
+            let mut output = Vec::new();
+            let mut parser = make_parser_with_options(keys.iter().map(|x| *x), options, &mut output, output_kind.clone(), threads);
+            let ok = parser.process_streaming(&mut std::io::BufReader::new(input));
+            std::mem::drop(parser);
+            let output = ok.map(move |()| output);
+
+            assert_eq!(output.map(|output| String::from_utf8(output).unwrap()),
+                       expected_output.clone().map(|expected_output| String::from_utf8(expected_output.to_owned()).unwrap()),
+                       "output_kind: {:?}, threads: {}", output_kind, threads);
+        }
     }
 
     #[test]
@@ -611,4 +1490,235 @@ bar baz
             keys,
             Ok(b"\n"));
     }
+
+    #[test]
+    fn test_json_lines() {
+        let input = br#"((foo "bar \"baz\"") (qux (1 2)) (other x))
+((qux 3) (foo a\b))
+((other y))
+"#;
+        let keys = &[&b"foo"[..], &b"qux"[..]];
+        run_test(
+            OutputKind::JsonLines,
+            input,
+            keys,
+            Ok(br#"{"foo":"bar \"baz\"","qux":"(1 2)"}
+{"qux":"3","foo":"a\\b"}
+"#));
+    }
+
+    #[test]
+    fn test_delimited() {
+        let input = b"((foo \"a\\tb\") (bar \"c|d\"))\n((bar x\\y))\n((foo \"e\\\"f\"))\n";
+        let keys = &[&b"foo"[..], &b"bar"[..]];
+        run_test(
+            OutputKind::Delimited(DelimitedOptions::tsv()),
+            input,
+            keys,
+            Ok(b"foo\tbar\na\\tb\tc|d\n\\N\tx\\\\y\ne\"f\t\\N\n"));
+        run_test(
+            OutputKind::Delimited(DelimitedOptions { delimiter: b'|', quoting: Quoting::Necessary, null: b"NULL".to_vec() }),
+            input,
+            keys,
+            Ok(b"foo|bar\na\tb|\"c|d\"\nNULL|x\\y\n\"e\"\"f\"|NULL\n"));
+        run_test(
+            OutputKind::Delimited(DelimitedOptions { delimiter: b'|', quoting: Quoting::Always, null: Vec::new() }),
+            input,
+            keys,
+            Ok(b"\"foo\"|\"bar\"\n\"a\tb\"|\"c|d\"\n|\"x\\y\"\n\"e\"\"f\"|\n"));
+    }
+
+    #[test]
+    fn test_many_lines_threaded() {
+        let line = b"((foo \"a b\") (bar 1))\n";
+        let input = line.repeat(100000);
+        let expected_json: Vec<u8> = b"{\"foo\":\"a b\",\"bar\":\"1\"}\n".repeat(100000);
+        run_test(OutputKind::JsonLines, &input[..], &[&b"foo"[..], &b"bar"[..]], Ok(&expected_json[..]));
+        let mut expected_tsv = b"foo\tbar\n".to_vec();
+        expected_tsv.extend(b"a b\t1\n".repeat(100000));
+        run_test(OutputKind::Delimited(DelimitedOptions::tsv()), &input[..], &[&b"foo"[..], &b"bar"[..]], Ok(&expected_tsv[..]));
+    }
+
+    #[test]
+    fn test_filter() {
+        let input = br#"((name a) (price 5) (tags (x y)))
+((name "b c") (price 12.5))
+((name d) (price x))
+((name e))
+((price 20) (name f) (extra "g\"h"))
+"#;
+        let keys = &[&b"name"[..], &b"price"[..]];
+        let csv = |filter: &str, expected: &[u8]| {
+            run_test_with_filter(OutputKind::Csv { atoms_as_sexps: false }, input, keys, Some(filter), Ok(expected));
+        };
+        csv("price > 10", b"name,price\nb c,12.5\nf,20\n");
+        csv("price <= 5 or name = \"b c\"", b"name,price\na,5\nb c,12.5\n");
+        csv("not has price", b"name,price\ne,\n");
+        csv("price != 5", b"name,price\nb c,12.5\nd,x\nf,20\n");
+        // filter keys need not be selected
+        csv("extra = \"g\\\"h\"", b"name,price\nf,20\n");
+        #[cfg(feature = "regex")]
+        csv("tags ~ \"^\\\\(x\"", b"name,price\na,5\n");
+        #[cfg(feature = "regex")]
+        csv("name ~ ^[ab] and not price < 10", b"name,price\nb c,12.5\n");
+        run_test_with_filter(OutputKind::Labeled, input, keys, Some("price >= 12.5"), Ok(b"((name \"b c\")(price 12.5))\n((price 20)(name f))\n"));
+        run_test_with_filter(OutputKind::Values, input, &[&b"price"[..]], Some("has extra or name = d"), Ok(b"(x)\n(20)\n"));
+        run_test_with_filter(OutputKind::JsonLines, input, &[&b"name"[..]], Some("price > 100"), Ok(b""));
+    }
+
+    #[test]
+    fn test_filter_many_lines() {
+        let mut input = Vec::new();
+        let mut expected = b"id\n".to_vec();
+        for i in 0..100000 {
+            input.extend_from_slice(format!("((id {}) (even {}))\n", i, i % 2 == 0).as_bytes());
+            if i % 2 == 0 && i >= 50000 {
+                expected.extend_from_slice(format!("{}\n", i).as_bytes());
+            }
+        }
+        run_test_with_filter(OutputKind::Csv { atoms_as_sexps: false }, &input[..], &[&b"id"[..]], Some("even = true and id >= 50000"), Ok(&expected[..]));
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_glob() {
+        let pattern = KeyPattern::glob("lat[!x]ncy_*").unwrap();
+        for (key, expected) in [("latency_p50", true), ("latency_", true), ("latxncy_p50", false), ("latency", false), ("xlatency_p50", false)] {
+            assert_eq!(pattern.is_match(key.as_bytes()), expected, "{}", key);
+        }
+        assert!(KeyPattern::glob("a.b?").unwrap().is_match(b"a.bc"));
+        assert!(!KeyPattern::glob("a.b?").unwrap().is_match(b"axbc"));
+        assert!(KeyPattern::glob("[ab").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_patterns() {
+        let input = br#"((name a) (latency_p50 1) ("latency_p99" 2) (latency_max 3) (size 4))
+((latency_p50 5) (name b) ("latency max" 6))
+((size 7))
+"#;
+        let patterns = [KeyPattern::glob("latency*").unwrap()];
+        let keys = &[&b"name"[..]];
+        run_test_with_options(OutputKind::Labeled, input, keys, None, &patterns,
+            Ok(b"((name a)(latency_p50 1)(latency_p99 2)(latency_max 3))\n((latency_p50 5)(name b)(\"latency max\" 6))\n"));
+        run_test_with_options(OutputKind::JsonLines, input, keys, None, &patterns,
+            Ok(b"{\"name\":\"a\",\"latency_p50\":\"1\",\"latency_p99\":\"2\",\"latency_max\":\"3\"}\n{\"latency_p50\":\"5\",\"name\":\"b\",\"latency max\":\"6\"}\n"));
+        run_test_with_options(OutputKind::Values, input, keys, None, &patterns,
+            Ok(b"(a 1 2 3)\n(5 b 6)\n"));
+        // explicitly selected keys take precedence over patterns
+        let patterns = [KeyPattern::regex("^latency_p[0-9]+$").unwrap(), KeyPattern::regex("^s").unwrap()];
+        run_test_with_options(OutputKind::Csv { atoms_as_sexps: false }, input, &[&b"latency_p50"[..]], None, &patterns,
+            Ok(b"latency_p50,^latency_p[0-9]+$,^s\n1,2,4\n5,,\n,,7\n"));
+        run_test_with_options(OutputKind::JsonLines, input, &[], Some("latency_p50 > 2"), &patterns,
+            Ok(b"{\"latency_p50\":\"5\"}\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_patterns_many_distinct_keys() {
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..100000 {
+            input.extend_from_slice(format!("((k{} {}) (other{} x))\n", i, i, i).as_bytes());
+            expected.extend_from_slice(format!("((k{} {}))\n", i, i).as_bytes());
+        }
+        let patterns = [KeyPattern::glob("k*").unwrap()];
+        run_test_with_options(OutputKind::Labeled, &input[..], &[], None, &patterns, Ok(&expected[..]));
+    }
+
+    #[test]
+    fn test_policies() {
+        let input = br#"((a 1) (b x) (a 2))
+((b y))
+((b "p q") (c z) (a 3))
+"#;
+        let keys = &[&b"a"[..], &b"b"[..]];
+        let policies = |duplicate_keys, missing_keys| Options { duplicate_keys, missing_keys, ..Options::default() };
+        run_test_with_policies(OutputKind::Labeled, input, keys, policies(None, None),
+            Ok(b"((a 1)(b x)(a 2))\n((b y))\n((b \"p q\")(a 3))\n"));
+        // keys are output in the order they were selected in
+        run_test_with_policies(OutputKind::Labeled, input, keys, policies(Some(DuplicateKeys::First), Some(MissingKeys::Empty)),
+            Ok(b"((a 1)(b x))\n((a \"\")(b y))\n((a 3)(b \"p q\"))\n"));
+        run_test_with_policies(OutputKind::Values, input, keys, policies(Some(DuplicateKeys::Last), Some(MissingKeys::Null(b"NULL"))),
+            Ok(b"(2 x)\n(NULL y)\n(3 \"p q\")\n"));
+        run_test_with_policies(OutputKind::Csv { atoms_as_sexps: false }, input, keys, policies(Some(DuplicateKeys::First), Some(MissingKeys::Null(b"NULL"))),
+            Ok(b"a,b\n1,x\nNULL,y\n3,p q\n"));
+        run_test_with_policies(OutputKind::JsonLines, input, keys, policies(Some(DuplicateKeys::List), Some(MissingKeys::SkipRow)),
+            Ok(b"{\"a\":\"(1 2)\",\"b\":\"(x)\"}\n{\"a\":\"(3)\",\"b\":\"(\\\"p q\\\")\"}\n"));
+        run_test_with_policies(OutputKind::Csv { atoms_as_sexps: false }, input, keys, policies(Some(DuplicateKeys::List), Some(MissingKeys::Null(b"NULL"))),
+            Ok(b"a,b\n(1 2),(x)\nNULL,(y)\n(3),\"(\"\"p q\"\")\"\n"));
+        // the empty string is distinguishable from a missing key
+        run_test_with_policies(OutputKind::Delimited(DelimitedOptions::tsv()), input, keys, policies(None, Some(MissingKeys::Empty)),
+            Ok(b"a\tb\n2\tx\n\ty\n3\tp q\n"));
+        run_test_with_policies(OutputKind::Delimited(DelimitedOptions::tsv()), input, keys, policies(Some(DuplicateKeys::Last), None),
+            Ok(b"a\tb\n2\tx\n\\N\ty\n3\tp q\n"));
+        run_test_with_policies(OutputKind::Csv { atoms_as_sexps: false }, input, keys, policies(Some(DuplicateKeys::Error), None),
+            Err(parser::Error::DuplicateKey));
+        run_test_with_policies(OutputKind::Csv { atoms_as_sexps: false }, input, keys, policies(Some(DuplicateKeys::First), Some(MissingKeys::Error)),
+            Err(parser::Error::MissingKey));
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_policies_with_filter_and_patterns() {
+        let input = br#"((name a) (lat_p50 1) (lat_p50 2) (lat_p99 3))
+((name b) (size 4))
+((lat_p99 5) (size 6))
+"#;
+        let filter = select_filter::Predicate::parse("has name").unwrap();
+        let patterns = [KeyPattern::glob("lat_*").unwrap()];
+        let options = Options {
+            filter: Some(&filter),
+            patterns: &patterns,
+            duplicate_keys: Some(DuplicateKeys::Last),
+            missing_keys: Some(MissingKeys::Null(b"-")),
+            ..Options::default()
+        };
+        run_test_with_policies(OutputKind::Labeled, input, &[&b"name"[..]], options,
+            Ok(b"((name a)(lat_p50 2)(lat_p99 3))\n((name b)(lat_* -))\n"));
+        run_test_with_policies(OutputKind::Csv { atoms_as_sexps: false }, input, &[&b"name"[..]], options,
+            Ok(b"name,lat_*\na,3\nb,-\n"));
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let path = PathSelector::parse("#2/@\"Some\"/id").unwrap();
+        assert_eq!(path.steps, vec![PathStep::Nth(2), PathStep::Tag(b"\"Some\"".to_vec())]);
+        assert_eq!(path.key, Some(b"id".to_vec()));
+        assert_eq!(PathSelector::parse("@Order").unwrap().key, None);
+        for input in ["", "id", "#", "#x", "@", "@A/id/#1", "@A//id", "#1/"] {
+            assert_eq!(PathSelector::parse(input).err(), Some(parser::Error::InvalidPath), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        let input = br#"(Order ((id 1) (qty 5)))
+(Cancel ((id 2)))
+(1 (x y) "z")
+("Order" ((qty 3) (nested ((id 9)))) extra)
+(Some (Order (id 4)))
+"#;
+        let paths = ["@Order/id", "#1", "@Order", "#1/@Order", "#1/#1/#0", "@Some/@Order/id"].map(|path| PathSelector::parse(path).unwrap());
+        let options = Options { paths: &paths, ..Options::default() };
+        run_test_with_policies(OutputKind::Csv { atoms_as_sexps: true }, input, &[&b"id"[..]], options, Ok(br#"id,@Order/id,#1,@Order,#1/@Order,#1/#1/#0,@Some/@Order/id
+1,1,((id 1) (qty 5)),((id 1) (qty 5)),,qty,
+2,,((id 2)),,,,
+,,(x y),,,,
+9,9,((qty 3) (nested ((id 9)))),((qty 3) (nested ((id 9)))),,nested,
+4,,(Order (id 4)),,(id 4),id,4
+"#));
+        let paths = [PathSelector::parse("@Order/id").unwrap(), PathSelector::parse("#2").unwrap()];
+        let options = Options { paths: &paths, missing_keys: Some(MissingKeys::SkipRow), ..Options::default() };
+        run_test_with_policies(OutputKind::Labeled, input, &[], options, Ok(b"((@Order/id 9)(#2 extra))\n"));
+    }
+
+    #[test]
+    fn test_error_in_later_chunk() {
+        let mut input = b"((foo \"a b\") (bar 1))\n".repeat(100000);
+        input.extend_from_slice(b"((foo x)))\n");
+        input.extend(b"((foo y))\n".repeat(100000));
+        run_test(OutputKind::JsonLines, &input[..], &[&b"foo"[..]], Err(parser::Error::UnmatchedCloseParen));
+    }
 }
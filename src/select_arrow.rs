@@ -0,0 +1,649 @@
+use crate::escape::{self, Unescape};
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use crate::select;
+#[cfg(feature = "threads")]
+use crate::structural;
+use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, RecordBatchOptions, StringArray};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// The Arrow IPC file format (random access, with a footer).
+    File,
+    /// The Arrow IPC streaming format.
+    Stream,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ColumnType {
+    Int,
+    Float,
+    Bool,
+    String,
+}
+
+impl ColumnType {
+    pub fn of_string(s: &str) -> Option<Self> {
+        match s {
+            "int" => Some(ColumnType::Int),
+            "float" => Some(ColumnType::Float),
+            "bool" => Some(ColumnType::Bool),
+            "string" => Some(ColumnType::String),
+            _ => None,
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        match self {
+            ColumnType::Int => DataType::Int64,
+            ColumnType::Float => DataType::Float64,
+            ColumnType::Bool => DataType::Boolean,
+            ColumnType::String => DataType::Utf8,
+        }
+    }
+
+    /// The narrowest type that can hold an unquoted atom.
+    fn infer(atom: &[u8]) -> Self {
+        if atom == b"true" || atom == b"false" {
+            return ColumnType::Bool;
+        }
+        let s = match std::str::from_utf8(atom) {
+            Ok(s) => s,
+            Err(_) => return ColumnType::String,
+        };
+        if s.parse::<i64>().is_ok() {
+            ColumnType::Int
+        } else if s.bytes().any(|ch| ch.is_ascii_digit()) && s.parse::<f64>().is_ok() {
+            ColumnType::Float
+        } else {
+            ColumnType::String
+        }
+    }
+
+    /// Whether a value of this type can be stored in a column of `column_type`
+    /// without loss.
+    fn fits(self, column_type: Self) -> bool {
+        self == column_type || column_type == ColumnType::String || (self == ColumnType::Int && column_type == ColumnType::Float)
+    }
+
+    fn unify(a: Option<Self>, b: Self) -> Self {
+        match (a, b) {
+            (None, b) => b,
+            (Some(a), b) if a == b => a,
+            (Some(ColumnType::Int), ColumnType::Float) | (Some(ColumnType::Float), ColumnType::Int) => ColumnType::Float,
+            _ => ColumnType::String,
+        }
+    }
+}
+
+/// The selected values of one column of a chunk, as (unescaped) text.
+#[derive(Default)]
+struct RawColumn {
+    data: Vec<u8>,
+    /// `offsets[i]..offsets[i + 1]` is the value of row `i`.
+    offsets: Vec<usize>,
+    /// The type of each value, `None` for nulls.
+    types: Vec<Option<ColumnType>>,
+    /// `None` if every value so far is null.
+    inferred_type: Option<ColumnType>,
+}
+
+impl RawColumn {
+    fn new() -> Self {
+        Self { offsets: vec![0], ..Self::default() }
+    }
+
+    fn push(&mut self, value: Option<(&[u8], ColumnType)>) {
+        if let Some((value, column_type)) = value {
+            self.data.extend_from_slice(value);
+            self.inferred_type = Some(ColumnType::unify(self.inferred_type, column_type));
+        }
+        self.offsets.push(self.data.len());
+        self.types.push(value.map(|(_, column_type)| column_type));
+    }
+
+    fn values(&self) -> impl Iterator<Item = Option<&[u8]>> {
+        self.types.iter().enumerate().map(move |(i, value_type)| {
+            value_type.map(|_| &self.data[self.offsets[i]..self.offsets[i + 1]])
+        })
+    }
+
+    fn parsed<T: std::str::FromStr>(&self) -> impl Iterator<Item = Option<T>> + '_ {
+        self.values().map(|value| value.and_then(|value| std::str::from_utf8(value).ok()?.parse().ok()))
+    }
+
+    /// Values that do not parse as `column_type` become null. Strings that are
+    /// not valid UTF-8 are converted lossily.
+    fn to_array(&self, column_type: ColumnType) -> ArrayRef {
+        match column_type {
+            ColumnType::Int => Arc::new(self.parsed::<i64>().collect::<Int64Array>()),
+            ColumnType::Float => Arc::new(self.parsed::<f64>().collect::<Float64Array>()),
+            ColumnType::Bool => Arc::new(self.parsed::<bool>().collect::<BooleanArray>()),
+            ColumnType::String => Arc::new(self.values().map(|value| value.map(String::from_utf8_lossy)).collect::<StringArray>()),
+        }
+    }
+}
+
+/// The rows selected from one chunk of input.
+pub struct RawBatch {
+    columns: Vec<RawColumn>,
+    num_rows: usize,
+}
+
+impl RawBatch {
+    fn new(num_columns: usize) -> Self {
+        Self {
+            columns: (0..num_columns).map(|_| RawColumn::new()).collect(),
+            num_rows: 0,
+        }
+    }
+
+    /// The types inferred for each column, with all-null columns as strings.
+    /// Numeric columns are floats, so that later batches may mix in floats.
+    fn inferred_types(&self) -> Vec<ColumnType> {
+        self.columns.iter().map(|column| {
+            match column.inferred_type {
+                None => ColumnType::String,
+                Some(ColumnType::Int) => ColumnType::Float,
+                Some(column_type) => column_type,
+            }
+        }).collect()
+    }
+
+    /// The column and row of the first value that does not fit `column_types`
+    /// without loss.
+    fn misfit(&self, column_types: &[ColumnType]) -> Option<(usize, usize, &[u8])> {
+        self.columns.iter().zip(column_types).enumerate().find_map(|(i, (column, column_type))| {
+            match column.inferred_type {
+                Some(inferred_type) if !inferred_type.fits(*column_type) => (),
+                _ => return None,
+            }
+            column.types.iter().zip(column.values()).enumerate().find_map(|(row, (value_type, value))| {
+                match (value_type, value) {
+                    (Some(value_type), Some(value)) if !value_type.fits(*column_type) => Some((i, row, value)),
+                    _ => None,
+                }
+            })
+        })
+    }
+
+    fn to_record_batch(&self, schema: &SchemaRef, column_types: &[ColumnType]) -> Result<RecordBatch, ArrowError> {
+        let columns = self.columns.iter().zip(column_types).map(|(column, column_type)| column.to_array(*column_type)).collect();
+        RecordBatch::try_new_with_options(schema.clone(), columns, &RecordBatchOptions::new().with_row_count(Some(self.num_rows)))
+    }
+}
+
+pub struct OutputArrow {
+    row: select::HeldRow,
+    batch: RawBatch,
+    unescaped: Vec<u8>,
+}
+
+impl OutputArrow {
+    pub fn new() -> Self {
+        Self {
+            row: select::HeldRow::default(),
+            batch: RawBatch::new(0),
+            unescaped: Vec::new(),
+        }
+    }
+
+    fn take_batch(&mut self) -> RawBatch {
+        let num_columns = self.batch.columns.len();
+        std::mem::replace(&mut self.batch, RawBatch::new(num_columns))
+    }
+}
+
+impl Default for OutputArrow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl select::Output for OutputArrow {
+    fn reset(&mut self, keys: &Vec<&[u8]>) {
+        self.row.reset(keys.len());
+        self.batch = RawBatch::new(keys.len());
+    }
+    fn select<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _input: &parser::Input, value_range: Range<usize>, _has_output_on_line: bool) {
+        self.row.hold(key_id, value_range);
+    }
+    fn select_value<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _key: &[u8], value: &[u8], _has_output_on_line: bool) {
+        self.row.hold_copy(key_id, value);
+    }
+    fn eol<WriteT: Write>(&mut self, _writer: &mut WriteT, input: &parser::Input) {
+        let columns = &mut self.batch.columns;
+        let unescaped = &mut self.unescaped;
+        self.row.drain(input, |i, value| {
+            let column = &mut columns[i];
+            let value = match value {
+                None => {
+                    column.push(None);
+                    return;
+                },
+                Some(value) => value.trim_ascii_end(),
+            };
+            match value[0] {
+                b'"' => {
+                    unescaped.resize(value.len(), 0u8);
+                    let (_, unescaped_len) = escape::GenericUnescape::new().unescape(&value[1..], &mut unescaped[..]).unwrap();
+                    column.push(Some((&unescaped[..unescaped_len], ColumnType::String)));
+                },
+                // lists are kept as sexp text
+                b'(' => column.push(Some((value, ColumnType::String))),
+                _ => column.push(Some((value, ColumnType::infer(value)))),
+            }
+        });
+        self.batch.num_rows += 1;
+    }
+}
+
+/// Runs `select::Stage2` for one chunk, returning the selected rows rather
+/// than writing them out.
+pub struct Stage2Adapter<'a> {
+    stage2: select::Stage2<'a, OutputArrow>,
+}
+
+impl<'a> Stage2Adapter<'a> {
+    pub fn new(keys: Vec<&'a [u8]>, options: select::Options<'a>) -> Self {
+        let mut stage2 = select::Stage2::new(keys, OutputArrow::new()).with_options(options);
+        // Ready for `process_partial`, which does not reset.
+        parser::WritingStage2::reset(&mut stage2);
+        Self { stage2 }
+    }
+}
+
+impl<'a> parser::Stage2 for Stage2Adapter<'a> {
+    type Return = RawBatch;
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        parser::WritingStage2::reset(&mut self.stage2);
+    }
+    #[inline(always)]
+    fn process_one(&mut self, input: parser::Input, this_index: usize, next_index: usize, is_eof: bool) -> Result<usize, parser::Error> {
+        parser::WritingStage2::process_one(&mut self.stage2, &mut std::io::sink(), input, this_index, next_index, is_eof)
+    }
+    fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
+        parser::WritingStage2::process_eof(&mut self.stage2, &mut std::io::sink())?;
+        Ok(self.stage2.output_mut().take_batch())
+    }
+}
+
+impl<'a> parser::ExtractPartialResult for Stage2Adapter<'a> {
+    type PartialReturn = RawBatch;
+    /// The rows completed so far.
+    fn extract_partial_result(&mut self) -> RawBatch {
+        self.stage2.output_mut().take_batch()
+    }
+}
+
+enum IpcWriter<'a, WriteT: Write> {
+    File(arrow_ipc::writer::FileWriter<&'a mut WriteT>),
+    Stream(arrow_ipc::writer::StreamWriter<&'a mut WriteT>),
+}
+
+fn to_parser_error(e: ArrowError) -> parser::Error {
+    match e {
+        ArrowError::IoError(_, e) => parser::Error::IOError(e.kind()),
+        _ => parser::Error::IOError(std::io::ErrorKind::Other),
+    }
+}
+
+/// Writes batches in the order they are pushed, each straight away. Without
+/// explicit column types, the types are inferred from the first batch with any
+/// rows, and a later value that does not fit them (such as `x` in a column of
+/// numbers) is a `ColumnTypeMismatch` error. Which rows make up the first batch
+/// depends on the chunk size and threads, so give the types where that matters.
+pub struct BatchWriter<'a, WriteT: Write> {
+    format: Format,
+    names: Vec<String>,
+    column_types: Option<Vec<ColumnType>>,
+    num_rows: usize,
+    writer: Option<&'a mut WriteT>,
+    ipc_writer: Option<(IpcWriter<'a, WriteT>, SchemaRef, Vec<ColumnType>)>,
+}
+
+impl<'a, WriteT: Write> BatchWriter<'a, WriteT> {
+    pub fn new(columns: &[&[u8]], writer: &'a mut WriteT, format: Format, column_types: Option<Vec<ColumnType>>) -> Self {
+        if let Some(column_types) = &column_types {
+            assert_eq!(column_types.len(), columns.len(), "there must be one column type per column");
+        }
+        Self {
+            format,
+            names: columns.iter().map(|key| String::from_utf8_lossy(key).into_owned()).collect(),
+            column_types,
+            num_rows: 0,
+            writer: Some(writer),
+            ipc_writer: None,
+        }
+    }
+
+    fn start(&mut self, column_types: Vec<ColumnType>) -> Result<(), ArrowError> {
+        let fields: Vec<Field> = self.names.iter().zip(column_types.iter())
+            .map(|(name, column_type)| Field::new(name, column_type.data_type(), true))
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        let writer = self.writer.take().unwrap();
+        let ipc_writer = match self.format {
+            Format::File => IpcWriter::File(arrow_ipc::writer::FileWriter::try_new(writer, &schema)?),
+            Format::Stream => IpcWriter::Stream(arrow_ipc::writer::StreamWriter::try_new(writer, &schema)?),
+        };
+        self.ipc_writer = Some((ipc_writer, schema, column_types));
+        Ok(())
+    }
+
+    fn write(&mut self, batch: &RawBatch) -> Result<(), ArrowError> {
+        let (ipc_writer, schema, column_types) = self.ipc_writer.as_mut().unwrap();
+        let batch = batch.to_record_batch(schema, column_types)?;
+        match ipc_writer {
+            IpcWriter::File(w) => w.write(&batch),
+            IpcWriter::Stream(w) => w.write(&batch),
+        }
+    }
+
+    pub fn push(&mut self, batch: RawBatch) -> Result<(), parser::Error> {
+        if batch.num_rows == 0 {
+            return Ok(());
+        }
+        match &self.ipc_writer {
+            None => {
+                let column_types = self.column_types.clone().unwrap_or_else(|| batch.inferred_types());
+                self.start(column_types).map_err(to_parser_error)?;
+            },
+            Some((_, _, column_types)) if self.column_types.is_none() => {
+                if let Some((i, row, value)) = batch.misfit(column_types) {
+                    return Err(parser::Error::ColumnTypeMismatch {
+                        column: self.names[i].clone(),
+                        row: self.num_rows + row,
+                        value: String::from_utf8_lossy(value).into_owned(),
+                    });
+                }
+            },
+            Some(_) => (),
+        }
+        self.num_rows += batch.num_rows;
+        self.write(&batch).map_err(to_parser_error)
+    }
+
+    pub fn finish(&mut self) -> Result<(), parser::Error> {
+        (|| {
+            if self.ipc_writer.is_none() {
+                let column_types = self.column_types.clone().unwrap_or_else(|| vec![ColumnType::String; self.names.len()]);
+                self.start(column_types)?;
+            }
+            match &mut self.ipc_writer.as_mut().unwrap().0 {
+                IpcWriter::File(w) => w.finish(),
+                IpcWriter::Stream(w) => w.finish(),
+            }
+        })().map_err(to_parser_error)
+    }
+}
+
+struct SingleThreaded<'a, WriteT: Write> {
+    keys: Vec<&'a [u8]>,
+    options: select::Options<'a>,
+    batch_writer: BatchWriter<'a, WriteT>,
+}
+
+/// Roughly how much input goes into each record batch written without threads.
+const BATCH_INPUT_SIZE: usize = 1024 * 1024;
+
+type PartialParser<'a> = Box<dyn parser::ParsePartial<Return = RawBatch, PartialReturn = RawBatch> + 'a>;
+
+impl<'a, WriteT: Write> SingleThreaded<'a, WriteT> {
+    fn new_parser(&self) -> PartialParser<'a> {
+        parser::partial_parser_new(Stage2Adapter::new(self.keys.clone(), self.options))
+    }
+
+    fn process_eof(&mut self, mut parser: PartialParser<'a>) -> Result<(), parser::Error> {
+        self.batch_writer.push(parser.process_eof()?)?;
+        self.batch_writer.finish()
+    }
+}
+
+impl<'a, ReadT: BufRead, WriteT: Write> parser::Stream<ReadT> for SingleThreaded<'a, WriteT> {
+    type Return = ();
+    fn process_streaming(&mut self, buf_reader: &mut ReadT) -> Result<(), parser::Error> {
+        let mut parser = self.new_parser();
+        let mut unbatched_len = 0;
+        loop {
+            match buf_reader.fill_buf() {
+                Ok(&[]) => { return self.process_eof(parser); },
+                Ok(buf) => {
+                    parser.process_partial(buf)?;
+                    let len = buf.len();
+                    buf_reader.consume(len);
+                    unbatched_len += len;
+                    if unbatched_len >= BATCH_INPUT_SIZE {
+                        self.batch_writer.push(parser.extract_partial_result())?;
+                        unbatched_len = 0;
+                    }
+                },
+                Err(e) => { return Err(parser::Error::IOError(e.kind())) },
+            }
+        }
+    }
+    fn process_slice(&mut self, input: &[u8]) -> Result<(), parser::Error> {
+        let mut parser = self.new_parser();
+        for piece in input.chunks(BATCH_INPUT_SIZE) {
+            parser.process_partial(piece)?;
+            self.batch_writer.push(parser.extract_partial_result())?;
+        }
+        self.process_eof(parser)
+    }
+}
+
+/// Each worker produces a `RawBatch` per chunk, which are written out as
+/// record batches in order.
+#[cfg(feature = "threads")]
+struct Joiner<'a, WriteT: Write, WorkerT> {
+    create_worker: Box<dyn Fn() -> WorkerT + 'a>,
+    batch_writer: BatchWriter<'a, WriteT>,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, WriteT: Write, WorkerT: parser::Parse<Return = RawBatch>> parser_parallel::Joiner for Joiner<'a, WriteT, WorkerT> {
+    type Worker = WorkerT;
+    type Return = ();
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+    }
+    fn create_worker(&mut self) -> Self::Worker {
+        (self.create_worker)()
+    }
+    fn join(&mut self, result: RawBatch) -> Result<(), parser::Error> {
+        self.batch_writer.push(result)
+    }
+    fn process_eof(&mut self) -> Result<(), parser::Error> {
+        self.batch_writer.finish()
+    }
+}
+
+#[cfg(feature = "threads")]
+struct MakeParallelStreamingFromClassifierCps<'a, WriteT: Write, BufReadT> {
+    keys: Vec<&'a [u8]>,
+    options: select::Options<'a>,
+    batch_writer: BatchWriter<'a, WriteT>,
+    config: parser_parallel::ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, WriteT: Write, BufReadT: BufRead + Send> structural::MakeClassifierCps<'a> for MakeParallelStreamingFromClassifierCps<'a, WriteT, BufReadT> {
+    type Return = Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        let keys = self.keys;
+        let options = self.options;
+        let create_worker = move || {
+            parser::State::new(classifier.clone(), Stage2Adapter::new(keys.clone(), options))
+        };
+        parser_parallel::streaming_new(Joiner { create_worker: Box::new(create_worker), batch_writer: self.batch_writer }, self.config)
+    }
+}
+
+pub fn make_parser<'a, ReadT: BufRead + Send, WriteT: Write>
+    (keys: Vec<&'a [u8]>, options: select::Options<'a>, stdout: &'a mut WriteT, format: Format, column_types: Option<Vec<ColumnType>>, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    #[cfg(feature = "threads")]
+    if threads {
+        return make_parser_with_config(keys, options, stdout, format, column_types, parser_parallel::ParallelConfig::default());
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    let columns: Vec<&[u8]> = keys.iter().copied().chain(options.extra_columns()).collect();
+    let batch_writer = BatchWriter::new(&columns[..], stdout, format, column_types);
+    Box::new(SingleThreaded { keys, options, batch_writer })
+}
+
+#[cfg(feature = "threads")]
+pub fn make_parser_with_config<'a, ReadT: BufRead + Send, WriteT: Write>
+    (keys: Vec<&'a [u8]>, options: select::Options<'a>, stdout: &'a mut WriteT, format: Format, column_types: Option<Vec<ColumnType>>, config: parser_parallel::ParallelConfig)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    let columns: Vec<&[u8]> = keys.iter().copied().chain(options.extra_columns()).collect();
+    let batch_writer = BatchWriter::new(&columns[..], stdout, format, column_types);
+    structural::make_classifier_cps(MakeParallelStreamingFromClassifierCps { keys, options, batch_writer, config, phantom: std::marker::PhantomData })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_batches(format: Format, output: Vec<u8>) -> Vec<RecordBatch> {
+        match format {
+            Format::File =>
+                arrow_ipc::reader::FileReader::try_new(std::io::Cursor::new(output), None).unwrap().map(Result::unwrap).collect(),
+            Format::Stream =>
+                arrow_ipc::reader::StreamReader::try_new(&output[..], None).unwrap().map(Result::unwrap).collect(),
+        }
+    }
+
+    fn run_test(input: &[u8], keys: &[&[u8]], column_types: Option<Vec<ColumnType>>, threads: bool, format: Format) -> Vec<RecordBatch> {
+        let mut output = Vec::new();
+        let output_kind = select::OutputKind::Arrow { format, column_types: column_types.clone() };
+        let mut parser = select::make_parser(keys.iter().copied(), &mut output, output_kind, threads);
+        parser.process_streaming(&mut std::io::BufReader::new(input)).unwrap();
+        std::mem::drop(parser);
+        let mut output_of_slice = Vec::new();
+        let output_kind = select::OutputKind::Arrow { format, column_types };
+        let mut parser = select::make_parser::<_, std::io::BufReader<&[u8]>, _>(keys.iter().copied(), &mut output_of_slice, output_kind, threads);
+        parser.process_slice(input).unwrap();
+        std::mem::drop(parser);
+        let batches = read_batches(format, output);
+        // The same rows, though perhaps split into batches differently.
+        let batches_of_slice = read_batches(format, output_of_slice);
+        for i in 0..keys.len() {
+            assert_eq!(column_to_strings(&batches_of_slice, i), column_to_strings(&batches, i));
+        }
+        batches
+    }
+
+    fn column_to_strings(batches: &[RecordBatch], i: usize) -> Vec<String> {
+        batches.iter().flat_map(|batch| {
+            let column = arrow_cast_to_string(batch.column(i));
+            let column = arrow_array::cast::as_string_array(&column);
+            column.iter().map(|value| value.unwrap_or("null").to_owned()).collect::<Vec<_>>()
+        }).collect()
+    }
+
+    fn arrow_cast_to_string(column: &ArrayRef) -> ArrayRef {
+        let any = column.as_any();
+        let strings: StringArray =
+            if let Some(a) = any.downcast_ref::<Int64Array>() { a.iter().map(|x| x.map(|x| x.to_string())).collect() }
+            else if let Some(a) = any.downcast_ref::<Float64Array>() { a.iter().map(|x| x.map(|x| x.to_string())).collect() }
+            else if let Some(a) = any.downcast_ref::<BooleanArray>() { a.iter().map(|x| x.map(|x| x.to_string())).collect() }
+            else { any.downcast_ref::<StringArray>().unwrap().clone() };
+        Arc::new(strings)
+    }
+
+    const INPUT: &[u8] = br#"((id 1) (score 2.5) (ok true) (name "a b"))
+((id 2) (name (x y)) (score 3))
+((other 1))
+((id -3) (ok false))
+"#;
+
+    #[test]
+    fn test_inferred() {
+        for format in [Format::File, Format::Stream] {
+            for threads in [false, true] {
+                let batches = run_test(INPUT, &[b"id", b"score", b"ok", b"name"], None, threads, format);
+                let schema = batches[0].schema();
+                let types: Vec<&DataType> = schema.fields().iter().map(|field| field.data_type()).collect();
+                assert_eq!(types, [&DataType::Float64, &DataType::Float64, &DataType::Boolean, &DataType::Utf8]);
+                assert_eq!(column_to_strings(&batches, 0), ["1", "2", "-3"]);
+                assert_eq!(column_to_strings(&batches, 1), ["2.5", "3", "null"]);
+                assert_eq!(column_to_strings(&batches, 2), ["true", "null", "false"]);
+                assert_eq!(column_to_strings(&batches, 3), ["a b", "(x y)", "null"]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_explicit() {
+        let column_types = Some(vec![ColumnType::String, ColumnType::Int]);
+        let batches = run_test(INPUT, &[b"id", b"score"], column_types, false, Format::File);
+        let schema = batches[0].schema();
+        assert_eq!(schema.field(0).data_type(), &DataType::Utf8);
+        assert_eq!(column_to_strings(&batches, 0), ["1", "2", "-3"]);
+        // 2.5 is not an int
+        assert_eq!(column_to_strings(&batches, 1), ["null", "3", "null"]);
+    }
+
+    fn run_test_result(input: &[u8], keys: &[&[u8]], column_types: Option<Vec<ColumnType>>, threads: bool) -> Result<Vec<RecordBatch>, parser::Error> {
+        let mut output = Vec::new();
+        let output_kind = select::OutputKind::Arrow { format: Format::Stream, column_types };
+        let mut parser = select::make_parser(keys.iter().copied(), &mut output, output_kind, threads);
+        parser.process_streaming(&mut std::io::BufReader::new(input))?;
+        std::mem::drop(parser);
+        Ok(read_batches(Format::Stream, output))
+    }
+
+    #[test]
+    fn test_many_chunks() {
+        let mut input = Vec::new();
+        for i in 0..100000 {
+            writeln!(input, "((id {}) (value {}))", i, i).unwrap();
+        }
+        let ids: Vec<String> = (0..100000).map(|i| i.to_string()).collect();
+        for threads in [false, true] {
+            for column_types in [None, Some(vec![ColumnType::Int, ColumnType::String])] {
+                let batches = run_test(&input[..], &[b"id", b"value"], column_types.clone(), threads, Format::Stream);
+                assert!(batches.len() > 1);
+                assert_eq!(column_to_strings(&batches, 0), ids);
+                assert_eq!(column_to_strings(&batches, 1), ids);
+                let value_type = if column_types.is_some() { DataType::Utf8 } else { DataType::Float64 };
+                assert_eq!(batches[0].schema().field(1).data_type(), &value_type);
+            }
+
+            // Ints in the first batch still allow floats later.
+            writeln!(input, "((id 0.5) (value 1))").unwrap();
+            let batches = run_test_result(&input[..], &[b"id", b"value"], None, threads).unwrap();
+            assert_eq!(column_to_strings(&batches, 0)[100000], "0.5");
+            input.truncate(input.len() - b"((id 0.5) (value 1))\n".len());
+
+            // A value that does not fit the types inferred from the first
+            // batch is an error, unless the types are given.
+            writeln!(input, "((id x) (value 1.5))").unwrap();
+            let result = run_test_result(&input[..], &[b"id", b"value"], None, threads);
+            let expected = parser::Error::ColumnTypeMismatch { column: "id".to_string(), row: 100000, value: "x".to_string() };
+            assert_eq!(result.unwrap_err(), expected);
+            let column_types = Some(vec![ColumnType::String, ColumnType::Float]);
+            let batches = run_test_result(&input[..], &[b"id", b"value"], column_types, threads).unwrap();
+            assert_eq!(column_to_strings(&batches, 0)[100000], "x");
+            assert_eq!(column_to_strings(&batches, 1)[100000], "1.5");
+            input.truncate(input.len() - b"((id x) (value 1.5))\n".len());
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let batches = run_test(b"", &[b"id"], None, false, Format::File);
+        assert!(batches.is_empty());
+    }
+}
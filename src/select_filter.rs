@@ -0,0 +1,261 @@
+use crate::parser;
+#[cfg(feature = "regex")]
+use regex::bytes::Regex;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+/// A condition on the fields of a record. Values are compared after quoted
+/// atoms are unescaped. Every comparison is false if the field is missing
+/// (including `NotEqual`), and numeric comparisons are false if the value is
+/// not a number.
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Equal { key: Vec<u8>, value: Vec<u8> },
+    NotEqual { key: Vec<u8>, value: Vec<u8> },
+    Compare { key: Vec<u8>, comparison: Comparison, value: f64 },
+    #[cfg(feature = "regex")]
+    Matches { key: Vec<u8>, regex: Regex },
+    Present { key: Vec<u8> },
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+impl Predicate {
+    /// Calls `f` on every key mentioned in the predicate.
+    pub fn iter_keys<'a, F: FnMut(&'a [u8])>(&'a self, f: &mut F) {
+        match self {
+            Predicate::Equal { key, .. } | Predicate::NotEqual { key, .. } | Predicate::Compare { key, .. }
+            | Predicate::Present { key } => f(&key[..]),
+            #[cfg(feature = "regex")]
+            Predicate::Matches { key, .. } => f(&key[..]),
+            Predicate::Not(p) => p.iter_keys(f),
+            Predicate::And(p, q) | Predicate::Or(p, q) => {
+                p.iter_keys(f);
+                q.iter_keys(f);
+            },
+        }
+    }
+
+    /// `lookup` returns the (unescaped) value of a field of the record.
+    pub fn eval<'b, F: Fn(&[u8]) -> Option<&'b [u8]>>(&self, lookup: &F) -> bool {
+        let number = |value: &[u8]| std::str::from_utf8(value).ok()?.parse::<f64>().ok();
+        match self {
+            Predicate::Equal { key, value } => lookup(key) == Some(&value[..]),
+            Predicate::NotEqual { key, value } => lookup(key).is_some_and(|x| x != &value[..]),
+            Predicate::Compare { key, comparison, value } => {
+                match lookup(key).and_then(number) {
+                    None => false,
+                    Some(x) => match comparison {
+                        Comparison::Less => x < *value,
+                        Comparison::LessEqual => x <= *value,
+                        Comparison::Greater => x > *value,
+                        Comparison::GreaterEqual => x >= *value,
+                    },
+                }
+            },
+            #[cfg(feature = "regex")]
+            Predicate::Matches { key, regex } => lookup(key).is_some_and(|x| regex.is_match(x)),
+            Predicate::Present { key } => lookup(key).is_some(),
+            Predicate::Not(p) => !p.eval(lookup),
+            Predicate::And(p, q) => p.eval(lookup) && q.eval(lookup),
+            Predicate::Or(p, q) => p.eval(lookup) || q.eval(lookup),
+        }
+    }
+
+    /// Parses predicates such as `price >= 10 and not (name ~ "^foo" or has discontinued)`.
+    ///
+    /// The operators are `=`, `!=`, `<`, `<=`, `>`, `>=` and `~` (regex
+    /// match, with the `regex` feature), and `has KEY` tests for presence. `not` binds tighter than
+    /// `and`, which binds tighter than `or`. Keys and values may be
+    /// double-quoted, with backslash escapes.
+    pub fn parse(input: &str) -> Result<Self, parser::Error> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens: &tokens[..], index: 0 };
+        let predicate = parser.or()?;
+        if parser.index != tokens.len() {
+            return Err(parser::Error::InvalidPredicate);
+        }
+        Ok(predicate)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Operator(&'static str),
+    Word(String),
+    Quoted(String),
+}
+
+const OPERATORS: [&str; 7] = ["!=", "<=", ">=", "=", "<", ">", "~"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, parser::Error> {
+    let is_special = |ch: char| ch.is_whitespace() || "()=!<>~\"".contains(ch);
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while let Some(ch) = rest.chars().next() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Operator(op));
+            rest = &rest[op.len()..];
+        } else if ch == '(' || ch == ')' {
+            tokens.push(if ch == '(' { Token::Open } else { Token::Close });
+            rest = &rest[1..];
+        } else if ch == '"' {
+            let mut string = String::new();
+            let mut chars = rest[1..].char_indices();
+            loop {
+                match chars.next() {
+                    None => return Err(parser::Error::InvalidPredicate),
+                    Some((i, '"')) => {
+                        rest = &rest[(i + 2)..];
+                        break;
+                    },
+                    Some((_, '\\')) => string.push(chars.next().ok_or(parser::Error::InvalidPredicate)?.1),
+                    Some((_, ch)) => string.push(ch),
+                }
+            }
+            tokens.push(Token::Quoted(string));
+        } else if is_special(ch) {
+            // a lone '!'
+            return Err(parser::Error::InvalidPredicate);
+        } else {
+            let len = rest.find(is_special).unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..len].to_owned()));
+            rest = &rest[len..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    index: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.index);
+        self.index += 1;
+        token
+    }
+
+    fn next_is_word(&mut self, keyword: &str) -> bool {
+        if matches!(self.tokens.get(self.index), Some(Token::Word(word)) if word == keyword) {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str, parser::Error> {
+        match self.next() {
+            Some(Token::Word(s)) | Some(Token::Quoted(s)) => Ok(s),
+            _ => Err(parser::Error::InvalidPredicate),
+        }
+    }
+
+    fn or(&mut self) -> Result<Predicate, parser::Error> {
+        let mut predicate = self.and()?;
+        while self.next_is_word("or") {
+            predicate = Predicate::Or(Box::new(predicate), Box::new(self.and()?));
+        }
+        Ok(predicate)
+    }
+
+    fn and(&mut self) -> Result<Predicate, parser::Error> {
+        let mut predicate = self.unary()?;
+        while self.next_is_word("and") {
+            predicate = Predicate::And(Box::new(predicate), Box::new(self.unary()?));
+        }
+        Ok(predicate)
+    }
+
+    fn unary(&mut self) -> Result<Predicate, parser::Error> {
+        if self.next_is_word("not") {
+            return Ok(Predicate::Not(Box::new(self.unary()?)));
+        }
+        if self.next_is_word("has") {
+            return Ok(Predicate::Present { key: self.string()?.as_bytes().to_owned() });
+        }
+        if self.tokens.get(self.index) == Some(&Token::Open) {
+            self.index += 1;
+            let predicate = self.or()?;
+            return match self.next() {
+                Some(Token::Close) => Ok(predicate),
+                _ => Err(parser::Error::InvalidPredicate),
+            };
+        }
+        let key = self.string()?.as_bytes().to_owned();
+        let op = match self.next() {
+            Some(Token::Operator(op)) => *op,
+            _ => return Err(parser::Error::InvalidPredicate),
+        };
+        let value = self.string()?;
+        let number = || value.parse::<f64>().map_err(|_| parser::Error::InvalidPredicate);
+        Ok(match op {
+            "=" => Predicate::Equal { key, value: value.as_bytes().to_owned() },
+            "!=" => Predicate::NotEqual { key, value: value.as_bytes().to_owned() },
+            "<" => Predicate::Compare { key, comparison: Comparison::Less, value: number()? },
+            "<=" => Predicate::Compare { key, comparison: Comparison::LessEqual, value: number()? },
+            ">" => Predicate::Compare { key, comparison: Comparison::Greater, value: number()? },
+            ">=" => Predicate::Compare { key, comparison: Comparison::GreaterEqual, value: number()? },
+            #[cfg(feature = "regex")]
+            "~" => Predicate::Matches { key, regex: Regex::new(value).map_err(|_| parser::Error::InvalidPredicate)? },
+            #[cfg(not(feature = "regex"))]
+            "~" => return Err(parser::Error::InvalidPredicate),
+            _ => unreachable!(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(predicate: &str, record: &[(&str, &str)]) -> bool {
+        let lookup = |key: &[u8]| record.iter().find(|(k, _)| k.as_bytes() == key).map(|(_, v)| v.as_bytes());
+        Predicate::parse(predicate).unwrap().eval(&lookup)
+    }
+
+    #[test]
+    fn test_eval() {
+        let record = [("a", "1"), ("b", "foo bar"), ("c", "-2.5")];
+        assert!(eval("a = 1", &record));
+        assert!(!eval("a = 1.0", &record));
+        assert!(eval("b = \"foo bar\"", &record));
+        assert!(eval("a != 2", &record));
+        assert!(!eval("missing != 2", &record));
+        assert!(eval("c < -2 and a >= 1", &record));
+        assert!(!eval("b > 0", &record));
+        #[cfg(feature = "regex")]
+        assert!(eval("b ~ ^fo+", &record));
+        assert!(eval("has a and not has missing", &record));
+        assert!(eval("missing = 1 or a <= 1", &record));
+        assert!(eval("not (a = 1 and b = x) and (c > -3)", &record));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let record = [("a", "1")];
+        // parsed as `(a = 1 or a = 2) and ...` this would be false
+        assert!(eval("a = 1 or a = 2 and a = 3", &record));
+        assert!(!eval("not a = 1 or a = 2", &record));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for input in ["", "a", "a =", "a = 1 b", "(a = 1", "a < x", "a ~ (", "\"a = 1", "a ! 1", "and"] {
+            assert_eq!(Predicate::parse(input).err(), Some(parser::Error::InvalidPredicate), "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use crate::select;
+#[cfg(feature = "threads")]
+use crate::structural;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+
+/// The selected values of one record, one per column (the keys, then the
+/// extra columns of `select::Options`). Quoted atoms are unescaped, and lists
+/// are kept as sexp text.
+pub struct Row<'b> {
+    pub values: Vec<Option<Cow<'b, [u8]>>>,
+}
+
+impl<'b> Row<'b> {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// `None` if the value is missing.
+    pub fn get(&self, column: usize) -> Option<&[u8]> {
+        self.values[column].as_deref()
+    }
+
+    /// `None` if the value is missing or not UTF-8.
+    pub fn as_str(&self, column: usize) -> Option<&str> {
+        std::str::from_utf8(self.get(column)?).ok()
+    }
+
+    /// `None` if the value is missing or not an integer.
+    pub fn as_i64(&self, column: usize) -> Option<i64> {
+        self.as_str(column)?.parse().ok()
+    }
+
+    /// `None` if the value is missing or not a number.
+    pub fn as_f64(&self, column: usize) -> Option<f64> {
+        self.as_str(column)?.parse().ok()
+    }
+}
+
+/// The rows selected from one chunk of input.
+pub struct RowBatch {
+    num_columns: usize,
+    values: Vec<u8>,
+    /// `num_columns` per row, ranges in `values`.
+    cells: Vec<Option<Range<usize>>>,
+}
+
+impl RowBatch {
+    fn new(num_columns: usize) -> Self {
+        Self { num_columns, values: Vec::new(), cells: Vec::new() }
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.cells.len().checked_div(self.num_columns).unwrap_or(0)
+    }
+
+    /// Calls `f` on each row in order.
+    pub fn for_each<F: FnMut(&Row)>(&self, f: &mut F) {
+        if self.num_columns == 0 {
+            return;
+        }
+        let mut row = Row { values: Vec::with_capacity(self.num_columns) };
+        for cells in self.cells.chunks(self.num_columns) {
+            row.values.clear();
+            row.values.extend(cells.iter().map(|cell| cell.clone().map(|range| Cow::Borrowed(&self.values[range]))));
+            f(&row);
+        }
+    }
+
+    /// The rows in order, owning their values.
+    fn to_owned_rows(&self) -> impl Iterator<Item = Row<'static>> + '_ {
+        self.cells.chunks(self.num_columns.max(1)).map(|cells| Row {
+            values: cells.iter().map(|cell| cell.clone().map(|range| Cow::Owned(self.values[range].to_vec()))).collect(),
+        })
+    }
+}
+
+pub struct OutputRows {
+    row: select::HeldRow,
+    unescaped: Vec<u8>,
+    batch: RowBatch,
+}
+
+impl OutputRows {
+    pub fn new() -> Self {
+        Self {
+            row: select::HeldRow::default(),
+            unescaped: Vec::new(),
+            batch: RowBatch::new(0),
+        }
+    }
+
+    fn take_batch(&mut self) -> RowBatch {
+        let num_columns = self.batch.num_columns;
+        std::mem::replace(&mut self.batch, RowBatch::new(num_columns))
+    }
+}
+
+impl Default for OutputRows {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl select::Output for OutputRows {
+    fn reset(&mut self, keys: &Vec<&[u8]>) {
+        self.row.reset(keys.len());
+        self.batch = RowBatch::new(keys.len());
+    }
+    fn select<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _input: &parser::Input, value_range: Range<usize>, _has_output_on_line: bool) {
+        self.row.hold(key_id, value_range);
+    }
+    fn select_value<WriteT: Write>(&mut self, _writer: &mut WriteT, _keys: &Vec<&[u8]>, key_id: usize, _key: &[u8], value: &[u8], _has_output_on_line: bool) {
+        self.row.hold_copy(key_id, value);
+    }
+    fn eol<WriteT: Write>(&mut self, _writer: &mut WriteT, input: &parser::Input) {
+        let batch = &mut self.batch;
+        let unescaped = &mut self.unescaped;
+        self.row.drain(input, |_, value| {
+            let cell = value.map(|value| select::push_value(&mut batch.values, select::unescaped_value(value, unescaped)));
+            batch.cells.push(cell);
+        });
+    }
+}
+
+/// Runs `select::Stage2` for one chunk, returning the selected rows.
+pub struct Stage2Adapter<'a> {
+    stage2: select::Stage2<'a, OutputRows>,
+}
+
+impl<'a> Stage2Adapter<'a> {
+    pub fn new(keys: Vec<&'a [u8]>, options: select::Options<'a>) -> Self {
+        let mut stage2 = select::Stage2::new(keys, OutputRows::new()).with_options(options);
+        // Ready for `process_partial`, which does not reset.
+        parser::WritingStage2::reset(&mut stage2);
+        Self { stage2 }
+    }
+}
+
+impl<'a> parser::Stage2 for Stage2Adapter<'a> {
+    type Return = RowBatch;
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        parser::WritingStage2::reset(&mut self.stage2);
+    }
+    #[inline(always)]
+    fn process_one(&mut self, input: parser::Input, this_index: usize, next_index: usize, is_eof: bool) -> Result<usize, parser::Error> {
+        parser::WritingStage2::process_one(&mut self.stage2, &mut std::io::sink(), input, this_index, next_index, is_eof)
+    }
+    fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
+        parser::WritingStage2::process_eof(&mut self.stage2, &mut std::io::sink())?;
+        Ok(self.stage2.output_mut().take_batch())
+    }
+}
+
+impl<'a> parser::ExtractPartialResult for Stage2Adapter<'a> {
+    type PartialReturn = RowBatch;
+    /// The rows completed so far.
+    fn extract_partial_result(&mut self) -> RowBatch {
+        self.stage2.output_mut().take_batch()
+    }
+}
+
+type PartialParser<'a> = Box<dyn parser::ParsePartial<Return = RowBatch, PartialReturn = RowBatch> + 'a>;
+
+fn new_parser<'a>(keys: Vec<&'a [u8]>, options: select::Options<'a>) -> PartialParser<'a> {
+    parser::partial_parser_new(Stage2Adapter::new(keys, options))
+}
+
+/// How much in-memory input is parsed before its rows are delivered.
+const SLICE_PIECE_SIZE: usize = 64 * 1024;
+
+struct SingleThreaded<'a, F> {
+    keys: Vec<&'a [u8]>,
+    options: select::Options<'a>,
+    f: F,
+}
+
+/// Delivers the rows completed by each buffer fill (or piece of in-memory
+/// input) as soon as it is parsed.
+impl<'a, ReadT: BufRead, F: FnMut(&Row)> parser::Stream<ReadT> for SingleThreaded<'a, F> {
+    type Return = ();
+    fn process_streaming(&mut self, buf_reader: &mut ReadT) -> Result<(), parser::Error> {
+        let mut parser = new_parser(self.keys.clone(), self.options);
+        loop {
+            match buf_reader.fill_buf() {
+                Ok(&[]) => {
+                    parser.process_eof()?.for_each(&mut self.f);
+                    return Ok(());
+                },
+                Ok(buf) => {
+                    parser.process_partial(buf)?;
+                    let len = buf.len();
+                    buf_reader.consume(len);
+                    parser.extract_partial_result().for_each(&mut self.f);
+                },
+                Err(e) => { return Err(parser::Error::IOError(e.kind())) },
+            }
+        }
+    }
+    fn process_slice(&mut self, input: &[u8]) -> Result<(), parser::Error> {
+        let mut parser = new_parser(self.keys.clone(), self.options);
+        for piece in input.chunks(SLICE_PIECE_SIZE) {
+            parser.process_partial(piece)?;
+            parser.extract_partial_result().for_each(&mut self.f);
+        }
+        parser.process_eof()?.for_each(&mut self.f);
+        Ok(())
+    }
+}
+
+/// Delivers each chunk's rows to the callback, in the order of the input.
+#[cfg(feature = "threads")]
+struct Joiner<'a, F, WorkerT> {
+    create_worker: Box<dyn Fn() -> WorkerT + 'a>,
+    f: F,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, F: FnMut(&Row), WorkerT: parser::Parse<Return = RowBatch>> parser_parallel::Joiner for Joiner<'a, F, WorkerT> {
+    type Worker = WorkerT;
+    type Return = ();
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+    }
+    fn create_worker(&mut self) -> Self::Worker {
+        (self.create_worker)()
+    }
+    fn join(&mut self, result: RowBatch) -> Result<(), parser::Error> {
+        result.for_each(&mut self.f);
+        Ok(())
+    }
+    fn process_eof(&mut self) -> Result<(), parser::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "threads")]
+struct MakeParallelStreamingFromClassifierCps<'a, F, BufReadT> {
+    keys: Vec<&'a [u8]>,
+    options: select::Options<'a>,
+    f: F,
+    config: parser_parallel::ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, F: FnMut(&Row) + 'a, BufReadT: BufRead + Send> structural::MakeClassifierCps<'a> for MakeParallelStreamingFromClassifierCps<'a, F, BufReadT> {
+    type Return = Box<dyn parser::Stream<BufReadT, Return = ()> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        let keys = self.keys;
+        let options = self.options;
+        let create_worker = move || {
+            parser::State::new(classifier.clone(), Stage2Adapter::new(keys.clone(), options))
+        };
+        parser_parallel::streaming_new(Joiner { create_worker: Box::new(create_worker), f: self.f }, self.config)
+    }
+}
+
+/// Like `select::make_parser_with_options`, but calls `f` on each selected
+/// row instead of writing it out. Rows are delivered in order, also when
+/// parsing in parallel, and every record produces a row unless the filter or
+/// `select::MissingKeys::SkipRow` drops it (or it has no selected values at
+/// all and there is no missing key policy).
+pub fn make_parser<'a, KeysT: IntoIterator<Item = &'a [u8]>, ReadT: BufRead + Send, F: FnMut(&Row) + 'a>
+    (keys: KeysT, options: select::Options<'a>, f: F, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    let keys: Vec<&'a [u8]> = keys.into_iter().collect();
+
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return structural::make_classifier_cps(MakeParallelStreamingFromClassifierCps { keys, options, f, config, phantom: std::marker::PhantomData });
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    Box::new(SingleThreaded { keys, options, f })
+}
+
+/// Reads all of `reader`, calling `f` on each selected row.
+pub fn for_each<'a, KeysT: IntoIterator<Item = &'a [u8]>, ReadT: BufRead + Send, F: FnMut(&Row) + 'a>
+    (keys: KeysT, options: select::Options<'a>, reader: &mut ReadT, threads: bool, f: F)
+    -> Result<(), parser::Error>
+{
+    let mut parser = make_parser(keys, options, f, threads);
+    parser.process_streaming(reader)
+}
+
+/// The selected rows of a reader, parsed on the calling thread a buffer fill
+/// at a time as they are asked for. See `rows`.
+pub struct Rows<'a, ReadT> {
+    reader: ReadT,
+    /// `None` once the input is finished or has failed.
+    parser: Option<PartialParser<'a>>,
+    rows: VecDeque<Row<'static>>,
+}
+
+impl<'a, ReadT: BufRead> Iterator for Rows<'a, ReadT> {
+    type Item = Result<Row<'static>, parser::Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.pop_front() {
+                return Some(Ok(row));
+            }
+            let parser = self.parser.as_mut()?;
+            let batch = match self.reader.fill_buf() {
+                Ok(&[]) => {
+                    let batch = parser.process_eof();
+                    self.parser = None;
+                    batch
+                },
+                Ok(buf) => {
+                    let len = buf.len();
+                    let result = parser.process_partial(buf);
+                    self.reader.consume(len);
+                    result.map(|()| parser.extract_partial_result())
+                },
+                Err(e) => Err(parser::Error::IOError(e.kind())),
+            };
+            match batch {
+                Ok(batch) => self.rows.extend(batch.to_owned_rows()),
+                Err(e) => {
+                    self.parser = None;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+/// Like `for_each` without threads, but as an iterator. The rows own their
+/// values. Iteration ends after the first error.
+pub fn rows<'a, KeysT: IntoIterator<Item = &'a [u8]>, ReadT: BufRead>
+    (keys: KeysT, options: select::Options<'a>, reader: ReadT)
+    -> Rows<'a, ReadT>
+{
+    Rows { reader, parser: Some(new_parser(keys.into_iter().collect(), options)), rows: VecDeque::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(input: &[u8], keys: &[&[u8]], options: select::Options, threads: bool) -> Vec<Vec<Option<Vec<u8>>>> {
+        let mut rows = Vec::new();
+        for_each(keys.iter().copied(), options, &mut std::io::BufReader::new(input), threads, |row: &Row| {
+            rows.push(row.values.iter().map(|value| value.as_ref().map(|value| value.to_vec())).collect());
+        }).unwrap();
+        rows
+    }
+
+    #[test]
+    fn test_rows() {
+        let input = br#"((name "a \"b\"") (n 1) (x 2.5))
+((n -3) (name (c d)))
+((other 1))
+((x nan) (n 1e3))
+"#;
+        let keys = &[&b"name"[..], &b"n"[..], &b"x"[..]];
+        for threads in [false, true] {
+            let rows = collect(input, keys, select::Options::default(), threads);
+            let some = |s: &str| Some(s.as_bytes().to_vec());
+            assert_eq!(rows, vec![
+                vec![some("a \"b\""), some("1"), some("2.5")],
+                vec![some("(c d)"), some("-3"), None],
+                vec![None, some("1e3"), some("nan")],
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_rows_iterator() {
+        let input = b"((id 1) (x a))\n((id 2))\n((x c) (id 3))";
+        let expected = collect(input, &[b"id", b"x"], select::Options::default(), false);
+        let rows: Vec<Vec<Option<Vec<u8>>>> = rows([&b"id"[..], &b"x"[..]], select::Options::default(), &input[..])
+            .map(|row| row.unwrap().values.into_iter().map(|value| value.map(Cow::into_owned)).collect())
+            .collect();
+        assert_eq!(rows, expected);
+        assert_eq!(rows.len(), 3);
+    }
+
+    struct FailingReader;
+
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    #[test]
+    fn test_rows_before_end_of_input() {
+        // The first record is delivered before the reader fails.
+        use std::io::Read;
+        let input = b"((id 1))\n((id 2))\n";
+        let reader = || std::io::BufReader::new(input.chain(FailingReader));
+        let mut ids = Vec::new();
+        let result = for_each([&b"id"[..]], select::Options::default(), &mut reader(), false, |row: &Row| ids.push(row.as_i64(0).unwrap()));
+        assert_eq!(result, Err(parser::Error::IOError(std::io::ErrorKind::ConnectionReset)));
+        assert_eq!(ids, [1]);
+
+        let mut rows = rows([&b"id"[..]], select::Options::default(), reader());
+        assert_eq!(rows.next().unwrap().unwrap().as_i64(0), Some(1));
+        assert!(rows.next().unwrap().is_err());
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let input = b"((s \"caf\\195\\169\") (i 42) (f -1.5) (b \"\\255\"))";
+        let keys = &[&b"s"[..], &b"i"[..], &b"f"[..], &b"b"[..], &b"missing"[..]];
+        let mut calls = 0;
+        for_each(keys.iter().copied(), select::Options::default(), &mut &input[..], false, |row: &Row| {
+            calls += 1;
+            assert_eq!(row.len(), 5);
+            assert_eq!(row.as_str(0), Some("café"));
+            assert_eq!(row.as_i64(0), None);
+            assert_eq!(row.as_i64(1), Some(42));
+            assert_eq!(row.as_f64(1), Some(42.));
+            assert_eq!(row.as_f64(2), Some(-1.5));
+            assert_eq!(row.as_i64(2), None);
+            assert_eq!(row.get(3), Some(&b"\xff"[..]));
+            assert_eq!(row.as_str(3), None);
+            assert_eq!(row.get(4), None);
+        }).unwrap();
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_many_rows_in_order() {
+        let mut input = Vec::new();
+        for i in 0..100000 {
+            input.extend_from_slice(format!("((id {}) (even {}))\n", i, i % 2 == 0).as_bytes());
+        }
+        let filter = crate::select_filter::Predicate::parse("even = true").unwrap();
+        let options = select::Options { filter: Some(&filter), ..select::Options::default() };
+        let mut ids = Vec::new();
+        for_each([&b"id"[..]], options, &mut &input[..], true, |row: &Row| ids.push(row.as_i64(0).unwrap())).unwrap();
+        assert_eq!(ids, (0..100000).step_by(2).collect::<Vec<i64>>());
+    }
+}
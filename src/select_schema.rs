@@ -0,0 +1,355 @@
+use crate::escape::{self, Unescape};
+use crate::escape_csv;
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use crate::select;
+#[cfg(feature = "threads")]
+use crate::structural;
+use crate::utils;
+use std::collections::HashMap;
+use std::io::{BufRead, Seek, SeekFrom, Write};
+
+/// The keys seen, in order of first appearance, with the number of times each
+/// was seen.
+#[derive(Clone, Debug, Default)]
+pub struct KeyCounts {
+    index: HashMap<Vec<u8>, usize>,
+    counts: Vec<(Vec<u8>, u64)>,
+}
+
+impl KeyCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, key: &[u8], count: u64) {
+        match self.index.get(key) {
+            Some(&i) => self.counts[i].1 += count,
+            None => {
+                self.index.insert(key.to_owned(), self.counts.len());
+                self.counts.push((key.to_owned(), count));
+            },
+        }
+    }
+
+    /// Adds the counts of `other`, whose keys are taken to appear after ours.
+    pub fn merge(&mut self, other: KeyCounts) {
+        if self.counts.is_empty() {
+            *self = other;
+            return;
+        }
+        for (key, count) in other.counts {
+            self.add(&key[..], count);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.counts.iter().map(|(key, count)| (&key[..], *count))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Field {
+    Start,
+    Key,
+    KeyValue,
+    Ignore,
+}
+
+/// Counts the keys of the fields `(key value)` of top-level records, matching
+/// them the way `select::Stage2` does (quoted keys are unescaped).
+pub struct Stage2 {
+    depth: usize,
+    field: Field,
+    key: Vec<u8>,
+    key_counts: KeyCounts,
+    unescape: escape::GenericUnescape,
+}
+
+impl Stage2 {
+    pub fn new() -> Self {
+        Self {
+            depth: 0,
+            field: Field::Start,
+            key: Vec::new(),
+            key_counts: KeyCounts::new(),
+            unescape: escape::GenericUnescape::new(),
+        }
+    }
+}
+
+impl Default for Stage2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl parser::Stage2 for Stage2 {
+    type Return = KeyCounts;
+
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.depth = 0;
+        self.field = Field::Start;
+        self.key_counts = KeyCounts::new();
+    }
+
+    #[inline]
+    fn process_one(&mut self, input: parser::Input, this_index: usize, next_index: usize, is_eof: bool) -> Result<usize, parser::Error> {
+        let ch = input.input[this_index - input.offset];
+        match ch {
+            b'(' => {
+                if self.depth == 2 {
+                    self.field = match self.field {
+                        Field::Key => Field::KeyValue,
+                        _ => Field::Ignore,
+                    };
+                }
+                self.depth += 1;
+                if self.depth == 2 {
+                    self.field = Field::Start;
+                }
+            },
+            b')' => {
+                if self.depth == 0 {
+                    utils::cold();
+                    return Err(parser::Error::UnmatchedCloseParen);
+                }
+                if self.depth == 2 {
+                    if let Field::KeyValue = self.field {
+                        self.key_counts.add(&self.key[..], 1);
+                    }
+                }
+                self.depth -= 1;
+            },
+            b' ' | b'\t' | b'\n' => (),
+            _ => {
+                let atom = &input.input[(this_index - input.offset)..(next_index - input.offset)];
+                let is_key = self.depth == 2 && matches!(self.field, Field::Start);
+                if ch == b'"' && (is_key || is_eof) {
+                    self.key.resize(atom.len(), 0u8);
+                    let (_, key_len) = self.unescape.unescape(&atom[1..], &mut self.key[..]).ok_or(parser::Error::BadQuotedAtom)?;
+                    self.key.truncate(key_len);
+                } else if is_key {
+                    self.key.clear();
+                    self.key.extend_from_slice(atom);
+                }
+                if self.depth == 2 {
+                    self.field = match self.field {
+                        Field::Start => Field::Key,
+                        Field::Key => Field::KeyValue,
+                        _ => Field::Ignore,
+                    };
+                }
+            },
+        }
+        Ok(next_index)
+    }
+
+    fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
+        if self.depth > 0 {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(std::mem::take(&mut self.key_counts))
+    }
+}
+
+/// Merges the key counts of each chunk, in order.
+#[cfg(feature = "threads")]
+struct Joiner<'a, WorkerT> {
+    create_worker: Box<dyn Fn() -> WorkerT + 'a>,
+    key_counts: KeyCounts,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, WorkerT: parser::Parse<Return = KeyCounts>> parser_parallel::Joiner for Joiner<'a, WorkerT> {
+    type Worker = WorkerT;
+    type Return = KeyCounts;
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.key_counts = KeyCounts::new();
+    }
+    fn create_worker(&mut self) -> Self::Worker {
+        (self.create_worker)()
+    }
+    fn join(&mut self, result: KeyCounts) -> Result<(), parser::Error> {
+        self.key_counts.merge(result);
+        Ok(())
+    }
+    fn process_eof(&mut self) -> Result<KeyCounts, parser::Error> {
+        Ok(std::mem::take(&mut self.key_counts))
+    }
+}
+
+#[cfg(feature = "threads")]
+struct MakeParallelStreamingFromClassifierCps<BufReadT> {
+    config: parser_parallel::ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, BufReadT: BufRead + Send> structural::MakeClassifierCps<'a> for MakeParallelStreamingFromClassifierCps<BufReadT> {
+    type Return = Box<dyn parser::Stream<BufReadT, Return = KeyCounts> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        let create_worker = move || parser::State::new(classifier.clone(), Stage2::new());
+        parser_parallel::streaming_new(Joiner { create_worker: Box::new(create_worker), key_counts: KeyCounts::new() }, self.config)
+    }
+}
+
+/// Discovers the keys of the input's records.
+pub fn make_parser<'a, ReadT: BufRead + Send>(threads: bool) -> Box<dyn parser::Stream<ReadT, Return = KeyCounts> + 'a> {
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return structural::make_classifier_cps(MakeParallelStreamingFromClassifierCps { config, phantom: std::marker::PhantomData });
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    parser::streaming_new(Stage2::new())
+}
+
+/// Writes the discovered keys as CSV, with columns `key` and `count`.
+pub fn print_key_counts<WriteT: Write>(key_counts: &KeyCounts, writer: &mut WriteT) -> std::io::Result<()> {
+    writer.write_all(b"key,count\n")?;
+    for (key, count) in key_counts.iter() {
+        if escape_csv::escape_is_necessary(key) {
+            escape_csv::escape(key, writer)?;
+        } else {
+            writer.write_all(key)?;
+        }
+        writeln!(writer, ",{}", count)?;
+    }
+    Ok(())
+}
+
+/// Selects every key that appears in the input, in order of first
+/// appearance. This needs two passes over the input: if `reader` cannot seek
+/// (e.g. it is a pipe), the input is buffered in memory.
+pub fn select_all_keys<ReadT: BufRead + Seek + Send, WriteT: Write>
+    (reader: &mut ReadT, options: select::Options, stdout: &mut WriteT, output_kind: select::OutputKind, threads: bool)
+    -> Result<(), parser::Error>
+{
+    let io_error = |e: std::io::Error| parser::Error::IOError(e.kind());
+    match reader.stream_position() {
+        Ok(start) => {
+            let key_counts = make_parser(threads).process_streaming(reader)?;
+            reader.seek(SeekFrom::Start(start)).map_err(io_error)?;
+            let mut parser = select::make_parser_with_options(key_counts.keys(), options, stdout, output_kind, threads);
+            parser.process_streaming(reader)
+        },
+        Err(_) => {
+            let mut input = Vec::new();
+            reader.read_to_end(&mut input).map_err(io_error)?;
+            let key_counts = make_parser(threads).process_streaming(&mut &input[..])?;
+            let mut parser = select::make_parser_with_options(key_counts.keys(), options, stdout, output_kind, threads);
+            parser.process_streaming(&mut &input[..])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// A reader that (like a pipe) cannot seek.
+    struct Pipe<'a>(&'a [u8]);
+
+    impl<'a> Read for Pipe<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl<'a> Seek for Pipe<'a> {
+        fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    fn run_test(input: &[u8], expected_output: Result<&[(&str, u64)], parser::Error>) {
+        let threads_options: &[bool] = if cfg!(feature = "threads") { &[false, true] } else { &[false] };
+        for &threads in threads_options {
+            let output = make_parser(threads).process_streaming(&mut std::io::BufReader::new(input));
+            let output = output.map(|key_counts| {
+                key_counts.iter().map(|(key, count)| (String::from_utf8(key.to_owned()).unwrap(), count)).collect::<Vec<_>>()
+            });
+            let expected_output = expected_output.clone().map(|expected| {
+                expected.iter().map(|(key, count)| (key.to_string(), *count)).collect::<Vec<_>>()
+            });
+            assert_eq!(output, expected_output, "threads: {}", threads);
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        run_test(br#"((a 1) (b (x y)) ("c d" 2))
+((b 3) (e) (f 1 2) ((g) 3) (a (h 4)))
+atom
+((a 5))
+"#, Ok(&[("a", 3), ("b", 2), ("c d", 1)]));
+    }
+
+    #[test]
+    fn test_errors() {
+        run_test(b"((a 1)", Err(parser::Error::UnmatchedOpenParen));
+        run_test(b"((a 1)))", Err(parser::Error::UnmatchedCloseParen));
+        run_test(b"((\"a 1))", Err(parser::Error::BadQuotedAtom));
+    }
+
+    #[test]
+    fn test_many_lines() {
+        let mut input = Vec::new();
+        for i in 0..100000 {
+            writeln!(input, "((id {}) (key{} x))", i, i % 7).unwrap();
+        }
+        let expected: Vec<(String, u64)> =
+            std::iter::once(("id".to_owned(), 100000))
+            .chain((0..7).map(|i| (format!("key{}", i), if i < 100000 % 7 { 14286 } else { 14285 })))
+            .collect();
+        let expected: Vec<(&str, u64)> = expected.iter().map(|(key, count)| (&key[..], *count)).collect();
+        run_test(&input[..], Ok(&expected[..]));
+    }
+
+    #[test]
+    fn test_print_key_counts() {
+        let mut key_counts = KeyCounts::new();
+        key_counts.add(b"a,b", 2);
+        key_counts.add(b"c", 1);
+        let mut output = Vec::new();
+        print_key_counts(&key_counts, &mut output).unwrap();
+        assert_eq!(output, b"key,count\n\"a,b\",2\nc,1\n");
+    }
+
+    #[test]
+    fn test_select_all_keys() {
+        let input = b"((a 1) (b 2))\n((c 3) (a 4))\n";
+        let expected = b"a,b,c\n1,2,\n4,,3\n";
+        for threads in [false, true] {
+            // seekable
+            let mut output = Vec::new();
+            let mut reader = std::io::Cursor::new(&input[..]);
+            select_all_keys(&mut reader, select::Options::default(), &mut output, select::OutputKind::Csv { atoms_as_sexps: false }, threads).unwrap();
+            assert_eq!(output, expected);
+
+            // not seekable
+            let mut output = Vec::new();
+            let mut reader = std::io::BufReader::new(Pipe(&input[..]));
+            select_all_keys(&mut reader, select::Options::default(), &mut output, select::OutputKind::Csv { atoms_as_sexps: false }, threads).unwrap();
+            assert_eq!(output, expected);
+        }
+    }
+}
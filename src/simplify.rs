@@ -0,0 +1,36 @@
+use crate::{extract, structural};
+use crate::structural::Classifier;
+use std::io::Write;
+
+/// Writes `input` with every structural character replaced by a paren (or,
+/// for an odd one out at the start, an `a`) and everything else by a space,
+/// which shows how the input is classified. A final line break is dropped.
+pub fn simplify<WriteT: Write>(input: &[u8], writer: &mut WriteT) -> std::io::Result<()> {
+    let input = input.strip_suffix(b"\n").unwrap_or(input);
+    let mut output: Vec<bool> = (0..input.len()).map(|_| false).collect();
+    let mut classifier = structural::Generic::new();
+    let mut index = 0;
+    let mut total_bits = 0;
+    classifier.structural_indices_bitmask(input, |bitmask, bitmask_len| {
+        extract::safe_generic(|bit_offset| {
+            output[index + bit_offset] = true;
+            total_bits += 1;
+        }, bitmask);
+        index += bitmask_len;
+        structural::CallbackResult::Continue
+    });
+    let mut next_char = if total_bits % 2 == 0 { b'(' } else { b'a' };
+    let output: Vec<u8> = output.into_iter().map(|ov| {
+        if ov {
+            let ch = next_char;
+            next_char = match next_char {
+                b'(' => b')',
+                _ => b'(',
+            };
+            ch
+        } else {
+            b' '
+        }
+    }).collect();
+    writer.write_all(&output[..])
+}
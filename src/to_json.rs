@@ -0,0 +1,295 @@
+use crate::escape::{self, Unescape};
+use crate::escape_json;
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+use std::io::{BufRead, Write};
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Options {
+    /// Lists of the form `((k1 v1) (k2 v2) ...)`, as produced by
+    /// ppx_sexp_conv for records, become JSON objects `{"k1": v1, ...}`.
+    pub records: bool,
+    /// Lists headed by a capitalised atom, as produced by ppx_sexp_conv for
+    /// variants, become single-field objects: `(Tag x)` becomes `{"Tag": x}`
+    /// and `(Tag x y)` becomes `{"Tag": [x, y]}`. Records take precedence.
+    pub variants: bool,
+    /// Unquoted atoms that are valid JSON numbers are written as numbers
+    /// rather than strings.
+    pub numbers: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Token {
+    /// `end` is the index of the matching `ListClose`.
+    ListOpen { end: usize },
+    ListClose,
+    Atom { start: usize, end: usize, quoted: bool },
+}
+
+/// Converts each top-level sexp into a single line of JSON (i.e. the output
+/// is JSON Lines).
+/// Each top-level sexp is buffered in its entirety before it is written out,
+/// since deciding whether a list is a record requires looking at all of its
+/// children.
+pub struct Stage2 {
+    options: Options,
+    unescape: escape::GenericUnescape,
+
+    // varying
+    tokens: Vec<Token>,
+    atoms: Vec<u8>,
+    open_stack: Vec<usize>,
+}
+
+impl Stage2 {
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            unescape: escape::GenericUnescape::new(),
+            tokens: Vec::new(),
+            atoms: Vec::new(),
+            open_stack: Vec::new(),
+        }
+    }
+
+    fn next_sibling(&self, index: usize) -> usize {
+        match self.tokens[index] {
+            Token::ListOpen { end } => end + 1,
+            _ => index + 1,
+        }
+    }
+
+    fn atom(&self, index: usize) -> Option<&[u8]> {
+        match self.tokens[index] {
+            Token::Atom { start, end, .. } => Some(&self.atoms[start..end]),
+            _ => None,
+        }
+    }
+
+    fn is_record(&self, start: usize, end: usize) -> bool {
+        let mut index = start + 1;
+        while index < end {
+            // each field must be a list of exactly a key atom and a value
+            match self.tokens[index] {
+                Token::ListOpen { end: field_end } => {
+                    if self.atom(index + 1).is_none() || self.next_sibling(index + 2) != field_end {
+                        return false;
+                    }
+                    index = field_end + 1;
+                },
+                _ => return false,
+            }
+        }
+        index > start + 1
+    }
+
+    fn is_variant(&self, start: usize, end: usize) -> bool {
+        match self.atom(start + 1) {
+            Some(tag) => start + 2 < end && tag.first().is_some_and(u8::is_ascii_uppercase),
+            None => false,
+        }
+    }
+
+    fn write_string<WriteT: Write>(&self, writer: &mut WriteT, string: &[u8]) {
+        writer.write_all(b"\"").unwrap();
+        escape_json::escape(string, writer).unwrap();
+        writer.write_all(b"\"").unwrap();
+    }
+
+    /// Writes the elements in `start..end` as a JSON array.
+    fn write_array<WriteT: Write>(&self, writer: &mut WriteT, start: usize, end: usize) {
+        writer.write_all(b"[").unwrap();
+        let mut index = start;
+        while index < end {
+            if index > start {
+                writer.write_all(b",").unwrap();
+            }
+            self.write_value(writer, index);
+            index = self.next_sibling(index);
+        }
+        writer.write_all(b"]").unwrap();
+    }
+
+    fn write_value<WriteT: Write>(&self, writer: &mut WriteT, index: usize) {
+        match self.tokens[index] {
+            Token::Atom { start, end, quoted } => {
+                let atom = &self.atoms[start..end];
+                if self.options.numbers && !quoted && escape_json::is_number(atom) {
+                    writer.write_all(atom).unwrap();
+                } else {
+                    self.write_string(writer, atom);
+                }
+            },
+            Token::ListOpen { end } => {
+                if self.options.records && self.is_record(index, end) {
+                    writer.write_all(b"{").unwrap();
+                    let mut field = index + 1;
+                    while field < end {
+                        if field > index + 1 {
+                            writer.write_all(b",").unwrap();
+                        }
+                        self.write_string(writer, self.atom(field + 1).unwrap());
+                        writer.write_all(b":").unwrap();
+                        self.write_value(writer, field + 2);
+                        field = self.next_sibling(field);
+                    }
+                    writer.write_all(b"}").unwrap();
+                } else if self.options.variants && self.is_variant(index, end) {
+                    writer.write_all(b"{").unwrap();
+                    self.write_string(writer, self.atom(index + 1).unwrap());
+                    writer.write_all(b":").unwrap();
+                    if self.next_sibling(index + 2) == end {
+                        self.write_value(writer, index + 2);
+                    } else {
+                        self.write_array(writer, index + 2, end);
+                    }
+                    writer.write_all(b"}").unwrap();
+                } else {
+                    self.write_array(writer, index + 1, end);
+                }
+            },
+            Token::ListClose => unreachable!(),
+        }
+    }
+
+    fn flush_line<WriteT: Write>(&mut self, writer: &mut WriteT) {
+        self.write_value(writer, 0);
+        writer.write_all(b"\n").unwrap();
+        self.tokens.clear();
+        self.atoms.clear();
+    }
+}
+
+impl parser::WritingStage2 for Stage2 {
+    fn reset(&mut self) {
+        self.tokens.clear();
+        self.atoms.clear();
+        self.open_stack.clear();
+    }
+
+    #[inline]
+    fn process_one<WriteT: Write>(&mut self, writer: &mut WriteT, input: parser::Input, this_index: usize, next_index: usize, _is_eof: bool) -> Result<usize, parser::Error> {
+        let ch = input.input[this_index - input.offset];
+
+        match ch {
+            b'(' => {
+                self.open_stack.push(self.tokens.len());
+                self.tokens.push(Token::ListOpen { end: 0 });
+            },
+            b')' => {
+                let open_index = self.open_stack.pop().ok_or(parser::Error::UnmatchedCloseParen)?;
+                let end = self.tokens.len();
+                self.tokens[open_index] = Token::ListOpen { end };
+                self.tokens.push(Token::ListClose);
+                if self.open_stack.is_empty() {
+                    self.flush_line(writer);
+                }
+            },
+            b' ' | b'\t' | b'\n' => (),
+            _ => {
+                let start = self.atoms.len();
+                let quoted = ch == b'"';
+                if quoted {
+                    self.atoms.resize(start + (next_index - this_index), 0u8);
+                    let (_, atom_len) =
+                        self.unescape.unescape(
+                            &input.input[(this_index + 1 - input.offset)..(next_index - input.offset)],
+                            &mut self.atoms[start..])
+                        .ok_or(parser::Error::BadQuotedAtom)?;
+                    self.atoms.truncate(start + atom_len);
+                } else {
+                    self.atoms.extend_from_slice(&input.input[(this_index - input.offset)..(next_index - input.offset)]);
+                }
+                self.tokens.push(Token::Atom { start, end: self.atoms.len(), quoted });
+                if self.open_stack.is_empty() {
+                    self.flush_line(writer);
+                }
+            },
+        }
+
+        Ok(next_index)
+    }
+
+    fn process_eof<WriteT: Write>(&mut self, _writer: &mut WriteT) -> Result<(), parser::Error> {
+        if !self.open_stack.is_empty() {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(())
+    }
+}
+
+pub fn make<'a, ReadT: BufRead + Send, WriteT: Write>
+    (stdout: &'a mut WriteT, options: Options, threads: bool)
+    -> Box<dyn parser::Stream<ReadT, Return = ()> + 'a>
+{
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return parser_parallel::streaming_from_writing_stage2(move || { Stage2::new(options) }, stdout, config);
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    parser::streaming_from_writing_stage2(Stage2::new(options), stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_test(options: Options, input: &[u8], expected_output: Result<&str, parser::Error>) {
+        let threads_options: &[bool] = if cfg!(feature = "threads") { &[false, true] } else { &[false] };
+        for &threads in threads_options {
+            let mut output = Vec::new();
+            let mut parser = make(&mut output, options, threads);
+            let ok = parser.process_streaming(&mut std::io::BufReader::new(input));
+            std::mem::drop(parser);
+            let output = ok.map(move |()| String::from_utf8(output).unwrap());
+            assert_eq!(output.as_deref().map_err(|e| e.clone()), expected_output, "options: {:?}, threads: {}", options, threads);
+        }
+    }
+
+    const PLAIN: Options = Options { records: false, variants: false, numbers: false };
+    const PPX: Options = Options { records: true, variants: true, numbers: true };
+
+    #[test]
+    fn test_plain() {
+        run_test(PLAIN, br#"foo (a "b\nc" (d)) () ((x 1))"#,
+                 Ok("\"foo\"\n[\"a\",\"b\\nc\",[\"d\"]]\n[]\n[[\"x\",\"1\"]]\n"));
+    }
+
+    #[test]
+    fn test_records() {
+        run_test(Options { records: true, ..PLAIN },
+                 br#"((name foo) (deps (a b)) (sub ((x 1)))) ((a 1) (b)) ((a 1) c)"#,
+                 Ok("{\"name\":\"foo\",\"deps\":[\"a\",\"b\"],\"sub\":{\"x\":\"1\"}}\n[[\"a\",\"1\"],[\"b\"]]\n[[\"a\",\"1\"],\"c\"]\n"));
+    }
+
+    #[test]
+    fn test_variants() {
+        run_test(PPX, br#"(Some 1) (Pair 1 "2") (lower 1) None ((x (Ok 1.5)))"#,
+                 Ok("{\"Some\":1}\n{\"Pair\":[1,\"2\"]}\n[\"lower\",1]\n\"None\"\n{\"x\":{\"Ok\":1.5}}\n"));
+    }
+
+    #[test]
+    fn test_numbers() {
+        run_test(Options { numbers: true, ..PLAIN }, br#"(1 -2.5e3 "3" 007 1_000 nan)"#,
+                 Ok("[1,-2.5e3,\"3\",\"007\",\"1_000\",\"nan\"]\n"));
+    }
+
+    #[test]
+    fn test_many_lines() {
+        let input = b"((id 1) (name \"x y\"))\n".repeat(100000);
+        let expected_output = "{\"id\":1,\"name\":\"x y\"}\n".repeat(100000);
+        run_test(PPX, &input[..], Ok(&expected_output[..]));
+    }
+
+    #[test]
+    fn test_errors() {
+        run_test(PLAIN, b"(a", Err(parser::Error::UnmatchedOpenParen));
+        run_test(PLAIN, b"a)", Err(parser::Error::UnmatchedCloseParen));
+        run_test(PLAIN, b"\"a", Err(parser::Error::BadQuotedAtom));
+    }
+}
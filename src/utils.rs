@@ -235,3 +235,56 @@ pub fn stdout() -> std::io::BufWriter<std::fs::File> {
     let stdout = unsafe { std::fs::File::from_raw_fd(1) };
     std::io::BufWriter::with_capacity(1048576, stdout)
 }
+
+/// Maps `file` into memory if it is a regular file. The file must not be
+/// modified while the map is alive.
+#[cfg(feature = "mmap")]
+pub fn mmap<FileT: std::os::unix::io::AsFd>(file: &FileT) -> Option<memmap2::Mmap> {
+    let file = std::fs::File::from(file.as_fd().try_clone_to_owned().ok()?);
+    if !file.metadata().ok()?.is_file() {
+        return None;
+    }
+    unsafe { memmap2::Mmap::map(&file) }.ok()
+}
+
+/// Processes `file` in place if it can be memory-mapped (with the `mmap`
+/// feature), and reads it otherwise.
+pub fn process_file<StreamT: crate::parser::Stream<std::io::BufReader<std::fs::File>> + ?Sized>
+    (parser: &mut StreamT, file: std::fs::File)
+    -> Result<StreamT::Return, crate::parser::Error>
+{
+    #[cfg(feature = "mmap")]
+    if let Some(input) = mmap(&file) {
+        return parser.process_slice(&input[..]);
+    }
+    parser.process_streaming(&mut std::io::BufReader::with_capacity(1048576, file))
+}
+
+pub fn process_stdin<StreamT: crate::parser::Stream<std::io::BufReader<std::fs::File>> + ?Sized>
+    (parser: &mut StreamT)
+    -> Result<StreamT::Return, crate::parser::Error>
+{
+    process_file(parser, stdin().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_file() {
+        let input = b"(a b)\n(c (d e))\n";
+        let path = std::env::temp_dir().join(format!("simd-sexp-test-process-file-{}", std::process::id()));
+        std::fs::write(&path, input).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        #[cfg(feature = "mmap")]
+        assert_eq!(&mmap(&file).unwrap()[..], input);
+
+        let mut expected = Vec::new();
+        crate::print::make(&mut expected, false).process_streaming(&mut &input[..]).unwrap();
+        let mut output = Vec::new();
+        process_file(&mut *crate::print::make(&mut output, true), file).unwrap();
+        assert_eq!(output, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
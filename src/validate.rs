@@ -0,0 +1,166 @@
+use crate::escape::{self, Unescape};
+use crate::parser;
+#[cfg(feature = "threads")]
+use crate::parser_parallel;
+#[cfg(feature = "threads")]
+use crate::structural;
+use crate::utils;
+use std::io::BufRead;
+
+/// Checks that the input is well-formed, returning the number of top-level
+/// sexps.
+pub struct Stage2 {
+    depth: usize,
+    num_sexps: usize,
+    unescape: escape::GenericUnescape,
+    buf: Vec<u8>,
+}
+
+impl Stage2 {
+    pub fn new() -> Self {
+        Self {
+            depth: 0,
+            num_sexps: 0,
+            unescape: escape::GenericUnescape::new(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Default for Stage2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl parser::Stage2 for Stage2 {
+    type Return = usize;
+
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.depth = 0;
+        self.num_sexps = 0;
+    }
+
+    #[inline]
+    fn process_one(&mut self, input: parser::Input, this_index: usize, next_index: usize, _is_eof: bool) -> Result<usize, parser::Error> {
+        match input.input[this_index - input.offset] {
+            b'(' => self.depth += 1,
+            b')' => {
+                if self.depth == 0 {
+                    utils::cold();
+                    return Err(parser::Error::UnmatchedCloseParen);
+                }
+                self.depth -= 1;
+                if self.depth == 0 {
+                    self.num_sexps += 1;
+                }
+            },
+            b' ' | b'\t' | b'\n' => (),
+            ch => {
+                if ch == b'"' {
+                    let atom = &input.input[(this_index + 1 - input.offset)..(next_index - input.offset)];
+                    self.buf.resize(atom.len(), 0u8);
+                    self.unescape.unescape(atom, &mut self.buf[..]).ok_or(parser::Error::BadQuotedAtom)?;
+                }
+                if self.depth == 0 {
+                    self.num_sexps += 1;
+                }
+            },
+        }
+        Ok(next_index)
+    }
+
+    fn process_eof(&mut self) -> Result<Self::Return, parser::Error> {
+        if self.depth > 0 {
+            return Err(parser::Error::UnmatchedOpenParen);
+        }
+        Ok(self.num_sexps)
+    }
+}
+
+/// Sums the number of sexps in each chunk.
+#[cfg(feature = "threads")]
+struct Joiner<'a, WorkerT> {
+    create_worker: Box<dyn Fn() -> WorkerT + 'a>,
+    num_sexps: usize,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, WorkerT: parser::Parse<Return = usize>> parser_parallel::Joiner for Joiner<'a, WorkerT> {
+    type Worker = WorkerT;
+    type Return = usize;
+    fn reset(&mut self, _input_size_hint: Option<usize>) {
+        self.num_sexps = 0;
+    }
+    fn create_worker(&mut self) -> Self::Worker {
+        (self.create_worker)()
+    }
+    fn join(&mut self, result: usize) -> Result<(), parser::Error> {
+        self.num_sexps += result;
+        Ok(())
+    }
+    fn process_eof(&mut self) -> Result<usize, parser::Error> {
+        Ok(self.num_sexps)
+    }
+}
+
+#[cfg(feature = "threads")]
+struct MakeParallelStreamingFromClassifierCps<BufReadT> {
+    config: parser_parallel::ParallelConfig,
+    phantom: std::marker::PhantomData<*const BufReadT>,
+}
+
+#[cfg(feature = "threads")]
+impl<'a, BufReadT: BufRead + Send> structural::MakeClassifierCps<'a> for MakeParallelStreamingFromClassifierCps<BufReadT> {
+    type Return = Box<dyn parser::Stream<BufReadT, Return = usize> + 'a>;
+    fn f<ClassifierT: structural::Classifier + 'a>(self, classifier: ClassifierT) -> Self::Return {
+        let create_worker = move || parser::State::new(classifier.clone(), Stage2::new());
+        parser_parallel::streaming_new(Joiner { create_worker: Box::new(create_worker), num_sexps: 0 }, self.config)
+    }
+}
+
+pub fn make_parser<'a, ReadT: BufRead + Send>(threads: bool) -> Box<dyn parser::Stream<ReadT, Return = usize> + 'a> {
+    #[cfg(feature = "threads")]
+    if threads {
+        let config = parser_parallel::ParallelConfig::default();
+        return structural::make_classifier_cps(MakeParallelStreamingFromClassifierCps { config, phantom: std::marker::PhantomData });
+    }
+
+    #[cfg(not(feature = "threads"))]
+    let _ = threads;
+
+    parser::streaming_new(Stage2::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_test(input: &[u8], expected_output: Result<usize, parser::Error>) {
+        let threads_options: &[bool] = if cfg!(feature = "threads") { &[false, true] } else { &[false] };
+        for &threads in threads_options {
+            let output = make_parser(threads).process_streaming(&mut std::io::BufReader::new(input));
+            assert_eq!(output, expected_output, "input: {:?}, threads: {}", String::from_utf8_lossy(input), threads);
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        run_test(b"", Ok(0));
+        run_test(b"a (b c) \"d e\"\n((f))", Ok(4));
+        run_test(b"(a \"\\\"\" (b))\n()\n", Ok(2));
+    }
+
+    #[test]
+    fn test_invalid() {
+        run_test(b"(a (b)", Err(parser::Error::UnmatchedOpenParen));
+        run_test(b"(a))", Err(parser::Error::UnmatchedCloseParen));
+        run_test(b"(a \"b)", Err(parser::Error::BadQuotedAtom));
+    }
+
+    #[test]
+    fn test_many_lines() {
+        let input = b"((foo \"a b\") (bar 1)) baz\n".repeat(100000);
+        run_test(&input[..], Ok(200000));
+    }
+}